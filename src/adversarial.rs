@@ -0,0 +1,257 @@
+// Adversarial inputs, checked not to panic.
+//
+// A service taking messages, proofs, pages and patches from peers must turn
+// bad input into errors, not panics. These tests drive every decoder and
+// every entry point that takes peer input with seeded random mutations of
+// valid encodings and with randomly built messages, and random operation
+// sequences through the range and structure queries. A panic anywhere fails
+// the test; the seed is fixed, so a failure replays.
+//
+// Every write merged from a peer goes through `insert_replicated`, which
+// returns the tree's refusal (its depth limit, a collision) for the
+// reconciler to report as a fault, and pages read from a store or a peer
+// fail as malformed however they decode. Panics that remain are local
+// contracts: `insert`, `new` and friends panic where `try_insert` and
+// `try_new` return the error, builders reject nonsensical parameters, and
+// the `unreachable!`s guard the shape of nodes the tree built itself.
+
+use crate::core::codec::{Decode, Encode};
+use crate::core::hash::NodeHash;
+use crate::core::range::KeyRange;
+use crate::core::rng::Rng;
+use crate::core::tree::MerkleSearchTree;
+use crate::crdt::patch::{Patch, create_patch};
+use crate::proof::Proof;
+use crate::proof::embedded::verify_proof;
+use crate::store::mapped::MappedTree;
+use crate::store::snapshot::Manifest;
+use crate::store::{MemoryStore, Store};
+use crate::sync::handshake::Hello;
+use crate::sync::wire::WireFormat;
+use crate::sync::{FaultKind, Message, Reconciler};
+
+type Merge = fn(&String, &String) -> String;
+
+fn lww(local: &String, remote: &String) -> String {
+    local.max(remote).clone()
+}
+
+// Overwrites, flips, inserts or truncates a few bytes.
+fn mutate(rng: &mut Rng, bytes: &[u8]) -> Vec<u8> {
+    let mut bytes = bytes.to_vec();
+    for _ in 0..=rng.below(4) {
+        let at = rng.below(bytes.len() as u64 + 1) as usize;
+        match rng.below(4) {
+            0 if at < bytes.len() => bytes[at] = rng.next_u64() as u8,
+            1 if at < bytes.len() => bytes[at] ^= 0xff,
+            2 => bytes.insert(at, rng.next_u64() as u8),
+            _ => bytes.truncate(at),
+        }
+    }
+    bytes
+}
+
+fn tree(entries: u32) -> MerkleSearchTree<u32> {
+    let mut tree = MerkleSearchTree::new(4);
+    for i in 0..entries {
+        tree.insert(i * 2, format!("v{i}"));
+    }
+    tree
+}
+
+fn key(rng: &mut Rng) -> u32 {
+    rng.below(700) as u32
+}
+
+fn range(rng: &mut Rng) -> KeyRange<u32> {
+    let bound = |rng: &mut Rng| rng.chance(0.7).then(|| key(rng));
+    KeyRange {
+        start: bound(rng),
+        end: bound(rng),
+    }
+}
+
+fn hash(rng: &mut Rng, tree: &MerkleSearchTree<u32>) -> NodeHash {
+    match rng.below(3) {
+        0 => NodeHash::default(),
+        1 => *tree.hash(),
+        _ => NodeHash::digest(&rng.next_u64().to_be_bytes()),
+    }
+}
+
+#[test]
+fn test_mutated_encodings() {
+    let tree = tree(300);
+    let mut store = MemoryStore::new();
+    let (manifest, _) = tree.write_snapshot(&mut store).unwrap();
+    let (root_page, root_hash) = (manifest.root_page, manifest.root_hash);
+    let proof = manifest.prove::<u32, String, _>(&store, &20).unwrap();
+    let (proof_bytes, proof_json) = (proof.to_bytes(), proof.to_json());
+    let mut manifest_bytes = Vec::new();
+    manifest.encode(&mut manifest_bytes);
+    let mut hello = Vec::new();
+    Hello::for_tree(&tree, WireFormat::new()).encode(&mut hello);
+    let mut other = tree.fork();
+    other.insert(5, "x".to_string());
+    other.remove(&100);
+    let mut patch = Vec::new();
+    create_patch(&tree, &other).encode(&mut patch);
+    let canonical = tree.canonical_bytes();
+
+    let reconciler = Reconciler::new(lww as Merge);
+    let wire = WireFormat::new();
+    let mut messages = Vec::new();
+    for opening in [reconciler.start(&tree), reconciler.start_tiered(&tree)] {
+        for reply in reconciler.handle(&mut other.fork(), opening) {
+            messages.push(wire.encode_frame(&reply).unwrap());
+        }
+    }
+
+    let ids = store.ids().unwrap();
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+    for _ in 0..500 {
+        let bytes = mutate(&mut rng, &proof_bytes);
+        if let Ok(proof) = Proof::from_bytes(&bytes, usize::MAX) {
+            let _ = proof.verify::<u32, String>(&root_page, &root_hash, &20);
+            let _ = Proof::verify_batch::<u32, String>(&root_page, &root_hash, &[(20, proof)]);
+        }
+        let _ = verify_proof::<u32>(&bytes, &root_page, &root_hash, &20u32.to_be_bytes());
+        let json = mutate(&mut rng, proof_json.as_bytes());
+        let _ = Proof::from_json(&String::from_utf8_lossy(&json), usize::MAX);
+        let _ = Manifest::decode(&mut mutate(&mut rng, &manifest_bytes).as_slice());
+        let _ = Hello::decode(&mut mutate(&mut rng, &hello).as_slice());
+
+        for message in &messages {
+            let frame = mutate(&mut rng, message);
+            if let Ok(message) = wire.decode_frame::<Message<u32, String>>(&frame) {
+                let _ = reconciler.handle_checked(&mut tree.fork(), message);
+            }
+        }
+        if let Ok(patch) = Patch::<u32, String>::decode(&mut mutate(&mut rng, &patch).as_slice()) {
+            let mut target = tree.fork();
+            let _ = patch.invert(&target);
+            let _ = target.apply_patch(patch);
+        }
+        if let Ok(mapped) = MappedTree::open_fixed(mutate(&mut rng, &canonical), 4) {
+            let _ = mapped.verify();
+            let _ = mapped.iter_from(&[0, 0, 0, 7]).count();
+        }
+
+        // A store serving one corrupted page.
+        let mut corrupted = MemoryStore::new();
+        for id in &ids {
+            corrupted
+                .put(*id, &store.get(id).unwrap().unwrap())
+                .unwrap();
+        }
+        let id = ids[rng.below(ids.len() as u64) as usize];
+        corrupted
+            .put(id, &mutate(&mut rng, &store.get(&id).unwrap().unwrap()))
+            .unwrap();
+        let _ = MerkleSearchTree::<u32>::new(4).restore(&corrupted, &manifest);
+        let _ = manifest.get::<u32, String, _>(&corrupted, &40);
+    }
+}
+
+#[test]
+fn test_adversarial_operations() {
+    let reconciler = Reconciler::new(lww as Merge);
+    let mut rng = Rng(12345);
+    for _ in 0..100 {
+        let mut tree = MerkleSearchTree::<u32>::new(3 + rng.below(5) as usize);
+        for i in 0..rng.below(300) {
+            tree.insert(key(&mut rng), format!("v{}", i % 7));
+        }
+        for _ in 0..40 {
+            let mut target = tree.fork();
+            let (a, b) = (key(&mut rng), key(&mut rng));
+            let entries: Vec<(u32, String)> = (0..rng.below(6))
+                .map(|_| (key(&mut rng), format!("e{}", rng.below(3))))
+                .collect();
+            let message = match rng.below(5) {
+                0 => Message::Fingerprint {
+                    range: range(&mut rng),
+                    hash: hash(&mut rng, &tree),
+                },
+                1 => Message::Entries {
+                    range: range(&mut rng),
+                    entries,
+                    reply: rng.chance(0.5),
+                    hash: hash(&mut rng, &tree),
+                },
+                2 => Message::Summary {
+                    total: rng.below(500) as usize,
+                    ranges: (0..rng.below(5))
+                        .map(|_| {
+                            let count = rng.below(50) as usize;
+                            (range(&mut rng), hash(&mut rng, &tree), count)
+                        })
+                        .collect(),
+                },
+                3 => Message::Delta {
+                    since: hash(&mut rng, &tree),
+                    changes: entries
+                        .into_iter()
+                        .map(|(key, value)| (key, rng.chance(0.5).then_some(value)))
+                        .collect(),
+                    hash: hash(&mut rng, &tree),
+                },
+                _ => Message::Since {
+                    root: hash(&mut rng, &tree),
+                },
+            };
+            let _ = reconciler.handle_checked(&mut target, message);
+
+            // Inverted and empty ranges included.
+            let _ = target.range(a..b).count();
+            let _ = (target.range_hash(a..b), target.range_root(a..=b));
+            let _ = target.usage(b..a);
+            let _ = (target.select(a as usize), target.rank(&a));
+            let _ = target.subtree_roots_at_depth(rng.below(6) as usize);
+            let _ = target.top_divergent_ranges(&tree, rng.below(6) as usize);
+            let _ = reconciler.start_range(&target, range(&mut rng));
+            let _ = target.extract_subtree(a..b);
+            let _ = target.remove_range(b..a);
+        }
+    }
+}
+
+// A peer shipping more entries than a depth-limited tree can take gets a
+// fault back, whether it lists them or sends them as a delta.
+#[test]
+fn test_entries_past_the_depth_limit() {
+    let reconciler = Reconciler::new(lww as Merge);
+    let entries: Vec<(u32, String)> = (0..10_000).map(|i| (i, format!("v{i}"))).collect();
+    let mut hash = NodeHash::default();
+    for (key, value) in &entries {
+        hash.xor(&NodeHash::leaf(key, value.as_bytes()));
+    }
+    let messages = [
+        Message::Entries {
+            range: KeyRange::full(),
+            entries: entries.clone(),
+            reply: true,
+            hash,
+        },
+        Message::Entries {
+            range: KeyRange::full(),
+            entries: entries.clone(),
+            reply: false,
+            hash,
+        },
+        Message::Delta {
+            since: NodeHash::empty_root(),
+            changes: entries
+                .into_iter()
+                .map(|(key, value)| (key, Some(value)))
+                .collect(),
+            hash,
+        },
+    ];
+    for message in messages {
+        let mut tree = MerkleSearchTree::<u32>::new(3).with_max_depth(3);
+        let fault = reconciler.handle_checked(&mut tree, message).unwrap_err();
+        assert_eq!(fault.kind, FaultKind::DepthLimitExceeded { limit: 3 });
+        assert!(tree.depth() <= 3);
+    }
+}
@@ -0,0 +1,192 @@
+// Where tree nodes are allocated.
+//
+// Every node is held through a `NodeRef`, a reference-counted handle that
+// forks share. By default that's a plain `Arc`. With the `allocator`
+// feature, a node's body is boxed in an `allocator_api2::Allocator` chosen
+// per tree with `MerkleSearchTree::with_allocator`, so an embedding
+// application can keep nodes in an arena, in hugepages or behind an
+// instrumented allocator. Only the body itself is placed there: its key,
+// value, hash and the child list's header. The heap buffers those own,
+// such as an internal node's array of child handles, stay with the global
+// allocator, and so does the reference count: `Arc::new_in` is
+// nightly-only, and the crate forbids the unsafe code a custom count needs.
+//
+// A node's copies and splits reuse the allocator of the node they came
+// from, so a tree's nodes all stay in the allocator it was built with.
+
+use std::cmp::Ordering;
+use std::ops::Deref;
+use std::sync::Arc;
+
+#[cfg(feature = "allocator")]
+pub use allocator_api2::alloc::Allocator;
+#[cfg(feature = "allocator")]
+use allocator_api2::alloc::Global;
+#[cfg(feature = "allocator")]
+use allocator_api2::boxed::Box;
+
+// The allocator a tree places its nodes in.
+#[cfg(feature = "allocator")]
+#[derive(Clone, Copy)]
+pub(crate) struct NodeAlloc(&'static (dyn Allocator + Sync));
+
+#[cfg(feature = "allocator")]
+impl NodeAlloc {
+    pub(crate) fn new(alloc: &'static (dyn Allocator + Sync)) -> Self {
+        NodeAlloc(alloc)
+    }
+}
+
+#[cfg(feature = "allocator")]
+impl Default for NodeAlloc {
+    fn default() -> Self {
+        NodeAlloc(&Global)
+    }
+}
+
+// Without the feature, nodes always go to the global allocator.
+#[cfg(not(feature = "allocator"))]
+#[derive(Clone, Copy, Default)]
+pub(crate) struct NodeAlloc(());
+
+#[cfg(feature = "allocator")]
+type Body<T> = Box<T, &'static (dyn Allocator + Sync)>;
+#[cfg(not(feature = "allocator"))]
+type Body<T> = T;
+
+// A shared handle to a node, allocated with a tree's `NodeAlloc`.
+pub(crate) struct NodeRef<T>(Arc<Body<T>>);
+
+impl<T> NodeRef<T> {
+    #[cfg(feature = "allocator")]
+    pub(crate) fn new(value: T, alloc: NodeAlloc) -> Self {
+        NodeRef(Arc::new(Box::new_in(value, alloc.0)))
+    }
+
+    #[cfg(not(feature = "allocator"))]
+    pub(crate) fn new(value: T, _alloc: NodeAlloc) -> Self {
+        NodeRef(Arc::new(value))
+    }
+
+    // The allocator this node was placed in, for the nodes copied from it.
+    #[cfg(feature = "allocator")]
+    pub(crate) fn alloc(&self) -> NodeAlloc {
+        NodeAlloc(*Box::allocator(&self.0))
+    }
+
+    #[cfg(not(feature = "allocator"))]
+    pub(crate) fn alloc(&self) -> NodeAlloc {
+        NodeAlloc(())
+    }
+
+    // The node, if no fork shares it.
+    pub(crate) fn get_mut(this: &mut Self) -> Option<&mut T> {
+        Arc::get_mut(&mut this.0).map(|body| -> &mut T { body })
+    }
+
+    // The node itself if this was its only handle, or the handle back.
+    #[cfg(feature = "allocator")]
+    pub(crate) fn try_unwrap(this: Self) -> Result<T, Self> {
+        Arc::try_unwrap(this.0)
+            .map(Box::into_inner)
+            .map_err(NodeRef)
+    }
+
+    #[cfg(not(feature = "allocator"))]
+    pub(crate) fn try_unwrap(this: Self) -> Result<T, Self> {
+        Arc::try_unwrap(this.0).map_err(NodeRef)
+    }
+
+    pub(crate) fn ptr_eq(this: &Self, other: &Self) -> bool {
+        Arc::ptr_eq(&this.0, &other.0)
+    }
+}
+
+impl<T> Clone for NodeRef<T> {
+    fn clone(&self) -> Self {
+        NodeRef(Arc::clone(&self.0))
+    }
+}
+
+impl<T> Deref for NodeRef<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+// An empty node in the global allocator, for placeholders and new trees.
+impl<T: Default> Default for NodeRef<T> {
+    fn default() -> Self {
+        NodeRef::new(T::default(), NodeAlloc::default())
+    }
+}
+
+// Handles compare by their nodes, as `Arc`s do.
+impl<T: PartialEq> PartialEq for NodeRef<T> {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl<T: Eq> Eq for NodeRef<T> {}
+
+impl<T: PartialOrd> PartialOrd for NodeRef<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        (**self).partial_cmp(&**other)
+    }
+}
+
+impl<T: Ord> Ord for NodeRef<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (**self).cmp(&**other)
+    }
+}
+
+#[cfg(all(test, feature = "allocator"))]
+mod test {
+    use crate::core::tree::{MerkleSearchTree, Node};
+    use blink_alloc::SyncBlinkAlloc;
+
+    // Whether every node of `tree` was placed in `arena`.
+    fn placed_in(tree: &MerkleSearchTree<u32>, arena: &SyncBlinkAlloc) -> bool {
+        let mut stack = vec![&tree.root];
+        while let Some(node) = stack.pop() {
+            if !std::ptr::addr_eq(node.alloc().0, arena) {
+                return false;
+            }
+            if let Node::Internal { children, .. } = &**node {
+                stack.extend(children.iter());
+            }
+        }
+        true
+    }
+
+    #[test]
+    fn test_nodes_in_arena() {
+        let arena: &'static SyncBlinkAlloc = std::boxed::Box::leak(Default::default());
+        let mut tree = MerkleSearchTree::new(4).with_allocator(arena);
+        let mut plain = MerkleSearchTree::new(4);
+        for i in 0..200u32 {
+            tree.insert(i, i.to_string());
+            plain.insert(i, i.to_string());
+        }
+        assert_eq!(tree.hash(), plain.hash());
+        assert!(placed_in(&tree, arena));
+        assert!(!placed_in(&plain, arena));
+
+        // Copies made for a fork, and the trees split off it, stay in the arena.
+        let mut fork = tree.fork();
+        for i in 0..50u32 {
+            fork.insert(i, "forked".to_string());
+        }
+        fork.remove(&60);
+        let extracted = fork.extract_subtree(100..150);
+        assert_eq!(extracted.len(), 50);
+        assert!(placed_in(&fork, arena));
+        assert!(placed_in(&extracted, arena));
+        assert!(placed_in(&tree, arena));
+        assert_eq!(tree.hash(), plain.hash());
+    }
+}
@@ -0,0 +1,462 @@
+// Named branches of a tree, plus the diff and comparisons used to merge and
+// check them.
+//
+// Branches are forks, so they share every node they have in common and
+// creating one costs O(1). The diff skips any subtree whose hash matches the
+// other tree's hash over the same key range, so comparing two branches that
+// differ in a few keys touches only the paths to those keys.
+
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+use crate::core::alloc::NodeRef;
+use crate::core::budget::{Budget, Progress};
+use crate::core::codec::Encode;
+use crate::core::error::Error;
+use crate::core::hash::NodeHash;
+use crate::core::tree::{MerkleSearchTree, Node};
+
+// One difference between two trees, from the point of view of `self` in
+// `self.diff(other)`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Diff<'a, K, V> {
+    // Only in `other`.
+    Added(&'a K, &'a V),
+    // Only in `self`.
+    Removed(&'a K, &'a V),
+    Changed {
+        key: &'a K,
+        ours: &'a V,
+        theirs: &'a V,
+    },
+}
+
+// Where a budgeted diff stopped: every key up to `after` is compared.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DiffCursor<K> {
+    pub after: Option<K>,
+}
+
+pub type DiffProgress<'a, K, V> = Progress<Vec<Diff<'a, K, V>>, DiffCursor<K>>;
+
+// A node still to compare, with the exclusive lower and inclusive upper bound
+// of its key range. None is unbounded.
+type Pending<'a, K, V> = (&'a Node<K, V>, Option<&'a K>, Option<&'a K>);
+
+impl<K: Ord + Clone + Default + Encode, V: AsRef<[u8]>> MerkleSearchTree<K, V> {
+    // The entries that differ between the two trees, in key order.
+    pub fn diff<'a>(&'a self, other: &'a Self) -> Vec<Diff<'a, K, V>> {
+        let Ok(progress) = self.walk_diff(other, false, None, &mut Budget::unlimited()) else {
+            unreachable!("only checked diffs fail");
+        };
+        progress.result
+    }
+
+    // `diff`, but if either tree has collision checks on, every leaf is
+    // compared by its bytes, and values that differ under equal digests fail
+    // with `Error::CollisionDetected`.
+    pub fn try_diff<'a>(&'a self, other: &'a Self) -> Result<Vec<Diff<'a, K, V>>, Error> {
+        let checked = self.collision_checks || other.collision_checks;
+        let progress = self.walk_diff(other, checked, None, &mut Budget::unlimited())?;
+        Ok(progress.result)
+    }
+
+    // `diff`, stopping when `budget` runs out. Pass the returned cursor back
+    // in to carry on with the keys after those already compared.
+    pub fn diff_with_budget<'a>(
+        &'a self,
+        other: &'a Self,
+        resume: Option<&DiffCursor<K>>,
+        budget: &mut Budget,
+    ) -> DiffProgress<'a, K, V> {
+        let after = resume.and_then(|cursor| cursor.after.as_ref());
+        let Ok(progress) = self.walk_diff(other, false, after, budget) else {
+            unreachable!("only checked diffs fail");
+        };
+        progress
+    }
+
+    // Compares the keys after `after`, one budget unit per node.
+    fn walk_diff<'a>(
+        &'a self,
+        other: &'a Self,
+        checked: bool,
+        after: Option<&K>,
+        budget: &mut Budget,
+    ) -> Result<DiffProgress<'a, K, V>, Error> {
+        let mut diffs = Vec::new();
+        let done = |upper: Option<&K>| {
+            upper.is_some_and(|upper| after.is_some_and(|after| upper <= after))
+        };
+
+        // Each pending node comes with the key range it is responsible for.
+        // The ranges of the children partition their parent's, so keys only
+        // `other` has are still covered. Nodes are taken in key order, so
+        // when the budget runs out, every key up to the next node's range is
+        // done.
+        let mut stack: Vec<Pending<K, V>> = vec![(&*self.root, None, None)];
+        // Each call compares at least one node of leaves before stopping.
+        let mut compared = false;
+        while let Some((node, lower, upper)) = stack.pop() {
+            let from = lower.max(after);
+            if compared && !budget.spend() {
+                return Ok(Progress {
+                    result: diffs,
+                    resume: Some(DiffCursor {
+                        after: from.cloned(),
+                    }),
+                });
+            }
+            let Node::Internal { children, .. } = node else {
+                unreachable!("leaves are handled by their parent");
+            };
+            let bounds = (
+                from.map_or(Bound::Unbounded, Bound::Excluded),
+                upper.map_or(Bound::Unbounded, Bound::Included),
+            );
+
+            if node.are_children_leaves() {
+                let skipped = from.map_or(0, |from| {
+                    children.partition_point(|child| child.key() <= from)
+                });
+                let ours = &children[skipped..];
+                Self::diff_leaves(ours, other.range(bounds), &mut diffs, checked)?;
+                compared = true;
+                continue;
+            }
+
+            let mut pending = Vec::with_capacity(children.len());
+            let mut child_lower = lower;
+            for (i, child) in children.iter().enumerate() {
+                let child_upper = if i + 1 == children.len() {
+                    upper
+                } else {
+                    Some(child.key())
+                };
+                let range = (
+                    child_lower.map_or(Bound::Unbounded, Bound::Excluded),
+                    child_upper.map_or(Bound::Unbounded, Bound::Included),
+                );
+                if !done(child_upper) && (checked || other.range_hash(range) != *child.hash()) {
+                    pending.push((&**child, child_lower, child_upper));
+                }
+                child_lower = Some(child.key());
+            }
+            stack.extend(pending.into_iter().rev());
+        }
+        Ok(Progress {
+            result: diffs,
+            resume: None,
+        })
+    }
+
+    // Whether both trees hold the same entries. Shared or differing roots
+    // answer right away; equal roots of distinct trees are confirmed leaf by
+    // leaf, since an XOR of hashes is far easier to collide than a hash.
+    pub fn content_eq(&self, other: &Self) -> bool {
+        NodeRef::ptr_eq(&self.root, &other.root)
+            || (self.hash() == other.hash()
+                && self.len() == other.len()
+                && self.leaf_hashes().eq(other.leaf_hashes()))
+    }
+
+    // Whether every entry of this tree is also in `other`, with the same
+    // value. Subtrees that hash the same as `other` over their range are
+    // taken as contained without descending.
+    pub fn is_subset_of(&self, other: &Self) -> bool {
+        self.len() <= other.len()
+            && self
+                .diff(other)
+                .iter()
+                .all(|diff| matches!(diff, Diff::Added(..)))
+    }
+
+    // Merge-joins our leaves with `theirs`, both in key order.
+    fn diff_leaves<'a>(
+        ours: &'a [NodeRef<Node<K, V>>],
+        theirs: impl Iterator<Item = (&'a K, &'a V)>,
+        diffs: &mut Vec<Diff<'a, K, V>>,
+        checked: bool,
+    ) -> Result<(), Error> {
+        let mut ours = ours.iter().map(|leaf| match &**leaf {
+            Node::Leaf { key, value, hash } => (key, value, hash),
+            Node::Internal { .. } => unreachable!("the children are leaves"),
+        });
+        let mut theirs =
+            theirs.map(|(key, value)| (key, value, NodeHash::leaf(key, value.as_ref())));
+        let mut a = ours.next();
+        let mut b = theirs.next();
+        loop {
+            match (a, &b) {
+                (None, None) => return Ok(()),
+                (Some((key, value, _)), None) => {
+                    diffs.push(Diff::Removed(key, value));
+                    a = ours.next();
+                }
+                (None, Some((key, value, _))) => {
+                    diffs.push(Diff::Added(key, value));
+                    b = theirs.next();
+                }
+                (
+                    Some((key, ours_value, ours_hash)),
+                    Some((their_key, theirs_value, theirs_hash)),
+                ) => match key.cmp(their_key) {
+                    Ordering::Less => {
+                        diffs.push(Diff::Removed(key, ours_value));
+                        a = ours.next();
+                    }
+                    Ordering::Greater => {
+                        diffs.push(Diff::Added(their_key, theirs_value));
+                        b = theirs.next();
+                    }
+                    Ordering::Equal => {
+                        if ours_hash != theirs_hash {
+                            diffs.push(Diff::Changed {
+                                key,
+                                ours: ours_value,
+                                theirs: theirs_value,
+                            });
+                        } else if checked && ours_value.as_ref() != theirs_value.as_ref() {
+                            return Err(Error::CollisionDetected(ours_hash.to_string()));
+                        }
+                        a = ours.next();
+                        b = theirs.next();
+                    }
+                },
+            }
+        }
+    }
+}
+
+// A registry of named trees, all forked from one another.
+pub struct Branches<K, V = String> {
+    branches: BTreeMap<String, MerkleSearchTree<K, V>>,
+}
+
+impl<K: Ord + Clone + Default + Encode, V: AsRef<[u8]>> Branches<K, V> {
+    pub const MAIN: &'static str = "main";
+
+    // Starts a registry holding `main` under the name "main".
+    pub fn new(main: MerkleSearchTree<K, V>) -> Self {
+        Branches {
+            branches: BTreeMap::from([(Self::MAIN.to_string(), main)]),
+        }
+    }
+
+    // Creates the branch `name` as a fork of `from`.
+    pub fn fork(&mut self, from: &str, name: &str) -> Result<&mut MerkleSearchTree<K, V>, Error> {
+        if self.branches.contains_key(name) {
+            return Err(Error::BranchExists(name.to_string()));
+        }
+        let fork = self.branch(from)?.fork();
+        Ok(self.branches.entry(name.to_string()).or_insert(fork))
+    }
+
+    pub fn get(&self, name: &str) -> Option<&MerkleSearchTree<K, V>> {
+        self.branches.get(name)
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut MerkleSearchTree<K, V>> {
+        self.branches.get_mut(name)
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<MerkleSearchTree<K, V>> {
+        self.branches.remove(name)
+    }
+
+    // Every branch name with its root hash, in name order.
+    pub fn roots(&self) -> impl Iterator<Item = (&str, &NodeHash)> {
+        self.branches
+            .iter()
+            .map(|(name, tree)| (name.as_str(), tree.hash()))
+    }
+
+    // What changes going from branch `from` to branch `to`.
+    pub fn diff(&self, from: &str, to: &str) -> Result<Vec<Diff<'_, K, V>>, Error> {
+        Ok(self.branch(from)?.diff(self.branch(to)?))
+    }
+
+    // Applies every entry of `from` that is missing or different in `into`,
+    // taking `from`'s value. Entries only `into` has are kept: a two-way diff
+    // can't tell a removal in `from` from an addition in `into`. Returns the
+    // number of entries written.
+    pub fn merge(&mut self, from: &str, into: &str) -> Result<usize, Error>
+    where
+        V: Clone,
+    {
+        let updates: Vec<(K, V)> = self
+            .branch(into)?
+            .diff(self.branch(from)?)
+            .into_iter()
+            .filter_map(|diff| match diff {
+                Diff::Added(key, value)
+                | Diff::Changed {
+                    key, theirs: value, ..
+                } => Some((key.clone(), value.clone())),
+                Diff::Removed(..) => None,
+            })
+            .collect();
+
+        let target = self.branches.get_mut(into).expect("looked up above");
+        let written = updates.len();
+        for (key, value) in updates {
+            target.try_insert(key, value)?;
+        }
+        Ok(written)
+    }
+
+    fn branch(&self, name: &str) -> Result<&MerkleSearchTree<K, V>, Error> {
+        self.branches
+            .get(name)
+            .ok_or_else(|| Error::UnknownBranch(name.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::tree::InsertOutcome;
+
+    fn tree(n: u32) -> MerkleSearchTree<u32> {
+        let mut tree = MerkleSearchTree::new(4);
+        for i in 0..n {
+            tree.insert(i, format!("v{i}"));
+        }
+        tree
+    }
+
+    #[test]
+    fn test_fork_is_isolated() {
+        let main = tree(200);
+        let before = *main.hash();
+        let mut fork = main.fork();
+        assert_eq!(fork.hash(), main.hash());
+
+        fork.insert(7, "changed".to_string());
+        fork.insert(500, "new".to_string());
+        assert_eq!(*main.hash(), before);
+        assert_eq!(main.get(&7), Some(&"v7".to_string()));
+        assert_eq!(main.get(&500), None);
+        assert_eq!(fork.get(&7), Some(&"changed".to_string()));
+
+        drop(fork);
+        assert_eq!(main.iter().count(), 200);
+    }
+
+    #[test]
+    fn test_diff() {
+        let ours = tree(300);
+        let mut theirs = ours.fork();
+        theirs.insert(10, "changed".to_string());
+        theirs.insert(1000, "added".to_string());
+        let mut ours = ours;
+        ours.insert(999, "removed".to_string());
+
+        let changed = "changed".to_string();
+        let added = "added".to_string();
+        let removed = "removed".to_string();
+        let v10 = "v10".to_string();
+        assert_eq!(
+            ours.diff(&theirs),
+            vec![
+                Diff::Changed {
+                    key: &10,
+                    ours: &v10,
+                    theirs: &changed
+                },
+                Diff::Removed(&999, &removed),
+                Diff::Added(&1000, &added),
+            ]
+        );
+        assert!(theirs.diff(&theirs.fork()).is_empty());
+    }
+
+    #[test]
+    fn test_content_eq_and_subset() {
+        let small = tree(100);
+        let mut large = small.fork();
+        assert!(small.content_eq(&large));
+        large.insert(500, "extra".to_string());
+        assert!(!small.content_eq(&large));
+        assert!(small.is_subset_of(&large));
+        assert!(!large.is_subset_of(&small));
+
+        // Same entries, different layout.
+        let mut other = MerkleSearchTree::new(3);
+        for i in (0..100).rev() {
+            other.insert(i, format!("v{i}"));
+        }
+        assert!(small.content_eq(&other));
+        assert!(other.is_subset_of(&large));
+
+        // Swapped values change the root as well as the content.
+        other.insert(3, "v4".to_string());
+        other.insert(4, "v3".to_string());
+        assert_ne!(small.hash(), other.hash());
+        assert!(!small.content_eq(&other));
+    }
+
+    #[test]
+    fn test_branches_merge() {
+        let mut branches = Branches::new(tree(100));
+        let feature = branches.fork(Branches::<u32>::MAIN, "feature").unwrap();
+        feature.insert(5, "five".to_string());
+        feature.insert(200, "two hundred".to_string());
+        assert!(matches!(
+            branches.fork("main", "feature"),
+            Err(Error::BranchExists(_))
+        ));
+        assert!(matches!(
+            branches.diff("main", "missing"),
+            Err(Error::UnknownBranch(_))
+        ));
+        assert_eq!(branches.diff("main", "feature").unwrap().len(), 2);
+
+        assert_eq!(branches.merge("feature", "main").unwrap(), 2);
+        assert_eq!(
+            branches.get("main").unwrap().hash(),
+            branches.get("feature").unwrap().hash()
+        );
+        assert_eq!(branches.roots().count(), 2);
+    }
+
+    // Stands in for a digest collision: a leaf holding `value` under the
+    // hash of its old value.
+    fn forge(node: &mut NodeRef<Node<u32, String>>, target: u32, forged: &str) {
+        match NodeRef::get_mut(node).expect("the tree is unshared") {
+            Node::Leaf { key, value, .. } if *key == target => *value = forged.to_string(),
+            Node::Leaf { .. } => {}
+            Node::Internal { children, .. } => {
+                for child in children {
+                    forge(child, target, forged);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_collision_checks() {
+        let mut ours = tree(100);
+        let theirs = tree(100);
+        forge(&mut ours.root, 42, "forged");
+        assert_eq!(ours.hash(), theirs.hash());
+        assert!(ours.diff(&theirs).is_empty());
+        assert!(ours.try_diff(&theirs).unwrap().is_empty());
+
+        let theirs = theirs.with_collision_checks();
+        let expected = NodeHash::<32>::leaf(&42u32, b"v42").to_string();
+        assert!(matches!(
+            ours.try_diff(&theirs),
+            Err(Error::CollisionDetected(hash)) if hash == expected
+        ));
+
+        // Without checks, rewriting the real value is taken as a no-op.
+        let outcome = ours.try_insert(42, "v42".to_string()).unwrap();
+        assert_eq!(outcome, InsertOutcome::Unchanged);
+        let mut ours = ours.with_collision_checks();
+        let result = ours.try_insert(42, "v42".to_string());
+        assert!(matches!(result, Err(Error::CollisionDetected(_))));
+        assert_eq!(ours.get(&42), Some(&"forged".to_string()));
+    }
+}
@@ -0,0 +1,161 @@
+// Time and work budgets for long walks, so they fit in request handlers
+// with latency targets.
+//
+// The budgeted variants of `diff`, `export_stream` and `verify_deep` spend a
+// unit per node, chunk or page and stop once the budget is gone, returning
+// what they found so far and a cursor to resume from. Each call makes some
+// progress, however small the budget, so a loop of calls always finishes.
+
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Budget {
+    deadline: Option<Instant>,
+    // Units left to spend.
+    work: Option<u64>,
+}
+
+impl Budget {
+    pub fn unlimited() -> Self {
+        Budget::default()
+    }
+
+    pub fn until(deadline: Instant) -> Self {
+        Budget {
+            deadline: Some(deadline),
+            work: None,
+        }
+    }
+
+    pub fn within(timeout: Duration) -> Self {
+        Self::until(Instant::now() + timeout)
+    }
+
+    // Also stops after `units` units, whatever the clock says.
+    pub fn with_work_limit(mut self, units: u64) -> Self {
+        self.work = Some(units);
+        self
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        self.work == Some(0)
+            || self
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    // Takes a unit, false if there is none left.
+    pub(crate) fn spend(&mut self) -> bool {
+        if self.is_exhausted() {
+            return false;
+        }
+        if let Some(work) = &mut self.work {
+            *work -= 1;
+        }
+        true
+    }
+}
+
+// The outcome of a budgeted call: the results so far, and where to resume
+// if it stopped early.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Progress<T, C> {
+    pub result: T,
+    pub resume: Option<C>,
+}
+
+impl<T, C> Progress<T, C> {
+    pub fn is_complete(&self) -> bool {
+        self.resume.is_none()
+    }
+}
+
+#[cfg(all(test, feature = "store"))]
+mod test {
+    use super::*;
+    use crate::core::range::KeyRange;
+    use crate::core::tree::MerkleSearchTree;
+    use crate::store::MemoryStore;
+
+    fn trees() -> (MerkleSearchTree<u32>, MerkleSearchTree<u32>) {
+        let mut a = MerkleSearchTree::new(4);
+        for i in 0..1000 {
+            a.insert(i, format!("v{i}"));
+        }
+        let mut b = a.fork();
+        for i in (0..1000).step_by(37) {
+            b.insert(i, "changed".to_string());
+        }
+        b.remove(&500);
+        b.insert(2000, "added".to_string());
+        (a, b)
+    }
+
+    #[test]
+    fn test_budgeted_walks_resume() {
+        let (a, b) = trees();
+        let mut diffs = Vec::new();
+        let mut cursor = None;
+        let mut calls = 0;
+        loop {
+            let mut budget = Budget::unlimited().with_work_limit(5);
+            let progress = a.diff_with_budget(&b, cursor.as_ref(), &mut budget);
+            diffs.extend(progress.result);
+            calls += 1;
+            match progress.resume {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+        assert!(calls > 5);
+        assert_eq!(diffs, a.diff(&b));
+
+        let mut chunks = Vec::new();
+        let mut range = KeyRange::full();
+        loop {
+            let mut budget = Budget::unlimited().with_work_limit(2);
+            let progress = b.export_with_budget(range, 500, &mut budget);
+            chunks.extend(progress.result);
+            match progress.resume {
+                Some(rest) => range = rest,
+                None => break,
+            }
+        }
+        assert_eq!(
+            chunks,
+            b.export_stream(KeyRange::full(), 500).collect::<Vec<_>>()
+        );
+
+        let mut store = MemoryStore::new();
+        let (manifest, _) = b.write_snapshot(&mut store).unwrap();
+        let mut cursor = None;
+        let mut pages = 0;
+        loop {
+            let mut budget = Budget::unlimited().with_work_limit(10);
+            let progress = manifest
+                .verify_deep_with_budget::<u32, String, _>(&store, cursor, &mut budget)
+                .unwrap();
+            assert!(progress.result.is_empty());
+            pages += 1;
+            match progress.resume {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+        assert!(pages > 1);
+    }
+
+    #[test]
+    fn test_deadline() {
+        let (a, b) = trees();
+        let mut budget = Budget::until(Instant::now());
+        assert!(budget.is_exhausted());
+        // The first leaves are always compared.
+        let progress = a.diff_with_budget(&b, None, &mut budget);
+        assert!(!progress.is_complete());
+        let mut budget = Budget::within(Duration::from_secs(60));
+        let progress = a.diff_with_budget(&b, None, &mut budget);
+        assert!(progress.is_complete());
+        assert!(!budget.is_exhausted());
+    }
+}
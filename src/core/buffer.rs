@@ -0,0 +1,146 @@
+// Write batching. A `WriteBuffer` collects inserts and removals, keeping
+// only the last write to each key, so a hot key costs one tree walk per
+// flush rather than one per update.
+
+use std::collections::BTreeMap;
+
+use crate::core::codec::Encode;
+use crate::core::error::Error;
+use crate::core::metrics::Work;
+use crate::core::tree::MerkleSearchTree;
+
+pub struct WriteBuffer<K, V> {
+    // The latest write to each key; None removes it.
+    pending: BTreeMap<K, Option<V>>,
+    // Writes absorbed by a later write to the same key.
+    coalesced: u64,
+}
+
+impl<K: Ord, V> Default for WriteBuffer<K, V> {
+    fn default() -> Self {
+        WriteBuffer {
+            pending: BTreeMap::new(),
+            coalesced: 0,
+        }
+    }
+}
+
+impl<K: Ord + Clone + Default + Encode, V: AsRef<[u8]>> WriteBuffer<K, V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        self.write(key, Some(value));
+    }
+
+    pub fn remove(&mut self, key: K) {
+        self.write(key, None);
+    }
+
+    fn write(&mut self, key: K, value: Option<V>) {
+        if self.pending.insert(key, value).is_some() {
+            self.coalesced += 1;
+        }
+    }
+
+    // The buffered value of `key`: Some(None) if it is to be removed, None
+    // if the buffer doesn't touch it.
+    pub fn get(&self, key: &K) -> Option<Option<&V>> {
+        self.pending.get(key).map(Option::as_ref)
+    }
+
+    // The number of distinct keys to write.
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    // The number of writes made redundant by a later one since construction.
+    pub fn coalesced(&self) -> u64 {
+        self.coalesced
+    }
+
+    // Applies the buffered writes to `tree` in key order and empties the
+    // buffer, returning the work the tree did. Inserts go through
+    // `try_insert`; if one fails, the tree is left as it was, with nothing
+    // logged to its op sink, and the buffer keeps every write.
+    pub fn flush_into<const N: usize>(
+        &mut self,
+        tree: &mut MerkleSearchTree<K, V, N>,
+    ) -> Result<Work, Error>
+    where
+        V: Clone,
+    {
+        let batch = tree.begin_batch();
+        let mut work = Work::default();
+        for (key, value) in &self.pending {
+            // Removing an absent key does no work.
+            let applied = match value {
+                Some(value) => tree.try_insert(key.clone(), value.clone()).map(|_| true),
+                None => Ok(tree.remove(key)),
+            };
+            match applied {
+                Ok(true) => work += tree.last_work(),
+                Ok(false) => {}
+                Err(err) => {
+                    tree.rollback_batch(batch);
+                    return Err(err);
+                }
+            }
+        }
+        tree.commit_batch(batch, self.pending.keys());
+        self.pending.clear();
+        Ok(work)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_coalescing() {
+        let mut tree = MerkleSearchTree::new(4);
+        tree.insert(1u32, "old".to_string());
+        tree.insert(2, "gone".to_string());
+
+        let mut buffer = WriteBuffer::new();
+        for i in 0..1000 {
+            buffer.insert(1, format!("hot {i}"));
+        }
+        buffer.insert(2, "soon removed".to_string());
+        buffer.remove(2);
+        buffer.insert(3, "new".to_string());
+        buffer.remove(7);
+        assert_eq!(buffer.len(), 4);
+        assert_eq!(buffer.coalesced(), 1000);
+        assert_eq!(buffer.get(&2), Some(None));
+        assert_eq!(buffer.get(&4), None);
+
+        let work = buffer.flush_into(&mut tree).unwrap();
+        assert!(buffer.is_empty());
+        assert_eq!(work.bytes_hashed, "hot 999".len() as u64 + 3);
+        let entries: Vec<_> = tree.iter().map(|(k, v)| (*k, v.as_str())).collect();
+        assert_eq!(entries, vec![(1, "hot 999"), (3, "new")]);
+    }
+
+    #[test]
+    fn test_failed_flush() {
+        let mut tree = MerkleSearchTree::new(3).with_max_depth(2);
+        let mut buffer = WriteBuffer::new();
+        for i in 0..10u32 {
+            buffer.insert(i, format!("v{i}"));
+        }
+        let before = *tree.hash();
+        assert!(matches!(
+            buffer.flush_into(&mut tree),
+            Err(Error::DepthLimitExceeded { limit: 2 })
+        ));
+        assert_eq!(*tree.hash(), before);
+        assert_eq!(buffer.len(), 10);
+    }
+}
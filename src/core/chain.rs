@@ -0,0 +1,186 @@
+// A hash chain over the roots a tree went through, so its history can be
+// audited and not just its current state.
+//
+// Each root the tree reaches is linked into the chain as
+// `H(previous head || root)`, so the head commits to every root before it,
+// in order. A peer that recorded an earlier head can ask for the roots
+// since then and check that they lead to the head claimed now; a replica
+// that rewrote or dropped any of them can't produce a matching list. The
+// chain starts from all zeros when enabled, and its first link is the root
+// the tree had then.
+//
+// Only the last `capacity` roots are kept for `history_since`; the head
+// still covers all of them.
+
+use std::collections::VecDeque;
+
+use crate::core::codec::Encode;
+use crate::core::hash::NodeHash;
+use crate::core::tree::MerkleSearchTree;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RootChain<const N: usize = 32> {
+    capacity: usize,
+    // The head before the oldest retained link.
+    base: NodeHash<N>,
+    // Each retained root with the head right after linking it, oldest first.
+    links: VecDeque<(NodeHash<N>, NodeHash<N>)>,
+    head: NodeHash<N>,
+    len: u64,
+}
+
+impl<const N: usize> RootChain<N> {
+    pub(crate) fn new(capacity: usize, root: NodeHash<N>) -> Self {
+        let mut chain = RootChain {
+            capacity,
+            base: NodeHash::default(),
+            links: VecDeque::with_capacity(capacity),
+            head: NodeHash::default(),
+            len: 0,
+        };
+        chain.link(root);
+        chain
+    }
+
+    pub fn head(&self) -> &NodeHash<N> {
+        &self.head
+    }
+
+    // The number of roots ever linked.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    // Always false: the chain holds the root it started from.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    // The retained roots, oldest first.
+    pub fn roots(&self) -> impl Iterator<Item = &NodeHash<N>> {
+        self.links.iter().map(|(root, _)| root)
+    }
+
+    // The roots linked after the chain was at `head`, oldest first, or None
+    // if `head` isn't retained.
+    pub fn history_since(&self, head: &NodeHash<N>) -> Option<Vec<NodeHash<N>>> {
+        let from = match self.links.iter().position(|(_, after)| after == head) {
+            Some(index) => index + 1,
+            None if self.base == *head => 0,
+            None => return None,
+        };
+        Some(self.links.range(from..).map(|(root, _)| *root).collect())
+    }
+
+    // The head after linking `roots` onto `head`.
+    pub fn extend<'a>(
+        head: &NodeHash<N>,
+        roots: impl IntoIterator<Item = &'a NodeHash<N>>,
+    ) -> NodeHash<N> {
+        roots
+            .into_iter()
+            .fold(*head, |head, root| NodeHash::digest_sequence([&head, root]))
+    }
+
+    // Whether `roots`, linked onto the trusted head `from`, lead to `claimed`.
+    pub fn verify<'a>(
+        from: &NodeHash<N>,
+        roots: impl IntoIterator<Item = &'a NodeHash<N>>,
+        claimed: &NodeHash<N>,
+    ) -> bool {
+        Self::extend(from, roots) == *claimed
+    }
+
+    pub(crate) fn link(&mut self, root: NodeHash<N>) {
+        self.head = Self::extend(&self.head, [&root]);
+        self.len += 1;
+        if self.capacity == 0 {
+            self.base = self.head;
+            return;
+        }
+        if self.links.len() == self.capacity
+            && let Some((_, oldest)) = self.links.pop_front()
+        {
+            self.base = oldest;
+        }
+        self.links.push_back((root, self.head));
+    }
+}
+
+impl<K: Ord + Clone + Default + Encode, V: AsRef<[u8]>, const N: usize> MerkleSearchTree<K, V, N> {
+    // Links every root change into a `RootChain`, keeping the last
+    // `capacity` roots.
+    pub fn with_root_chain(mut self, capacity: usize) -> Self {
+        self.root_chain = Some(RootChain::new(capacity, self.root_hash()));
+        self
+    }
+
+    pub fn root_chain(&self) -> Option<&RootChain<N>> {
+        self.root_chain.as_ref()
+    }
+
+    pub fn chain_head(&self) -> Option<&NodeHash<N>> {
+        self.root_chain.as_ref().map(RootChain::head)
+    }
+
+    pub(crate) fn link_root(&mut self) {
+        let root = self.root_hash();
+        if let Some(chain) = &mut self.root_chain {
+            chain.link(root);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "store"))]
+mod test {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    #[test]
+    fn test_audit_history() {
+        let mut tree = MerkleSearchTree::<u32>::new(4).with_root_chain(8);
+        tree.insert(1, "a".to_string());
+        let audited = *tree.chain_head().unwrap();
+        let mut roots = Vec::new();
+        for i in 2..6 {
+            tree.insert(i, "b".to_string());
+            roots.push(tree.root_hash());
+        }
+        tree.insert(5, "b".to_string());
+        let chain = tree.root_chain().unwrap();
+        assert_eq!(chain.len(), 6);
+        assert_eq!(chain.history_since(&audited), Some(roots.clone()));
+        assert!(RootChain::verify(&audited, &roots, chain.head()));
+
+        // A rewritten or dropped root breaks the chain.
+        let mut forged = roots.clone();
+        forged[1] = NodeHash::digest(b"forged");
+        assert!(!RootChain::verify(&audited, &forged, chain.head()));
+        assert!(!RootChain::verify(&audited, &roots[1..], chain.head()));
+
+        // Restoring a snapshot is linked like any other change.
+        let mut store = MemoryStore::new();
+        let (manifest, _) = MerkleSearchTree::<u32>::new(4)
+            .write_snapshot(&mut store)
+            .unwrap();
+        let head = *tree.chain_head().unwrap();
+        tree.restore(&store, &manifest).unwrap();
+        let chain = tree.root_chain().unwrap();
+        assert_eq!(
+            chain.history_since(&head),
+            Some(vec![NodeHash::empty_root()])
+        );
+
+        // Old links fall out of the window but stay covered by the head.
+        for i in 0..20 {
+            tree.insert(i, "c".to_string());
+        }
+        let chain = tree.root_chain().unwrap();
+        assert_eq!(chain.roots().count(), 8);
+        assert_eq!(chain.history_since(&audited), None);
+    }
+}
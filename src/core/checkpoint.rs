@@ -0,0 +1,329 @@
+// Checkpoints in the text format of transparency logs, so a tree's roots can
+// be published to and consumed from witnesses, monitors and other tooling
+// built for signed notes.
+//
+// The body follows the checkpoint layout: the origin line naming the log,
+// the size in decimal, and the root in standard base64, one per line, each
+// ending in a newline. The timestamp, in seconds since the Unix epoch, goes
+// on an extension line after them. The size is the tree's entry count.
+//
+// Signing is left to the caller's note signer: it signs the bytes of
+// `note_body`, and `signed_note` appends its signatures after a blank line
+// as `— <name> <base64 of key hash and signature>` lines. Parsing splits a
+// note back into the checkpoint and its signature lines, for the caller's
+// verifier to check against `note_body`.
+//
+// Witnesses that share a secret with the log instead of holding its public
+// key can use `sign_hmac` and `verify_hmac`, with the `zeroize` feature: the
+// signature is the HMAC-SHA256 of the note body under a `SecretKey`, and the
+// key hash the first four bytes of the HMAC of the signer's name, so the
+// key isn't derivable from either.
+
+use std::fmt::Write;
+
+use crate::core::codec::Encode;
+use crate::core::error::Error;
+use crate::core::hash::NodeHash;
+#[cfg(feature = "zeroize")]
+use crate::core::secret::{SecretKey, constant_time_eq};
+use crate::core::tree::MerkleSearchTree;
+
+const TIMESTAMP_PREFIX: &str = "timestamp ";
+const SIGNATURE_PREFIX: &str = "\u{2014} ";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Checkpoint<const N: usize = 32> {
+    // The log's name, conventionally a schema-less URL.
+    pub origin: String,
+    pub size: u64,
+    pub root: NodeHash<N>,
+    pub timestamp: u64,
+}
+
+// One signature line of a signed note.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NoteSignature {
+    pub name: String,
+    // The first four bytes of the signer's key hash.
+    pub key_hash: [u8; 4],
+    pub signature: Vec<u8>,
+}
+
+impl<const N: usize> Checkpoint<N> {
+    // The text signers sign.
+    pub fn note_body(&self) -> String {
+        format!(
+            "{}\n{}\n{}\n{TIMESTAMP_PREFIX}{}\n",
+            self.origin,
+            self.size,
+            base64_encode(&self.root.0),
+            self.timestamp
+        )
+    }
+
+    pub fn signed_note(&self, signatures: &[NoteSignature]) -> String {
+        let mut note = self.note_body();
+        note.push('\n');
+        for signature in signatures {
+            let mut signed = signature.key_hash.to_vec();
+            signed.extend_from_slice(&signature.signature);
+            // Writing to a String can't fail.
+            let _ = writeln!(
+                note,
+                "{SIGNATURE_PREFIX}{} {}",
+                signature.name,
+                base64_encode(&signed)
+            );
+        }
+        note
+    }
+
+    pub fn from_note_body(body: &str) -> Result<Self, Error> {
+        let malformed = |what: &str| Error::Malformed(format!("checkpoint {what}"));
+        let lines = body
+            .strip_suffix('\n')
+            .ok_or_else(|| malformed("body must end in a newline"))?;
+        let mut lines = lines.split('\n');
+        let origin = lines
+            .next()
+            .filter(|origin| !origin.is_empty())
+            .ok_or_else(|| malformed("without an origin"))?;
+        let size = lines
+            .next()
+            .filter(|size| !size.starts_with('+') && (*size == "0" || !size.starts_with('0')))
+            .and_then(|size| size.parse().ok())
+            .ok_or_else(|| malformed("size is not a decimal number"))?;
+        let root = lines
+            .next()
+            .and_then(base64_decode)
+            .and_then(|root| <[u8; N]>::try_from(root).ok())
+            .ok_or_else(|| malformed("root is not a base64 hash of the right width"))?;
+        // Other extension lines are allowed and skipped.
+        let timestamp = lines
+            .find_map(|line| line.strip_prefix(TIMESTAMP_PREFIX))
+            .and_then(|timestamp| timestamp.parse().ok())
+            .ok_or_else(|| malformed("without a timestamp line"))?;
+        Ok(Checkpoint {
+            origin: origin.to_string(),
+            size,
+            root: NodeHash(root),
+            timestamp,
+        })
+    }
+
+    // Splits a signed note into its checkpoint and signatures. The
+    // signatures are not checked.
+    pub fn from_signed_note(note: &str) -> Result<(Self, Vec<NoteSignature>), Error> {
+        let (body, signatures) = note
+            .split_once("\n\n")
+            .ok_or_else(|| Error::Malformed("note without signatures".to_string()))?;
+        let checkpoint = Self::from_note_body(&note[..body.len() + 1])?;
+        let signatures = signatures
+            .strip_suffix('\n')
+            .unwrap_or(signatures)
+            .split('\n')
+            .map(parse_signature)
+            .collect::<Result<_, _>>()?;
+        Ok((checkpoint, signatures))
+    }
+
+    // Whether the checkpoint describes the tree as it is now.
+    pub fn matches<K, V>(&self, tree: &MerkleSearchTree<K, V, N>) -> bool
+    where
+        K: Ord + Clone + Default + Encode,
+        V: AsRef<[u8]>,
+    {
+        self.root == tree.root_hash() && self.size == tree.len() as u64
+    }
+}
+
+#[cfg(feature = "zeroize")]
+impl<const N: usize> Checkpoint<N> {
+    // Signs the note body as `name` with HMAC-SHA256 under `key`.
+    pub fn sign_hmac(&self, name: &str, key: &SecretKey) -> NoteSignature {
+        NoteSignature {
+            name: name.to_string(),
+            key_hash: hmac_key_hash(name, key),
+            signature: key.mac(self.note_body().as_bytes()).0.to_vec(),
+        }
+    }
+
+    // Whether `signature` is `sign_hmac`'s under `key` for this checkpoint.
+    // A signature from another key or signer name fails.
+    pub fn verify_hmac(&self, signature: &NoteSignature, key: &SecretKey) -> bool {
+        signature.key_hash == hmac_key_hash(&signature.name, key)
+            && constant_time_eq(
+                &signature.signature,
+                &key.mac(self.note_body().as_bytes()).0,
+            )
+    }
+}
+
+#[cfg(feature = "zeroize")]
+fn hmac_key_hash(name: &str, key: &SecretKey) -> [u8; 4] {
+    let hash = key.mac(name.as_bytes());
+    [hash.0[0], hash.0[1], hash.0[2], hash.0[3]]
+}
+
+fn parse_signature(line: &str) -> Result<NoteSignature, Error> {
+    let malformed = || Error::Malformed(format!("bad signature line {line:?}"));
+    let (name, signed) = line
+        .strip_prefix(SIGNATURE_PREFIX)
+        .and_then(|line| line.split_once(' '))
+        .ok_or_else(malformed)?;
+    let signed = base64_decode(signed)
+        .filter(|signed| signed.len() > 4)
+        .ok_or_else(malformed)?;
+    Ok(NoteSignature {
+        name: name.to_string(),
+        key_hash: signed[..4].try_into().expect("checked the length"),
+        signature: signed[4..].to_vec(),
+    })
+}
+
+impl<K: Ord + Clone + Default + Encode, V: AsRef<[u8]>, const N: usize> MerkleSearchTree<K, V, N> {
+    // A checkpoint of the tree as it is now, for the log named `origin`.
+    pub fn checkpoint(&self, origin: &str, timestamp: u64) -> Checkpoint<N> {
+        Checkpoint {
+            origin: origin.to_string(),
+            size: self.len() as u64,
+            root: self.root_hash(),
+            timestamp,
+        }
+    }
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// Standard base64 with padding.
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let triple = chunk.iter().enumerate().fold(0u32, |triple, (i, byte)| {
+            triple | u32::from(*byte) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(triple >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+// Decodes padded standard base64, rejecting anything non-canonical.
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(4) {
+        return None;
+    }
+    let mut out = Vec::with_capacity(text.len() / 4 * 3);
+    for chunk in text.as_bytes().chunks(4) {
+        let padding = chunk.iter().rev().take_while(|byte| **byte == b'=').count();
+        if padding > 2 || (padding > 0 && out.len() + 3 < text.len() / 4 * 3) {
+            return None;
+        }
+        let mut triple = 0u32;
+        for (i, byte) in chunk[..4 - padding].iter().enumerate() {
+            let value = BASE64.iter().position(|c| c == byte)? as u32;
+            triple |= value << (18 - 6 * i);
+        }
+        let bytes = triple.to_be_bytes();
+        out.extend_from_slice(&bytes[1..4 - padding]);
+        // Bits past the last byte must be zero.
+        if bytes[4 - padding..].iter().any(|byte| *byte != 0) {
+            return None;
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_checkpoint_note() {
+        let mut tree = MerkleSearchTree::<u32>::new(8);
+        for i in 0..100 {
+            tree.insert(i, format!("v{i}"));
+        }
+        let checkpoint = tree.checkpoint("example.com/mst", 1_700_000_000);
+        assert!(checkpoint.matches(&tree));
+        let body = checkpoint.note_body();
+        let lines: Vec<&str> = body.lines().collect();
+        assert_eq!(lines[..2], ["example.com/mst", "100"]);
+        assert_eq!(lines[2].len(), 44);
+        assert_eq!(lines[3], "timestamp 1700000000");
+
+        let signature = NoteSignature {
+            name: "witness.example".to_string(),
+            key_hash: [1, 2, 3, 4],
+            signature: vec![9; 64],
+        };
+        let note = checkpoint.signed_note(std::slice::from_ref(&signature));
+        assert!(note.starts_with(&body));
+        assert!(note.contains("\n\n\u{2014} witness.example AQIDBAkJ"));
+        let (parsed, signatures) = Checkpoint::from_signed_note(&note).unwrap();
+        assert_eq!(parsed, checkpoint);
+        assert_eq!(signatures, [signature]);
+
+        tree.insert(100, "v100".to_string());
+        assert!(!checkpoint.matches(&tree));
+        for bad in [
+            "example.com/mst\n100\n",
+            "example.com/mst\n0100\nAAAA\ntimestamp 1\n",
+            "example.com/mst\n1\nAAAA\ntimestamp 1\n",
+            "example.com/mst\n1\nAAAA",
+        ] {
+            assert!(Checkpoint::<32>::from_note_body(bad).is_err(), "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn test_base64() {
+        // RFC 4648 test vectors.
+        for (plain, encoded) in [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ] {
+            assert_eq!(base64_encode(plain.as_bytes()), encoded);
+            assert_eq!(base64_decode(encoded).unwrap(), plain.as_bytes());
+        }
+        assert_eq!(base64_decode("Zh=="), None);
+        assert_eq!(base64_decode("Zg==Zg=="), None);
+        assert_eq!(base64_decode("Zg"), None);
+    }
+
+    #[cfg(feature = "zeroize")]
+    #[test]
+    fn test_hmac_signed_note() {
+        let mut tree = MerkleSearchTree::<u32>::new(8);
+        for i in 0..100 {
+            tree.insert(i, format!("v{i}"));
+        }
+        let checkpoint = tree.checkpoint("example.com/mst", 1_700_000_000);
+        let key = SecretKey::new(b"shared with the witnesses".to_vec());
+        let signature = checkpoint.sign_hmac("log.example", &key);
+        let note = checkpoint.signed_note(std::slice::from_ref(&signature));
+        let (parsed, signatures) = Checkpoint::<32>::from_signed_note(&note).unwrap();
+        assert!(parsed.verify_hmac(&signatures[0], &key));
+
+        let other = SecretKey::new(b"someone else's".to_vec());
+        assert!(!parsed.verify_hmac(&signatures[0], &other));
+        let renamed = NoteSignature {
+            name: "mallory.example".to_string(),
+            ..signature.clone()
+        };
+        assert!(!parsed.verify_hmac(&renamed, &key));
+        tree.insert(100, "v100".to_string());
+        let later = tree.checkpoint("example.com/mst", 1_700_000_000);
+        assert!(!later.verify_hmac(&signature, &key));
+    }
+}
@@ -0,0 +1,274 @@
+// Byte encodings of keys and values.
+//
+// Integers are written big-endian with the sign bit flipped, so the encoded
+// bytes sort the same way as the numbers. Variable-length data is prefixed
+// with its length as a big-endian u32.
+//
+// Nothing depends on the platform: every integer has a fixed width and byte
+// order, `usize` is never written, and hashes cover these bytes only. Data
+// longer than `u32::MAX` bytes can't be encoded rather than being truncated,
+// so 32- and 64-bit, big- and little-endian replicas agree byte for byte.
+
+use crate::core::error::Error;
+
+pub trait Encode {
+    fn encode(&self, out: &mut Vec<u8>);
+
+    fn encoded_len(&self) -> usize {
+        let mut out = Vec::new();
+        self.encode(&mut out);
+        out.len()
+    }
+}
+
+// The inverse of `Encode`. Consumes the decoded bytes from the front of `input`.
+pub trait Decode: Sized {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error>;
+}
+
+// Splits `len` bytes off the front of `input`.
+pub fn take<'a>(input: &mut &'a [u8], len: usize) -> Result<&'a [u8], Error> {
+    if input.len() < len {
+        return Err(Error::Malformed(format!(
+            "needed {len} bytes, {} left",
+            input.len()
+        )));
+    }
+    let (head, rest) = input.split_at(len);
+    *input = rest;
+    Ok(head)
+}
+
+fn take_array<const N: usize>(input: &mut &[u8]) -> Result<[u8; N], Error> {
+    Ok(take(input, N)?.try_into().expect("took exactly N bytes"))
+}
+
+macro_rules! encode_unsigned {
+    ($($ty:ty),*) => {$(
+        impl Encode for $ty {
+            fn encode(&self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.to_be_bytes());
+            }
+
+            fn encoded_len(&self) -> usize {
+                std::mem::size_of::<$ty>()
+            }
+        }
+
+        impl Decode for $ty {
+            fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+                Ok(<$ty>::from_be_bytes(take_array(input)?))
+            }
+        }
+    )*};
+}
+
+macro_rules! encode_signed {
+    ($($ty:ty => $unsigned:ty),*) => {$(
+        impl Encode for $ty {
+            fn encode(&self, out: &mut Vec<u8>) {
+                let flipped = (*self as $unsigned) ^ (1 << (<$unsigned>::BITS - 1));
+                out.extend_from_slice(&flipped.to_be_bytes());
+            }
+
+            fn encoded_len(&self) -> usize {
+                std::mem::size_of::<$ty>()
+            }
+        }
+
+        impl Decode for $ty {
+            fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+                let flipped = <$unsigned>::from_be_bytes(take_array(input)?);
+                Ok((flipped ^ (1 << (<$unsigned>::BITS - 1))) as $ty)
+            }
+        }
+    )*};
+}
+
+encode_unsigned!(u8, u16, u32, u64, u128);
+encode_signed!(i8 => u8, i16 => u16, i32 => u32, i64 => u64, i128 => u128);
+
+// Writes a length prefix. Panics if `len` doesn't fit.
+pub(crate) fn encode_len(len: usize, out: &mut Vec<u8>) {
+    let len = u32::try_from(len).expect("encoded data is limited to u32::MAX bytes");
+    len.encode(out);
+}
+
+impl Encode for [u8] {
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_len(self.len(), out);
+        out.extend_from_slice(self);
+    }
+
+    fn encoded_len(&self) -> usize {
+        4 + self.len()
+    }
+}
+
+impl Encode for Vec<u8> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.as_slice().encode(out)
+    }
+
+    fn encoded_len(&self) -> usize {
+        self.as_slice().encoded_len()
+    }
+}
+
+impl Encode for str {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.as_bytes().encode(out)
+    }
+
+    fn encoded_len(&self) -> usize {
+        self.as_bytes().encoded_len()
+    }
+}
+
+impl Encode for String {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.as_str().encode(out)
+    }
+
+    fn encoded_len(&self) -> usize {
+        self.as_str().encoded_len()
+    }
+}
+
+impl<const N: usize> Encode for [u8; N] {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self);
+    }
+
+    fn encoded_len(&self) -> usize {
+        N
+    }
+}
+
+// Nothing, e.g. for `Tagged` values without local tags.
+impl Encode for () {
+    fn encode(&self, _: &mut Vec<u8>) {}
+
+    fn encoded_len(&self) -> usize {
+        0
+    }
+}
+
+impl Decode for () {
+    fn decode(_: &mut &[u8]) -> Result<Self, Error> {
+        Ok(())
+    }
+}
+
+impl Decode for Vec<u8> {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        let len = u32::decode(input)? as usize;
+        Ok(take(input, len)?.to_vec())
+    }
+}
+
+impl Decode for String {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        String::from_utf8(Vec::decode(input)?)
+            .map_err(|err| Error::Malformed(format!("invalid utf-8: {err}")))
+    }
+}
+
+impl<const N: usize> Decode for [u8; N] {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        take_array(input)
+    }
+}
+
+// CRC-32C (Castagnoli), used to catch bytes damaged at rest.
+pub fn crc32c(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc = CRC32C_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+const CRC32C_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn bytes<T: Encode + ?Sized>(value: &T) -> Vec<u8> {
+        let mut out = Vec::new();
+        value.encode(&mut out);
+        assert_eq!(out.len(), value.encoded_len());
+        out
+    }
+
+    #[test]
+    fn test_integer_encoding_preserves_order() {
+        let values = [i64::MIN, -300, -1, 0, 1, 255, i64::MAX];
+        for pair in values.windows(2) {
+            assert!(bytes(&pair[0]) < bytes(&pair[1]));
+        }
+        assert_eq!(bytes(&0x0102u16), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_length_prefixed_encoding() {
+        assert_eq!(bytes("ab"), vec![0, 0, 0, 2, b'a', b'b']);
+        assert_eq!(bytes(&vec![7u8]), vec![0, 0, 0, 1, 7]);
+    }
+
+    #[test]
+    fn test_decode_round_trip() {
+        let mut out = Vec::new();
+        (-5i32).encode(&mut out);
+        "hello".encode(&mut out);
+        u64::MAX.encode(&mut out);
+
+        let mut input = out.as_slice();
+        assert_eq!(i32::decode(&mut input).unwrap(), -5);
+        assert_eq!(String::decode(&mut input).unwrap(), "hello");
+        assert_eq!(u64::decode(&mut input).unwrap(), u64::MAX);
+        assert!(input.is_empty());
+        assert!(matches!(u8::decode(&mut input), Err(Error::Malformed(_))));
+    }
+
+    // Fixed vectors: these bytes are the same on every target.
+    #[test]
+    fn test_conformance_vectors() {
+        assert_eq!(bytes(&0x0102_0304u32), [1, 2, 3, 4]);
+        assert_eq!(bytes(&1u64), [0, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(bytes(&1u128)[15], 1);
+        assert_eq!(bytes(&-1i8), [0x7f]);
+        assert_eq!(bytes(&0i16), [0x80, 0]);
+        assert_eq!(bytes(&-2i32), [0x7f, 0xff, 0xff, 0xfe]);
+        assert_eq!(bytes(&i64::MIN), [0; 8]);
+        assert_eq!(bytes(&i128::MAX), [0xff; 16]);
+        assert_eq!(bytes(&[9u8, 8]), [9, 8]);
+        assert_eq!(bytes("é"), [0, 0, 0, 2, 0xc3, 0xa9]);
+    }
+
+    #[test]
+    fn test_crc32c() {
+        // The check value from the CRC catalogue.
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+        assert_eq!(crc32c(b""), 0);
+    }
+}
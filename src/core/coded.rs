@@ -0,0 +1,397 @@
+// Values stored through a pluggable codec, so a tree can hold structured
+// values without the application turning them into strings first.
+//
+// A `ValueCodec` turns values into bytes and back, and names the version of
+// the encoding it writes. A `Coded` value keeps the value next to its
+// encoded bytes, prefixed with that version; the bytes are what gets hashed,
+// written to snapshot pages and sent in sync and transfer messages, so every
+// path that persists or ships values goes through the codec.
+//
+// Changing an encoding means bumping `VERSION` and keeping `decode` able to
+// read the older versions. A decoded value keeps the bytes it was read from,
+// so old values hash the same as before until `reencode` rewrites them, and
+// replicas on either side of an upgrade keep agreeing.
+//
+// With the `serde_json` and `bincode` features, `JsonCodec` and
+// `BincodeCodec` store any serde type. Serde writes fields in declaration
+// order but maps in iteration order, so values holding a `HashMap` don't
+// encode the same way twice; use a `BTreeMap` in hashed values.
+
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::Deref;
+
+use crate::core::codec::{Decode, Encode};
+use crate::core::error::Error;
+
+pub trait ValueCodec<T> {
+    // The version `encode` writes.
+    const VERSION: u8;
+
+    fn encode(value: &T, out: &mut Vec<u8>);
+
+    // Decodes bytes written by `version` of the encoding.
+    fn decode(version: u8, bytes: &[u8]) -> Result<T, Error>;
+}
+
+// Bytes as they are.
+pub struct RawCodec;
+
+impl ValueCodec<Vec<u8>> for RawCodec {
+    const VERSION: u8 = 0;
+
+    fn encode(value: &Vec<u8>, out: &mut Vec<u8>) {
+        out.extend_from_slice(value);
+    }
+
+    fn decode(version: u8, bytes: &[u8]) -> Result<Vec<u8>, Error> {
+        check_version(version, Self::VERSION)?;
+        Ok(bytes.to_vec())
+    }
+}
+
+// Strings as UTF-8, rejecting anything else on the way in.
+pub struct Utf8Codec;
+
+impl ValueCodec<String> for Utf8Codec {
+    const VERSION: u8 = 0;
+
+    fn encode(value: &String, out: &mut Vec<u8>) {
+        out.extend_from_slice(value.as_bytes());
+    }
+
+    fn decode(version: u8, bytes: &[u8]) -> Result<String, Error> {
+        check_version(version, Self::VERSION)?;
+        String::from_utf8(bytes.to_vec())
+            .map_err(|_| Error::Malformed("value is not UTF-8".to_string()))
+    }
+}
+
+// Anything with the crate's own `Encode` and `Decode`: compact, fixed
+// byte order, and rejecting trailing bytes.
+pub struct BinaryCodec;
+
+impl<T: Encode + Decode> ValueCodec<T> for BinaryCodec {
+    const VERSION: u8 = 0;
+
+    fn encode(value: &T, out: &mut Vec<u8>) {
+        value.encode(out);
+    }
+
+    fn decode(version: u8, mut bytes: &[u8]) -> Result<T, Error> {
+        check_version(version, <Self as ValueCodec<T>>::VERSION)?;
+        let value = T::decode(&mut bytes)?;
+        if !bytes.is_empty() {
+            return Err(Error::Malformed(format!(
+                "{} bytes after the value",
+                bytes.len()
+            )));
+        }
+        Ok(value)
+    }
+}
+
+// Serde values as JSON, readable in snapshot pages and sync messages.
+#[cfg(feature = "serde_json")]
+pub struct JsonCodec;
+
+#[cfg(feature = "serde_json")]
+impl<T: serde::Serialize + serde::de::DeserializeOwned> ValueCodec<T> for JsonCodec {
+    const VERSION: u8 = 0;
+
+    // Panics if `value` has no JSON form, such as a map with non-string keys.
+    fn encode(value: &T, out: &mut Vec<u8>) {
+        if let Err(err) = serde_json::to_writer(out, value) {
+            panic!("value can't be written as JSON: {err}");
+        }
+    }
+
+    fn decode(version: u8, bytes: &[u8]) -> Result<T, Error> {
+        check_version(version, <Self as ValueCodec<T>>::VERSION)?;
+        serde_json::from_slice(bytes).map_err(|err| Error::Malformed(format!("JSON value: {err}")))
+    }
+}
+
+// Serde values as bincode's standard configuration: compact, but opaque.
+#[cfg(feature = "bincode")]
+pub struct BincodeCodec;
+
+#[cfg(feature = "bincode")]
+impl<T: serde::Serialize + serde::de::DeserializeOwned> ValueCodec<T> for BincodeCodec {
+    const VERSION: u8 = 0;
+
+    // Panics if `value` can't be serialized, such as a sequence of unknown
+    // length.
+    fn encode(value: &T, out: &mut Vec<u8>) {
+        let config = bincode::config::standard();
+        if let Err(err) = bincode::serde::encode_into_std_write(value, out, config) {
+            panic!("value can't be written as bincode: {err}");
+        }
+    }
+
+    fn decode(version: u8, bytes: &[u8]) -> Result<T, Error> {
+        check_version(version, <Self as ValueCodec<T>>::VERSION)?;
+        let (value, read) = bincode::serde::decode_from_slice(bytes, bincode::config::standard())
+            .map_err(|err| Error::Malformed(format!("bincode value: {err}")))?;
+        if read < bytes.len() {
+            return Err(Error::Malformed(format!(
+                "{} bytes after the value",
+                bytes.len() - read
+            )));
+        }
+        Ok(value)
+    }
+}
+
+// For codecs with a single version.
+fn check_version(version: u8, expected: u8) -> Result<(), Error> {
+    if version != expected {
+        return Err(Error::UnknownEncoding(version));
+    }
+    Ok(())
+}
+
+pub struct Coded<T, C> {
+    value: T,
+    // The encoding version followed by the encoded value. All of it is hashed.
+    bytes: Vec<u8>,
+    codec: PhantomData<fn() -> C>,
+}
+
+impl<T, C: ValueCodec<T>> Coded<T, C> {
+    pub fn new(value: T) -> Self {
+        let mut bytes = vec![C::VERSION];
+        C::encode(&value, &mut bytes);
+        Coded {
+            value,
+            bytes,
+            codec: PhantomData,
+        }
+    }
+
+    // Decodes the bytes of a `Coded`, as `as_ref` returns them.
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Self, Error> {
+        let Some((version, encoded)) = bytes.split_first() else {
+            return Err(Error::Malformed(
+                "coded value without a version".to_string(),
+            ));
+        };
+        let value = C::decode(*version, encoded)?;
+        Ok(Coded {
+            value,
+            bytes,
+            codec: PhantomData,
+        })
+    }
+
+    // The value written with the current version of the encoding, or None
+    // if it already is. Inserting the result changes the leaf's hash.
+    pub fn reencode(&self) -> Option<Self>
+    where
+        T: Clone,
+    {
+        (self.version() != C::VERSION).then(|| Self::new(self.value.clone()))
+    }
+}
+
+impl<T, C> Coded<T, C> {
+    pub fn value(&self) -> &T {
+        &self.value
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+
+    // The version of the encoding these bytes were written with.
+    pub fn version(&self) -> u8 {
+        self.bytes[0]
+    }
+}
+
+impl<T, C> Deref for Coded<T, C> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+// The hashed bytes: the version and the encoded value.
+impl<T, C> AsRef<[u8]> for Coded<T, C> {
+    fn as_ref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+// Written by hand, since derives would ask the same of the codec.
+impl<T: Clone, C> Clone for Coded<T, C> {
+    fn clone(&self) -> Self {
+        Coded {
+            value: self.value.clone(),
+            bytes: self.bytes.clone(),
+            codec: PhantomData,
+        }
+    }
+}
+
+// Equal when the bytes are, as the tree sees it.
+impl<T, C> PartialEq for Coded<T, C> {
+    fn eq(&self, other: &Self) -> bool {
+        self.bytes == other.bytes
+    }
+}
+
+impl<T, C> Eq for Coded<T, C> {}
+
+impl<T: fmt::Debug, C> fmt::Debug for Coded<T, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.value.fmt(f)
+    }
+}
+
+impl<T: Default, C: ValueCodec<T>> Default for Coded<T, C> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T, C> Encode for Coded<T, C> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.bytes.encode(out);
+    }
+
+    fn encoded_len(&self) -> usize {
+        self.bytes.encoded_len()
+    }
+}
+
+impl<T, C: ValueCodec<T>> Decode for Coded<T, C> {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        Self::from_bytes(Vec::decode(input)?)
+    }
+}
+
+#[cfg(all(test, feature = "store"))]
+mod test {
+    use super::*;
+    use crate::core::range::KeyRange;
+    use crate::core::transfer::PendingImport;
+    use crate::core::tree::MerkleSearchTree;
+    use crate::store::MemoryStore;
+
+    #[derive(Clone, Debug, Default, PartialEq)]
+    struct Point {
+        x: i32,
+        y: i32,
+    }
+
+    type Points = MerkleSearchTree<u32, Coded<Point, PointCodec>>;
+
+    // Version 0 wrote the coordinates as text; version 1 writes them binary.
+    struct PointCodec;
+
+    impl ValueCodec<Point> for PointCodec {
+        const VERSION: u8 = 1;
+
+        fn encode(value: &Point, out: &mut Vec<u8>) {
+            value.x.encode(out);
+            value.y.encode(out);
+        }
+
+        fn decode(version: u8, mut bytes: &[u8]) -> Result<Point, Error> {
+            match version {
+                0 => {
+                    let text = String::from_utf8(bytes.to_vec())
+                        .map_err(|_| Error::Malformed("not UTF-8".to_string()))?;
+                    let (x, y) = text
+                        .split_once(',')
+                        .ok_or_else(|| Error::Malformed(text.clone()))?;
+                    let parse = |n: &str| n.parse().map_err(|_| Error::Malformed(n.to_string()));
+                    Ok(Point {
+                        x: parse(x)?,
+                        y: parse(y)?,
+                    })
+                }
+                1 => Ok(Point {
+                    x: i32::decode(&mut bytes)?,
+                    y: i32::decode(&mut bytes)?,
+                }),
+                version => Err(Error::UnknownEncoding(version)),
+            }
+        }
+    }
+
+    #[test]
+    fn test_coded_values_persist_and_transfer() {
+        let mut tree = Points::new(8);
+        for i in 0..200 {
+            tree.insert(i, Coded::new(Point { x: i as i32, y: -1 }));
+        }
+        let mut store = MemoryStore::new();
+        let (manifest, _) = tree.write_snapshot(&mut store).unwrap();
+        let mut restored = Points::new(8);
+        restored.restore(&store, &manifest).unwrap();
+        assert_eq!(restored.get(&7).unwrap().value(), &Point { x: 7, y: -1 });
+        assert_eq!(restored.hash(), tree.hash());
+
+        let mut import = PendingImport::<u32, Coded<Point, PointCodec>>::new(KeyRange::full());
+        for chunk in tree.export_stream(KeyRange::full(), 256) {
+            let mut bytes = Vec::new();
+            chunk.encode(&mut bytes);
+            import
+                .add(Decode::decode(&mut bytes.as_slice()).unwrap())
+                .unwrap();
+        }
+        let mut copy = Points::new(8);
+        import.commit(&mut copy).unwrap();
+        assert_eq!(copy.hash(), tree.hash());
+
+        let wrong = Coded::<String, Utf8Codec>::from_bytes(vec![0, 0xff]);
+        assert!(matches!(wrong, Err(Error::Malformed(_))));
+        let newer = Coded::<Vec<u8>, RawCodec>::from_bytes(vec![3, 1]);
+        assert!(matches!(newer, Err(Error::UnknownEncoding(3))));
+        let number = Coded::<u64, BinaryCodec>::new(9);
+        assert_eq!(number.as_ref().len(), 9);
+        assert!(Coded::<u64, BinaryCodec>::from_bytes([number.as_ref(), &[0]].concat()).is_err());
+    }
+
+    #[test]
+    fn test_old_versions_keep_their_hash() {
+        let old = Coded::<Point, PointCodec>::from_bytes(b"\x003,4".to_vec()).unwrap();
+        assert_eq!(old.version(), 0);
+        assert_eq!(*old, Point { x: 3, y: 4 });
+
+        let mut tree = MerkleSearchTree::<u32, _>::new(8);
+        tree.insert(1, old.clone());
+        let before = *tree.hash();
+        let upgraded = old.reencode().unwrap();
+        assert_eq!(upgraded.version(), 1);
+        assert!(upgraded.reencode().is_none());
+        tree.insert(1, upgraded);
+        assert_ne!(*tree.hash(), before);
+        assert_eq!(tree.get(&1).unwrap().value(), old.value());
+    }
+
+    #[cfg(all(feature = "serde_json", feature = "bincode"))]
+    #[test]
+    fn test_serde_codecs() {
+        use std::collections::BTreeMap;
+
+        type Doc = BTreeMap<String, Vec<u32>>;
+        let doc: Doc = [("a".to_string(), vec![1, 2]), ("b".to_string(), vec![])].into();
+
+        let json = Coded::<Doc, JsonCodec>::new(doc.clone());
+        assert_eq!(&json.as_ref()[1..], br#"{"a":[1,2],"b":[]}"#);
+        let decoded = Coded::<Doc, JsonCodec>::from_bytes(json.as_ref().to_vec()).unwrap();
+        assert_eq!(*decoded, doc);
+        assert!(Coded::<Doc, JsonCodec>::from_bytes(b"\0{\"a\":".to_vec()).is_err());
+
+        let binary = Coded::<Doc, BincodeCodec>::new(doc.clone());
+        assert!(binary.as_ref().len() < json.as_ref().len());
+        let decoded = Coded::<Doc, BincodeCodec>::from_bytes(binary.as_ref().to_vec()).unwrap();
+        assert_eq!(*decoded, doc);
+        let trailing = [binary.as_ref(), &[0]].concat();
+        assert!(Coded::<Doc, BincodeCodec>::from_bytes(trailing).is_err());
+    }
+}
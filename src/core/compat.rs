@@ -0,0 +1,264 @@
+// Differential tests against other Merkle search tree implementations, for
+// users migrating from one.
+//
+// A `Differential` runs one sequence of upserts through two replicas of
+// this crate's tree and two of another implementation, behind
+// `ReferenceTree`, and checks that both detect the same divergence: the
+// replicas differ in one exactly when they differ in the other, and every
+// key this crate finds differing lies in a range the other reports. Any
+// disagreement panics, naming the key, as `testing::Oracle` does.
+//
+// The influxdata `merkle-search-tree` crate only upserts, which is why
+// operations here don't remove, and reports divergence as key ranges from
+// `diff` over its serialised page ranges. Its `diff` only returns the
+// ranges the local side is missing, so `Influx` runs it both ways. The
+// `compat-tests` feature enables this module and pulls that crate in.
+
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+#[cfg(feature = "compat-tests")]
+use std::hash::Hash;
+
+use crate::core::branch::Diff;
+use crate::core::codec::Encode;
+use crate::core::tree::MerkleSearchTree;
+
+// One replica of the implementation under comparison.
+pub trait ReferenceTree<K> {
+    fn upsert(&mut self, key: K, value: &[u8]);
+
+    // Inclusive key ranges in which `self` and `other` may differ, covering
+    // every key that does. Empty if they hold the same entries.
+    fn diff(&mut self, other: &mut Self) -> Vec<(K, K)>;
+}
+
+// The influxdata tree as a reference. It stores value hashes only, and
+// needs keys it can hash as bytes.
+#[cfg(feature = "compat-tests")]
+#[derive(Default)]
+pub struct Influx<K>(merkle_search_tree::MerkleSearchTree<K, Vec<u8>>);
+
+#[cfg(feature = "compat-tests")]
+impl<K: AsRef<[u8]> + Ord + Clone + Hash + Debug> ReferenceTree<K> for Influx<K> {
+    fn upsert(&mut self, key: K, value: &[u8]) {
+        self.0.upsert(key, &value.to_vec());
+    }
+
+    fn diff(&mut self, other: &mut Self) -> Vec<(K, K)> {
+        // Page ranges are only serialised once the root hash is current.
+        self.0.root_hash();
+        other.0.root_hash();
+        let ours = self.0.serialise_page_ranges().unwrap_or_default();
+        let theirs = other.0.serialise_page_ranges().unwrap_or_default();
+        let missing_here = merkle_search_tree::diff::diff(ours.clone(), theirs.clone());
+        let missing_there = merkle_search_tree::diff::diff(theirs, ours);
+        missing_here
+            .iter()
+            .chain(&missing_there)
+            .map(|range| (range.start().clone(), range.end().clone()))
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Replica {
+    A,
+    B,
+    Both,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Op<K> {
+    pub replica: Replica,
+    pub key: K,
+    pub value: Vec<u8>,
+}
+
+pub struct Differential<K, R> {
+    ours: [MerkleSearchTree<K, Vec<u8>>; 2],
+    theirs: [R; 2],
+    // The entries both sides should hold, to tell which is wrong.
+    model: [BTreeMap<K, Vec<u8>>; 2],
+}
+
+impl<K, R> Differential<K, R>
+where
+    K: Ord + Clone + Default + Encode + Debug,
+    R: ReferenceTree<K>,
+{
+    // Compares against two empty reference replicas, with this crate's
+    // trees splitting at `max_children`.
+    pub fn new(max_children: usize, a: R, b: R) -> Self {
+        Differential {
+            ours: [
+                MerkleSearchTree::new(max_children),
+                MerkleSearchTree::new(max_children),
+            ],
+            theirs: [a, b],
+            model: [BTreeMap::new(), BTreeMap::new()],
+        }
+    }
+
+    pub fn apply(&mut self, op: Op<K>) {
+        let replicas: &[usize] = match op.replica {
+            Replica::A => &[0],
+            Replica::B => &[1],
+            Replica::Both => &[0, 1],
+        };
+        for &replica in replicas {
+            self.ours[replica].insert(op.key.clone(), op.value.clone());
+            self.theirs[replica].upsert(op.key.clone(), &op.value);
+            self.model[replica].insert(op.key.clone(), op.value.clone());
+        }
+    }
+
+    // Checks that both implementations see the same divergence. Returns the
+    // number of keys that differ.
+    pub fn check(&mut self) -> usize {
+        let [ours_a, ours_b] = &self.ours;
+        let differing: Vec<K> = ours_a
+            .diff(ours_b)
+            .into_iter()
+            .map(|diff| match diff {
+                Diff::Added(key, _) | Diff::Removed(key, _) | Diff::Changed { key, .. } => {
+                    key.clone()
+                }
+            })
+            .collect();
+        let [theirs_a, theirs_b] = &mut self.theirs;
+        let ranges = theirs_a.diff(theirs_b);
+
+        let [model_a, model_b] = &self.model;
+        let diverged = model_a != model_b;
+        assert_eq!(
+            !differing.is_empty(),
+            diverged,
+            "this crate's divergence disagrees with the entries"
+        );
+        assert_eq!(
+            !ranges.is_empty(),
+            diverged,
+            "the reference's divergence disagrees with the entries"
+        );
+        for key in &differing {
+            assert!(
+                ranges.iter().any(|(start, end)| start <= key && key <= end),
+                "{key:?} differs but no reference range covers it"
+            );
+        }
+        differing.len()
+    }
+
+    pub fn run(&mut self, ops: impl IntoIterator<Item = Op<K>>) -> usize {
+        for op in ops {
+            self.apply(op);
+        }
+        self.check()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[cfg(feature = "compat-tests")]
+    use crate::core::rng::Rng;
+
+    // A reference that compares entries one by one.
+    #[derive(Default)]
+    struct Naive(BTreeMap<u32, Vec<u8>>);
+
+    impl ReferenceTree<u32> for Naive {
+        fn upsert(&mut self, key: u32, value: &[u8]) {
+            self.0.insert(key, value.to_vec());
+        }
+
+        fn diff(&mut self, other: &mut Self) -> Vec<(u32, u32)> {
+            let keys: BTreeMap<&u32, ()> = self
+                .0
+                .keys()
+                .chain(other.0.keys())
+                .map(|key| (key, ()))
+                .collect();
+            keys.into_keys()
+                .filter(|key| self.0.get(key) != other.0.get(key))
+                .map(|key| (*key, *key))
+                .collect()
+        }
+    }
+
+    fn op(replica: Replica, key: u32, value: &str) -> Op<u32> {
+        Op {
+            replica,
+            key,
+            value: value.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_differential() {
+        let mut differential = Differential::new(4, Naive::default(), Naive::default());
+        let shared = (0..200).map(|i| op(Replica::Both, i, &format!("v{i}")));
+        assert_eq!(differential.run(shared), 0);
+        let ops = [
+            op(Replica::A, 7, "a"),
+            op(Replica::B, 300, "b"),
+            op(Replica::B, 50, "v50"),
+        ];
+        assert_eq!(differential.run(ops), 2);
+        // Converging again by upserts alone.
+        let ops = [op(Replica::B, 7, "a"), op(Replica::A, 300, "b")];
+        assert_eq!(differential.run(ops), 0);
+    }
+
+    #[cfg(feature = "compat-tests")]
+    #[test]
+    fn test_against_influxdata() {
+        let key = |i: u64| format!("key/{i:04}").into_bytes();
+        let put = |replica, i, value: &str| Op {
+            replica,
+            key: key(i),
+            value: value.as_bytes().to_vec(),
+        };
+        let mut rng = Rng(7);
+        let mut differential = Differential::new(8, Influx::default(), Influx::default());
+        let shared = (0..500).map(|i| put(Replica::Both, i, "v"));
+        assert_eq!(differential.run(shared), 0);
+        let mut diverged = 0;
+        for round in 0..20 {
+            let ops: Vec<_> = (0..rng.below(20))
+                .map(|_| {
+                    let replica = match rng.below(3) {
+                        0 => Replica::A,
+                        1 => Replica::B,
+                        _ => Replica::Both,
+                    };
+                    put(replica, rng.below(600), &format!("r{round}"))
+                })
+                .collect();
+            diverged += differential.run(ops);
+        }
+        assert!(diverged > 0);
+        // Converging by writing every key to both sides.
+        let ops = (0..600).map(|i| put(Replica::Both, i, "last"));
+        assert_eq!(differential.run(ops), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "no reference range covers it")]
+    fn test_differential_catches_missed_key() {
+        // A reference that only ever reports the first range.
+        struct Narrow(Naive);
+        impl ReferenceTree<u32> for Narrow {
+            fn upsert(&mut self, key: u32, value: &[u8]) {
+                self.0.upsert(key, value);
+            }
+
+            fn diff(&mut self, other: &mut Self) -> Vec<(u32, u32)> {
+                self.0.diff(&mut other.0).into_iter().take(1).collect()
+            }
+        }
+        let mut differential =
+            Differential::new(4, Narrow(Naive::default()), Narrow(Naive::default()));
+        differential.run([op(Replica::A, 1, "a"), op(Replica::B, 2, "b")]);
+    }
+}
@@ -0,0 +1,165 @@
+// One digest over the roots of several unrelated trees, e.g. for a node
+// replicating a few datasets with different key and value types that wants
+// a single health check against its peers.
+//
+// Each tree's root is recorded under a label. The digest covers the labels
+// and roots in label order, so it doesn't depend on the order components
+// were added in. When two composites' digests differ, exchanging the
+// composites themselves shows which labels diverged.
+
+use std::collections::BTreeMap;
+
+use crate::core::codec::{Decode, Encode};
+use crate::core::error::Error;
+use crate::core::hash::NodeHash;
+use crate::core::tree::MerkleSearchTree;
+
+// Keeps composite digests apart from digests of other byte strings.
+const COMPOSITE_TAG: &[u8] = b"merkle-search-tree composite root";
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CompositeRoot<const N: usize = 32> {
+    components: BTreeMap<String, NodeHash<N>>,
+}
+
+impl<const N: usize> CompositeRoot<N> {
+    pub fn new() -> Self {
+        CompositeRoot {
+            components: BTreeMap::new(),
+        }
+    }
+
+    // Records `tree`'s root under `label`, replacing any earlier one.
+    pub fn with_tree<K, V>(self, label: &str, tree: &MerkleSearchTree<K, V, N>) -> Self
+    where
+        K: Ord + Clone + Default + Encode,
+        V: AsRef<[u8]>,
+    {
+        self.with_root(label, tree.root_hash())
+    }
+
+    pub fn with_root(mut self, label: &str, root: NodeHash<N>) -> Self {
+        self.set(label, root);
+        self
+    }
+
+    // Records a new root for `label`, e.g. after its tree changed.
+    pub fn set(&mut self, label: &str, root: NodeHash<N>) {
+        self.components.insert(label.to_string(), root);
+    }
+
+    pub fn remove(&mut self, label: &str) -> Option<NodeHash<N>> {
+        self.components.remove(label)
+    }
+
+    pub fn get(&self, label: &str) -> Option<&NodeHash<N>> {
+        self.components.get(label)
+    }
+
+    // The components in label order.
+    pub fn components(&self) -> impl Iterator<Item = (&str, &NodeHash<N>)> {
+        self.components
+            .iter()
+            .map(|(label, root)| (label.as_str(), root))
+    }
+
+    pub fn digest(&self) -> NodeHash<N> {
+        let mut bytes = COMPOSITE_TAG.to_vec();
+        self.encode(&mut bytes);
+        NodeHash::digest(&bytes)
+    }
+
+    // The labels whose roots differ from `other`'s, including those only
+    // one side has, in label order.
+    pub fn diverged<'a>(&'a self, other: &'a Self) -> Vec<&'a str> {
+        let mut labels: Vec<&str> = self
+            .components
+            .iter()
+            .filter(|(label, root)| other.components.get(*label) != Some(root))
+            .map(|(label, _)| label.as_str())
+            .collect();
+        labels.extend(
+            other
+                .components
+                .keys()
+                .filter(|label| !self.components.contains_key(*label))
+                .map(String::as_str),
+        );
+        labels.sort();
+        labels
+    }
+}
+
+impl<const N: usize> Encode for CompositeRoot<N> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (self.components.len() as u32).encode(out);
+        for (label, root) in &self.components {
+            label.encode(out);
+            root.encode(out);
+        }
+    }
+}
+
+impl<const N: usize> Decode for CompositeRoot<N> {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        let count = u32::decode(input)?;
+        let mut composite = CompositeRoot::new();
+        let mut previous: Option<String> = None;
+        for _ in 0..count {
+            let label = String::decode(input)?;
+            // Label order keeps the encoding canonical.
+            if previous.as_ref().is_some_and(|previous| *previous >= label) {
+                return Err(Error::Malformed(
+                    "composite labels are out of order".to_string(),
+                ));
+            }
+            let root = NodeHash::decode(input)?;
+            composite.components.insert(label.clone(), root);
+            previous = Some(label);
+        }
+        Ok(composite)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_composite_root() {
+        let mut users = MerkleSearchTree::<u64>::new(8);
+        let mut blobs = MerkleSearchTree::<String, Vec<u8>>::new(8);
+        for i in 0..50 {
+            users.insert(i, format!("user {i}"));
+            blobs.insert(format!("blob-{i}"), vec![i as u8; 10]);
+        }
+        let ours = CompositeRoot::new()
+            .with_tree("users", &users)
+            .with_tree("blobs", &blobs);
+        let theirs = CompositeRoot::new()
+            .with_tree("blobs", &blobs)
+            .with_tree("users", &users);
+        assert_eq!(ours.digest(), theirs.digest());
+        assert!(ours.diverged(&theirs).is_empty());
+
+        users.insert(7, "changed".to_string());
+        let mut theirs = theirs.with_tree("users", &users);
+        assert_ne!(ours.digest(), theirs.digest());
+        assert_eq!(ours.diverged(&theirs), ["users"]);
+        theirs.set("events", NodeHash::empty_root());
+        assert_eq!(ours.diverged(&theirs), ["events", "users"]);
+
+        // The labels are covered, not just the roots.
+        let relabeled = CompositeRoot::new()
+            .with_root("people", *ours.get("users").unwrap())
+            .with_root("blobs", *ours.get("blobs").unwrap());
+        assert_ne!(relabeled.digest(), ours.digest());
+
+        let mut bytes = Vec::new();
+        theirs.encode(&mut bytes);
+        assert_eq!(
+            CompositeRoot::decode(&mut bytes.as_slice()).unwrap(),
+            theirs
+        );
+    }
+}
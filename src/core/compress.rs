@@ -0,0 +1,197 @@
+// Compression of the values stored in snapshot pages.
+//
+// Values whose encoding is longer than a threshold are compressed with a
+// small LZ77 coder when that makes them shorter. Only the stored bytes
+// change: leaf hashes are computed over the uncompressed values, so root
+// hashes are the same whatever the compression setting, and a tree restores
+// snapshots written with any setting.
+//
+// The compressed stream is a sequence of ops. A control byte below 0x80
+// starts a run of `control + 1` literal bytes; any other copies
+// `(control & 0x7f) + 4` bytes from `offset` bytes back, with the offset in
+// the two bytes that follow.
+
+use crate::core::codec::{Decode, Encode, encode_len, take};
+use crate::core::error::Error;
+use crate::core::tree::MerkleSearchTree;
+
+const RAW: u8 = 0;
+const COMPRESSED: u8 = 1;
+
+const MIN_MATCH: usize = 4;
+const MAX_MATCH: usize = MIN_MATCH + 0x7f;
+const MAX_LITERALS: usize = 0x80;
+const TABLE_BITS: u32 = 12;
+
+impl<K: Ord + Clone + Default + Encode, V: AsRef<[u8]>, const N: usize> MerkleSearchTree<K, V, N> {
+    // Compresses values encoding to more than `threshold` bytes in snapshots.
+    pub fn with_value_compression(mut self, threshold: usize) -> Self {
+        self.compress_above = Some(threshold);
+        self
+    }
+}
+
+// Writes `value` as a flag followed by its encoding, compressed if it's
+// longer than `threshold` bytes and compression pays off.
+pub(crate) fn encode_value<V: Encode>(value: &V, threshold: usize, out: &mut Vec<u8>) {
+    let mut raw = Vec::new();
+    value.encode(&mut raw);
+    if raw.len() > threshold {
+        let packed = compress(&raw);
+        if packed.len() < raw.len() {
+            out.push(COMPRESSED);
+            encode_len(raw.len(), out);
+            packed.encode(out);
+            return;
+        }
+    }
+    out.push(RAW);
+    out.extend_from_slice(&raw);
+}
+
+// The inverse of `encode_value`.
+pub(crate) fn decode_value<V: Decode>(input: &mut &[u8]) -> Result<V, Error> {
+    match u8::decode(input)? {
+        RAW => V::decode(input),
+        COMPRESSED => {
+            let len = u32::decode(input)? as usize;
+            let raw = decompress(&Vec::<u8>::decode(input)?, len)?;
+            let mut raw = raw.as_slice();
+            let value = V::decode(&mut raw)?;
+            if !raw.is_empty() {
+                return Err(Error::Malformed(
+                    "trailing bytes after compressed value".to_string(),
+                ));
+            }
+            Ok(value)
+        }
+        flag => Err(Error::Malformed(format!("unknown value flag {flag}"))),
+    }
+}
+
+pub(crate) fn compress(input: &[u8]) -> Vec<u8> {
+    // The last position of each 4-byte sequence, by hash.
+    let mut table = vec![usize::MAX; 1 << TABLE_BITS];
+    let mut out = Vec::new();
+    let mut literals = 0;
+    let mut i = 0;
+    while i + MIN_MATCH <= input.len() {
+        let window = &input[i..i + MIN_MATCH];
+        let slot = (u32::from_le_bytes(window.try_into().expect("4 bytes"))
+            .wrapping_mul(2654435761)
+            >> (32 - TABLE_BITS)) as usize;
+        let candidate = std::mem::replace(&mut table[slot], i);
+        if candidate == usize::MAX
+            || i - candidate > u16::MAX as usize
+            || input[candidate..candidate + MIN_MATCH] != *window
+        {
+            i += 1;
+            continue;
+        }
+
+        let len = MIN_MATCH
+            + input[i + MIN_MATCH..]
+                .iter()
+                .zip(&input[candidate + MIN_MATCH..])
+                .take(MAX_MATCH - MIN_MATCH)
+                .take_while(|(a, b)| a == b)
+                .count();
+        push_literals(&mut out, &input[literals..i]);
+        out.push(0x80 | (len - MIN_MATCH) as u8);
+        ((i - candidate) as u16).encode(&mut out);
+        i += len;
+        literals = i;
+    }
+    push_literals(&mut out, &input[literals..]);
+    out
+}
+
+fn push_literals(out: &mut Vec<u8>, literals: &[u8]) {
+    for run in literals.chunks(MAX_LITERALS) {
+        out.push((run.len() - 1) as u8);
+        out.extend_from_slice(run);
+    }
+}
+
+// Decompresses `input`, which must expand to exactly `len` bytes.
+pub(crate) fn decompress(mut input: &[u8], len: usize) -> Result<Vec<u8>, Error> {
+    let mut out = Vec::with_capacity(len);
+    while !input.is_empty() {
+        let control = u8::decode(&mut input)?;
+        if control < 0x80 {
+            out.extend_from_slice(take(&mut input, control as usize + 1)?);
+        } else {
+            let offset = u16::decode(&mut input)? as usize;
+            if offset == 0 || offset > out.len() {
+                return Err(Error::Malformed(
+                    "compressed match before the start".to_string(),
+                ));
+            }
+            // Copy byte by byte: the match may overlap what it produces.
+            let start = out.len() - offset;
+            for i in 0..(control & 0x7f) as usize + MIN_MATCH {
+                out.push(out[start + i]);
+            }
+        }
+        if out.len() > len {
+            return Err(Error::Malformed(
+                "compressed value longer than declared".to_string(),
+            ));
+        }
+    }
+    if out.len() != len {
+        return Err(Error::Malformed(
+            "compressed value shorter than declared".to_string(),
+        ));
+    }
+    Ok(out)
+}
+
+#[cfg(all(test, feature = "store"))]
+mod test {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    #[test]
+    fn test_round_trip() {
+        let mut noise = Vec::new();
+        let mut state = 7u32;
+        for _ in 0..1000 {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            noise.push((state >> 16) as u8);
+        }
+        let repetitive = "abcabcabd".repeat(200).into_bytes();
+        for input in [vec![], b"a".to_vec(), noise, repetitive.clone()] {
+            let packed = compress(&input);
+            assert_eq!(decompress(&packed, input.len()).unwrap(), input);
+        }
+        assert!(compress(&repetitive).len() < repetitive.len() / 10);
+
+        assert!(decompress(&[0x80, 0, 1], 4).is_err());
+        assert!(decompress(&compress(b"abcd"), 3).is_err());
+    }
+
+    #[test]
+    fn test_compressed_snapshot() {
+        let mut plain = MerkleSearchTree::<u32>::new(8);
+        let mut compressed = MerkleSearchTree::<u32>::new(8).with_value_compression(64);
+        for i in 0..200 {
+            let value = format!("{i}:").repeat(50);
+            plain.insert(i, value.clone());
+            compressed.insert(i, value);
+        }
+        compressed.insert(500, "short".to_string());
+        plain.insert(500, "short".to_string());
+
+        let (plain_manifest, plain_stats) = plain.write_snapshot(&mut MemoryStore::new()).unwrap();
+        let mut store = MemoryStore::new();
+        let (manifest, stats) = compressed.write_snapshot(&mut store).unwrap();
+        assert_eq!(manifest.root_hash, plain_manifest.root_hash);
+        assert!(stats.bytes_written < plain_stats.bytes_written / 3);
+
+        // Restoring doesn't depend on the reader's setting.
+        let mut restored = MerkleSearchTree::<u32>::new(4);
+        restored.restore(&store, &manifest).unwrap();
+        assert!(restored.iter().eq(plain.iter()));
+    }
+}
@@ -0,0 +1,144 @@
+// Validated tree configuration.
+//
+// A node must be able to hold three children. With one, every split leaves
+// a node as full as before and inserts never finish; with two, a full node
+// of three splits into halves of one and two, and ascending inserts chain
+// those single-child nodes into a spine as long as the tree. A depth limit
+// below one level rejects every insert. `MaxChildren` can only hold a usable fanout, and
+// `TreeConfig` checks the rest when the tree is built, so a bad
+// configuration fails there instead of inside the first insert.
+//
+// `HashScheme` and `FanoutPolicy` describe a tree's configuration to others:
+// to peers in a sync handshake and to verifiers in a commitment.
+
+use crate::core::error::Error;
+
+// A node fanout of at least three children.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MaxChildren(usize);
+
+impl MaxChildren {
+    pub const MIN: usize = 3;
+    // Used where no fanout is given, e.g. converting from a `BTreeMap`.
+    pub const DEFAULT: MaxChildren = MaxChildren(16);
+
+    // None if `max_children` is below `MIN`.
+    pub const fn new(max_children: usize) -> Option<Self> {
+        if max_children >= Self::MIN {
+            Some(MaxChildren(max_children))
+        } else {
+            None
+        }
+    }
+
+    pub const fn get(self) -> usize {
+        self.0
+    }
+}
+
+impl TryFrom<usize> for MaxChildren {
+    type Error = Error;
+
+    fn try_from(max_children: usize) -> Result<Self, Error> {
+        MaxChildren::new(max_children).ok_or_else(|| {
+            Error::InvalidConfig(format!(
+                "max_children is {max_children}, must be at least {}",
+                MaxChildren::MIN
+            ))
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TreeConfig {
+    pub max_children: MaxChildren,
+    // See `MerkleSearchTree::with_max_depth`.
+    pub max_depth: Option<usize>,
+}
+
+impl TreeConfig {
+    pub fn new(max_children: MaxChildren) -> Self {
+        TreeConfig {
+            max_children,
+            max_depth: None,
+        }
+    }
+
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.max_depth == Some(0) {
+            return Err(Error::InvalidConfig(
+                "max_depth must be at least 1".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+// How leaf hashes are computed: the digest and the hash width in bytes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HashScheme {
+    pub algorithm: String,
+    pub width: u16,
+}
+
+impl HashScheme {
+    // The scheme of `NodeHash<N>`.
+    pub fn of<const N: usize>() -> Self {
+        let algorithm = if N == 64 { "sha512" } else { "sha256" };
+        HashScheme {
+            algorithm: algorithm.to_string(),
+            width: N as u16,
+        }
+    }
+}
+
+// How a tree decides to split a node; see `with_target_node_bytes`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FanoutPolicy {
+    Children(u32),
+    NodeBytes(u32),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::tree::MerkleSearchTree;
+
+    #[test]
+    fn test_validation() {
+        assert_eq!(MaxChildren::new(1), None);
+        assert_eq!(MaxChildren::new(2), None);
+        assert_eq!(MaxChildren::new(3).map(MaxChildren::get), Some(3));
+        assert!(matches!(
+            MaxChildren::try_from(0),
+            Err(Error::InvalidConfig(_))
+        ));
+        assert!(MerkleSearchTree::<u32>::try_new(1).is_err());
+        assert!(matches!(
+            MerkleSearchTree::<u32>::try_new(2),
+            Err(Error::InvalidConfig(_))
+        ));
+
+        let config = TreeConfig::new(MaxChildren::new(3).unwrap());
+        assert!(MerkleSearchTree::<u32>::from_config(config.with_max_depth(0)).is_err());
+        let mut tree = MerkleSearchTree::from_config(config.with_max_depth(2)).unwrap();
+        // Two levels of three children hold at most nine entries.
+        let failed = (0..10u32).find_map(|i| tree.try_insert(i, format!("v{i}")).err());
+        assert!(matches!(
+            failed,
+            Some(Error::DepthLimitExceeded { limit: 2 })
+        ));
+        assert!(tree.len() <= 9);
+    }
+
+    #[test]
+    #[should_panic(expected = "max_children is 1")]
+    fn test_new_rejects_small_fanout() {
+        MerkleSearchTree::<u32>::new(1);
+    }
+}
@@ -0,0 +1,82 @@
+// Conversions between trees and ordinary collections.
+//
+// A tree built from entries hashes the same whatever their order or the
+// fanout, so a migration can check the rebuilt root against the one the
+// source recorded and refuse data that lost or changed entries on the way.
+
+use std::collections::BTreeMap;
+
+use crate::core::codec::Encode;
+use crate::core::config::MaxChildren;
+use crate::core::error::Error;
+use crate::core::hash::NodeHash;
+use crate::core::tree::MerkleSearchTree;
+
+// Uses the default fanout, `MaxChildren::DEFAULT`.
+impl<K: Ord + Clone + Default + Encode, V: AsRef<[u8]> + Clone> From<BTreeMap<K, V>>
+    for MerkleSearchTree<K, V>
+{
+    fn from(map: BTreeMap<K, V>) -> Self {
+        let mut tree = MerkleSearchTree::new(MaxChildren::DEFAULT.get());
+        for (key, value) in map {
+            tree.insert(key, value);
+        }
+        tree
+    }
+}
+
+impl<K: Ord + Clone + Default + Encode, V: AsRef<[u8]> + Clone> MerkleSearchTree<K, V> {
+    // Builds a tree with the default fanout from `entries`, failing with
+    // `Error::RootMismatch` unless it hashes to `expected_root`. Later
+    // entries replace earlier ones with the same key.
+    pub fn try_from_entries_with_root<I>(entries: I, expected_root: NodeHash) -> Result<Self, Error>
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let mut tree = MerkleSearchTree::new(MaxChildren::DEFAULT.get());
+        for (key, value) in entries {
+            tree.insert(key, value);
+        }
+        if *tree.hash() != expected_root {
+            return Err(Error::RootMismatch {
+                expected: expected_root,
+                actual: *tree.hash(),
+            });
+        }
+        Ok(tree)
+    }
+
+    // The entries as a map. Values shared with forks are cloned.
+    pub fn into_btree_map(self) -> BTreeMap<K, V> {
+        self.iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_btree_map_round_trip() {
+        let map: BTreeMap<u32, String> = (0..500).map(|i| (i, format!("v{i}"))).collect();
+        let tree = MerkleSearchTree::from(map.clone());
+        assert_eq!(tree.len(), 500);
+        let root = *tree.hash();
+        assert_eq!(tree.into_btree_map(), map);
+
+        // Entries in any order give the same root.
+        let rebuilt =
+            MerkleSearchTree::try_from_entries_with_root(map.clone().into_iter().rev(), root)
+                .unwrap();
+        assert!(rebuilt.iter().eq(map.iter()));
+
+        let mut lossy = map.clone();
+        lossy.remove(&250);
+        assert!(matches!(
+            MerkleSearchTree::try_from_entries_with_root(lossy, root),
+            Err(Error::RootMismatch { .. })
+        ));
+    }
+}
@@ -0,0 +1,192 @@
+// Deduplicated values: datasets where many keys hold the same small value,
+// such as a status document or an empty JSON object, can keep one copy of
+// each distinct value in a `ValuePool` and share it between every leaf that
+// holds it.
+//
+// A `SharedValue` hashes, compares and encodes exactly like the value it
+// wraps, so a tree of shared values has the same digests and snapshot pages
+// as one of plain values, and the two can sync. Only memory differs.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Deref;
+use std::sync::Arc;
+
+use crate::core::codec::{Decode, Encode};
+use crate::core::error::Error;
+use crate::core::hash::NodeHash;
+
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SharedValue<V> {
+    value: Arc<V>,
+}
+
+impl<V> SharedValue<V> {
+    // Whether two values share storage, not just content.
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.value, &other.value)
+    }
+}
+
+// An unpooled value, e.g. for a one-off write.
+impl<V> From<V> for SharedValue<V> {
+    fn from(value: V) -> Self {
+        SharedValue {
+            value: Arc::new(value),
+        }
+    }
+}
+
+impl<V> Deref for SharedValue<V> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        &self.value
+    }
+}
+
+impl<V: AsRef<[u8]>> AsRef<[u8]> for SharedValue<V> {
+    fn as_ref(&self) -> &[u8] {
+        (*self.value).as_ref()
+    }
+}
+
+impl<V: fmt::Debug> fmt::Debug for SharedValue<V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.value.fmt(f)
+    }
+}
+
+impl<V: Encode> Encode for SharedValue<V> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.value.encode(out);
+    }
+
+    fn encoded_len(&self) -> usize {
+        self.value.encoded_len()
+    }
+}
+
+// Decoded values aren't pooled: a decoder has no pool to share. They hold
+// their own copy until passed through `ValuePool::intern`.
+impl<V: Decode> Decode for SharedValue<V> {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        V::decode(input).map(SharedValue::from)
+    }
+}
+
+// The table of distinct values, by content hash.
+pub struct ValuePool<V> {
+    values: HashMap<NodeHash, Arc<V>>,
+}
+
+impl<V: AsRef<[u8]>> ValuePool<V> {
+    pub fn new() -> Self {
+        ValuePool {
+            values: HashMap::new(),
+        }
+    }
+
+    pub fn value(&mut self, value: V) -> SharedValue<V> {
+        let hash = NodeHash::digest(value.as_ref());
+        let shared = self.values.entry(hash).or_insert_with(|| Arc::new(value));
+        SharedValue {
+            value: shared.clone(),
+        }
+    }
+
+    // Swaps `value` for the pool's copy, e.g. after decoding it.
+    pub fn intern(&mut self, value: &SharedValue<V>) -> SharedValue<V> {
+        let hash = NodeHash::digest(value.as_ref());
+        let shared = self
+            .values
+            .entry(hash)
+            .or_insert_with(|| value.value.clone());
+        SharedValue {
+            value: shared.clone(),
+        }
+    }
+
+    // The number of distinct values.
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    // The live references to pooled values per distinct value: 1.0 when
+    // nothing is shared, and the average number of leaves per value when
+    // every value is in a tree. None when the pool holds nothing in use.
+    pub fn dedup_ratio(&self) -> Option<f64> {
+        let (mut refs, mut used) = (0, 0);
+        for value in self.values.values() {
+            let count = Arc::strong_count(value) - 1;
+            refs += count;
+            used += usize::from(count > 0);
+        }
+        (used > 0).then(|| refs as f64 / used as f64)
+    }
+
+    // The value bytes sharing saves over one copy per reference.
+    pub fn bytes_saved(&self) -> usize {
+        self.values
+            .values()
+            .map(|value| (Arc::strong_count(value).saturating_sub(2)) * (**value).as_ref().len())
+            .sum()
+    }
+
+    // Drops the values nothing uses any more and returns how many were
+    // dropped. Values kept elsewhere, e.g. in a tree's history, still count.
+    pub fn release_unused(&mut self) -> usize {
+        let before = self.values.len();
+        self.values.retain(|_, value| Arc::strong_count(value) > 1);
+        before - self.values.len()
+    }
+}
+
+impl<V: AsRef<[u8]>> Default for ValuePool<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::tree::MerkleSearchTree;
+
+    #[test]
+    fn test_pooled_tree() {
+        let mut pool = ValuePool::new();
+        let mut pooled = MerkleSearchTree::new(8);
+        let mut plain = MerkleSearchTree::new(8);
+        for i in 0u32..1000 {
+            let value = format!("{{\"status\":{}}}", i % 4);
+            pooled.insert(i, pool.value(value.clone()));
+            plain.insert(i, value);
+        }
+        assert_eq!(pool.len(), 4);
+        assert_eq!(pooled.content_digest(), plain.content_digest());
+        assert_eq!(pooled.hash(), plain.hash());
+        assert!(pooled.get(&0).unwrap().ptr_eq(pooled.get(&4).unwrap()));
+        assert_eq!(pool.dedup_ratio(), Some(250.0));
+        assert_eq!(pool.bytes_saved(), 4 * 249 * 12);
+
+        // Decoded values share again once interned.
+        let mut bytes = Vec::new();
+        pooled.get(&1).unwrap().encode(&mut bytes);
+        let decoded = SharedValue::<String>::decode(&mut bytes.as_slice()).unwrap();
+        assert!(!decoded.ptr_eq(pooled.get(&1).unwrap()));
+        assert!(pool.intern(&decoded).ptr_eq(pooled.get(&1).unwrap()));
+
+        for i in (0..1000).filter(|i| i % 4 == 3) {
+            pooled.remove(&i);
+        }
+        #[cfg(feature = "structure-log")]
+        pooled.take_structure_log();
+        assert_eq!(pool.release_unused(), 1);
+        assert_eq!(pool.len(), 3);
+    }
+}
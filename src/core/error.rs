@@ -0,0 +1,126 @@
+use std::fmt;
+use std::io;
+
+// Variants are added as new failures arise, so matches need a wildcard arm.
+// The page ids and roots in variants are 32-byte hashes: only stores,
+// proofs, patches and imports report them, and those only take trees of
+// the default width.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    // A branch with this name already exists.
+    BranchExists(String),
+    // Media corruption: a page's bytes fail their checksum.
+    ChecksumMismatch(crate::core::hash::NodeHash),
+    // Two different values share this digest, found with collision checks
+    // on. The hash width is the tree's, so it is given in hex.
+    CollisionDetected(String),
+    // The insert needed another tree level beyond the configured limit.
+    DepthLimitExceeded {
+        limit: usize,
+    },
+    // The key is already present and the duplicate policy rejects writes
+    // to it.
+    DuplicateKey,
+    // The grafted tree's keys interleave with the tree's own.
+    GraftOverlap,
+    // An update meant to leave a value's hash alone changed it.
+    HashChanged,
+    // Logical corruption: content is intact but doesn't hash to what was
+    // recorded for it, e.g. a page stored under the wrong id.
+    HashMismatch {
+        expected: crate::core::hash::NodeHash,
+        actual: crate::core::hash::NodeHash,
+    },
+    // A peer's hello leaves nothing both sides can speak.
+    IncompatiblePeer(String),
+    // A tree configuration that can't work, e.g. a fanout below 3.
+    InvalidConfig(String),
+    // Reading from or writing to a store failed.
+    Io(io::Error),
+    // Bytes that should hold an encoded value, page or manifest don't.
+    Malformed(String),
+    // A frame longer than the negotiated cap, before or after decompression,
+    // or a serialized proof longer than the caller allows.
+    MessageTooLarge {
+        size: u64,
+        limit: u64,
+    },
+    // A page referenced by a snapshot is not in the store.
+    MissingPage(crate::core::hash::NodeHash),
+    // The insert would take its tenant past its quota, to this usage.
+    QuotaExceeded {
+        entries: usize,
+        bytes: u64,
+    },
+    // A tree's root isn't the one an operation expected.
+    RootMismatch {
+        expected: crate::core::hash::NodeHash,
+        actual: crate::core::hash::NodeHash,
+    },
+    // No branch has this name.
+    UnknownBranch(String),
+    // A value was encoded with a version of its codec this build can't read.
+    UnknownEncoding(u8),
+    // No version has this number.
+    UnknownVersion(u64),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::BranchExists(name) => write!(f, "branch {name:?} already exists"),
+            Error::ChecksumMismatch(id) => write!(f, "page {id} fails its checksum"),
+            Error::CollisionDetected(hash) => {
+                write!(f, "different values share the digest {hash}")
+            }
+            Error::DepthLimitExceeded { limit } => {
+                write!(
+                    f,
+                    "insert would grow the tree beyond its depth limit of {limit}"
+                )
+            }
+            Error::DuplicateKey => write!(f, "the key is already present"),
+            Error::GraftOverlap => write!(f, "grafted keys overlap the tree's keys"),
+            Error::HashChanged => write!(f, "the update changed hashed bytes"),
+            Error::HashMismatch { expected, actual } => {
+                write!(f, "expected hash {expected}, found {actual}")
+            }
+            Error::IncompatiblePeer(reason) => write!(f, "incompatible peer: {reason}"),
+            Error::InvalidConfig(reason) => write!(f, "invalid configuration: {reason}"),
+            Error::Io(err) => write!(f, "store i/o failed: {err}"),
+            Error::Malformed(reason) => write!(f, "malformed data: {reason}"),
+            Error::MessageTooLarge { size, limit } => {
+                write!(f, "message of {size} bytes exceeds the limit of {limit}")
+            }
+            Error::MissingPage(id) => write!(f, "page {id} is missing from the store"),
+            Error::QuotaExceeded { entries, bytes } => write!(
+                f,
+                "insert would exceed the quota with {entries} entries of {bytes} bytes"
+            ),
+            Error::RootMismatch { expected, actual } => {
+                write!(f, "expected root {expected}, found {actual}")
+            }
+            Error::UnknownBranch(name) => write!(f, "no branch named {name:?}"),
+            Error::UnknownEncoding(version) => {
+                write!(f, "no codec for value encoding version {version}")
+            }
+            Error::UnknownVersion(version) => write!(f, "no version {version}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
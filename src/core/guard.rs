@@ -0,0 +1,142 @@
+// Mutable access to a stored value, with the hashes fixed afterwards.
+//
+// `get_mut` hands out a guard that derefs to the value. While it lives, the
+// tree is borrowed and its hashes may be stale; when it drops after a
+// mutable borrow, or on `commit`, the leaf is rehashed and so is every node
+// above it, as `update` would, and the change reaches the change history,
+// op log and watchers like any write. A guard that is leaked instead of
+// dropped leaves the hashes stale.
+
+use std::ops::{Deref, DerefMut};
+
+use crate::core::codec::Encode;
+use crate::core::hash::NodeHash;
+use crate::core::tree::MerkleSearchTree;
+
+pub struct ValueGuard<
+    'a,
+    K: Ord + Clone + Default + Encode,
+    V: AsRef<[u8]> + Clone,
+    const N: usize = 32,
+> {
+    tree: &'a mut MerkleSearchTree<K, V, N>,
+    key: K,
+    // Whether the value was borrowed mutably, so needs rehashing.
+    touched: bool,
+}
+
+impl<K: Ord + Clone + Default + Encode, V: AsRef<[u8]>, const N: usize> MerkleSearchTree<K, V, N> {
+    // A guard over `key`'s value, or None if the key is absent. The value
+    // is copied only if a fork shares it and the guard is written through.
+    pub fn get_mut(&mut self, key: &K) -> Option<ValueGuard<'_, K, V, N>>
+    where
+        V: Clone,
+    {
+        self.get(key)?;
+        Some(ValueGuard {
+            tree: self,
+            key: key.clone(),
+            touched: false,
+        })
+    }
+}
+
+impl<K: Ord + Clone + Default + Encode, V: AsRef<[u8]> + Clone, const N: usize>
+    ValueGuard<'_, K, V, N>
+{
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    // Rehashes now rather than on drop, returning the tree's new root.
+    pub fn commit(mut self) -> NodeHash<N> {
+        self.rehash();
+        self.tree.root_hash()
+    }
+
+    fn rehash(&mut self) {
+        if std::mem::take(&mut self.touched) {
+            self.tree.update(&self.key, |_| {});
+        }
+    }
+}
+
+impl<K: Ord + Clone + Default + Encode, V: AsRef<[u8]> + Clone, const N: usize> Deref
+    for ValueGuard<'_, K, V, N>
+{
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        self.tree
+            .get(&self.key)
+            .expect("the guarded key is present")
+    }
+}
+
+impl<K: Ord + Clone + Default + Encode, V: AsRef<[u8]> + Clone, const N: usize> DerefMut
+    for ValueGuard<'_, K, V, N>
+{
+    fn deref_mut(&mut self) -> &mut V {
+        self.touched = true;
+        self.tree
+            .value_mut(&self.key)
+            .expect("the guarded key is present")
+    }
+}
+
+impl<K: Ord + Clone + Default + Encode, V: AsRef<[u8]> + Clone, const N: usize> Drop
+    for ValueGuard<'_, K, V, N>
+{
+    fn drop(&mut self) {
+        self.rehash();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_get_mut_rehashes_on_drop() {
+        let mut tree = MerkleSearchTree::<u32>::new(4);
+        for i in 0..100 {
+            tree.insert(i, format!("value {i}"));
+        }
+        let fork = tree.fork();
+        tree.get_mut(&10).unwrap().push_str(" and more");
+        assert!(tree.get_mut(&1000).is_none());
+
+        let mut expected = fork.fork();
+        expected.insert(10, "value 10 and more".to_string());
+        assert_eq!(tree.hash(), expected.hash());
+        assert_eq!(tree.usage(..), expected.usage(..));
+        assert_eq!(fork.get(&10).map(String::as_str), Some("value 10"));
+
+        // Reading through a guard changes nothing.
+        let root = *tree.hash();
+        assert_eq!(tree.get_mut(&10).unwrap().len(), 17);
+        assert_eq!(*tree.hash(), root);
+    }
+
+    #[test]
+    fn test_commit() {
+        let mut tree = MerkleSearchTree::<u32>::new(4).with_recent_changes(8);
+        for i in 0..50 {
+            tree.insert(i, format!("value {i}"));
+        }
+        let token = tree.session_token();
+        let mut guard = tree.get_mut(&3).unwrap();
+        guard.clear();
+        guard.push_str("replaced");
+        assert_eq!(*guard.key(), 3);
+        let root = guard.commit();
+        assert_eq!(root, tree.root_hash());
+        assert_eq!(tree.generation(), token.generation + 1);
+        assert!(tree.satisfies(&token));
+
+        // Committing an untouched guard writes nothing.
+        let generation = tree.generation();
+        assert_eq!(tree.get_mut(&3).unwrap().commit(), root);
+        assert_eq!(tree.generation(), generation);
+    }
+}
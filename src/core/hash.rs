@@ -0,0 +1,235 @@
+use sha2::Digest;
+
+use crate::core::codec::{Decode, Encode};
+use crate::core::error::Error;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+
+// A hash of `N` bytes. 32 bytes (SHA-256) is the default, and the only
+// width snapshots, proofs and patches take. Memory-constrained deployments
+// can build trees with 16-byte hashes, a truncated SHA-256, and paranoid
+// ones with 64-byte SHA-512 hashes, which sync between replicas of the same
+// width but aren't persisted; other widths don't compile.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct NodeHash<const N: usize = 32>(pub [u8; N]);
+
+// `[u8; N]` is only `Default` up to 32 bytes.
+impl<const N: usize> Default for NodeHash<N> {
+    fn default() -> Self {
+        NodeHash([0; N])
+    }
+}
+
+impl<const N: usize> From<[u8; N]> for NodeHash<N> {
+    fn from(value: [u8; N]) -> Self {
+        NodeHash(value)
+    }
+}
+impl<const N: usize> Deref for NodeHash<N> {
+    type Target = [u8; N];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<const N: usize> DerefMut for NodeHash<N> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+impl<const N: usize> fmt::Display for NodeHash<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+// The width is part of the type, so hashes are written without a length.
+impl<const N: usize> Encode for NodeHash<N> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.0);
+    }
+
+    fn encoded_len(&self) -> usize {
+        N
+    }
+}
+
+impl<const N: usize> Decode for NodeHash<N> {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        Ok(NodeHash(<[u8; N]>::decode(input)?))
+    }
+}
+
+// The root of an empty tree, wherever one is shown: `hash()`, snapshots,
+// watches, peers and patches. The XOR of no leaves is all zeros, which says
+// nothing about what a tree holds, and the hash of no bytes is no better a
+// marker. This is the SHA-256 of a fixed tag instead; other widths use
+// `NodeHash::empty_root`.
+pub const EMPTY_ROOT: NodeHash = NodeHash([
+    0x7f, 0xbf, 0xcc, 0x5d, 0xb8, 0x68, 0x84, 0x17, 0xa2, 0x68, 0x83, 0xa8, 0x64, 0x86, 0x06, 0x26,
+    0x60, 0x10, 0x1e, 0xbb, 0x6f, 0x48, 0x04, 0x1e, 0xc5, 0x2a, 0xc8, 0x74, 0x57, 0xf7, 0x9c, 0x30,
+]);
+
+// The SHA-512 of the same tag, for 64-byte hashes. 16-byte ones truncate
+// `EMPTY_ROOT`, as they truncate every SHA-256.
+const EMPTY_ROOT_64: [u8; 64] = [
+    0x98, 0x0b, 0xc4, 0x26, 0x22, 0x6c, 0xcd, 0xa9, 0x33, 0x21, 0x7f, 0xa6, 0xa5, 0xb3, 0x3a, 0x84,
+    0xd2, 0xa0, 0x0a, 0x24, 0x23, 0x16, 0xa3, 0x82, 0x9d, 0x05, 0x76, 0x88, 0xf3, 0xc2, 0x1a, 0x9a,
+    0x86, 0x14, 0x2e, 0xea, 0xdf, 0x7a, 0x8c, 0x57, 0x66, 0x9f, 0x6b, 0xb1, 0xab, 0x5b, 0x02, 0xbc,
+    0x5e, 0x68, 0x38, 0x93, 0x57, 0xe8, 0x41, 0xac, 0x22, 0x6d, 0x30, 0x60, 0x8e, 0x68, 0x0b, 0xf3,
+];
+const LEAF_TAG: &[u8] = b"merkle-search-tree leaf";
+
+pub fn is_empty_root<const N: usize>(hash: &NodeHash<N>) -> bool {
+    *hash == NodeHash::empty_root()
+}
+
+impl<const N: usize> NodeHash<N> {
+    const SUPPORTED: () = assert!(
+        N == 16 || N == 32 || N == 64,
+        "hashes are 16, 32 or 64 bytes wide"
+    );
+
+    // Parses the lowercase hex form produced by `Display`.
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        if hex.len() != 2 * N || !hex.is_ascii() {
+            return None;
+        }
+        let mut bytes = [0; N];
+        for (byte, pair) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+            *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+        }
+        Some(NodeHash(bytes))
+    }
+
+    // The hash of the given bytes.
+    pub fn digest(bytes: &[u8]) -> Self {
+        Self::digest_parts([bytes])
+    }
+
+    // A leaf's hash, over its key as well as its value. Parents XOR their
+    // children's hashes, so hashing values alone would let two equal values
+    // cancel out, and let two keys swap values without changing any root.
+    pub fn leaf<K: Encode + ?Sized>(key: &K, value: &[u8]) -> Self {
+        let mut encoded = Vec::with_capacity(key.encoded_len());
+        key.encode(&mut encoded);
+        Self::leaf_encoded(&encoded, value)
+    }
+
+    // `leaf` for a key that is already encoded, as in pages and packets.
+    pub fn leaf_encoded(key: &[u8], value: &[u8]) -> Self {
+        Self::digest_parts([LEAF_TAG, key, value])
+    }
+
+    // `EMPTY_ROOT` at this width, as a constant so an empty tree can lend
+    // it out from `hash()`.
+    pub(crate) const EMPTY: Self = {
+        let source: &[u8] = if N == 64 {
+            &EMPTY_ROOT_64
+        } else {
+            &EMPTY_ROOT.0
+        };
+        let mut bytes = [0; N];
+        let mut i = 0;
+        while i < N {
+            bytes[i] = source[i];
+            i += 1;
+        }
+        NodeHash(bytes)
+    };
+
+    // `EMPTY_ROOT` at this width.
+    pub fn empty_root() -> Self {
+        let () = Self::SUPPORTED;
+        Self::EMPTY
+    }
+
+    // The root shown for `entries` entries whose leaf hashes XOR to `xor`:
+    // `xor` itself, or `empty_root` if there are none.
+    pub(crate) fn root_of(xor: Self, entries: usize) -> Self {
+        if entries == 0 {
+            Self::empty_root()
+        } else {
+            xor
+        }
+    }
+
+    // The hash of the concatenation of `hashes`, in order.
+    pub fn digest_sequence<'a>(hashes: impl IntoIterator<Item = &'a NodeHash<N>>) -> Self {
+        Self::digest_parts(hashes.into_iter().map(|hash| hash.0))
+    }
+
+    fn digest_parts<P: AsRef<[u8]>>(parts: impl IntoIterator<Item = P>) -> Self {
+        let () = Self::SUPPORTED;
+        let mut bytes = [0; N];
+        if N == 64 {
+            let mut hasher = sha2::Sha512::new();
+            parts.into_iter().for_each(|part| hasher.update(part));
+            bytes.copy_from_slice(&hasher.finalize());
+        } else {
+            let mut hasher = sha2::Sha256::new();
+            parts.into_iter().for_each(|part| hasher.update(part));
+            bytes.copy_from_slice(&hasher.finalize()[..N]);
+        }
+        NodeHash(bytes)
+    }
+
+    #[inline]
+    pub fn xor(&mut self, source: &NodeHash<N>) {
+        for (t, s) in self.iter_mut().zip(source.iter()) {
+            *t ^= s;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Published SHA-256 and SHA-512 vectors for "abc".
+    #[test]
+    fn test_digest_vectors() {
+        let sha256 = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        assert_eq!(NodeHash::<32>::digest(b"abc").to_string(), sha256);
+        assert_eq!(NodeHash::<16>::digest(b"abc").to_string(), sha256[..32]);
+        assert_eq!(
+            NodeHash::<64>::digest(b"abc").to_string(),
+            concat!(
+                "ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a",
+                "2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f"
+            )
+        );
+    }
+
+    #[test]
+    fn test_empty_root() {
+        let tag = b"merkle-search-tree empty root";
+        assert_eq!(NodeHash::<32>::empty_root(), EMPTY_ROOT);
+        assert_eq!(NodeHash::<32>::empty_root(), NodeHash::digest(tag));
+        assert_eq!(NodeHash::<16>::empty_root(), NodeHash::digest(tag));
+        assert_eq!(NodeHash::<64>::empty_root(), NodeHash::digest(tag));
+        assert_eq!(NodeHash::<16>::empty_root()[..], EMPTY_ROOT[..16]);
+        assert!(is_empty_root(&EMPTY_ROOT));
+        assert!(!is_empty_root(&NodeHash::<32>::default()));
+        assert!(!is_empty_root(&NodeHash::<32>::digest(b"")));
+    }
+
+    #[test]
+    fn test_hash_widths() {
+        let short = NodeHash::<16>::digest(b"value");
+        let long = NodeHash::<64>::digest(b"value");
+        assert_eq!(short[..], NodeHash::<32>::digest(b"value")[..16]);
+        assert_ne!(long[..32], NodeHash::<32>::digest(b"value")[..]);
+
+        let mut bytes = Vec::new();
+        long.encode(&mut bytes);
+        assert_eq!(bytes.len(), 64);
+        assert_eq!(NodeHash::<64>::decode(&mut bytes.as_slice()).unwrap(), long);
+        assert_eq!(NodeHash::<16>::from_hex(&short.to_string()), Some(short));
+        assert_eq!(NodeHash::<32>::from_hex(&short.to_string()), None);
+    }
+}
@@ -0,0 +1,149 @@
+// Hash-ordered keys: entries are ordered by the SHA-256 of their encoded key
+// rather than by the key itself.
+//
+// Hashed keys spread evenly over the tree whatever order they arrive in, so
+// sequential or adversarially chosen keys can't skew its shape. The price is
+// that key order is lost: there are no range queries, and iteration follows
+// the key hashes.
+
+use crate::core::codec::{Decode, Encode};
+use crate::core::error::Error;
+use crate::core::hash::NodeHash;
+use crate::core::tree::{InsertOutcome, MerkleSearchTree};
+
+// A key paired with its hash, ordered by the hash first.
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct HashedKey<K> {
+    pub hash: NodeHash,
+    pub key: K,
+}
+
+impl<K: Encode> HashedKey<K> {
+    pub fn new(key: K) -> Self {
+        let mut bytes = Vec::new();
+        key.encode(&mut bytes);
+        HashedKey {
+            hash: NodeHash::digest(&bytes),
+            key,
+        }
+    }
+}
+
+// Only the key is written; the hash is recomputed when decoding.
+impl<K: Encode> Encode for HashedKey<K> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.key.encode(out)
+    }
+
+    fn encoded_len(&self) -> usize {
+        self.key.encoded_len()
+    }
+}
+
+impl<K: Encode + Decode> Decode for HashedKey<K> {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        Ok(HashedKey::new(K::decode(input)?))
+    }
+}
+
+// A tree over hashed keys, taking and returning plain keys. Created with
+// `MerkleSearchTree::with_hashed_keys`.
+pub struct HashedTree<K, V = String> {
+    inner: MerkleSearchTree<HashedKey<K>, V>,
+}
+
+impl<K, V> HashedTree<K, V>
+where
+    K: Ord + Clone + Default + Encode,
+    V: AsRef<[u8]>,
+{
+    pub(crate) fn from_inner(inner: MerkleSearchTree<HashedKey<K>, V>) -> Self {
+        HashedTree { inner }
+    }
+
+    // Panics where `try_insert` fails, as `MerkleSearchTree::insert` does.
+    pub fn insert(&mut self, key: K, value: V) -> InsertOutcome<V>
+    where
+        V: Clone,
+    {
+        self.inner.insert(HashedKey::new(key), value)
+    }
+
+    pub fn try_insert(&mut self, key: K, value: V) -> Result<InsertOutcome<V>, Error>
+    where
+        V: Clone,
+    {
+        self.inner.try_insert(HashedKey::new(key), value)
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.inner.get(&HashedKey::new(key.clone()))
+    }
+
+    pub fn remove(&mut self, key: &K) -> bool {
+        self.inner.remove(&HashedKey::new(key.clone()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    pub fn hash(&self) -> &NodeHash {
+        self.inner.hash()
+    }
+
+    // Iterates all entries in key hash order.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.inner.iter().map(|(key, value)| (&key.key, value))
+    }
+
+    // The underlying tree, for syncing and snapshots.
+    pub fn inner(&self) -> &MerkleSearchTree<HashedKey<K>, V> {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut MerkleSearchTree<HashedKey<K>, V> {
+        &mut self.inner
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_hashed_keys_balance_sequential_inserts() {
+        let mut plain = MerkleSearchTree::new(3);
+        let mut hashed = MerkleSearchTree::new(3).with_hashed_keys();
+        for i in 0..300u32 {
+            plain.insert(i, format!("v{i}"));
+            hashed.insert(i, format!("v{i}"));
+        }
+        // Splits keep both shallow; hashing only changes which keys share
+        // a node.
+        assert!(plain.depth() <= 9);
+        assert!(hashed.inner().depth() <= 9);
+
+        assert_eq!(hashed.len(), 300);
+        assert_eq!(hashed.get(&42), Some(&"v42".to_string()));
+        assert!(hashed.remove(&42));
+        assert_eq!(hashed.get(&42), None);
+        assert!(!hashed.iter().map(|(key, _)| key).is_sorted());
+    }
+
+    #[test]
+    fn test_hashed_tree_is_order_independent() {
+        let mut forward = MerkleSearchTree::new(4).with_hashed_keys();
+        let mut backward = MerkleSearchTree::new(4).with_hashed_keys();
+        for i in 0..100u32 {
+            forward.insert(i, format!("v{i}"));
+            backward.insert(99 - i, format!("v{}", 99 - i));
+        }
+        assert_eq!(forward.hash(), backward.hash());
+        assert!(forward.inner().content_eq(backward.inner()));
+    }
+}
@@ -0,0 +1,243 @@
+// Interned keys: structured string keys such as `tenant/table/pk` whose
+// components are stored once in an `Interner` and shared by every key that
+// uses them, so a million keys under one tenant hold one copy of its name.
+//
+// An `InternedKey` behaves exactly like the joined string: it orders,
+// compares and encodes the same way, so a tree of interned keys has the same
+// content digest and snapshot pages as one of `String` keys, and the two can
+// sync. Comparing keys skips the leading components they share by pointer.
+
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::core::codec::{Decode, Encode, encode_len};
+use crate::core::error::Error;
+
+// A key held as interned components. Each component but the last ends with
+// the separator, so the key is their concatenation.
+#[derive(Clone, Default)]
+pub struct InternedKey {
+    parts: Box<[Arc<str>]>,
+}
+
+impl InternedKey {
+    // The length of the joined key in bytes.
+    pub fn len(&self) -> usize {
+        self.parts.iter().map(|part| part.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn parts(&self) -> impl Iterator<Item = &str> {
+        self.parts.iter().map(|part| &**part)
+    }
+
+    fn bytes(parts: &[Arc<str>]) -> impl Iterator<Item = u8> + '_ {
+        parts.iter().flat_map(|part| part.bytes())
+    }
+}
+
+impl Ord for InternedKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let shared = self
+            .parts
+            .iter()
+            .zip(other.parts.iter())
+            .take_while(|(a, b)| Arc::ptr_eq(a, b))
+            .count();
+        Self::bytes(&self.parts[shared..]).cmp(Self::bytes(&other.parts[shared..]))
+    }
+}
+
+impl PartialOrd for InternedKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Keys split differently, or not interned at all, are still equal if they
+// join to the same string.
+impl PartialEq for InternedKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for InternedKey {}
+
+impl fmt::Display for InternedKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.parts().try_for_each(|part| f.write_str(part))
+    }
+}
+
+impl fmt::Debug for InternedKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.to_string())
+    }
+}
+
+// Written as the joined string.
+impl Encode for InternedKey {
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_len(self.len(), out);
+        self.parts()
+            .for_each(|part| out.extend_from_slice(part.as_bytes()));
+    }
+
+    fn encoded_len(&self) -> usize {
+        4 + self.len()
+    }
+}
+
+// Decoded keys aren't interned: a decoder has no interner to share. They
+// hold a single component until passed through `Interner::intern`.
+impl Decode for InternedKey {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        let key = String::decode(input)?;
+        Ok(InternedKey {
+            parts: Box::new([key.into()]),
+        })
+    }
+}
+
+// The table of shared components.
+pub struct Interner {
+    separator: char,
+    components: HashSet<Arc<str>>,
+}
+
+impl Interner {
+    // Keys will be split after each `separator`.
+    pub fn new(separator: char) -> Self {
+        Interner {
+            separator,
+            components: HashSet::new(),
+        }
+    }
+
+    pub fn key(&mut self, key: &str) -> InternedKey {
+        let parts = key
+            .split_inclusive(self.separator)
+            .map(|part| match self.components.get(part) {
+                Some(shared) => shared.clone(),
+                None => {
+                    let part: Arc<str> = part.into();
+                    self.components.insert(part.clone());
+                    part
+                }
+            })
+            .collect();
+        InternedKey { parts }
+    }
+
+    // Re-splits `key` against the table, e.g. after decoding it.
+    pub fn intern(&mut self, key: &InternedKey) -> InternedKey {
+        self.key(&key.to_string())
+    }
+
+    // The number of distinct components.
+    pub fn len(&self) -> usize {
+        self.components.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.components.is_empty()
+    }
+
+    // Drops the components no key uses any more, such as those of removed
+    // keys or unique last components, and returns how many were dropped.
+    // Keys kept elsewhere, e.g. in a tree's structure log, still count.
+    pub fn release_unused(&mut self) -> usize {
+        let before = self.components.len();
+        self.components
+            .retain(|component| Arc::strong_count(component) > 1);
+        before - self.components.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::tree::MerkleSearchTree;
+
+    #[test]
+    fn test_orders_like_strings() {
+        let mut interner = Interner::new('/');
+        let keys = [
+            "",
+            "a",
+            "a/",
+            "a/b",
+            "a.c",
+            "a/b/c",
+            "a0",
+            "ab/c",
+            "t1/users/7",
+            "t1/users/70",
+            "t1/orders/7",
+            "t1/users",
+        ];
+        for a in keys {
+            for b in keys {
+                let (x, y) = (interner.key(a), interner.key(b));
+                assert_eq!(x.cmp(&y), a.cmp(b), "{a:?} vs {b:?}");
+                assert_eq!(x == y, a == b);
+            }
+        }
+
+        // Decoded keys are equal to interned ones, and encode the same.
+        let key = interner.key("t1/users/7");
+        let mut bytes = Vec::new();
+        key.encode(&mut bytes);
+        assert_eq!(bytes, {
+            let mut plain = Vec::new();
+            "t1/users/7".encode(&mut plain);
+            plain
+        });
+        let decoded = InternedKey::decode(&mut bytes.as_slice()).unwrap();
+        assert_eq!(decoded.parts().count(), 1);
+        assert_eq!(decoded, key);
+        assert_eq!(interner.intern(&decoded).parts().count(), 3);
+    }
+
+    #[test]
+    fn test_interned_tree() {
+        let mut interner = Interner::new('/');
+        let mut interned = MerkleSearchTree::new(8);
+        let mut plain = MerkleSearchTree::new(8);
+        for tenant in 0..3 {
+            for pk in 0..200 {
+                let key = format!("tenant-{tenant}/orders/{pk}");
+                interned.insert(interner.key(&key), format!("v{pk}"));
+                plain.insert(key, format!("v{pk}"));
+            }
+        }
+        // 3 tenants, 1 table and 200 primary keys.
+        assert_eq!(interner.len(), 3 + 1 + 200);
+        assert_eq!(interned.content_digest(), plain.content_digest());
+        let probe = interner.key("tenant-1/orders/42");
+        assert_eq!(interned.get(&probe), Some(&"v42".to_string()));
+
+        for pk in 100..200 {
+            let key = interner.key(&format!("tenant-0/orders/{pk}"));
+            interned.remove(&key);
+        }
+        drop(probe);
+        #[cfg(feature = "structure-log")]
+        interned.take_structure_log();
+        assert_eq!(interner.release_unused(), 0);
+        for tenant in 0..3 {
+            for pk in 100..200 {
+                interned.remove(&interner.key(&format!("tenant-{tenant}/orders/{pk}")));
+            }
+        }
+        #[cfg(feature = "structure-log")]
+        interned.take_structure_log();
+        assert_eq!(interner.release_unused(), 100);
+    }
+}
@@ -0,0 +1,103 @@
+// Inventory reports: the entry count and hash of each bucket of keys, for
+// reconciling a tree against a system that holds the same data in another
+// shape, such as a warehouse table partitioned by day.
+//
+// A caller-supplied function maps each key to its bucket, e.g. a timestamp
+// key to its day. A bucket's hash is the XOR of its entries' leaf hashes,
+// the digests of their values, like `range_hash`; any system that can hash
+// its values the same way can compute the same summaries and compare them
+// bucket by bucket, without agreeing on the tree's shape.
+
+use std::collections::BTreeMap;
+use std::ops::RangeInclusive;
+
+use crate::core::codec::Encode;
+use crate::core::hash::NodeHash;
+use crate::core::tree::MerkleSearchTree;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BucketSummary<K, B, const N: usize = 32> {
+    pub bucket: B,
+    // The first and last key that fell into the bucket.
+    pub range: RangeInclusive<K>,
+    pub count: u64,
+    pub hash: NodeHash<N>,
+}
+
+impl<K: Ord + Clone + Default + Encode, V: AsRef<[u8]>, const N: usize> MerkleSearchTree<K, V, N> {
+    // One summary per bucket holding entries, in bucket order. Buckets need
+    // not be contiguous key ranges, though with ones that are, e.g. days of
+    // timestamp keys, the ranges don't overlap.
+    pub fn inventory<B: Ord>(
+        &self,
+        mut bucket_of: impl FnMut(&K) -> B,
+    ) -> Vec<BucketSummary<K, B, N>> {
+        let mut buckets: BTreeMap<B, BucketSummary<K, (), N>> = BTreeMap::new();
+        for (key, hash) in self.leaf_hashes() {
+            // Keys come in order, so the last key seen is a bucket's largest.
+            let summary = buckets
+                .entry(bucket_of(key))
+                .or_insert_with(|| BucketSummary {
+                    bucket: (),
+                    range: key.clone()..=key.clone(),
+                    count: 0,
+                    hash: NodeHash::default(),
+                });
+            summary.range = summary.range.start().clone()..=key.clone();
+            summary.count += 1;
+            summary.hash.xor(hash);
+        }
+        buckets
+            .into_iter()
+            .map(|(bucket, summary)| BucketSummary {
+                bucket,
+                range: summary.range,
+                count: summary.count,
+                hash: summary.hash,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const DAY: u64 = 86_400;
+
+    #[test]
+    fn test_inventory_by_day() {
+        let mut events = MerkleSearchTree::<u64>::new(8);
+        for i in 0..300 {
+            events.insert(i * 1000, format!("event {i}"));
+        }
+        let inventory = events.inventory(|time| time / DAY);
+        assert_eq!(inventory.len(), 4);
+        assert_eq!(inventory.iter().map(|b| b.count).sum::<u64>(), 300);
+        for summary in &inventory {
+            assert_eq!(summary.range.start() / DAY, summary.bucket);
+            let day = summary.bucket * DAY..(summary.bucket + 1) * DAY;
+            assert_eq!(summary.hash, events.range_hash(day));
+        }
+
+        // Another system hashing its rows the same way reaches the same
+        // summaries, and a changed row shows up in its day only.
+        let mut warehouse: BTreeMap<u64, (u64, NodeHash)> = BTreeMap::new();
+        for i in 0..300u64 {
+            let value = if i == 100 {
+                "lost".to_string()
+            } else {
+                format!("event {i}")
+            };
+            let row = warehouse.entry(i * 1000 / DAY).or_default();
+            row.0 += 1;
+            row.1.xor(&NodeHash::leaf(&(i * 1000), value.as_bytes()));
+        }
+        let differing: Vec<u64> = inventory
+            .iter()
+            .filter(|summary| warehouse[&summary.bucket] != (summary.count, summary.hash))
+            .map(|summary| summary.bucket)
+            .collect();
+        assert_eq!(differing, [1]);
+    }
+}
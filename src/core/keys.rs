@@ -0,0 +1,395 @@
+// Canonical keys for UUIDs, timestamps and decimals.
+//
+// Services that key trees by these types must encode them identically, or
+// their content digests and snapshot pages won't match even with the same
+// data. Each key type here has one encoding per value: a UUID is its 16
+// bytes, an instant is UTC seconds and nanoseconds, and a decimal is
+// normalized so that 1.5 and 1.50 are the same key.
+//
+// With the `uuid`, `chrono` and `rust_decimal` features, the key types
+// convert to and from `Uuid`, `DateTime` and `Decimal`.
+//
+// `PrefixKey` is for the other side of key choice: keys listed by byte
+// prefix, such as paths.
+
+use std::cmp::Ordering;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::core::codec::{Decode, Encode};
+use crate::core::error::Error;
+
+// A UUID, ordered by its bytes. Version 7 UUIDs start with their creation
+// time, so they also sort by it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct UuidKey(pub [u8; 16]);
+
+impl UuidKey {
+    pub fn version(&self) -> u8 {
+        self.0[6] >> 4
+    }
+
+    // The creation time in Unix milliseconds of a version 7 UUID.
+    pub fn timestamp_ms(&self) -> Option<u64> {
+        (self.version() == 7).then(|| {
+            self.0[..6]
+                .iter()
+                .fold(0, |ms, byte| (ms << 8) | *byte as u64)
+        })
+    }
+}
+
+#[cfg(feature = "uuid")]
+impl From<uuid::Uuid> for UuidKey {
+    fn from(uuid: uuid::Uuid) -> Self {
+        UuidKey(uuid.into_bytes())
+    }
+}
+
+#[cfg(feature = "uuid")]
+impl From<UuidKey> for uuid::Uuid {
+    fn from(key: UuidKey) -> Self {
+        uuid::Uuid::from_bytes(key.0)
+    }
+}
+
+impl Encode for UuidKey {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.0.encode(out)
+    }
+
+    fn encoded_len(&self) -> usize {
+        16
+    }
+}
+
+impl Decode for UuidKey {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        Ok(UuidKey(Decode::decode(input)?))
+    }
+}
+
+// An instant in UTC with nanosecond precision. Time zones are dropped, so
+// the same instant written from any zone is the same key.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TimestampKey {
+    secs: i64,
+    nanos: u32,
+}
+
+impl TimestampKey {
+    // None if `nanos` isn't below one second.
+    pub fn from_unix(secs: i64, nanos: u32) -> Option<Self> {
+        (nanos < 1_000_000_000).then_some(TimestampKey { secs, nanos })
+    }
+
+    pub fn from_system_time(time: SystemTime) -> Self {
+        match time.duration_since(UNIX_EPOCH) {
+            Ok(after) => TimestampKey {
+                secs: after.as_secs() as i64,
+                nanos: after.subsec_nanos(),
+            },
+            Err(err) => {
+                let before = err.duration();
+                let secs = -(before.as_secs() as i64);
+                match before.subsec_nanos() {
+                    0 => TimestampKey { secs, nanos: 0 },
+                    nanos => TimestampKey {
+                        secs: secs - 1,
+                        nanos: 1_000_000_000 - nanos,
+                    },
+                }
+            }
+        }
+    }
+
+    pub fn secs(&self) -> i64 {
+        self.secs
+    }
+
+    pub fn subsec_nanos(&self) -> u32 {
+        self.nanos
+    }
+
+    pub fn to_system_time(&self) -> SystemTime {
+        let nanos = Duration::from_nanos(self.nanos as u64);
+        if self.secs >= 0 {
+            UNIX_EPOCH + Duration::from_secs(self.secs as u64) + nanos
+        } else {
+            UNIX_EPOCH - Duration::from_secs(self.secs.unsigned_abs()) + nanos
+        }
+    }
+}
+
+// A leap second, which chrono gives as nanoseconds past one second, is
+// clamped to the last nanosecond of the second before, as Unix time has no
+// leap seconds.
+#[cfg(feature = "chrono")]
+impl<Tz: chrono::TimeZone> From<chrono::DateTime<Tz>> for TimestampKey {
+    fn from(time: chrono::DateTime<Tz>) -> Self {
+        TimestampKey {
+            secs: time.timestamp(),
+            nanos: time.timestamp_subsec_nanos().min(999_999_999),
+        }
+    }
+}
+
+#[cfg(feature = "chrono")]
+impl TimestampKey {
+    // None if the instant is outside chrono's range.
+    pub fn to_datetime(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        chrono::DateTime::from_timestamp(self.secs, self.nanos)
+    }
+}
+
+// The seconds as a sign-flipped i64, then the nanoseconds, so the bytes sort
+// like the instants.
+impl Encode for TimestampKey {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.secs.encode(out);
+        self.nanos.encode(out);
+    }
+
+    fn encoded_len(&self) -> usize {
+        12
+    }
+}
+
+impl Decode for TimestampKey {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        let secs = i64::decode(input)?;
+        let nanos = u32::decode(input)?;
+        TimestampKey::from_unix(secs, nanos)
+            .ok_or_else(|| Error::Malformed(format!("{nanos} nanoseconds out of range")))
+    }
+}
+
+// A decimal number `mantissa / 10^scale` with the range of `rust_decimal`:
+// a 96-bit mantissa and a scale up to 28. Trailing zeros are stripped, so
+// each number has one representation.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct DecimalKey {
+    mantissa: i128,
+    scale: u8,
+}
+
+impl DecimalKey {
+    pub const MAX_SCALE: u32 = 28;
+
+    // None if the number is out of range.
+    pub fn new(mut mantissa: i128, mut scale: u32) -> Option<Self> {
+        if scale > Self::MAX_SCALE || mantissa.unsigned_abs() >= 1 << 96 {
+            return None;
+        }
+        while scale > 0 && mantissa % 10 == 0 {
+            mantissa /= 10;
+            scale -= 1;
+        }
+        Some(DecimalKey {
+            mantissa,
+            scale: scale as u8,
+        })
+    }
+
+    pub fn mantissa(&self) -> i128 {
+        self.mantissa
+    }
+
+    pub fn scale(&self) -> u32 {
+        self.scale as u32
+    }
+
+    // The integer part, and the fraction scaled to `MAX_SCALE` digits. Both
+    // fit an i128 where the number itself scaled up might not.
+    fn parts(&self) -> (i128, i128) {
+        let unit = 10i128.pow(self.scale as u32);
+        let fraction = self.mantissa % unit;
+        (
+            self.mantissa / unit,
+            fraction * 10i128.pow(Self::MAX_SCALE - self.scale as u32),
+        )
+    }
+}
+
+// Every `Decimal` is in range, so the conversions can't fail.
+#[cfg(feature = "rust_decimal")]
+impl From<rust_decimal::Decimal> for DecimalKey {
+    fn from(decimal: rust_decimal::Decimal) -> Self {
+        DecimalKey::new(decimal.mantissa(), decimal.scale())
+            .expect("a Decimal has a 96-bit mantissa and a scale up to 28")
+    }
+}
+
+#[cfg(feature = "rust_decimal")]
+impl From<DecimalKey> for rust_decimal::Decimal {
+    fn from(key: DecimalKey) -> Self {
+        rust_decimal::Decimal::from_i128_with_scale(key.mantissa, key.scale as u32)
+    }
+}
+
+// Numeric order: -1.5 < -1 < 0.25 < 1.
+impl Ord for DecimalKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.parts().cmp(&other.parts())
+    }
+}
+
+impl PartialOrd for DecimalKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// The normalized mantissa, then the scale.
+impl Encode for DecimalKey {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.mantissa.encode(out);
+        self.scale.encode(out);
+    }
+
+    fn encoded_len(&self) -> usize {
+        17
+    }
+}
+
+impl Decode for DecimalKey {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        let mantissa = i128::decode(input)?;
+        let scale = u8::decode(input)?;
+        match DecimalKey::new(mantissa, scale as u32) {
+            Some(key) if key.scale == scale => Ok(key),
+            _ => Err(Error::Malformed(format!(
+                "{mantissa}e-{scale} isn't a canonical decimal"
+            ))),
+        }
+    }
+}
+
+// A key `MerkleSearchTree::iter_prefix` can list by byte prefix. The
+// listing seeks straight to the prefix only if the key's `Ord` sorts as its
+// bytes do, as for strings and byte vectors, so only those set
+// `BYTE_ORDERED`; any other key is filtered over a full scan.
+pub trait PrefixKey: AsRef<[u8]> {
+    const BYTE_ORDERED: bool = false;
+}
+
+impl PrefixKey for String {
+    const BYTE_ORDERED: bool = true;
+}
+
+impl PrefixKey for Vec<u8> {
+    const BYTE_ORDERED: bool = true;
+}
+
+impl<const N: usize> PrefixKey for [u8; N] {
+    const BYTE_ORDERED: bool = true;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn round_trip<K: Encode + Decode + PartialEq + std::fmt::Debug>(key: K) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        key.encode(&mut bytes);
+        assert_eq!(bytes.len(), key.encoded_len());
+        assert_eq!(K::decode(&mut bytes.as_slice()).unwrap(), key);
+        bytes
+    }
+
+    #[test]
+    fn test_uuid_and_timestamp_keys() {
+        // Version 7, created at Unix millisecond 0x0189_0000_0001.
+        let mut bytes = [0u8; 16];
+        bytes[..6].copy_from_slice(&[0x01, 0x89, 0, 0, 0, 1]);
+        bytes[6] = 0x70;
+        let earlier = UuidKey(bytes);
+        bytes[5] = 2;
+        let later = UuidKey(bytes);
+        assert_eq!(earlier.timestamp_ms(), Some(0x0189_0000_0001));
+        assert!(earlier < later);
+        assert!(round_trip(earlier) < round_trip(later));
+        assert_eq!(UuidKey([0; 16]).timestamp_ms(), None);
+
+        let times = [
+            TimestampKey::from_unix(-2, 500).unwrap(),
+            TimestampKey::from_unix(-1, 0).unwrap(),
+            TimestampKey::from_unix(0, 1).unwrap(),
+            TimestampKey::from_unix(1_700_000_000, 999_999_999).unwrap(),
+        ];
+        let encoded: Vec<_> = times.iter().map(|time| round_trip(*time)).collect();
+        assert!(times.is_sorted() && encoded.is_sorted());
+        for time in times {
+            assert_eq!(TimestampKey::from_system_time(time.to_system_time()), time);
+        }
+        assert_eq!(TimestampKey::from_unix(0, 1_000_000_000), None);
+    }
+
+    #[test]
+    fn test_decimal_keys() {
+        let decimal = |mantissa, scale| DecimalKey::new(mantissa, scale).unwrap();
+        assert_eq!(decimal(150, 2), decimal(15, 1));
+        assert_eq!(round_trip(decimal(150, 2)), round_trip(decimal(15, 1)));
+        assert_eq!(decimal(1000, 0).scale(), 0);
+
+        let max = (1i128 << 96) - 1;
+        let ordered = [
+            decimal(-max, 0),
+            decimal(-15, 1),
+            decimal(-1, 0),
+            decimal(-1, 28),
+            decimal(0, 0),
+            decimal(25, 2),
+            decimal(1, 0),
+            decimal(15, 1),
+            decimal(max, 28),
+            decimal(max, 0),
+        ];
+        assert!(ordered.is_sorted_by(|a, b| a < b));
+        assert_eq!(DecimalKey::new(1, 29), None);
+        assert_eq!(DecimalKey::new(1 << 96, 0), None);
+
+        // Non-canonical bytes are rejected rather than aliasing 1.5.
+        let mut bytes = Vec::new();
+        150i128.encode(&mut bytes);
+        2u8.encode(&mut bytes);
+        assert!(DecimalKey::decode(&mut bytes.as_slice()).is_err());
+    }
+
+    #[cfg(feature = "uuid")]
+    #[test]
+    fn test_uuid_conversion() {
+        let uuid = uuid::Uuid::from_u128(0x0189_0000_0001_7000_8000_0000_0000_0001);
+        let key = UuidKey::from(uuid);
+        assert_eq!(key.timestamp_ms(), Some(0x0189_0000_0001));
+        assert_eq!(uuid::Uuid::from(key), uuid);
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn test_chrono_conversion() {
+        use chrono::{FixedOffset, TimeZone, Utc};
+
+        let utc = Utc.timestamp_opt(1_700_000_000, 250).unwrap();
+        let key = TimestampKey::from(utc);
+        assert_eq!((key.secs(), key.subsec_nanos()), (1_700_000_000, 250));
+        assert_eq!(key.to_datetime(), Some(utc));
+        // The same instant from another zone is the same key.
+        let offset = FixedOffset::east_opt(3600).unwrap();
+        assert_eq!(TimestampKey::from(utc.with_timezone(&offset)), key);
+        assert_eq!(
+            TimestampKey::from_unix(i64::MAX, 0).unwrap().to_datetime(),
+            None
+        );
+    }
+
+    #[cfg(feature = "rust_decimal")]
+    #[test]
+    fn test_decimal_conversion() {
+        use rust_decimal::Decimal;
+
+        let key = DecimalKey::from(Decimal::new(150, 2));
+        assert_eq!(key, DecimalKey::new(15, 1).unwrap());
+        assert_eq!(Decimal::from(key), Decimal::new(15, 1));
+        assert_eq!(DecimalKey::from(Decimal::MAX).mantissa(), (1i128 << 96) - 1);
+    }
+}
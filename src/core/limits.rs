@@ -0,0 +1,178 @@
+// Soft limits: thresholds that don't stop any write, but report when the
+// tree crosses them, so a misconfiguration (say `max_children` of 2 in
+// production) shows up in monitoring before it becomes an incident.
+//
+// Events go to a plain function, like the quota and fanout hooks, so the
+// configuration stays `Copy` and forks keep it. Each crossing is reported
+// once: a node when it grows past the entry limit, the tree when it grows
+// past the depth limit, and the root once per churn window.
+
+use std::time::{Duration, Instant};
+
+use crate::core::codec::Encode;
+use crate::core::tree::MerkleSearchTree;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum LimitEvent {
+    // A node grew to `entries` children, past `limit`.
+    NodeEntries { entries: usize, limit: usize },
+    // The tree grew to `depth` levels, past `limit`.
+    Depth { depth: usize, limit: usize },
+    // The root changed more than `limit` times within `window`.
+    RootChurn { limit: u64, window: Duration },
+}
+
+#[derive(Clone, Copy)]
+pub struct SoftLimits {
+    node_entries: Option<usize>,
+    depth: Option<usize>,
+    root_churn: Option<(u64, Duration)>,
+    on_event: fn(&LimitEvent),
+}
+
+impl SoftLimits {
+    // No thresholds yet; events will be passed to `on_event`.
+    pub fn new(on_event: fn(&LimitEvent)) -> Self {
+        SoftLimits {
+            node_entries: None,
+            depth: None,
+            root_churn: None,
+            on_event,
+        }
+    }
+
+    pub fn node_entries(mut self, limit: usize) -> Self {
+        self.node_entries = Some(limit);
+        self
+    }
+
+    pub fn depth(mut self, limit: usize) -> Self {
+        self.depth = Some(limit);
+        self
+    }
+
+    // Reports more than `limit` root changes within `window`.
+    pub fn root_churn(mut self, limit: u64, window: Duration) -> Self {
+        self.root_churn = Some((limit, window));
+        self
+    }
+
+    // A node now has `entries` children, one more than before.
+    pub(crate) fn check_node(&self, entries: usize) {
+        if let Some(limit) = self.node_entries
+            && entries == limit + 1
+        {
+            (self.on_event)(&LimitEvent::NodeEntries { entries, limit });
+        }
+    }
+
+    pub(crate) fn check_depth(&self, before: usize, depth: usize) {
+        if let Some(limit) = self.depth
+            && before < depth
+            && depth > limit
+        {
+            (self.on_event)(&LimitEvent::Depth { depth, limit });
+        }
+    }
+
+    pub(crate) fn check_churn(&self, churn: &mut Churn, now: Instant) {
+        let Some((limit, window)) = self.root_churn else {
+            return;
+        };
+        match churn.window_start {
+            Some(start) if now.duration_since(start) < window => churn.changes += 1,
+            _ => {
+                churn.window_start = Some(now);
+                churn.changes = 1;
+            }
+        }
+        if churn.changes == limit + 1 {
+            (self.on_event)(&LimitEvent::RootChurn { limit, window });
+        }
+    }
+}
+
+// The root changes counted in the current churn window.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct Churn {
+    window_start: Option<Instant>,
+    changes: u64,
+}
+
+impl<K: Ord + Clone + Default + Encode, V: AsRef<[u8]>, const N: usize> MerkleSearchTree<K, V, N> {
+    pub fn with_soft_limits(mut self, limits: SoftLimits) -> Self {
+        self.soft_limits = Some(limits);
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::RefCell;
+
+    thread_local! {
+        static EVENTS: RefCell<Vec<LimitEvent>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn record(event: &LimitEvent) {
+        EVENTS.with(|events| events.borrow_mut().push(*event));
+    }
+
+    fn take_events() -> Vec<LimitEvent> {
+        EVENTS.with(|events| events.take())
+    }
+
+    #[test]
+    fn test_node_and_depth_limits() {
+        let limits = SoftLimits::new(record).node_entries(6).depth(4);
+        let mut tree = MerkleSearchTree::new(8).with_soft_limits(limits);
+        for i in 0..7u32 {
+            tree.insert(i, format!("v{i}"));
+        }
+        assert_eq!(
+            take_events(),
+            vec![LimitEvent::NodeEntries {
+                entries: 7,
+                limit: 6
+            }]
+        );
+
+        // Narrow nodes make a deep tree.
+        let mut narrow =
+            MerkleSearchTree::new(3).with_soft_limits(SoftLimits::new(record).depth(4));
+        for i in 0..100u32 {
+            narrow.insert(i, format!("v{i}"));
+        }
+        let events = take_events();
+        assert_eq!(events[0], LimitEvent::Depth { depth: 5, limit: 4 });
+        assert_eq!(events.len(), narrow.depth() - 4);
+    }
+
+    #[test]
+    fn test_root_churn() {
+        let limits = SoftLimits::new(record).root_churn(10, Duration::from_secs(3600));
+        let mut tree = MerkleSearchTree::new(8).with_soft_limits(limits);
+        for i in 0..25u32 {
+            tree.insert(i, format!("v{i}"));
+        }
+        tree.remove(&3);
+        assert_eq!(
+            take_events(),
+            vec![LimitEvent::RootChurn {
+                limit: 10,
+                window: Duration::from_secs(3600)
+            }]
+        );
+
+        // A fork keeps the limits but starts a window of its own.
+        let mut fork = tree.fork();
+        for i in 0..10u32 {
+            fork.insert(i, "changed".to_string());
+        }
+        assert!(take_events().is_empty());
+        fork.insert(100, "one too many".to_string());
+        assert_eq!(take_events().len(), 1);
+    }
+}
@@ -0,0 +1,229 @@
+// Logical locks on key ranges, for writers sharing one tree.
+//
+// The tree takes `&mut self` for every write, so writers sharing one put it
+// behind a mutex, held for a single operation. Read-modify-write sequences
+// that span several operations, or that compute between reading and writing,
+// need more: a writer locks the key ranges it works on here first. Writers
+// on disjoint ranges go ahead in parallel and overlapping ones queue.
+//
+// A writer that needs several ranges asks for them in one call and gets all
+// of them at once or waits holding none, so two writers can never each hold
+// what the other waits for. There is no queue order: a writer asking for a
+// wide range can wait behind a stream of narrow ones.
+
+use std::sync::{Condvar, Mutex, MutexGuard};
+
+use crate::core::range::KeyRange;
+
+pub struct RangeLocks<K> {
+    state: Mutex<LockState<K>>,
+    released: Condvar,
+}
+
+struct LockState<K> {
+    next_id: u64,
+    // Each held range with the guard that holds it.
+    held: Vec<(u64, KeyRange<K>)>,
+}
+
+// Releases its ranges when dropped.
+pub struct RangeGuard<'a, K> {
+    locks: &'a RangeLocks<K>,
+    id: u64,
+}
+
+impl<K: Ord + Clone> RangeLocks<K> {
+    pub fn new() -> Self {
+        RangeLocks {
+            state: Mutex::new(LockState {
+                next_id: 0,
+                held: Vec::new(),
+            }),
+            released: Condvar::new(),
+        }
+    }
+
+    // Blocks until no other guard holds a key in `range`.
+    pub fn lock_range(&self, range: KeyRange<K>) -> RangeGuard<'_, K> {
+        self.lock_ranges(vec![range])
+    }
+
+    // Blocks until all of `ranges` are free, then takes them together.
+    pub fn lock_ranges(&self, ranges: Vec<KeyRange<K>>) -> RangeGuard<'_, K> {
+        let ranges = normalize(ranges);
+        let mut state = self.lock();
+        while state.conflicts(&ranges) {
+            state = self
+                .released
+                .wait(state)
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+        self.acquire(state, ranges)
+    }
+
+    // Takes `range` if it is free right now.
+    pub fn try_lock_range(&self, range: KeyRange<K>) -> Option<RangeGuard<'_, K>> {
+        let ranges = normalize(vec![range]);
+        let state = self.lock();
+        if state.conflicts(&ranges) {
+            return None;
+        }
+        Some(self.acquire(state, ranges))
+    }
+
+    // The number of ranges held, for metrics.
+    pub fn held(&self) -> usize {
+        self.lock().held.len()
+    }
+
+    fn acquire(
+        &self,
+        mut state: MutexGuard<'_, LockState<K>>,
+        ranges: Vec<KeyRange<K>>,
+    ) -> RangeGuard<'_, K> {
+        let id = state.next_id;
+        state.next_id += 1;
+        state
+            .held
+            .extend(ranges.into_iter().map(|range| (id, range)));
+        RangeGuard { locks: self, id }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, LockState<K>> {
+        // Guards only add and remove whole entries; a panic can't tear one.
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl<K: Ord + Clone> Default for RangeLocks<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Ord> LockState<K> {
+    fn conflicts(&self, ranges: &[KeyRange<K>]) -> bool {
+        ranges
+            .iter()
+            .any(|range| self.held.iter().any(|(_, held)| overlaps(range, held)))
+    }
+}
+
+impl<K> Drop for RangeGuard<'_, K> {
+    fn drop(&mut self) {
+        let mut state = self
+            .locks
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        state.held.retain(|(id, _)| *id != self.id);
+        drop(state);
+        self.locks.released.notify_all();
+    }
+}
+
+// Drops empty ranges and sorts the rest by start key.
+fn normalize<K: Ord>(mut ranges: Vec<KeyRange<K>>) -> Vec<KeyRange<K>> {
+    ranges.retain(|range| match (&range.start, &range.end) {
+        (Some(start), Some(end)) => start < end,
+        _ => true,
+    });
+    ranges.sort_by(|a, b| match (&a.start, &b.start) {
+        (None, None) => std::cmp::Ordering::Equal,
+        (None, Some(_)) => std::cmp::Ordering::Less,
+        (Some(_), None) => std::cmp::Ordering::Greater,
+        (Some(a), Some(b)) => a.cmp(b),
+    });
+    ranges
+}
+
+// Whether two half-open ranges share a key.
+fn overlaps<K: Ord>(a: &KeyRange<K>, b: &KeyRange<K>) -> bool {
+    let starts_before_end =
+        |range: &KeyRange<K>, other: &KeyRange<K>| match (&range.start, &other.end) {
+            (Some(start), Some(end)) => start < end,
+            _ => true,
+        };
+    starts_before_end(a, b) && starts_before_end(b, a)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::tree::MerkleSearchTree;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    fn range(start: u32, end: u32) -> KeyRange<u32> {
+        KeyRange {
+            start: Some(start),
+            end: Some(end),
+        }
+    }
+
+    #[test]
+    fn test_overlapping_ranges_serialize() {
+        let locks = RangeLocks::new();
+        let low = locks.lock_range(range(0, 100));
+        let high = locks.try_lock_range(range(100, 200));
+        assert!(high.is_some());
+        assert!(locks.try_lock_range(range(50, 150)).is_none());
+        assert!(locks.try_lock_range(KeyRange::full()).is_none());
+        assert!(locks.try_lock_range(range(70, 70)).is_some());
+        drop(low);
+        assert!(locks.try_lock_range(range(0, 100)).is_some());
+        drop(high);
+        assert_eq!(locks.held(), 0);
+
+        // A writer waiting for two ranges holds neither meanwhile.
+        let locks = Arc::new(RangeLocks::new());
+        let held = locks.lock_range(range(100, 200));
+        let waiter = {
+            let locks = locks.clone();
+            thread::spawn(move || {
+                let _both = locks.lock_ranges(vec![range(100, 200), range(0, 10)]);
+            })
+        };
+        thread::sleep(Duration::from_millis(20));
+        assert!(locks.try_lock_range(range(0, 10)).is_some());
+        drop(held);
+        waiter.join().unwrap();
+        assert_eq!(locks.held(), 0);
+    }
+
+    #[test]
+    fn test_read_modify_write() {
+        let tree = Arc::new(Mutex::new(MerkleSearchTree::<u32>::new(8)));
+        let locks = Arc::new(RangeLocks::new());
+        let writers: Vec<_> = (0..4)
+            .map(|_| {
+                let (tree, locks) = (tree.clone(), locks.clone());
+                thread::spawn(move || {
+                    for _ in 0..50 {
+                        // Every writer bumps the counters of the same range.
+                        let _guard = locks.lock_range(range(0, 4));
+                        for key in 0..4 {
+                            let count: u32 = tree
+                                .lock()
+                                .unwrap()
+                                .get(&key)
+                                .map_or(0, |count| count.parse().unwrap());
+                            thread::yield_now();
+                            tree.lock().unwrap().insert(key, (count + 1).to_string());
+                        }
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        let tree = tree.lock().unwrap();
+        for key in 0..4 {
+            assert_eq!(tree.get(&key), Some(&"200".to_string()));
+        }
+    }
+}
@@ -0,0 +1,45 @@
+use std::ops::{Add, AddAssign};
+
+// Work done by the tree to apply mutations.
+// Writers can use it for backpressure, e.g. throttling when a batch rehashes too much.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct Work {
+    // Bytes fed into SHA-256 (the values of the written leaves).
+    pub bytes_hashed: u64,
+    // Nodes whose hash was updated or that were created by a split.
+    pub nodes_touched: u64,
+    // Nodes that split because they overflowed.
+    pub splits: u64,
+    // Writes that changed the tree, each counted once however many keys it
+    // moved.
+    pub mutations: u64,
+}
+
+impl Work {
+    pub(crate) fn count_node(&mut self, split: bool) {
+        self.nodes_touched += 1;
+        if split {
+            self.nodes_touched += 1;
+            self.splits += 1;
+        }
+    }
+}
+
+impl Add for Work {
+    type Output = Work;
+
+    fn add(self, rhs: Work) -> Work {
+        Work {
+            bytes_hashed: self.bytes_hashed + rhs.bytes_hashed,
+            nodes_touched: self.nodes_touched + rhs.nodes_touched,
+            splits: self.splits + rhs.splits,
+            mutations: self.mutations + rhs.mutations,
+        }
+    }
+}
+
+impl AddAssign for Work {
+    fn add_assign(&mut self, rhs: Work) {
+        *self = *self + rhs;
+    }
+}
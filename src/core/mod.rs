@@ -0,0 +1,50 @@
+// The tree itself and everything that needs nothing but the tree: hashing,
+// encoding, configuration, and the views and wrappers built over a single
+// in-memory tree. The other layers (`store`, `sync`, `proof` and `crdt`) are
+// behind features of the same name and build on this one.
+
+pub mod alloc;
+pub mod branch;
+pub mod budget;
+pub mod buffer;
+pub mod chain;
+pub mod checkpoint;
+pub mod codec;
+pub mod coded;
+#[cfg(any(test, feature = "compat-tests"))]
+pub mod compat;
+pub mod composite;
+#[cfg(feature = "compression")]
+pub mod compress;
+pub mod config;
+pub mod convert;
+pub mod dedup;
+pub mod error;
+pub mod guard;
+pub mod hash;
+pub mod hashed;
+pub mod interned;
+pub mod inventory;
+pub mod keys;
+pub mod limits;
+pub mod locks;
+pub mod metrics;
+pub mod quota;
+pub mod range;
+pub mod recent;
+pub mod report;
+pub mod ring;
+pub(crate) mod rng;
+pub mod scan;
+pub mod scoped;
+#[cfg(feature = "zeroize")]
+pub mod secret;
+pub mod session;
+pub mod structure;
+pub mod table;
+pub mod tagged;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod transfer;
+pub mod tree;
+pub mod watch;
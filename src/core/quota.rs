@@ -0,0 +1,198 @@
+// Per-tenant usage accounting and quotas.
+//
+// Every internal node keeps the entry count and value bytes of its subtree,
+// so the usage of any key range is read off in O(log n) and always matches
+// the content the hashes cover. A tenant is a key range, typically a prefix
+// (see `prefix_range`). A quota policy maps a key to its tenant and decides
+// whether the tenant may grow; it is checked on local inserts, while entries
+// arriving through sync are always accepted so replicas converge.
+
+use std::iter::Sum;
+use std::ops::{AddAssign, SubAssign};
+
+use crate::core::codec::Encode;
+use crate::core::error::Error;
+use crate::core::range::KeyRange;
+use crate::core::tree::MerkleSearchTree;
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct Usage {
+    pub entries: usize,
+    // The total length of the values.
+    pub bytes: u64,
+}
+
+impl AddAssign for Usage {
+    fn add_assign(&mut self, rhs: Usage) {
+        self.entries += rhs.entries;
+        self.bytes += rhs.bytes;
+    }
+}
+
+impl SubAssign for Usage {
+    fn sub_assign(&mut self, rhs: Usage) {
+        self.entries -= rhs.entries;
+        self.bytes -= rhs.bytes;
+    }
+}
+
+impl Sum for Usage {
+    fn sum<I: Iterator<Item = Usage>>(iter: I) -> Usage {
+        let mut total = Usage::default();
+        for usage in iter {
+            total += usage;
+        }
+        total
+    }
+}
+
+// The tenant `key` belongs to, or None if it isn't subject to a quota.
+pub type TenantOf<K> = fn(&K) -> Option<KeyRange<K>>;
+
+// Whether `tenant` may grow to `after`.
+pub type QuotaCheck<K> = fn(tenant: &KeyRange<K>, after: Usage) -> bool;
+
+pub(crate) struct Quota<K> {
+    tenant_of: TenantOf<K>,
+    allow: QuotaCheck<K>,
+}
+
+// Derived impls would require `K: Copy`.
+impl<K> Clone for Quota<K> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<K> Copy for Quota<K> {}
+
+// The range of all strings starting with `prefix`.
+pub fn prefix_range(prefix: &str) -> KeyRange<String> {
+    // The end is the smallest string above every extension of the prefix:
+    // the prefix with its last char bumped, dropping chars that can't be.
+    let mut end = prefix.to_string();
+    let end = loop {
+        let Some(last) = end.pop() else {
+            break None;
+        };
+        if let Some(next) = (last as u32 + 1..=char::MAX as u32).find_map(char::from_u32) {
+            end.push(next);
+            break Some(end);
+        }
+    };
+    KeyRange {
+        start: Some(prefix.to_string()),
+        end,
+    }
+}
+
+impl<K: Ord + Clone + Default + Encode, V: AsRef<[u8]>, const N: usize> MerkleSearchTree<K, V, N> {
+    // Enforces a quota on inserts: those that would take their tenant to a
+    // usage `allow` rejects fail with `Error::QuotaExceeded`.
+    pub fn with_quota(mut self, tenant_of: TenantOf<K>, allow: QuotaCheck<K>) -> Self {
+        self.quota = Some(Quota { tenant_of, allow });
+        self
+    }
+
+    // Whether `key` may hold `value_len` bytes in place of `old_len`.
+    pub(crate) fn check_quota(
+        &self,
+        key: &K,
+        old_len: Option<usize>,
+        value_len: usize,
+    ) -> Result<(), Error> {
+        let Some(quota) = self.quota else {
+            return Ok(());
+        };
+        let Some(tenant) = (quota.tenant_of)(key) else {
+            return Ok(());
+        };
+        let mut after = self.usage(tenant.clone());
+        match old_len {
+            Some(old_len) => after.bytes -= old_len as u64,
+            None => after.entries += 1,
+        }
+        after.bytes += value_len as u64;
+        if (quota.allow)(&tenant, after) {
+            Ok(())
+        } else {
+            Err(Error::QuotaExceeded {
+                entries: after.entries,
+                bytes: after.bytes,
+            })
+        }
+    }
+}
+
+#[cfg(all(test, feature = "sync"))]
+mod test {
+    use super::*;
+    use crate::sync::Reconciler;
+
+    // Tenants are the part of the key up to the first '/'.
+    #[allow(clippy::ptr_arg)]
+    fn tenant_of(key: &String) -> Option<KeyRange<String>> {
+        let (tenant, _) = key.split_once('/')?;
+        Some(prefix_range(&format!("{tenant}/")))
+    }
+
+    fn at_most_three(_: &KeyRange<String>, after: Usage) -> bool {
+        after.entries <= 3 && after.bytes <= 100
+    }
+
+    #[test]
+    fn test_prefix_range() {
+        let range = prefix_range("ab");
+        assert_eq!(range.end, Some("ac".to_string()));
+        assert_eq!(prefix_range("a\u{10FFFF}").end, Some("b".to_string()));
+        assert_eq!(prefix_range("").end, None);
+    }
+
+    #[test]
+    fn test_usage_and_quota() {
+        let mut tree = MerkleSearchTree::new(3).with_quota(tenant_of, at_most_three);
+        for i in 0..3 {
+            tree.try_insert(format!("a/{i}"), "12345".to_string())
+                .unwrap();
+        }
+        assert!(matches!(
+            tree.try_insert("a/3".to_string(), "x".to_string()),
+            Err(Error::QuotaExceeded { entries: 4, .. })
+        ));
+        // Updates don't add entries, and other tenants have their own budget.
+        tree.try_insert("a/0".to_string(), "x".repeat(90)).unwrap();
+        assert!(tree.try_insert("a/1".to_string(), "x".repeat(20)).is_err());
+        tree.try_insert("b/0".to_string(), "x".to_string()).unwrap();
+        tree.try_insert("untenanted".to_string(), "x".repeat(500))
+            .unwrap();
+
+        assert_eq!(
+            tree.usage(prefix_range("a/")),
+            Usage {
+                entries: 3,
+                bytes: 100
+            }
+        );
+        assert_eq!(tree.usage(..).entries, 5);
+        tree.remove(&"a/2".to_string());
+        assert_eq!(tree.usage(prefix_range("a/")).bytes, 95);
+    }
+
+    #[test]
+    fn test_sync_bypasses_quota() {
+        let reconciler = Reconciler::new(|a: &String, b: &String| a.max(b).clone());
+        let mut source = MerkleSearchTree::new(4);
+        for i in 0..10 {
+            source.insert(format!("a/{i}"), format!("v{i}"));
+        }
+        let mut replica = MerkleSearchTree::new(4).with_quota(tenant_of, at_most_three);
+        let mut pending = reconciler.handle(&mut replica, reconciler.start(&source));
+        while let Some(message) = pending.pop() {
+            for reply in reconciler.handle(&mut source, message) {
+                pending.extend(reconciler.handle(&mut replica, reply));
+            }
+        }
+
+        assert_eq!(replica.usage(prefix_range("a/")).entries, 10);
+    }
+}
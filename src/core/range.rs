@@ -0,0 +1,69 @@
+// Key ranges, the unit that sync, proofs and exports are scoped by.
+//
+// A range is half-open, `[start, end)`, with either side optionally
+// unbounded, so a set of ranges can partition the key space without gaps.
+
+use std::ops::{Bound, RangeBounds};
+
+use crate::core::codec::{Decode, Encode};
+use crate::core::error::Error;
+
+// A half-open key range `[start, end)`. `None` leaves that side unbounded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyRange<K> {
+    pub start: Option<K>,
+    pub end: Option<K>,
+}
+
+impl<K> KeyRange<K> {
+    pub fn full() -> Self {
+        KeyRange {
+            start: None,
+            end: None,
+        }
+    }
+}
+
+impl<K> RangeBounds<K> for KeyRange<K> {
+    fn start_bound(&self) -> Bound<&K> {
+        match &self.start {
+            Some(start) => Bound::Included(start),
+            None => Bound::Unbounded,
+        }
+    }
+
+    fn end_bound(&self) -> Bound<&K> {
+        match &self.end {
+            Some(end) => Bound::Excluded(end),
+            None => Bound::Unbounded,
+        }
+    }
+}
+
+impl<K: Encode> Encode for KeyRange<K> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        for bound in [&self.start, &self.end] {
+            match bound {
+                Some(key) => {
+                    1u8.encode(out);
+                    key.encode(out);
+                }
+                None => 0u8.encode(out),
+            }
+        }
+    }
+}
+
+impl<K: Decode> Decode for KeyRange<K> {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        let mut bound = || match u8::decode(input)? {
+            0 => Ok(None),
+            1 => Ok(Some(K::decode(input)?)),
+            tag => Err(Error::Malformed(format!("unknown bound tag {tag}"))),
+        };
+        Ok(KeyRange {
+            start: bound()?,
+            end: bound()?,
+        })
+    }
+}
@@ -0,0 +1,169 @@
+// A short history of the keys a tree changed, for peers that sync often.
+//
+// Each insert or removal that changes the root is logged with the root it
+// left behind, in a ring of fixed capacity. A peer that was last at one of
+// those roots only needs the keys changed since then, which `delta_since`
+// returns with their current values; one that fell behind the oldest logged
+// root gets None and falls back to full reconciliation. Bulk changes such as
+// `extract_subtree`, `graft` or loading a snapshot aren't logged key by key,
+// so they clear the history.
+
+use std::collections::VecDeque;
+
+use crate::core::codec::Encode;
+use crate::core::hash::NodeHash;
+use crate::core::tree::MerkleSearchTree;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecentChanges<K, const N: usize = 32> {
+    capacity: usize,
+    // The root before the oldest logged change.
+    base: NodeHash<N>,
+    // Each changed key with the root right after its change, oldest first.
+    changes: VecDeque<(K, NodeHash<N>)>,
+}
+
+impl<K: Ord, const N: usize> RecentChanges<K, N> {
+    pub(crate) fn new(capacity: usize, base: NodeHash<N>) -> Self {
+        RecentChanges {
+            capacity,
+            base,
+            changes: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.changes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    // The oldest root changes can still be listed from.
+    pub fn base(&self) -> &NodeHash<N> {
+        &self.base
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(K, NodeHash<N>)> {
+        self.changes.iter()
+    }
+
+    // The keys changed after the tree was at `root`, sorted and without
+    // duplicates, or None if `root` isn't in the history.
+    pub fn keys_since(&self, root: &NodeHash<N>) -> Option<Vec<&K>> {
+        // A root can recur, e.g. after a key is written and removed again;
+        // its last occurrence leaves the fewest changes.
+        let from = match self.changes.iter().rposition(|(_, after)| after == root) {
+            Some(index) => index + 1,
+            None if self.base == *root => 0,
+            None => return None,
+        };
+        let mut keys: Vec<&K> = self.changes.range(from..).map(|(key, _)| key).collect();
+        keys.sort();
+        keys.dedup();
+        Some(keys)
+    }
+
+    // Whether the tree was at `root` at some point the log still covers.
+    pub(crate) fn contains_root(&self, root: &NodeHash<N>) -> bool {
+        self.base == *root || self.changes.iter().any(|(_, after)| after == root)
+    }
+
+    pub(crate) fn push(&mut self, key: K, root: NodeHash<N>) {
+        if self.capacity == 0 {
+            self.base = root;
+            return;
+        }
+        if self.changes.len() == self.capacity
+            && let Some((_, oldest)) = self.changes.pop_front()
+        {
+            self.base = oldest;
+        }
+        self.changes.push_back((key, root));
+    }
+
+    pub(crate) fn reset(&mut self, root: NodeHash<N>) {
+        self.changes.clear();
+        self.base = root;
+    }
+}
+
+impl<K: Ord + Clone + Default + Encode, V: AsRef<[u8]>, const N: usize> MerkleSearchTree<K, V, N> {
+    // Logs the last `capacity` changes; see `RecentChanges`.
+    pub fn with_recent_changes(mut self, capacity: usize) -> Self {
+        self.recent = Some(RecentChanges::new(capacity, self.root_hash()));
+        self
+    }
+
+    pub fn recent_changes(&self) -> Option<&RecentChanges<K, N>> {
+        self.recent.as_ref()
+    }
+
+    // The current value of each key changed since the tree was at `root`,
+    // None for removed keys. None if changes aren't logged or `root` is
+    // older than the log.
+    pub fn delta_since(&self, root: &NodeHash<N>) -> Option<Vec<(K, Option<V>)>>
+    where
+        V: Clone,
+    {
+        let keys = self.recent.as_ref()?.keys_since(root)?;
+        Some(
+            keys.into_iter()
+                .map(|key| (key.clone(), self.get(key).cloned()))
+                .collect(),
+        )
+    }
+
+    pub(crate) fn record_change(&mut self, key: K) {
+        let root = self.root_hash();
+        if let Some(recent) = &mut self.recent {
+            recent.push(key, root);
+        }
+    }
+
+    pub(crate) fn reset_changes(&mut self) {
+        let root = self.root_hash();
+        if let Some(recent) = &mut self.recent {
+            recent.reset(root);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_delta_since() {
+        let mut tree = MerkleSearchTree::<u32>::new(4).with_recent_changes(4);
+        tree.insert(1, "a".to_string());
+        let start = *tree.hash();
+        tree.insert(2, "b".to_string());
+        tree.insert(1, "c".to_string());
+        tree.insert(1, "c".to_string());
+        assert_eq!(tree.recent_changes().unwrap().len(), 3);
+        assert_eq!(
+            tree.delta_since(&start),
+            Some(vec![(1, Some("c".to_string())), (2, Some("b".to_string()))])
+        );
+        assert_eq!(tree.delta_since(tree.hash()), Some(vec![]));
+
+        tree.remove(&2);
+        assert_eq!(
+            tree.delta_since(&start),
+            Some(vec![(1, Some("c".to_string())), (2, None)])
+        );
+        // The change that led away from `start` falls out of the ring.
+        tree.insert(3, "d".to_string());
+        tree.insert(4, "e".to_string());
+        assert_eq!(tree.delta_since(&start), None);
+        tree.extract_subtree(4..);
+        assert!(tree.recent_changes().unwrap().is_empty());
+        assert_eq!(tree.delta_since(tree.hash()), Some(vec![]));
+    }
+}
@@ -0,0 +1,238 @@
+// Divergence reports: a summary of how two replicas differ, for operators
+// triaging divergence tickets. It counts the added, removed and changed
+// keys with a few samples of each, and lists the top-level ranges whose
+// hashes disagree, so the damage can be located without dumping the trees.
+//
+// The report renders as JSON by hand, as the crate has no serde.
+
+use std::fmt::{self, Write};
+
+use crate::core::branch::Diff;
+use crate::core::codec::Encode;
+use crate::core::hash::NodeHash;
+use crate::core::range::KeyRange;
+use crate::core::tree::MerkleSearchTree;
+
+// Keys sampled per category.
+const SAMPLES: usize = 10;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DiffReport<K> {
+    pub root_a: NodeHash,
+    pub root_b: NodeHash,
+    // Keys only in `b`.
+    pub added: KeySample<K>,
+    // Keys only in `a`.
+    pub removed: KeySample<K>,
+    pub changed: KeySample<K>,
+    // The ranges of `a`'s top-level subtrees whose hash differs in `b`.
+    pub mismatched_ranges: Vec<RangeMismatch<K>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeySample<K> {
+    pub count: usize,
+    // The first keys, in key order.
+    pub sample: Vec<K>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RangeMismatch<K> {
+    pub range: KeyRange<K>,
+    pub hash_a: NodeHash,
+    pub hash_b: NodeHash,
+}
+
+impl<K> Default for KeySample<K> {
+    fn default() -> Self {
+        KeySample {
+            count: 0,
+            sample: Vec::new(),
+        }
+    }
+}
+
+impl<K> KeySample<K> {
+    fn add(&mut self, key: K) {
+        self.count += 1;
+        if self.sample.len() < SAMPLES {
+            self.sample.push(key);
+        }
+    }
+}
+
+// How `b` differs from `a`.
+pub fn diff_report<K, V>(a: &MerkleSearchTree<K, V>, b: &MerkleSearchTree<K, V>) -> DiffReport<K>
+where
+    K: Ord + Clone + Default + Encode,
+    V: AsRef<[u8]>,
+{
+    let mut report = DiffReport {
+        root_a: *a.hash(),
+        root_b: *b.hash(),
+        added: KeySample::default(),
+        removed: KeySample::default(),
+        changed: KeySample::default(),
+        mismatched_ranges: Vec::new(),
+    };
+    for diff in a.diff(b) {
+        match diff {
+            Diff::Added(key, _) => report.added.add(key.clone()),
+            Diff::Removed(key, _) => report.removed.add(key.clone()),
+            Diff::Changed { key, .. } => report.changed.add(key.clone()),
+        }
+    }
+    for (range, hash_a) in a.subtree_roots_at_depth(1) {
+        let hash_b = b.range_hash(range.clone());
+        if hash_a != hash_b {
+            report.mismatched_ranges.push(RangeMismatch {
+                range,
+                hash_a,
+                hash_b,
+            });
+        }
+    }
+    report
+}
+
+impl<K: fmt::Display> DiffReport<K> {
+    // Keys are written as their `Display` strings; unbounded range ends as null.
+    pub fn to_json(&self) -> String {
+        let mut out = String::new();
+        let _ = self.write_json(&mut out);
+        out
+    }
+
+    fn write_json(&self, out: &mut String) -> fmt::Result {
+        write!(
+            out,
+            "{{\"root_a\":\"{}\",\"root_b\":\"{}\"",
+            self.root_a, self.root_b
+        )?;
+        for (name, keys) in [
+            ("added", &self.added),
+            ("removed", &self.removed),
+            ("changed", &self.changed),
+        ] {
+            write!(out, ",\"{name}\":{{\"count\":{},\"sample\":[", keys.count)?;
+            for (i, key) in keys.sample.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_json_string(out, key)?;
+            }
+            out.push_str("]}");
+        }
+        out.push_str(",\"mismatched_ranges\":[");
+        for (i, mismatch) in self.mismatched_ranges.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            out.push_str("{\"start\":");
+            write_json_bound(out, mismatch.range.start.as_ref())?;
+            out.push_str(",\"end\":");
+            write_json_bound(out, mismatch.range.end.as_ref())?;
+            write!(
+                out,
+                ",\"hash_a\":\"{}\",\"hash_b\":\"{}\"}}",
+                mismatch.hash_a, mismatch.hash_b
+            )?;
+        }
+        out.push_str("]}");
+        Ok(())
+    }
+}
+
+pub(crate) fn write_json_bound<K: fmt::Display>(
+    out: &mut String,
+    bound: Option<&K>,
+) -> fmt::Result {
+    match bound {
+        Some(key) => write_json_string(out, key),
+        None => {
+            out.push_str("null");
+            Ok(())
+        }
+    }
+}
+
+fn write_json_string<K: fmt::Display>(out: &mut String, key: &K) -> fmt::Result {
+    out.push('"');
+    for c in key.to_string().chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => write!(out, "\\u{:04x}", c as u32)?,
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_diff_report() {
+        let mut a = MerkleSearchTree::new(4);
+        for i in 0..100u32 {
+            a.insert(i, format!("v{i}"));
+        }
+        let mut b = a.fork();
+        for i in 100..130 {
+            b.insert(i, format!("v{i}"));
+        }
+        b.remove(&5);
+        b.insert(50, "changed".to_string());
+
+        let report = diff_report(&a, &b);
+        assert_eq!(report.added.count, 30);
+        assert_eq!(report.added.sample, (100..110).collect::<Vec<_>>());
+        assert_eq!(report.removed.sample, vec![5]);
+        assert_eq!(report.changed.sample, vec![50]);
+        // 5 and 50 are in the middle, the added keys past the end.
+        let ranges = &report.mismatched_ranges;
+        assert!(!ranges.is_empty() && ranges.len() <= 3);
+        assert_eq!(ranges.last().unwrap().range.end, None);
+        for mismatch in ranges {
+            assert_eq!(mismatch.hash_b, b.range_hash(mismatch.range.clone()));
+        }
+
+        let same = diff_report(&a, &a.fork());
+        assert_eq!(
+            same.added.count + same.removed.count + same.changed.count,
+            0
+        );
+        assert!(same.mismatched_ranges.is_empty());
+    }
+
+    #[test]
+    fn test_json() {
+        let mut a = MerkleSearchTree::<String>::new(4);
+        a.insert("plain".to_string(), "v".to_string());
+        let mut b = MerkleSearchTree::new(4);
+        b.insert("quote\"back\\slash\n".to_string(), "w".to_string());
+
+        let json = diff_report(&a, &b).to_json();
+        let expected = format!(
+            concat!(
+                "{{\"root_a\":\"{}\",\"root_b\":\"{}\",",
+                "\"added\":{{\"count\":1,\"sample\":[\"quote\\\"back\\\\slash\\n\"]}},",
+                "\"removed\":{{\"count\":1,\"sample\":[\"plain\"]}},",
+                "\"changed\":{{\"count\":0,\"sample\":[]}},",
+                "\"mismatched_ranges\":[{{\"start\":null,\"end\":null,",
+                "\"hash_a\":\"{}\",\"hash_b\":\"{}\"}}]}}"
+            ),
+            a.hash(),
+            b.hash(),
+            a.hash(),
+            b.hash()
+        );
+        assert_eq!(json, expected);
+    }
+}
@@ -0,0 +1,149 @@
+// Key range ownership for sharded clusters.
+//
+// A ring assigns every key to an owner. `assign_ranges` splits the tree's
+// keyspace into ranges that end only where subtrees do, each wholly owned by
+// one node of the ring, so rebalancing moves whole subtrees and the receiver
+// verifies each range against its `range_hash` on arrival. Ranges only end
+// at keys in the tree, so a key the tree doesn't hold may fall in a
+// neighbour's range; every key it does hold is in its owner's.
+
+use std::collections::BTreeMap;
+
+use crate::core::codec::Encode;
+use crate::core::range::KeyRange;
+use crate::core::tree::{MerkleSearchTree, Node};
+
+pub type OwnerId = u64;
+
+pub trait Ring<K> {
+    fn owner(&self, key: &K) -> OwnerId;
+
+    // The smallest key above `key` that may have another owner, if any.
+    fn next_boundary(&self, key: &K) -> Option<K>;
+}
+
+// A ring of tokens, each owning the keys from itself up to the next token.
+// Keys below the first token wrap around to the last one.
+#[derive(Clone, Debug, Default)]
+pub struct TokenRing<K> {
+    tokens: BTreeMap<K, OwnerId>,
+}
+
+impl<K: Ord + Clone> TokenRing<K> {
+    pub fn new() -> Self {
+        TokenRing {
+            tokens: BTreeMap::new(),
+        }
+    }
+
+    pub fn add_token(&mut self, token: K, owner: OwnerId) {
+        self.tokens.insert(token, owner);
+    }
+
+    pub fn remove_token(&mut self, token: &K) -> Option<OwnerId> {
+        self.tokens.remove(token)
+    }
+}
+
+impl<K: Ord + Clone> Ring<K> for TokenRing<K> {
+    fn owner(&self, key: &K) -> OwnerId {
+        let owner = self.tokens.range(..=key).next_back();
+        let (_, owner) = owner
+            .or_else(|| self.tokens.last_key_value())
+            .expect("a ring needs at least one token");
+        *owner
+    }
+
+    fn next_boundary(&self, key: &K) -> Option<K> {
+        use std::ops::Bound::{Excluded, Unbounded};
+        let mut above = self.tokens.range((Excluded(key), Unbounded));
+        above.next().map(|(token, _)| token.clone())
+    }
+}
+
+impl<K: Ord + Clone + Default + Encode, V: AsRef<[u8]>, const N: usize> MerkleSearchTree<K, V, N> {
+    // Splits the keyspace into ranges along subtree boundaries, each owned by
+    // one node of `ring`. Adjacent ranges have different owners, and they
+    // partition the keyspace: the first starts and the last ends unbounded.
+    pub fn assign_ranges(&self, ring: &impl Ring<K>) -> Vec<(KeyRange<K>, OwnerId)> {
+        // The start key and owner of each run, in key order.
+        let mut runs: Vec<(&K, OwnerId)> = Vec::new();
+        let mut pending: Vec<&Node<K, V, N>> =
+            self.root.children().iter().rev().map(|c| &**c).collect();
+        while let Some(node) = pending.pop() {
+            let mut min = node;
+            while min.is_internal() {
+                min = &min.children()[0];
+            }
+            let (min, max) = (min.key(), node.key());
+            let split = ring
+                .next_boundary(min)
+                .is_some_and(|boundary| boundary <= *max);
+            if split && node.is_internal() {
+                pending.extend(node.children().iter().rev().map(|child| &**child));
+                continue;
+            }
+            let owner = ring.owner(min);
+            if runs.last().is_none_or(|(_, last)| *last != owner) {
+                runs.push((min, owner));
+            }
+        }
+
+        let mut ranges = Vec::with_capacity(runs.len());
+        for (index, (_, owner)) in runs.iter().enumerate() {
+            let range = KeyRange {
+                start: (index > 0).then(|| runs[index].0.clone()),
+                end: runs.get(index + 1).map(|(start, _)| (*start).clone()),
+            };
+            ranges.push((range, *owner));
+        }
+        ranges
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_assign_ranges() {
+        let mut tree = MerkleSearchTree::new(4);
+        for i in 0..1000u32 {
+            tree.insert(i, format!("v{i}"));
+        }
+        let mut ring = TokenRing::new();
+        for (token, owner) in [(100, 1), (333, 2), (700, 3), (950, 1)] {
+            ring.add_token(token, owner);
+        }
+
+        // Keys below 100 wrap around to 950's owner, joining the first run.
+        let ranges = tree.assign_ranges(&ring);
+        let owners: Vec<_> = ranges.iter().map(|(_, owner)| *owner).collect();
+        assert_eq!(owners, vec![1, 2, 3, 1]);
+        assert_eq!(ranges[0].0.start, None);
+        assert_eq!(ranges.last().unwrap().0.end, None);
+        for window in ranges.windows(2) {
+            assert_eq!(window[0].0.end, window[1].0.start);
+            assert_ne!(window[0].1, window[1].1);
+        }
+        // Every key is in its owner's range.
+        for (range, owner) in &ranges {
+            assert!(
+                tree.range(range.clone())
+                    .all(|(key, _)| ring.owner(key) == *owner)
+            );
+        }
+
+        // A receiver holding the same entries matches each range's hash.
+        let mut replica = MerkleSearchTree::new(16);
+        for (key, value) in tree.iter() {
+            replica.insert(*key, value.clone());
+        }
+        for (range, _) in &ranges {
+            assert_eq!(
+                replica.range_hash(range.clone()),
+                tree.range_hash(range.clone())
+            );
+        }
+    }
+}
@@ -0,0 +1,133 @@
+// Iterators over a snapshot of the tree, for long scans that must not hold
+// up writers.
+//
+// `iter` and `range` borrow the tree, so it can't change while they run,
+// and a tree behind a lock stays locked for the whole scan. A `Scan` holds
+// the root it started from instead, which costs what a fork does: nodes are
+// shared and copied on write, so later writes to the tree build new nodes
+// and leave the scanned ones alone. The scan sees the tree as it was when
+// it began, however long it runs and whatever is written meanwhile; it can
+// be moved to another thread while the lock is released. Entries are
+// cloned as they are yielded, since the scan owns no borrow to hand out.
+
+use std::ops::{Bound, RangeBounds};
+
+use crate::core::alloc::NodeRef;
+use crate::core::codec::Encode;
+use crate::core::tree::{MerkleSearchTree, Node};
+
+pub struct Scan<K, V, const N: usize = 32> {
+    // A node per level on the path, and the index of its next child.
+    stack: Vec<(NodeRef<Node<K, V, N>>, usize)>,
+    end: Bound<K>,
+}
+
+impl<K: Ord + Clone + Default + Encode, V: AsRef<[u8]>, const N: usize> MerkleSearchTree<K, V, N> {
+    // The entries in `range` as of now, in key order.
+    pub fn scan<R: RangeBounds<K>>(&self, range: R) -> Scan<K, V, N> {
+        let mut stack = Vec::new();
+        let mut node = self.root.clone();
+        // Seek as `range` does: subtrees left of the path hold smaller keys.
+        while let Node::Internal { children, .. } = &*node {
+            let index = match range.start_bound() {
+                Bound::Included(start) => children.partition_point(|child| child.key() < start),
+                Bound::Excluded(start) => children.partition_point(|child| child.key() <= start),
+                Bound::Unbounded => 0,
+            };
+            match children.get(index).cloned() {
+                Some(child) if matches!(*child, Node::Internal { .. }) => {
+                    stack.push((node, index + 1));
+                    node = child;
+                }
+                _ => {
+                    stack.push((node, index));
+                    break;
+                }
+            }
+        }
+        Scan {
+            stack,
+            end: range.end_bound().cloned(),
+        }
+    }
+}
+
+impl<K: Ord + Clone + Default + Encode, V: AsRef<[u8]> + Clone, const N: usize> Iterator
+    for Scan<K, V, N>
+{
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        loop {
+            let (node, index) = self.stack.last_mut()?;
+            let Some(child) = node.children().get(*index).cloned() else {
+                self.stack.pop();
+                continue;
+            };
+            *index += 1;
+            let Node::Leaf { key, value, .. } = &*child else {
+                self.stack.push((child, 0));
+                continue;
+            };
+            let in_range = match &self.end {
+                Bound::Included(end) => key <= end,
+                Bound::Excluded(end) => key < end,
+                Bound::Unbounded => true,
+            };
+            if !in_range {
+                self.stack.clear();
+                return None;
+            }
+            return Some((key.clone(), value.clone()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    #[test]
+    fn test_scan_ignores_later_writes() {
+        let mut tree = MerkleSearchTree::<u32>::new(4);
+        for i in 0..500 {
+            tree.insert(i, format!("v{i}"));
+        }
+        let expected: Vec<_> = tree
+            .range(100..=300)
+            .map(|(k, v)| (*k, v.clone()))
+            .collect();
+        let mut scan = tree.scan(100..=300);
+        let mut seen = Vec::new();
+        for i in 0.. {
+            let Some(entry) = scan.next() else { break };
+            seen.push(entry);
+            // Writes all over the scanned range while the scan runs.
+            tree.remove(&(i * 3));
+            tree.insert(150 + i, "new".to_string());
+        }
+        assert_eq!(seen, expected);
+        // Later scans see the writes, and seek like `range`.
+        assert_eq!(tree.scan(..).count(), tree.len());
+        assert_eq!(tree.scan(490..).count(), tree.range(490..).count());
+        let bound = (Bound::Excluded(497), Bound::Unbounded);
+        assert_eq!(tree.scan(bound).count(), tree.range(bound).count());
+        assert_eq!(MerkleSearchTree::<u32>::new(4).scan(..).next(), None);
+    }
+
+    #[test]
+    fn test_scan_without_holding_the_lock() {
+        let shared = Arc::new(Mutex::new(MerkleSearchTree::<u32>::new(8)));
+        for i in 0..1000 {
+            shared.lock().unwrap().insert(i, format!("v{i}"));
+        }
+        let scan = shared.lock().unwrap().scan(..);
+        let reader = thread::spawn(move || scan.count());
+        for i in 1000..2000 {
+            shared.lock().unwrap().insert(i, format!("v{i}"));
+        }
+        assert_eq!(reader.join().unwrap(), 1000);
+    }
+}
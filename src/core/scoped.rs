@@ -0,0 +1,100 @@
+// A read-only view of the part of a tree inside one key range, e.g. a
+// tenant's prefix. Lookups outside the range find nothing, and the hash
+// covers only the entries inside, so it matches the root of a tree holding
+// just those entries.
+
+use std::ops::RangeBounds;
+
+use crate::core::codec::Encode;
+use crate::core::hash::NodeHash;
+use crate::core::range::KeyRange;
+use crate::core::tree::{MerkleSearchTree, Range};
+
+pub struct ScopedTreeView<'a, K, V> {
+    tree: &'a MerkleSearchTree<K, V>,
+    range: KeyRange<K>,
+}
+
+impl<K: Ord + Clone + Default + Encode, V: AsRef<[u8]>> MerkleSearchTree<K, V> {
+    pub fn scoped(&self, range: KeyRange<K>) -> ScopedTreeView<'_, K, V> {
+        ScopedTreeView { tree: self, range }
+    }
+}
+
+impl<'a, K: Ord + Clone + Default + Encode, V: AsRef<[u8]>> ScopedTreeView<'a, K, V> {
+    pub fn range(&self) -> &KeyRange<K> {
+        &self.range
+    }
+
+    pub fn get(&self, key: &K) -> Option<&'a V> {
+        if !self.range.contains(key) {
+            return None;
+        }
+        self.tree.get(key)
+    }
+
+    // Iterates the entries in the view, in key order.
+    pub fn iter(&self) -> Range<'a, K, V> {
+        self.tree.range(self.range.clone())
+    }
+
+    // The range hash of the view.
+    pub fn hash(&self) -> NodeHash {
+        self.tree.range_hash(self.range.clone())
+    }
+
+    pub fn len(&self) -> usize {
+        let start = self
+            .range
+            .start
+            .as_ref()
+            .map_or(0, |start| self.tree.rank(start));
+        let end = self
+            .range
+            .end
+            .as_ref()
+            .map_or(self.tree.len(), |end| self.tree.rank(end));
+        end.saturating_sub(start)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_scoped_view() {
+        let mut tree = MerkleSearchTree::new(4);
+        let mut tenant = MerkleSearchTree::new(8);
+        for i in 0..100 {
+            for prefix in ["a", "b", "c"] {
+                let key = format!("{prefix}/{i:03}");
+                tree.insert(key.clone(), format!("v{i}"));
+                if prefix == "b" {
+                    tenant.insert(key, format!("v{i}"));
+                }
+            }
+        }
+
+        let view = tree.scoped(KeyRange {
+            start: Some("b/".to_string()),
+            end: Some("c/".to_string()),
+        });
+        assert_eq!(view.hash(), *tenant.hash());
+        assert_eq!(view.len(), 100);
+        assert!(view.iter().eq(tenant.iter()));
+        assert_eq!(view.get(&"b/007".to_string()), Some(&"v7".to_string()));
+        assert_eq!(view.get(&"a/007".to_string()), None);
+
+        let empty = tree.scoped(KeyRange {
+            start: Some("x".to_string()),
+            end: None,
+        });
+        assert!(empty.is_empty());
+        assert_eq!(empty.hash(), NodeHash::default());
+    }
+}
@@ -0,0 +1,139 @@
+// Secret keys for keyed hashing, wiped from memory when dropped. Behind the
+// `zeroize` feature, which does the wiping.
+//
+// A `SecretKey` holds its bytes in a `Zeroizing` buffer, so they are
+// overwritten on drop, and so is every buffer `mac` derives from it: the
+// padded key blocks and the inner digest. What this can't reach is the
+// state inside sha2's hashers, which only holds the padded key mixed through
+// the compression function, and copies the allocator or the OS made before
+// the key got here. The key is never printed: `Debug` shows its length only.
+//
+// Checkpoints are what the keys protect: `Checkpoint::sign_hmac` signs a
+// note body for witnesses sharing a key with the log.
+
+use std::fmt;
+
+use sha2::{Digest, Sha256};
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+use crate::core::hash::NodeHash;
+
+// SHA-256's block size, which HMAC pads keys to.
+const BLOCK: usize = 64;
+
+#[derive(Clone)]
+pub struct SecretKey(Zeroizing<Vec<u8>>);
+
+impl SecretKey {
+    // Takes `bytes` without copying them, so no unwiped copy is left.
+    pub fn new(bytes: Vec<u8>) -> Self {
+        SecretKey(Zeroizing::new(bytes))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    // The key's bytes, for handing to a signer.
+    pub fn expose(&self) -> &[u8] {
+        &self.0
+    }
+
+    // HMAC-SHA256 of `message` under this key.
+    pub fn mac(&self, message: &[u8]) -> NodeHash {
+        let mut block = Zeroizing::new([0u8; BLOCK]);
+        if self.0.len() > BLOCK {
+            let digest = Zeroizing::new(<[u8; 32]>::from(Sha256::digest(&*self.0)));
+            block[..32].copy_from_slice(&*digest);
+        } else {
+            block[..self.0.len()].copy_from_slice(&self.0);
+        }
+
+        let mut pad = Zeroizing::new([0u8; BLOCK]);
+        for (pad, key) in pad.iter_mut().zip(*block) {
+            *pad = key ^ 0x36;
+        }
+        let inner = Zeroizing::new(<[u8; 32]>::from(
+            Sha256::new()
+                .chain_update(*pad)
+                .chain_update(message)
+                .finalize(),
+        ));
+        for (pad, key) in pad.iter_mut().zip(*block) {
+            *pad = key ^ 0x5c;
+        }
+        NodeHash(
+            Sha256::new()
+                .chain_update(*pad)
+                .chain_update(*inner)
+                .finalize()
+                .into(),
+        )
+    }
+}
+
+// Overwrites the key with zeros now rather than on drop, leaving it empty.
+impl Zeroize for SecretKey {
+    fn zeroize(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+// The `Zeroizing` buffer wipes itself on drop.
+impl ZeroizeOnDrop for SecretKey {}
+
+impl PartialEq for SecretKey {
+    fn eq(&self, other: &Self) -> bool {
+        constant_time_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for SecretKey {}
+
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SecretKey({} bytes)", self.0.len())
+    }
+}
+
+// Compares every byte, so the time taken doesn't tell where `a` and `b`
+// differ. Lengths aren't secret.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_hmac_vectors() {
+        // RFC 4231, test cases 2 and 6.
+        let key = SecretKey::new(b"Jefe".to_vec());
+        assert_eq!(
+            key.mac(b"what do ya want for nothing?").to_string(),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        let long = SecretKey::new(vec![0xaa; 131]);
+        assert_eq!(
+            long.mac(b"Test Using Larger Than Block-Size Key - Hash Key First")
+                .to_string(),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+        assert_eq!(format!("{key:?}"), "SecretKey(4 bytes)");
+    }
+
+    #[test]
+    fn test_zeroize() {
+        let mut key = SecretKey::new(vec![7; 16]);
+        assert_eq!(key, key.clone());
+        assert_ne!(key, SecretKey::new(vec![7; 15]));
+        assert_ne!(key, SecretKey::new(vec![8; 16]));
+        key.zeroize();
+        assert!(key.is_empty());
+    }
+}
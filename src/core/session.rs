@@ -0,0 +1,132 @@
+// Read-your-writes across replicas.
+//
+// After a write, the writer hands the client a `SessionToken`: the root its
+// tree reached and its generation, the number of root changes behind it. A
+// routing layer keeps the newest token per session and sends the session's
+// reads only to replicas whose tree `satisfies` it.
+//
+// A tree satisfies a token when it is at the token's root, or when its
+// recent-changes log shows it passed through that root on its way to where
+// it is now, as a replica replaying the writer's ops does. Generations only
+// compare between trees forked from one another, which is what replicas fed
+// from one writer's op log are. The check is conservative: a replica that
+// caught up some other way, e.g. by reconciling past the token's root, is
+// turned down until it matches the writer's root exactly.
+
+use crate::core::codec::{Decode, Encode};
+use crate::core::error::Error;
+use crate::core::hash::NodeHash;
+use crate::core::tree::MerkleSearchTree;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SessionToken<const N: usize = 32> {
+    pub root_hash: NodeHash<N>,
+    pub generation: u64,
+}
+
+impl<const N: usize> SessionToken<N> {
+    // The later of two tokens from one writer, e.g. to keep one per session.
+    pub fn newest(self, other: Self) -> Self {
+        if other.generation > self.generation {
+            other
+        } else {
+            self
+        }
+    }
+}
+
+impl<const N: usize> Encode for SessionToken<N> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.root_hash.encode(out);
+        self.generation.encode(out);
+    }
+
+    fn encoded_len(&self) -> usize {
+        N + 8
+    }
+}
+
+impl<const N: usize> Decode for SessionToken<N> {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        Ok(SessionToken {
+            root_hash: NodeHash::decode(input)?,
+            generation: u64::decode(input)?,
+        })
+    }
+}
+
+impl<K: Ord + Clone + Default + Encode, V: AsRef<[u8]>, const N: usize> MerkleSearchTree<K, V, N> {
+    // The number of times the root changed, counting from when the tree
+    // was created. Forks start from their parent's count.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    // The token to hand out after a write.
+    pub fn session_token(&self) -> SessionToken<N> {
+        SessionToken {
+            root_hash: self.root_hash(),
+            generation: self.generation,
+        }
+    }
+
+    // Whether this tree is known to include the writes behind `token`.
+    pub fn satisfies(&self, token: &SessionToken<N>) -> bool {
+        if self.root_hash() == token.root_hash {
+            return true;
+        }
+        self.generation > token.generation
+            && self
+                .recent
+                .as_ref()
+                .is_some_and(|recent| recent.contains_root(&token.root_hash))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_read_your_writes() {
+        let mut writer = MerkleSearchTree::<u32>::new(4).with_recent_changes(16);
+        for i in 0..10 {
+            writer.insert(i, format!("v{i}"));
+        }
+        let mut replica = writer.fork();
+        let mut lagging = writer.fork();
+
+        writer.insert(3, "mine".to_string());
+        let token = writer.session_token();
+        assert_eq!(token.generation, 11);
+        assert!(writer.satisfies(&token));
+        assert!(!replica.satisfies(&token));
+
+        // The replica replays the writer's changes and moves on past them.
+        replica.insert(3, "mine".to_string());
+        assert!(replica.satisfies(&token));
+        writer.insert(20, "later".to_string());
+        replica.insert(20, "later".to_string());
+        assert!(replica.satisfies(&token));
+        assert_eq!(token.newest(writer.session_token()), writer.session_token());
+
+        // Without a log, only the exact root counts.
+        let mut unlogged = MerkleSearchTree::<u32>::new(4);
+        for (key, value) in writer.iter() {
+            unlogged.insert(*key, value.clone());
+        }
+        assert!(unlogged.satisfies(&writer.session_token()));
+        assert!(!unlogged.satisfies(&token));
+
+        // A replica that never saw the write doesn't pass by writing more.
+        for i in 30..40 {
+            lagging.insert(i, "other".to_string());
+        }
+        assert!(!lagging.satisfies(&token));
+
+        let mut bytes = Vec::new();
+        token.encode(&mut bytes);
+        assert_eq!(bytes.len(), token.encoded_len());
+        assert_eq!(SessionToken::decode(&mut bytes.as_slice()).unwrap(), token);
+    }
+}
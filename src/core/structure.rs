@@ -0,0 +1,109 @@
+// A log of the structural decisions a tree makes: where nodes split, when
+// the root grows or collapses, and which nodes removals drop. Replicas with
+// the same content can still differ in shape, since the layout depends on
+// the order of the writes; comparing their logs shows the first decision
+// where they parted.
+//
+// Recording is enabled by the `structure-log` feature, meant for debug
+// builds: the log grows with every split until it is taken.
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum StructureEvent<K> {
+    // A node `level` levels below the root split after the key `at`.
+    Split { level: usize, at: K },
+    // The root split, and the tree grew to `depth` levels.
+    RootGrew { depth: usize },
+    // A removal of `key` left a node `level` levels below the root empty.
+    NodeDropped { level: usize, key: K },
+    // The root had a single child and the tree shrank to `depth` levels.
+    RootCollapsed { depth: usize },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StructureLog<K> {
+    events: Vec<StructureEvent<K>>,
+}
+
+impl<K> Default for StructureLog<K> {
+    fn default() -> Self {
+        StructureLog { events: Vec::new() }
+    }
+}
+
+impl<K: PartialEq> StructureLog<K> {
+    pub fn events(&self) -> &[StructureEvent<K>] {
+        &self.events
+    }
+
+    // The index of the first event where the two logs differ, or None if
+    // they are the same.
+    pub fn first_divergence(&self, other: &Self) -> Option<usize> {
+        match self
+            .events
+            .iter()
+            .zip(&other.events)
+            .position(|(a, b)| a != b)
+        {
+            Some(index) => Some(index),
+            None if self.events.len() != other.events.len() => {
+                Some(self.events.len().min(other.events.len()))
+            }
+            None => None,
+        }
+    }
+
+    #[cfg_attr(not(feature = "structure-log"), allow(dead_code))]
+    pub(crate) fn push(&mut self, event: StructureEvent<K>) {
+        self.events.push(event);
+    }
+}
+
+#[cfg(all(test, feature = "structure-log"))]
+mod test {
+    use super::*;
+    use crate::core::tree::MerkleSearchTree;
+
+    #[test]
+    fn test_structure_log() {
+        let mut tree = MerkleSearchTree::new(3);
+        for i in [10u32, 20, 30, 40] {
+            tree.insert(i, format!("v{i}"));
+        }
+        assert_eq!(
+            tree.structure_log().events(),
+            [
+                StructureEvent::Split { level: 0, at: 20 },
+                StructureEvent::RootGrew { depth: 2 },
+            ]
+        );
+
+        // Same content in another order: the logs show where shapes parted.
+        let (mut ascending, mut descending) = (tree.fork(), tree.fork());
+        for i in 0..5 {
+            ascending.insert(100 + i, format!("v{i}"));
+            descending.insert(104 - i, format!("v{}", 4 - i));
+        }
+        assert_eq!(ascending.hash(), descending.hash());
+        assert_ne!(ascending.level_digests(), descending.level_digests());
+        let divergence = ascending
+            .structure_log()
+            .first_divergence(descending.structure_log());
+        // They share the fork's history, and their first inserts split alike.
+        assert!(matches!(divergence, Some(index) if index > 2));
+
+        let mut log = tree.take_structure_log();
+        assert!(tree.structure_log().events().is_empty());
+        tree.remove(&40);
+        tree.remove(&30);
+        assert_eq!(
+            tree.structure_log().events(),
+            [
+                StructureEvent::NodeDropped { level: 1, key: 30 },
+                StructureEvent::RootCollapsed { depth: 1 },
+            ]
+        );
+        log.push(StructureEvent::RootGrew { depth: 9 });
+        assert_eq!(log.first_divergence(&log.clone()), None);
+    }
+}
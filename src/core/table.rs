@@ -0,0 +1,249 @@
+// Typed rows over a tree: a struct's primary key becomes the entry's key and
+// its other columns, encoded in a fixed order, the value.
+//
+// A row type implements `Row` by hand, naming its table and columns in a
+// `Schema` and encoding its columns with the crate's canonical codec. Two
+// services built from the same schema encode equal rows to equal bytes, so
+// their tables have equal roots and sync like any other trees. `schema_id`
+// digests the schema; services exchange it first, since tables of different
+// schemas can't be compared meaningfully.
+
+use crate::core::codec::{Decode, Encode};
+use crate::core::error::Error;
+use crate::core::hash::NodeHash;
+use crate::core::tree::{InsertOutcome, MerkleSearchTree};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Schema {
+    name: String,
+    // Each column's name and type, in encoding order.
+    columns: Vec<(String, String)>,
+}
+
+impl Schema {
+    pub fn new(name: &str) -> Self {
+        Schema {
+            name: name.to_string(),
+            columns: Vec::new(),
+        }
+    }
+
+    // Adds a column. `kind` names its type, e.g. "u64" or "string"; changing
+    // it changes the schema id.
+    pub fn column(mut self, name: &str, kind: &str) -> Self {
+        self.columns.push((name.to_string(), kind.to_string()));
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn columns(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.columns
+            .iter()
+            .map(|(name, kind)| (name.as_str(), kind.as_str()))
+    }
+
+    // The digest of the schema's canonical encoding.
+    pub fn id(&self) -> NodeHash {
+        let mut bytes = Vec::new();
+        self.name.encode(&mut bytes);
+        (self.columns.len() as u32).encode(&mut bytes);
+        for (name, kind) in &self.columns {
+            name.encode(&mut bytes);
+            kind.encode(&mut bytes);
+        }
+        NodeHash::digest(&bytes)
+    }
+}
+
+pub trait Row: Sized {
+    type Key: Ord + Clone + Default + Encode + Decode;
+
+    // The key column comes first, then the columns `encode_body` writes.
+    fn schema() -> Schema;
+
+    fn key(&self) -> Self::Key;
+
+    // Writes every column but the key, in schema order.
+    fn encode_body(&self, out: &mut Vec<u8>);
+
+    fn decode_body(key: Self::Key, input: &mut &[u8]) -> Result<Self, Error>;
+}
+
+pub struct Table<T: Row> {
+    tree: MerkleSearchTree<T::Key, Vec<u8>>,
+}
+
+impl<T: Row> Table<T> {
+    pub fn new(max_children: usize) -> Self {
+        Table {
+            tree: MerkleSearchTree::new(max_children),
+        }
+    }
+
+    // Wraps a tree received from a peer, checking that every value decodes
+    // as a row.
+    pub fn from_tree(tree: MerkleSearchTree<T::Key, Vec<u8>>) -> Result<Self, Error> {
+        for (key, body) in tree.iter() {
+            decode_row::<T>(key, body)?;
+        }
+        Ok(Table { tree })
+    }
+
+    pub fn schema_id() -> NodeHash {
+        T::schema().id()
+    }
+
+    // Inserts or replaces the row with the same key. Returns whether the
+    // table changed. Panics where `try_upsert` fails.
+    pub fn upsert(&mut self, row: &T) -> bool {
+        match self.try_upsert(row) {
+            Ok(changed) => changed,
+            Err(err) => panic!("{err}"),
+        }
+    }
+
+    // `upsert`, failing as `try_insert` does on a tree from `from_tree`.
+    pub fn try_upsert(&mut self, row: &T) -> Result<bool, Error> {
+        let mut body = Vec::new();
+        row.encode_body(&mut body);
+        Ok(self.tree.try_insert(row.key(), body)? != InsertOutcome::Unchanged)
+    }
+
+    pub fn get(&self, key: &T::Key) -> Result<Option<T>, Error> {
+        self.tree
+            .get(key)
+            .map(|body| decode_row(key, body))
+            .transpose()
+    }
+
+    pub fn delete(&mut self, key: &T::Key) -> bool {
+        self.tree.remove(key)
+    }
+
+    // The rows in key order.
+    pub fn rows(&self) -> impl Iterator<Item = Result<T, Error>> + '_ {
+        self.tree.iter().map(|(key, body)| decode_row(key, body))
+    }
+
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    pub fn root_hash(&self) -> NodeHash {
+        self.tree.root_hash()
+    }
+
+    // The underlying tree, e.g. to sync or snapshot it.
+    pub fn tree(&self) -> &MerkleSearchTree<T::Key, Vec<u8>> {
+        &self.tree
+    }
+
+    pub fn into_tree(self) -> MerkleSearchTree<T::Key, Vec<u8>> {
+        self.tree
+    }
+}
+
+fn decode_row<T: Row>(key: &T::Key, body: &[u8]) -> Result<T, Error> {
+    let mut input = body;
+    let row = T::decode_body(key.clone(), &mut input)?;
+    if !input.is_empty() {
+        return Err(Error::Malformed(format!(
+            "{} bytes after the row",
+            input.len()
+        )));
+    }
+    Ok(row)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct User {
+        id: u64,
+        email: String,
+        age: u8,
+    }
+
+    impl Row for User {
+        type Key = u64;
+
+        fn schema() -> Schema {
+            Schema::new("users")
+                .column("id", "u64")
+                .column("email", "string")
+                .column("age", "u8")
+        }
+
+        fn key(&self) -> u64 {
+            self.id
+        }
+
+        fn encode_body(&self, out: &mut Vec<u8>) {
+            self.email.encode(out);
+            self.age.encode(out);
+        }
+
+        fn decode_body(id: u64, input: &mut &[u8]) -> Result<Self, Error> {
+            Ok(User {
+                id,
+                email: String::decode(input)?,
+                age: u8::decode(input)?,
+            })
+        }
+    }
+
+    fn user(id: u64) -> User {
+        User {
+            id,
+            email: format!("user{id}@example.com"),
+            age: (id % 90) as u8,
+        }
+    }
+
+    #[test]
+    fn test_tables_agree_across_services() {
+        let mut ours = Table::<User>::new(8);
+        let mut theirs = Table::<User>::new(16);
+        for id in 0..300 {
+            ours.upsert(&user(id));
+        }
+        for id in (0..300).rev() {
+            theirs.upsert(&user(id));
+        }
+        assert_eq!(ours.root_hash(), theirs.root_hash());
+        assert!(!ours.upsert(&user(7)));
+        assert_eq!(ours.get(&7).unwrap(), Some(user(7)));
+        assert!(ours.delete(&7));
+        assert_eq!(ours.get(&7).unwrap(), None);
+        assert_eq!(ours.rows().count(), 299);
+
+        let received = Table::<User>::from_tree(theirs.tree().fork()).unwrap();
+        assert_eq!(received.len(), 300);
+        let mut corrupt = theirs.into_tree();
+        corrupt.insert(1, vec![0, 0, 0, 9]);
+        assert!(Table::<User>::from_tree(corrupt).is_err());
+
+        // A tree with a depth limit refuses rows instead of panicking.
+        let limited = MerkleSearchTree::new(3).with_max_depth(2);
+        let mut limited = Table::<User>::from_tree(limited).unwrap();
+        let refused = (0..100).find_map(|id| limited.try_upsert(&user(id)).err());
+        assert!(matches!(
+            refused,
+            Some(Error::DepthLimitExceeded { limit: 2 })
+        ));
+
+        assert_ne!(
+            Table::<User>::schema_id(),
+            Schema::new("users").column("id", "u64").id()
+        );
+    }
+}
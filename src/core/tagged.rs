@@ -0,0 +1,148 @@
+// Values with per-entry metadata, for bookkeeping that would otherwise need
+// a map kept next to the tree.
+//
+// A `Tagged` value carries two kinds of tags. Covered tags, such as a
+// replication timestamp, are hashed with the value: replicas only agree if
+// their tags do, and sync carries them along. Local tags, such as cache
+// hints, are left out of the hash, so replicas whose local tags differ still
+// have the same root. Both are stored in snapshots and returned by lookups
+// and iterators; change local tags in place with `update_unhashed`, since an
+// insert of an equal hashed value is skipped as unchanged.
+
+use crate::core::codec::{Decode, Encode, encode_len};
+use crate::core::error::Error;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Tagged<L = ()> {
+    // The value followed by the covered tags. All of it is hashed.
+    hashed: Vec<u8>,
+    value_len: usize,
+    pub local: L,
+}
+
+impl<L: Default> Tagged<L> {
+    pub fn new(value: impl AsRef<[u8]>) -> Self {
+        let hashed = value.as_ref().to_vec();
+        Tagged {
+            value_len: hashed.len(),
+            hashed,
+            local: L::default(),
+        }
+    }
+}
+
+impl<L> Tagged<L> {
+    // Appends the encoding of `tag` to the covered tags.
+    pub fn with_covered<T: Encode + ?Sized>(mut self, tag: &T) -> Self {
+        tag.encode(&mut self.hashed);
+        self
+    }
+
+    pub fn with_local(mut self, local: L) -> Self {
+        self.local = local;
+        self
+    }
+
+    pub fn value(&self) -> &[u8] {
+        &self.hashed[..self.value_len]
+    }
+
+    // The encoded covered tags, in the order they were added.
+    pub fn covered(&self) -> &[u8] {
+        &self.hashed[self.value_len..]
+    }
+}
+
+// The hashed bytes: the value and its covered tags.
+impl<L> AsRef<[u8]> for Tagged<L> {
+    fn as_ref(&self) -> &[u8] {
+        &self.hashed
+    }
+}
+
+impl<L: Encode> Encode for Tagged<L> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.hashed.encode(out);
+        encode_len(self.value_len, out);
+        self.local.encode(out);
+    }
+}
+
+impl<L: Decode> Decode for Tagged<L> {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        let hashed = Vec::decode(input)?;
+        let value_len = u32::decode(input)? as usize;
+        if value_len > hashed.len() {
+            return Err(Error::Malformed(format!(
+                "value of {value_len} bytes in {} tagged bytes",
+                hashed.len()
+            )));
+        }
+        Ok(Tagged {
+            hashed,
+            value_len,
+            local: L::decode(input)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::tree::MerkleSearchTree;
+
+    fn entry(value: &str, stamp: u64, hint: u8) -> Tagged<u8> {
+        Tagged::new(value).with_covered(&stamp).with_local(hint)
+    }
+
+    #[test]
+    fn test_tags_and_hashes() {
+        let mut a = MerkleSearchTree::new(4);
+        let mut b = MerkleSearchTree::new(4);
+        for i in 0..50u32 {
+            a.insert(i, entry("v", 1000 + i as u64, 1));
+            b.insert(i, entry("v", 1000 + i as u64, 2));
+        }
+        // Local tags differ, covered ones don't.
+        assert_eq!(a.hash(), b.hash());
+        assert!(a.iter().all(|(_, tagged)| tagged.local == 1));
+        b.insert(7, entry("v", 0, 2));
+        assert_ne!(a.hash(), b.hash());
+
+        let tagged = a.get(&3).unwrap();
+        assert_eq!(tagged.value(), b"v");
+        assert_eq!(u64::decode(&mut tagged.covered()).unwrap(), 1003);
+        let mut bytes = Vec::new();
+        tagged.encode(&mut bytes);
+        assert_eq!(Tagged::decode(&mut bytes.as_slice()).unwrap(), *tagged);
+    }
+
+    #[test]
+    fn test_update_unhashed() {
+        let mut tree = MerkleSearchTree::new(3);
+        for i in 0..100u32 {
+            tree.insert(i, entry("v", 0, 0));
+        }
+        let fork = tree.fork();
+        let hash = *tree.hash();
+
+        assert!(
+            tree.update_unhashed(&42, |tagged| tagged.local = 9)
+                .unwrap()
+        );
+        assert_eq!(tree.get(&42).unwrap().local, 9);
+        assert_eq!(fork.get(&42).unwrap().local, 0);
+        assert_eq!(*tree.hash(), hash);
+        assert!(
+            !tree
+                .update_unhashed(&500, |tagged| tagged.local = 9)
+                .unwrap()
+        );
+
+        assert!(matches!(
+            tree.update_unhashed(&42, |tagged| *tagged = entry("other", 0, 9)),
+            Err(Error::HashChanged)
+        ));
+        assert_eq!(tree.get(&42), Some(&entry("v", 0, 9)));
+    }
+}
@@ -0,0 +1,151 @@
+// Test utilities for downstream crates.
+//
+// An `Oracle` mirrors every operation on a tree into a `BTreeMap` and
+// checks that the two agree: lookups, iteration, ranges, size and the root
+// hash, which must be the XOR of the value hashes whatever the node layout.
+// Any disagreement panics, naming the key for lookups, so it drops straight
+// into property or integration tests. Enable the `testing` feature to use it.
+
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::ops::RangeBounds;
+
+use crate::core::codec::Encode;
+use crate::core::hash::NodeHash;
+use crate::core::tree::MerkleSearchTree;
+
+pub struct Oracle<K, V, const N: usize = 32> {
+    tree: MerkleSearchTree<K, V, N>,
+    model: BTreeMap<K, V>,
+}
+
+impl<K, V, const N: usize> Oracle<K, V, N>
+where
+    K: Ord + Clone + Default + Encode + Debug,
+    V: AsRef<[u8]> + Clone + PartialEq + Debug,
+{
+    // Wraps `tree`, which may already hold entries.
+    pub fn new(tree: MerkleSearchTree<K, V, N>) -> Self {
+        let model = tree
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        Oracle { tree, model }
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        self.tree.insert(key.clone(), value.clone());
+        self.model.insert(key.clone(), value);
+        self.get(&key);
+        assert_eq!(self.tree.len(), self.model.len(), "len disagrees");
+    }
+
+    pub fn remove(&mut self, key: &K) -> bool {
+        let removed = self.tree.remove(key);
+        assert_eq!(
+            removed,
+            self.model.remove(key).is_some(),
+            "remove({key:?}) disagrees"
+        );
+        removed
+    }
+
+    // Looks `key` up in both and checks they agree.
+    pub fn get(&self, key: &K) -> Option<&V> {
+        let found = self.tree.get(key);
+        assert_eq!(found, self.model.get(key), "get({key:?}) disagrees");
+        found
+    }
+
+    // Checks the entries in `range`, and their range hash.
+    pub fn check_range<R: RangeBounds<K> + Clone>(&self, range: R) {
+        let expected: Vec<_> = self.model.range(range.clone()).collect();
+        let actual: Vec<_> = self.tree.range(range.clone()).collect();
+        assert_eq!(actual, expected, "range disagrees");
+        assert_eq!(
+            self.tree.range_hash(range),
+            Self::xor_of(expected.iter().copied()),
+            "range hash disagrees"
+        );
+    }
+
+    // Checks everything: size, iteration, every lookup and the root hash.
+    pub fn check(&self) {
+        assert_eq!(self.tree.len(), self.model.len(), "len disagrees");
+        assert!(
+            self.tree.iter().eq(self.model.iter()),
+            "iteration disagrees"
+        );
+        for key in self.model.keys() {
+            self.get(key);
+        }
+        assert_eq!(
+            *self.tree.hash(),
+            Self::xor_of(self.model.iter()),
+            "root hash disagrees"
+        );
+    }
+
+    pub fn tree(&self) -> &MerkleSearchTree<K, V, N> {
+        &self.tree
+    }
+
+    pub fn model(&self) -> &BTreeMap<K, V> {
+        &self.model
+    }
+
+    pub fn into_tree(self) -> MerkleSearchTree<K, V, N> {
+        self.tree
+    }
+
+    fn xor_of<'a>(entries: impl Iterator<Item = (&'a K, &'a V)>) -> NodeHash<N>
+    where
+        K: 'a,
+        V: 'a,
+    {
+        let mut hash = NodeHash::default();
+        for (key, value) in entries {
+            hash.xor(&NodeHash::leaf(key, value.as_ref()));
+        }
+        hash
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_oracle_agrees() {
+        let mut oracle = Oracle::new(MerkleSearchTree::new(3));
+        let mut state = 1u32;
+        for _ in 0..2000 {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            let key = (state >> 16) % 300;
+            if (state >> 20).is_multiple_of(4) {
+                oracle.remove(&key);
+            } else {
+                oracle.insert(key, format!("v{}", state % 50));
+            }
+        }
+        oracle.check();
+        oracle.check_range(50..120);
+        oracle.check_range(..=10);
+        assert_eq!(oracle.get(&1000), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "iteration disagrees")]
+    fn test_oracle_catches_divergence() {
+        let mut oracle = Oracle::new(MerkleSearchTree::new(4));
+        oracle.insert(7u32, "v".to_string());
+        // Write behind the oracle's back.
+        let mut tree = oracle.into_tree();
+        tree.insert(7, "other".to_string());
+        let oracle = Oracle {
+            tree,
+            model: BTreeMap::from([(7, "v".to_string())]),
+        };
+        oracle.check();
+    }
+}
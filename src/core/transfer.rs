@@ -0,0 +1,512 @@
+// Bulk transfer of a key range in bounded chunks.
+//
+// An export is split into chunks of roughly `chunk_bytes` encoded bytes. Each
+// chunk carries the key range it covers and the XOR of its entries' leaf
+// hashes, so it can be checked on its own, and the chunk ranges partition the
+// exported range. Chunks are produced lazily, one at a time, so neither side
+// has to hold the whole range in memory.
+//
+// On the receiving side a `PendingImport` collects chunks in any order,
+// ignores repeats, and can be encoded and reloaded to survive a crash. It
+// touches the tree only once every chunk has landed, and then all at once.
+//
+// The export is a plain iterator; there is no async `Stream` variant since
+// the crate has no dependency that defines one. Pulling one chunk at a time
+// from an async task gives the same backpressure.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::iter::Peekable;
+use std::ops::RangeBounds;
+
+use crate::core::budget::{Budget, Progress};
+use crate::core::codec::{Decode, Encode};
+use crate::core::error::Error;
+use crate::core::hash::NodeHash;
+use crate::core::range::KeyRange;
+use crate::core::tree::{MerkleSearchTree, Range};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExportChunk<K, V> {
+    pub range: KeyRange<K>,
+    // Every entry of the source in `range`, in key order.
+    pub entries: Vec<(K, V)>,
+    // The source's range hash over `range`.
+    pub hash: NodeHash,
+}
+
+impl<K: Ord + Encode, V: AsRef<[u8]>> ExportChunk<K, V> {
+    // Whether the entries are sorted, inside the range and match the hash.
+    pub fn verify(&self) -> bool {
+        let mut hash = NodeHash::default();
+        for (key, value) in &self.entries {
+            hash.xor(&NodeHash::leaf(key, value.as_ref()));
+            if !self.range.contains(key) {
+                return false;
+            }
+        }
+        hash == self.hash && self.entries.is_sorted_by(|a, b| a.0 < b.0)
+    }
+}
+
+impl<K, V> MerkleSearchTree<K, V>
+where
+    K: Ord + Clone + Default + Encode,
+    V: AsRef<[u8]> + Clone + Encode,
+{
+    // Exports `range` in chunks of about `chunk_bytes` encoded bytes. A chunk
+    // holds at least one entry, however large. An empty range still yields
+    // one empty chunk, so the receiver learns that it is empty.
+    pub fn export_stream(&self, range: KeyRange<K>, chunk_bytes: usize) -> ExportStream<'_, K, V> {
+        ExportStream {
+            entries: self.range(range.clone()).peekable(),
+            next_start: Some(range.start),
+            end: range.end,
+            chunk_bytes,
+        }
+    }
+
+    // `export_stream`, collecting chunks until `budget` runs out, one unit
+    // per chunk. The cursor is the range still to export.
+    pub fn export_with_budget(
+        &self,
+        range: KeyRange<K>,
+        chunk_bytes: usize,
+        budget: &mut Budget,
+    ) -> Progress<Vec<ExportChunk<K, V>>, KeyRange<K>> {
+        let mut stream = self.export_stream(range, chunk_bytes);
+        let mut chunks = Vec::new();
+        while let Some(start) = &stream.next_start {
+            if !chunks.is_empty() && !budget.spend() {
+                let rest = KeyRange {
+                    start: start.clone(),
+                    end: stream.end,
+                };
+                return Progress {
+                    result: chunks,
+                    resume: Some(rest),
+                };
+            }
+            chunks.extend(stream.next());
+        }
+        Progress {
+            result: chunks,
+            resume: None,
+        }
+    }
+}
+
+pub struct ExportStream<'a, K: Ord, V> {
+    entries: Peekable<Range<'a, K, V>>,
+    // Where the next chunk starts; None once the last chunk is out.
+    next_start: Option<Option<K>>,
+    end: Option<K>,
+    chunk_bytes: usize,
+}
+
+impl<K, V> Iterator for ExportStream<'_, K, V>
+where
+    K: Ord + Clone + Encode,
+    V: AsRef<[u8]> + Clone + Encode,
+{
+    type Item = ExportChunk<K, V>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let start = self.next_start.take()?;
+        let mut entries = Vec::new();
+        let mut hash = NodeHash::default();
+        let mut bytes = 0;
+        while bytes < self.chunk_bytes.max(1) {
+            let Some((key, value)) = self.entries.next() else {
+                break;
+            };
+            bytes += key.encoded_len() + value.encoded_len();
+            hash.xor(&NodeHash::leaf(key, value.as_ref()));
+            entries.push((key.clone(), value.clone()));
+        }
+
+        let end = match self.entries.peek() {
+            Some((key, _)) => {
+                self.next_start = Some(Some((*key).clone()));
+                Some((*key).clone())
+            }
+            None => self.end.clone(),
+        };
+        Some(ExportChunk {
+            range: KeyRange { start, end },
+            entries,
+            hash,
+        })
+    }
+}
+
+// An import of one key range, chunk by chunk.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PendingImport<K, V> {
+    range: KeyRange<K>,
+    // Landed chunks by range start. A None start sorts first, as it should.
+    chunks: BTreeMap<Option<K>, ExportChunk<K, V>>,
+}
+
+impl<K: Ord + Clone + Default + Encode, V: AsRef<[u8]>> PendingImport<K, V> {
+    pub fn new(range: KeyRange<K>) -> Self {
+        PendingImport {
+            range,
+            chunks: BTreeMap::new(),
+        }
+    }
+
+    // Stages a chunk. Returns false if it had already landed. Chunks that
+    // fail verification, stick out of the import range or overlap a
+    // different chunk are rejected.
+    pub fn add(&mut self, chunk: ExportChunk<K, V>) -> Result<bool, Error> {
+        if !chunk.verify() {
+            return Err(Error::Malformed("chunk hash mismatch".to_string()));
+        }
+        // Chunks are verified, so the same range and hash mean the same chunk.
+        if let Some(landed) = self.chunks.get(&chunk.range.start)
+            && landed.range == chunk.range
+            && landed.hash == chunk.hash
+        {
+            return Ok(false);
+        }
+        let inside = starts_before(&self.range.start, &chunk.range.start)
+            && ends_after(&self.range.end, &chunk.range.end);
+        let overlaps = self.chunks.values().any(|other| {
+            before(&other.range.start, &chunk.range.end)
+                && before(&chunk.range.start, &other.range.end)
+        });
+        if !inside || overlaps {
+            return Err(Error::Malformed(
+                "chunk overlaps another or lies outside the import".to_string(),
+            ));
+        }
+        self.chunks.insert(chunk.range.start.clone(), chunk);
+        Ok(true)
+    }
+
+    // The parts of the import range no chunk has covered yet, in order.
+    pub fn missing(&self) -> Vec<KeyRange<K>> {
+        let mut missing = Vec::new();
+        // Where coverage ends so far; None once it reaches the top of the key space.
+        let mut covered = Some(self.range.start.clone());
+        for chunk in self.chunks.values() {
+            let Some(start) = covered else {
+                break;
+            };
+            if start < chunk.range.start {
+                missing.push(KeyRange {
+                    start,
+                    end: chunk.range.start.clone(),
+                });
+            }
+            covered = chunk.range.end.clone().map(Some);
+        }
+        if let Some(start) = covered
+            && before(&start, &self.range.end)
+        {
+            missing.push(KeyRange {
+                start,
+                end: self.range.end.clone(),
+            });
+        }
+        missing
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.missing().is_empty()
+    }
+
+    // Replaces the tree's content in the import range with the imported
+    // entries, in one step, and returns the new root. An op sink gets an op
+    // for each key that changed. Fails without touching the tree if chunks
+    // are still missing, or if the entries don't hash as the chunks said.
+    pub fn commit(self, tree: &mut MerkleSearchTree<K, V>) -> Result<NodeHash, Error> {
+        if !self.is_complete() {
+            return Err(Error::Malformed("import is missing chunks".to_string()));
+        }
+
+        let mut expected = NodeHash::default();
+        let mut imported = BTreeSet::new();
+        for chunk in self.chunks.values() {
+            expected.xor(&chunk.hash);
+            imported.extend(chunk.entries.iter().map(|(key, _)| key.clone()));
+        }
+
+        let stale: Vec<K> = tree
+            .range(self.range.clone())
+            .map(|(key, _)| key)
+            .filter(|key| !imported.contains(key))
+            .cloned()
+            .collect();
+        let batch = tree.begin_batch();
+        for key in &stale {
+            tree.remove(key);
+        }
+        let result = self
+            .chunks
+            .into_values()
+            .flat_map(|chunk| chunk.entries)
+            .try_for_each(|(key, value)| tree.try_write(key, value).map(|_| ()))
+            .and_then(|()| {
+                let actual = tree.range_hash(self.range.clone());
+                if actual == expected {
+                    Ok(())
+                } else {
+                    Err(Error::RootMismatch { expected, actual })
+                }
+            });
+        match result {
+            Ok(()) => {
+                tree.commit_batch(batch, stale.iter().chain(&imported));
+                Ok(*tree.hash())
+            }
+            Err(err) => {
+                tree.rollback_batch(batch);
+                Err(err)
+            }
+        }
+    }
+}
+
+// Comparisons between range bounds, where a None start is the bottom of the
+// key space and a None end the top.
+fn before<K: Ord>(start: &Option<K>, end: &Option<K>) -> bool {
+    match (start, end) {
+        (Some(start), Some(end)) => start < end,
+        _ => true,
+    }
+}
+
+fn starts_before<K: Ord>(outer: &Option<K>, inner: &Option<K>) -> bool {
+    match (outer, inner) {
+        (None, _) => true,
+        (Some(_), None) => false,
+        (Some(outer), Some(inner)) => outer <= inner,
+    }
+}
+
+fn ends_after<K: Ord>(outer: &Option<K>, inner: &Option<K>) -> bool {
+    match (outer, inner) {
+        (None, _) => true,
+        (Some(_), None) => false,
+        (Some(outer), Some(inner)) => outer >= inner,
+    }
+}
+
+impl<K: Encode, V: Encode> Encode for PendingImport<K, V> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.range.encode(out);
+        (self.chunks.len() as u64).encode(out);
+        for chunk in self.chunks.values() {
+            chunk.encode(out);
+        }
+    }
+}
+
+impl<K: Ord + Clone + Decode, V: Decode> Decode for PendingImport<K, V> {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        let range = KeyRange::decode(input)?;
+        let count = u64::decode(input)?;
+        let chunks = (0..count)
+            .map(|_| {
+                let chunk = ExportChunk::<K, V>::decode(input)?;
+                Ok((chunk.range.start.clone(), chunk))
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(PendingImport { range, chunks })
+    }
+}
+
+impl<K: Encode, V: Encode> Encode for ExportChunk<K, V> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.range.encode(out);
+        self.hash.encode(out);
+        (self.entries.len() as u64).encode(out);
+        for (key, value) in &self.entries {
+            key.encode(out);
+            value.encode(out);
+        }
+    }
+}
+
+impl<K: Decode, V: Decode> Decode for ExportChunk<K, V> {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        let range = KeyRange::decode(input)?;
+        let hash = NodeHash::decode(input)?;
+        let count = u64::decode(input)?;
+        let entries = (0..count)
+            .map(|_| Ok((K::decode(input)?, V::decode(input)?)))
+            .collect::<Result<_, Error>>()?;
+        Ok(ExportChunk {
+            range,
+            entries,
+            hash,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tree(n: u32) -> MerkleSearchTree<u32> {
+        let mut tree = MerkleSearchTree::new(4);
+        for i in 0..n {
+            tree.insert(i, format!("value-{i}"));
+        }
+        tree
+    }
+
+    #[test]
+    fn test_export_chunks_partition_the_range() {
+        let tree = tree(1000);
+        let range = KeyRange {
+            start: Some(100),
+            end: Some(900),
+        };
+        let chunks: Vec<_> = tree.export_stream(range.clone(), 256).collect();
+        assert!(chunks.len() > 10);
+        assert_eq!(chunks[0].range.start, range.start);
+        assert_eq!(chunks.last().unwrap().range.end, range.end);
+        for pair in chunks.windows(2) {
+            assert_eq!(pair[0].range.end, pair[1].range.start);
+        }
+        for chunk in &chunks {
+            assert!(chunk.verify());
+            assert_eq!(chunk.hash, tree.range_hash(chunk.range.clone()));
+            let mut bytes = Vec::new();
+            chunk.encode(&mut bytes);
+            assert_eq!(&ExportChunk::decode(&mut bytes.as_slice()).unwrap(), chunk);
+        }
+        let total: usize = chunks.iter().map(|chunk| chunk.entries.len()).sum();
+        assert_eq!(total, 800);
+
+        let mut forged = chunks[0].clone();
+        forged.entries[0].1 = "forged".to_string();
+        assert!(!forged.verify());
+    }
+
+    #[test]
+    fn test_export_empty_range() {
+        let tree = tree(10);
+        let range = KeyRange {
+            start: Some(50),
+            end: None,
+        };
+        let chunks: Vec<_> = tree.export_stream(range.clone(), 1024).collect();
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].range, range);
+        assert!(chunks[0].entries.is_empty());
+        assert!(chunks[0].verify());
+    }
+
+    #[test]
+    fn test_resumable_import() {
+        let source = tree(600);
+        let mut target = MerkleSearchTree::new(4);
+        for i in (0..1000).step_by(2) {
+            target.insert(i, "stale".to_string());
+        }
+        let range = KeyRange {
+            start: Some(200),
+            end: Some(400),
+        };
+        let mut chunks: Vec<_> = source.export_stream(range.clone(), 300).collect();
+        chunks.reverse();
+
+        let mut import = PendingImport::new(range.clone());
+        let half = chunks.len() / 2;
+        for chunk in &chunks[..half] {
+            assert!(import.add(chunk.clone()).unwrap());
+        }
+        assert!(!import.add(chunks[0].clone()).unwrap());
+        assert!(!import.is_complete());
+        assert_eq!(import.missing()[0].start, range.start);
+
+        // Crash and resume from the persisted state.
+        let mut bytes = Vec::new();
+        import.encode(&mut bytes);
+        let mut import = PendingImport::decode(&mut bytes.as_slice()).unwrap();
+        let before = *target.hash();
+        assert!(import.clone().commit(&mut target).is_err());
+        assert_eq!(*target.hash(), before);
+
+        for chunk in chunks {
+            import.add(chunk).unwrap();
+        }
+        assert!(import.is_complete());
+        import.commit(&mut target).unwrap();
+
+        assert!(target.range(range.clone()).eq(source.range(range.clone())));
+        assert_eq!(target.get(&100), Some(&"stale".to_string()));
+        assert_eq!(target.get(&401), None);
+        assert_eq!(target.get(&600), Some(&"stale".to_string()));
+    }
+
+    #[cfg(feature = "crdt")]
+    #[test]
+    fn test_import_logs_ops() {
+        let source = tree(100);
+        let mut target = MerkleSearchTree::new(4);
+        for i in (0..100).step_by(2) {
+            target.insert(i, "stale".to_string());
+        }
+        let mut replica = target.fork();
+        let (sender, ops) = std::sync::mpsc::channel();
+        let mut target = target.with_op_sink(1, sender);
+
+        let range = KeyRange {
+            start: Some(20),
+            end: Some(40),
+        };
+        let mut import = PendingImport::new(range.clone());
+        for chunk in source.export_stream(range, 64) {
+            import.add(chunk).unwrap();
+        }
+        let root = import.commit(&mut target).unwrap();
+
+        // Every key in the range changed, and replaying the ops gets there.
+        let ops: Vec<_> = ops.try_iter().collect();
+        assert_eq!(ops.len(), 20);
+        assert_eq!(ops.last().unwrap().1, root);
+        for (op, op_root) in ops {
+            replica.apply_op(op).unwrap();
+            assert_eq!(*replica.hash(), op_root);
+        }
+        assert_eq!(replica.hash(), target.hash());
+    }
+
+    #[test]
+    fn test_import_rejects_bad_chunks() {
+        let source = tree(100);
+        let mut import = PendingImport::new(KeyRange {
+            start: Some(10),
+            end: Some(20),
+        });
+        let outside = source
+            .export_stream(KeyRange::full(), 1 << 20)
+            .next()
+            .unwrap();
+        assert!(import.add(outside).is_err());
+
+        let mut chunk = source
+            .export_stream(
+                KeyRange {
+                    start: Some(10),
+                    end: Some(20),
+                },
+                1 << 20,
+            )
+            .next()
+            .unwrap();
+        let mut overlapping = chunk.clone();
+        overlapping.range.end = Some(15);
+        overlapping.entries.truncate(5);
+        overlapping.hash = source.range_hash(overlapping.range.clone());
+        import.add(overlapping).unwrap();
+        assert!(import.add(chunk.clone()).is_err());
+
+        chunk.entries[0].1 = "forged".to_string();
+        assert!(matches!(import.add(chunk), Err(Error::Malformed(_))));
+    }
+}
@@ -0,0 +1,378 @@
+// The hashes and digests read off a tree: the root, range hashes and usage
+// in O(log n) from the subtree totals, and the level and content digests
+// for comparing layouts and contents.
+
+use std::ops::{Bound, RangeBounds};
+
+use crate::core::codec::Encode;
+use crate::core::hash::NodeHash;
+use crate::core::quota::Usage;
+use crate::core::range::KeyRange;
+
+use super::{MerkleSearchTree, Node};
+
+impl<K: Ord + Clone + Default + Encode, V: AsRef<[u8]>, const N: usize> MerkleSearchTree<K, V, N> {
+    // One digest per level, from the root (index 0) down to the leaves.
+    //
+    // Every level XORs to the root hash, so instead each digest covers the
+    // ordered sequence of node hashes on that level. The leaf level depends
+    // only on content; levels above also depend on the node layout, so they
+    // are only comparable between trees with the same fanout policy.
+    pub fn level_digests(&self) -> Vec<NodeHash<N>> {
+        let mut digests = vec![*self.root.hash()];
+        let mut level: Vec<&Node<K, V, N>> = vec![&self.root];
+        loop {
+            level = level
+                .into_iter()
+                .flat_map(|node| match node {
+                    Node::Internal { children, .. } => children.iter(),
+                    Node::Leaf { .. } => [].iter(),
+                })
+                .map(|child| &**child)
+                .collect();
+            if level.is_empty() {
+                return digests;
+            }
+            digests.push(NodeHash::digest_sequence(
+                level.iter().map(|node| node.hash()),
+            ));
+        }
+    }
+
+    // A digest of the (key, value hash) pairs in key order. Unlike the root
+    // hash it binds each value to its key, and unlike the level digests it
+    // doesn't depend on the node layout, so trees with different fanouts can
+    // compare content. A sequential digest can't be patched in place, so it is
+    // computed on first use and cached until the next mutation.
+    pub fn content_digest(&self) -> NodeHash<N>
+    where
+        K: Encode,
+    {
+        *self.content_digest.get_or_init(|| {
+            let mut bytes = Vec::new();
+            let entries: Vec<NodeHash<N>> = self
+                .leaf_hashes()
+                .map(|(key, hash)| {
+                    bytes.clear();
+                    key.encode(&mut bytes);
+                    bytes.extend_from_slice(&hash.0);
+                    NodeHash::digest(&bytes)
+                })
+                .collect();
+            NodeHash::digest_sequence(&entries)
+        })
+    }
+
+    // Every key with its leaf hash, in key order.
+    pub(crate) fn leaf_hashes(&self) -> impl Iterator<Item = (&K, &NodeHash<N>)> {
+        let mut stack = vec![self.root.children().iter()];
+        std::iter::from_fn(move || {
+            loop {
+                match stack.last_mut()?.next().map(|node| &**node) {
+                    Some(Node::Leaf { key, hash, .. }) => return Some((key, hash)),
+                    Some(node) => stack.push(node.children().iter()),
+                    None => {
+                        stack.pop();
+                    }
+                }
+            }
+        })
+    }
+
+    // The subtrees `depth` levels below the root, as independent sync units.
+    // Their ranges partition the key space: each starts at its subtree's
+    // smallest key and ends where the next one starts. The hash is the
+    // subtree's, which equals `range_hash` over the range. Depths beyond the
+    // lowest internal level are clamped to it.
+    pub fn subtree_roots_at_depth(&self, depth: usize) -> Vec<(KeyRange<K>, NodeHash<N>)> {
+        let mut level: Vec<&Node<K, V, N>> = vec![&self.root];
+        for _ in 0..depth.min(self.depth - 1) {
+            level = level
+                .into_iter()
+                .flat_map(|node| node.children().iter().map(|child| &**child))
+                .collect();
+        }
+
+        let starts: Vec<Option<&K>> = level
+            .iter()
+            .enumerate()
+            .map(|(i, node)| {
+                if i == 0 {
+                    return None;
+                }
+                let mut node = *node;
+                while node.is_internal() {
+                    node = &node.children()[0];
+                }
+                Some(node.key())
+            })
+            .collect();
+        level
+            .iter()
+            .enumerate()
+            .map(|(i, node)| {
+                let range = KeyRange {
+                    start: starts[i].cloned(),
+                    end: starts.get(i + 1).copied().flatten().cloned(),
+                };
+                (range, *node.hash())
+            })
+            .collect()
+    }
+
+    // The XOR of the leaf hashes whose keys fall into `range`.
+    // Because XOR is order independent, two trees holding the same entries in
+    // a range agree on this digest regardless of how their nodes are split.
+    pub fn range_hash<R: RangeBounds<K>>(&self, range: R) -> NodeHash<N> {
+        let mut acc = NodeHash::default();
+        self.visit_range(&range, |node| acc.xor(node.hash()));
+        acc
+    }
+
+    // `range_hash`, but `EMPTY_ROOT` if no key falls into `range`. This is
+    // what goes to peers, so an empty range is never confused with entries
+    // whose hashes happen to XOR to zero.
+    pub fn range_root<R: RangeBounds<K>>(&self, range: R) -> NodeHash<N> {
+        let (mut acc, mut entries) = (NodeHash::default(), 0);
+        self.visit_range(&range, |node| {
+            acc.xor(node.hash());
+            entries += node.leaf_count();
+        });
+        NodeHash::root_of(acc, entries)
+    }
+
+    // `hash()`, by value.
+    pub fn root_hash(&self) -> NodeHash<N> {
+        *self.hash()
+    }
+
+    // The number of entries and value bytes whose keys fall into `range`.
+    // Like the hash, this is read off the subtree totals in O(log n), and
+    // always agrees with the content.
+    pub fn usage<R: RangeBounds<K>>(&self, range: R) -> Usage {
+        let mut usage = Usage::default();
+        self.visit_range(&range, |node| usage += node.usage());
+        usage
+    }
+
+    // Calls `visit` on disjoint subtrees and leaves that together hold
+    // exactly the keys in `range`.
+    fn visit_range<R: RangeBounds<K>>(&self, range: &R, mut visit: impl FnMut(&Node<K, V, N>)) {
+        // Subtrees still to visit, each with an exclusive lower bound on its
+        // keys (if known). Callers only add up what they visit, so order
+        // doesn't matter and a plain stack will do.
+        let mut stack: Vec<(&Node<K, V, N>, Option<&K>)> = vec![(&*self.root, None)];
+        while let Some((node, mut lower)) = stack.pop() {
+            let Node::Internal { children, .. } = node else {
+                unreachable!("leaves are handled by their parent");
+            };
+            for child in children {
+                let upper = child.key();
+                let after_end = match (range.end_bound(), lower) {
+                    (Bound::Included(end) | Bound::Excluded(end), Some(lower)) => lower >= end,
+                    _ => false,
+                };
+                if after_end {
+                    break;
+                }
+
+                if !child.is_internal() {
+                    if range.contains(upper) {
+                        visit(child);
+                    }
+                } else if !Self::is_before_start(range, upper) {
+                    // Subtrees entirely inside the range are visited whole.
+                    if Self::covers(range, lower, upper) {
+                        visit(child);
+                    } else {
+                        stack.push((&**child, lower));
+                    }
+                }
+                lower = Some(upper);
+            }
+        }
+    }
+
+    // Whether every key up to and including `upper` lies before the range.
+    pub(super) fn is_before_start<R: RangeBounds<K>>(range: &R, upper: &K) -> bool {
+        match range.start_bound() {
+            Bound::Included(start) => upper < start,
+            Bound::Excluded(start) => upper <= start,
+            Bound::Unbounded => false,
+        }
+    }
+
+    // Whether the range contains every key in `(lower, upper]`.
+    fn covers<R: RangeBounds<K>>(range: &R, lower: Option<&K>, upper: &K) -> bool {
+        let covers_start = match (range.start_bound(), lower) {
+            (Bound::Unbounded, _) => true,
+            (Bound::Included(start) | Bound::Excluded(start), Some(lower)) => start <= lower,
+            _ => false,
+        };
+        let covers_end = match range.end_bound() {
+            Bound::Included(end) => upper <= end,
+            Bound::Excluded(end) => upper < end,
+            Bound::Unbounded => true,
+        };
+        covers_start && covers_end
+    }
+}
+
+// The shallowest level at which two `level_digests` results differ, if any.
+// A deeper answer means the trees share more of their structure.
+pub fn shallowest_divergent_level<const N: usize>(
+    ours: &[NodeHash<N>],
+    theirs: &[NodeHash<N>],
+) -> Option<usize> {
+    match ours.iter().zip(theirs).position(|(a, b)| a != b) {
+        Some(level) => Some(level),
+        None if ours.len() != theirs.len() => Some(ours.len().min(theirs.len())),
+        None => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_merkle_property() {
+        let mut tree1 = MerkleSearchTree::new(4);
+        tree1.insert(1, "apple".to_string());
+        tree1.insert(2, "banana".to_string());
+
+        let mut tree2 = MerkleSearchTree::new(4);
+        tree2.insert(1, "apple".to_string());
+        tree2.insert(2, "banana".to_string());
+
+        assert_eq!(
+            tree1.hash(),
+            tree2.hash(),
+            "Identical content should yield identical hashes"
+        );
+
+        // Modify tree2
+        tree2.insert(3, "cherry".to_string());
+        assert_ne!(
+            tree1.hash(),
+            tree2.hash(),
+            "Different content must yield different hashes"
+        );
+
+        // Add same content to tree1
+        tree1.insert(3, "cherry".to_string());
+        assert_eq!(tree1.hash(), tree2.hash(), "Trees should match again");
+    }
+
+    #[test]
+    fn test_range_hash_is_layout_independent() {
+        let mut tree1 = MerkleSearchTree::new(3);
+        let mut tree2 = MerkleSearchTree::new(7);
+        for i in 0..100 {
+            tree1.insert(i, format!("v{i}"));
+            tree2.insert(99 - i, format!("v{}", 99 - i));
+        }
+
+        assert_eq!(tree1.range_hash(..), *tree1.hash());
+        for range in [0..100, 5..6, 17..63, 50..200] {
+            let mut expected = NodeHash::default();
+            for (key, value) in tree1.range(range.clone()) {
+                expected.xor(&NodeHash::leaf(key, value.as_bytes()));
+            }
+            assert_eq!(tree1.range_hash(range.clone()), expected);
+            assert_eq!(tree2.range_hash(range), expected);
+        }
+    }
+
+    #[test]
+    fn test_range_hash_deep_tree() {
+        let mut tree = MerkleSearchTree::new(3);
+        for i in 0..500 {
+            tree.insert(i, format!("v{i}"));
+        }
+        assert!(tree.depth() > 6);
+
+        for range in [0..500, 1..499, 234..235, 400..1000] {
+            let mut expected = NodeHash::default();
+            for (key, value) in tree.range(range.clone()) {
+                expected.xor(&NodeHash::leaf(key, value.as_bytes()));
+            }
+            assert_eq!(tree.range_hash(range), expected);
+        }
+    }
+
+    #[test]
+    fn test_level_digests() {
+        let mut tree1 = MerkleSearchTree::new(4);
+        let mut tree2 = MerkleSearchTree::new(4);
+        for i in 0..100 {
+            tree1.insert(i, format!("v{i}"));
+            tree2.insert(i, format!("v{i}"));
+        }
+        let digests = tree1.level_digests();
+        assert_eq!(digests.len(), tree1.depth() + 1);
+        assert_eq!(digests[0], *tree1.hash());
+        assert_eq!(
+            shallowest_divergent_level(&digests, &tree2.level_digests()),
+            None
+        );
+
+        // A changed value shows up at the root already.
+        tree2.insert(42, "changed".to_string());
+        assert_eq!(
+            shallowest_divergent_level(&digests, &tree2.level_digests()),
+            Some(0)
+        );
+
+        // So does swapping two values, since leaf hashes cover the keys.
+        tree2.insert(42, "v43".to_string());
+        tree2.insert(43, "v42".to_string());
+        assert_ne!(tree1.hash(), tree2.hash());
+        assert_eq!(
+            shallowest_divergent_level(&digests, &tree2.level_digests()),
+            Some(0)
+        );
+    }
+
+    // A fixed root: the XOR of the two leaf hashes, whatever the fanout or
+    // platform.
+    #[test]
+    fn test_root_vector() {
+        let mut tree = MerkleSearchTree::new(3);
+        tree.insert(-7i64, "b".to_string());
+        tree.insert(u32::MAX as i64, "a".to_string());
+        assert_eq!(
+            tree.hash().to_string(),
+            "3af0f54d19db0ecc94c3d81b9c0caf49255d6606491c79a77a848f44724f35ae"
+        );
+    }
+
+    #[test]
+    fn test_content_digest() {
+        let mut narrow = MerkleSearchTree::new(3);
+        let mut wide = MerkleSearchTree::new(16).with_target_node_bytes(256);
+        for i in 0..300u32 {
+            narrow.insert(i, format!("v{i}"));
+            wide.insert(299 - i, format!("v{}", 299 - i));
+        }
+        assert_ne!(narrow.level_digests()[1..], wide.level_digests()[1..]);
+        let digest = narrow.content_digest();
+        assert_eq!(digest, wide.content_digest());
+
+        // Swapped values change the root hash and the content digest.
+        wide.insert(1, "v2".to_string());
+        wide.insert(2, "v1".to_string());
+        assert_ne!(narrow.hash(), wide.hash());
+        assert_ne!(wide.content_digest(), digest);
+
+        wide.insert(1, "v1".to_string());
+        wide.insert(2, "v2".to_string());
+        assert_eq!(wide.content_digest(), digest);
+        wide.remove(&7);
+        assert_ne!(wide.content_digest(), digest);
+        assert_eq!(
+            MerkleSearchTree::<u32>::new(4).content_digest(),
+            NodeHash::digest_sequence([])
+        );
+    }
+}
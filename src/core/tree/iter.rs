@@ -0,0 +1,300 @@
+// Iteration in key order, borrowed over a range or owning the whole tree.
+
+use std::ops::{Bound, RangeBounds};
+
+use crate::core::alloc::NodeRef;
+use crate::core::codec::Encode;
+use crate::core::keys::PrefixKey;
+
+use super::{MerkleSearchTree, Node};
+
+impl<K: Ord + Clone + Default + Encode, V: AsRef<[u8]>, const N: usize> MerkleSearchTree<K, V, N> {
+    // Iterates all entries in key order.
+    pub fn iter(&self) -> Range<'_, K, V, N> {
+        self.range(..)
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.iter().map(|(key, _)| key)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.iter().map(|(_, value)| value)
+    }
+
+    // Iterates the entries whose keys fall into `range`, in key order.
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> Range<'_, K, V, N> {
+        let end = range.end_bound().cloned();
+        match range.start_bound() {
+            Bound::Included(start) => self.seek(|key| key < start, end),
+            Bound::Excluded(start) => self.seek(|key| key <= start, end),
+            Bound::Unbounded => self.seek(|_| false, end),
+        }
+    }
+
+    // Iterates the entries whose keys start with `prefix`, in key order, e.g.
+    // the paths under a directory. If the keys sort as their bytes do (see
+    // `PrefixKey`), these are contiguous: the scan seeks to the first and
+    // stops after the last. Otherwise every entry is checked.
+    pub fn iter_prefix<'a>(&'a self, prefix: &'a [u8]) -> impl Iterator<Item = (&'a K, &'a V)>
+    where
+        K: PrefixKey,
+    {
+        let ordered = K::BYTE_ORDERED;
+        self.seek(
+            move |key| ordered && key.as_ref() < prefix,
+            Bound::Unbounded,
+        )
+        .take_while(move |(key, _)| !ordered || key.as_ref().starts_with(prefix))
+        .filter(move |(key, _)| key.as_ref().starts_with(prefix))
+    }
+
+    // Iterates from the first entry whose key isn't `before` the start, up to
+    // `end`.
+    fn seek(&self, before: impl Fn(&K) -> bool, end: Bound<K>) -> Range<'_, K, V, N> {
+        let mut stack = Vec::new();
+        let mut node: &Node<K, V, N> = &self.root;
+
+        // Seek to the first entry at or after the start bound. Every subtree
+        // left of the path only holds smaller keys, so it is never visited.
+        while let Node::Internal { children, .. } = node {
+            let index = children.partition_point(|child| before(child.key()));
+            let mut rest = children[index.min(children.len())..].iter();
+            match rest.next() {
+                Some(first) => {
+                    stack.push(rest);
+                    node = first;
+                }
+                None => break,
+            }
+        }
+
+        // The loop above stops either on the first leaf or on an exhausted node.
+        let first = match node {
+            Node::Leaf { key, value, .. } => Some((key, value)),
+            Node::Internal { .. } => None,
+        };
+        Range { stack, first, end }
+    }
+}
+
+// Iterator over a key range of the tree, see `MerkleSearchTree::range`.
+pub struct Range<'a, K, V, const N: usize = 32> {
+    stack: Vec<std::slice::Iter<'a, NodeRef<Node<K, V, N>>>>,
+    first: Option<(&'a K, &'a V)>,
+    end: Bound<K>,
+}
+
+impl<'a, K: Ord, V, const N: usize> Iterator for Range<'a, K, V, N> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let next = match self.first.take() {
+            Some(entry) => entry,
+            None => loop {
+                let top = self.stack.last_mut()?;
+                match top.next().map(|node| &**node) {
+                    Some(Node::Leaf { key, value, .. }) => break (key, value),
+                    Some(Node::Internal { children, .. }) => self.stack.push(children.iter()),
+                    None => {
+                        self.stack.pop();
+                    }
+                }
+            },
+        };
+
+        let in_range = match &self.end {
+            Bound::Included(end) => next.0 <= end,
+            Bound::Excluded(end) => next.0 < end,
+            Bound::Unbounded => true,
+        };
+        if in_range {
+            Some(next)
+        } else {
+            self.stack.clear();
+            None
+        }
+    }
+}
+
+impl<'a, K: Ord + Clone + Default + Encode, V: AsRef<[u8]>, const N: usize> IntoIterator
+    for &'a MerkleSearchTree<K, V, N>
+{
+    type Item = (&'a K, &'a V);
+    type IntoIter = Range<'a, K, V, N>;
+
+    fn into_iter(self) -> Range<'a, K, V, N> {
+        self.iter()
+    }
+}
+
+// Owning iterator over the entries in key order. Entries are moved out of
+// the nodes; those a fork still shares are cloned.
+pub struct IntoIter<K, V, const N: usize = 32> {
+    stack: Vec<std::vec::IntoIter<NodeRef<Node<K, V, N>>>>,
+}
+
+impl<K: Clone, V: Clone, const N: usize> Iterator for IntoIter<K, V, N> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        loop {
+            let top = self.stack.last_mut()?;
+            let Some(node) = top.next() else {
+                self.stack.pop();
+                continue;
+            };
+            match NodeRef::try_unwrap(node) {
+                Ok(Node::Leaf { key, value, .. }) => return Some((key, value)),
+                Ok(Node::Internal { children, .. }) => self.stack.push(children.into_iter()),
+                Err(shared) => match &*shared {
+                    Node::Leaf { key, value, .. } => return Some((key.clone(), value.clone())),
+                    Node::Internal { children, .. } => {
+                        self.stack.push(children.clone().into_iter())
+                    }
+                },
+            }
+        }
+    }
+}
+
+// Drops what's left one node at a time, like the tree itself.
+impl<K, V, const N: usize> Drop for IntoIter<K, V, N> {
+    fn drop(&mut self) {
+        let mut pending: Vec<_> = self.stack.drain(..).flatten().collect();
+        while let Some(node) = pending.pop() {
+            if let Ok(Node::Internal { mut children, .. }) = NodeRef::try_unwrap(node) {
+                pending.append(&mut children);
+            }
+        }
+    }
+}
+
+impl<K: Default + Clone, V: Clone, const N: usize> IntoIterator for MerkleSearchTree<K, V, N> {
+    type Item = (K, V);
+    type IntoIter = IntoIter<K, V, N>;
+
+    fn into_iter(mut self) -> IntoIter<K, V, N> {
+        let root = std::mem::take(&mut self.root);
+        IntoIter {
+            stack: vec![vec![root].into_iter()],
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_get_and_iter() {
+        let mut tree = MerkleSearchTree::new(3);
+        for i in (0..50).rev() {
+            tree.insert(i, format!("v{i}"));
+        }
+
+        assert_eq!(tree.get(&7), Some(&"v7".to_string()));
+        assert_eq!(tree.get(&50), None);
+        let keys: Vec<i32> = tree.iter().map(|(k, _)| *k).collect();
+        assert_eq!(keys, (0..50).collect::<Vec<_>>());
+
+        let keys: Vec<i32> = tree.range(10..=20).map(|(k, _)| *k).collect();
+        assert_eq!(keys, (10..=20).collect::<Vec<_>>());
+        assert_eq!(tree.range(60..).count(), 0);
+    }
+
+    #[test]
+    fn test_borrowed_and_owned_iteration() {
+        let mut tree = MerkleSearchTree::<u32>::new(4);
+        for i in 0..100 {
+            tree.insert(i, format!("v{i}"));
+        }
+        assert_eq!(tree.get_key_value(&7), Some((&7, &"v7".to_string())));
+        assert_eq!(tree.get_key_value(&700), None);
+        assert!(tree.keys().copied().eq(0..100));
+        assert_eq!((&tree).into_iter().count(), tree.values().count());
+
+        // Entries a fork shares are cloned, the rest moved.
+        let fork = tree.fork();
+        tree.insert(5, "changed".to_string());
+        let owned: Vec<(u32, String)> = tree.into_iter().collect();
+        assert_eq!(owned.len(), 100);
+        assert_eq!(owned[5], (5, "changed".to_string()));
+        assert!(fork.into_iter().take(10).map(|(key, _)| key).eq(0..10));
+    }
+
+    #[test]
+    fn test_iter_prefix() {
+        let mut tree = MerkleSearchTree::<String>::new(4);
+        for dir in ["a", "a/b", "ab", "b"] {
+            for i in 0..40 {
+                let path = format!("{dir}/{i:02}");
+                tree.insert(path.clone(), path);
+            }
+        }
+        let listed: Vec<_> = tree.iter_prefix(b"a/").map(|(k, _)| k.clone()).collect();
+        let expected: Vec<_> = tree
+            .iter()
+            .map(|(k, _)| k.clone())
+            .filter(|k| k.starts_with("a/"))
+            .collect();
+        assert_eq!(listed.len(), 80);
+        assert_eq!(listed, expected);
+        assert_eq!(tree.iter_prefix(b"a/b/").count(), 40);
+        assert_eq!(tree.iter_prefix(b"").count(), tree.len());
+        assert_eq!(tree.iter_prefix(b"c").count(), 0);
+        assert_eq!(tree.iter_prefix(b"b/39").count(), 1);
+
+        let mut bytes = MerkleSearchTree::<Vec<u8>>::new(4);
+        for i in 0..=255u8 {
+            bytes.insert(vec![i, 0], i.to_string());
+            bytes.insert(vec![i], i.to_string());
+        }
+        assert_eq!(bytes.iter_prefix(&[255]).count(), 2);
+
+        // Keys sorting against their bytes are listed by a full scan.
+        let mut reversed = MerkleSearchTree::<Reversed>::new(4);
+        for (key, _) in tree.iter() {
+            reversed.insert(Reversed(key.clone()), key.clone());
+        }
+        let listed: Vec<_> = reversed
+            .iter_prefix(b"a/")
+            .map(|(k, _)| k.0.clone())
+            .collect();
+        assert!(expected.iter().rev().eq(&listed));
+    }
+
+    // A key ordered backwards, so its `Ord` disagrees with its bytes.
+    #[derive(Clone, Default, PartialEq, Eq)]
+    struct Reversed(String);
+
+    impl PartialOrd for Reversed {
+        fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+
+    impl Ord for Reversed {
+        fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+            other.0.cmp(&self.0)
+        }
+    }
+
+    impl AsRef<[u8]> for Reversed {
+        fn as_ref(&self) -> &[u8] {
+            self.0.as_bytes()
+        }
+    }
+
+    impl PrefixKey for Reversed {}
+
+    impl Encode for Reversed {
+        fn encode(&self, out: &mut Vec<u8>) {
+            self.0.encode(out)
+        }
+
+        fn encoded_len(&self) -> usize {
+            self.0.encoded_len()
+        }
+    }
+}
@@ -0,0 +1,534 @@
+// A Merkle search tree: an ordered map whose internal nodes hash to the XOR
+// of their children, so two trees holding the same entries share a root
+// hash whatever their node layout.
+//
+// This module holds the tree, its configuration and its lookups. `node` has
+// the nodes and the fanout deciding when they split, `write` the insert and
+// remove paths, `split` the moves of whole ranges between trees, `iter` the
+// iterators, and `digest` the hashes and digests read off the tree.
+
+use std::fmt;
+use std::sync::OnceLock;
+
+#[cfg(feature = "allocator")]
+use crate::core::alloc::Allocator;
+use crate::core::alloc::{NodeAlloc, NodeRef};
+use crate::core::chain::RootChain;
+use crate::core::codec::Encode;
+use crate::core::config::{FanoutPolicy, MaxChildren, TreeConfig};
+use crate::core::error::Error;
+use crate::core::hash::NodeHash;
+use crate::core::hashed::{HashedKey, HashedTree};
+use crate::core::limits::{Churn, SoftLimits};
+use crate::core::metrics::Work;
+use crate::core::quota::Quota;
+use crate::core::recent::RecentChanges;
+#[cfg(feature = "structure-log")]
+use crate::core::structure::StructureLog;
+use crate::core::watch::RootWatch;
+#[cfg(feature = "crdt")]
+use crate::crdt::ops::OpLog;
+
+mod digest;
+mod iter;
+mod node;
+mod split;
+mod write;
+
+pub use digest::shallowest_divergent_level;
+pub use iter::{IntoIter, Range};
+use node::Fanout;
+pub(crate) use node::Node;
+
+// The public interface to the tree
+pub struct MerkleSearchTree<K, V = String, const N: usize = 32> {
+    pub(crate) root: NodeRef<Node<K, V, N>>,
+    // Where new nodes go; see `with_allocator`.
+    pub(crate) alloc: NodeAlloc,
+    fanout: Fanout<K>,
+    pub(crate) depth: usize,
+    max_depth: Option<usize>,
+    // See `with_collision_checks`.
+    pub(crate) collision_checks: bool,
+    // See `with_duplicate_policy`.
+    duplicate_policy: DuplicatePolicy<V>,
+    // Root changes since the tree was created; see `session_token`.
+    pub(crate) generation: u64,
+    last_work: Work,
+    total_work: Work,
+    // Reset by every mutation.
+    pub(crate) content_digest: OnceLock<NodeHash<N>>,
+    pub(crate) quota: Option<Quota<K>>,
+    pub(crate) soft_limits: Option<SoftLimits>,
+    churn: Churn,
+    pub(crate) recent: Option<RecentChanges<K, N>>,
+    pub(crate) root_chain: Option<RootChain<N>>,
+    #[cfg(feature = "crdt")]
+    pub(crate) op_log: Option<OpLog<K, V, N>>,
+    pub(crate) watch: Option<RootWatch<N>>,
+    #[cfg(feature = "structure-log")]
+    structure_log: StructureLog<K>,
+    // Values encoding to more bytes are compressed in snapshots.
+    #[cfg(feature = "compression")]
+    pub(crate) compress_above: Option<usize>,
+}
+
+// What an insert did to the tree.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum InsertOutcome<V> {
+    // The key was new.
+    Inserted,
+    // The key's value was replaced by a different one, returned here.
+    Updated(V),
+    // The key already held an equal value, so nothing was written.
+    Unchanged,
+}
+
+impl<V> InsertOutcome<V> {
+    // The replaced value, if the insert changed one.
+    pub fn into_previous(self) -> Option<V> {
+        match self {
+            InsertOutcome::Updated(previous) => Some(previous),
+            InsertOutcome::Inserted | InsertOutcome::Unchanged => None,
+        }
+    }
+
+    // Whether the key was in the tree before.
+    pub fn existed(&self) -> bool {
+        !matches!(self, InsertOutcome::Inserted)
+    }
+}
+
+// What an insert does when its key is already present.
+pub enum DuplicatePolicy<V> {
+    // Replace the stored value.
+    Overwrite,
+    // Fail with `Error::DuplicateKey`.
+    Reject,
+    // Leave the stored value, reporting `InsertOutcome::Unchanged`.
+    KeepExisting,
+    // Store `resolve(stored, new)`, e.g. the larger of two versions.
+    Resolve(fn(&V, V) -> V),
+}
+
+impl<K: Ord + Clone + Default + Encode, V: AsRef<[u8]>> MerkleSearchTree<K, V> {
+    // Panics if `max_children` is below 3; see `try_new`.
+    pub fn new(max_children: usize) -> Self {
+        match Self::try_new(max_children) {
+            Ok(tree) => tree,
+            Err(err) => panic!("{err}"),
+        }
+    }
+
+    pub fn try_new(max_children: usize) -> Result<Self, Error> {
+        Self::from_config(TreeConfig::new(MaxChildren::try_from(max_children)?))
+    }
+
+    pub fn from_config(config: TreeConfig) -> Result<Self, Error> {
+        config.validate()?;
+        Ok(MerkleSearchTree {
+            root: NodeRef::default(),
+            alloc: NodeAlloc::default(),
+            fanout: Fanout::Children(config.max_children.get()),
+            depth: 1,
+            max_depth: config.max_depth,
+            collision_checks: false,
+            duplicate_policy: DuplicatePolicy::Overwrite,
+            generation: 0,
+            last_work: Work::default(),
+            total_work: Work::default(),
+            content_digest: OnceLock::new(),
+            quota: None,
+            soft_limits: None,
+            churn: Churn::default(),
+            recent: None,
+            root_chain: None,
+            #[cfg(feature = "crdt")]
+            op_log: None,
+            watch: None,
+            #[cfg(feature = "structure-log")]
+            structure_log: StructureLog::default(),
+            #[cfg(feature = "compression")]
+            compress_above: None,
+        })
+    }
+
+    // Orders entries by the hash of their encoded key instead; see `HashedTree`.
+    // Keeps the rest of the configuration. Panics if anything was inserted.
+    pub fn with_hashed_keys(self) -> HashedTree<K, V>
+    where
+        K: Encode,
+    {
+        assert!(self.is_empty(), "keys can only be hashed in an empty tree");
+        let fanout = match self.fanout {
+            Fanout::Children(max_children) => Fanout::Children(max_children),
+            Fanout::Bytes { target, .. } => Fanout::Bytes {
+                target,
+                key_len: |key: &HashedKey<K>| key.encoded_len(),
+            },
+        };
+        HashedTree::from_inner(MerkleSearchTree {
+            root: NodeRef::new(Node::default(), self.alloc),
+            alloc: self.alloc,
+            fanout,
+            depth: 1,
+            max_depth: self.max_depth,
+            collision_checks: self.collision_checks,
+            duplicate_policy: self.duplicate_policy,
+            generation: 0,
+            last_work: Work::default(),
+            total_work: Work::default(),
+            content_digest: OnceLock::new(),
+            quota: None,
+            soft_limits: self.soft_limits,
+            churn: Churn::default(),
+            recent: self
+                .recent
+                .as_ref()
+                .map(|recent| RecentChanges::new(recent.capacity(), NodeHash::empty_root())),
+            root_chain: self
+                .root_chain
+                .as_ref()
+                .map(|chain| RootChain::new(chain.capacity(), NodeHash::empty_root())),
+            #[cfg(feature = "crdt")]
+            op_log: None,
+            watch: None,
+            #[cfg(feature = "structure-log")]
+            structure_log: StructureLog::default(),
+            #[cfg(feature = "compression")]
+            compress_above: self.compress_above,
+        })
+    }
+
+    // Uses `M`-byte hashes instead of the default 32; see `NodeHash`. Keeps
+    // the rest of the configuration. Panics if anything was inserted.
+    pub fn with_hash_width<const M: usize>(self) -> MerkleSearchTree<K, V, M> {
+        assert!(
+            self.is_empty(),
+            "the hash width can only change in an empty tree"
+        );
+        MerkleSearchTree {
+            root: NodeRef::new(Node::default(), self.alloc),
+            alloc: self.alloc,
+            fanout: self.fanout,
+            depth: 1,
+            max_depth: self.max_depth,
+            collision_checks: self.collision_checks,
+            duplicate_policy: self.duplicate_policy,
+            generation: 0,
+            last_work: Work::default(),
+            total_work: Work::default(),
+            content_digest: OnceLock::new(),
+            quota: self.quota,
+            soft_limits: self.soft_limits,
+            churn: Churn::default(),
+            recent: self
+                .recent
+                .as_ref()
+                .map(|recent| RecentChanges::new(recent.capacity(), NodeHash::empty_root())),
+            root_chain: self
+                .root_chain
+                .as_ref()
+                .map(|chain| RootChain::new(chain.capacity(), NodeHash::empty_root())),
+            #[cfg(feature = "crdt")]
+            op_log: None,
+            watch: None,
+            #[cfg(feature = "structure-log")]
+            structure_log: StructureLog::default(),
+            #[cfg(feature = "compression")]
+            compress_above: self.compress_above,
+        }
+    }
+}
+
+impl<K: Ord + Clone + Default + Encode, V: AsRef<[u8]>, const N: usize> MerkleSearchTree<K, V, N> {
+    // Sizes nodes by bytes rather than entries: a node splits once its
+    // estimated encoded size exceeds `target` bytes. A leaf entry counts as
+    // its key, value and hash; a child pointer as its key and hash.
+    pub fn with_target_node_bytes(mut self, target: usize) -> Self
+    where
+        K: Encode,
+    {
+        self.fanout = Fanout::Bytes {
+            target,
+            key_len: |key: &K| key.encoded_len(),
+        };
+        self
+    }
+
+    // Places the tree's nodes in `alloc`, e.g. an arena or an instrumented
+    // allocator; see `alloc`. Forks and copies of nodes stay in it. Panics
+    // if anything was inserted.
+    #[cfg(feature = "allocator")]
+    pub fn with_allocator(mut self, alloc: &'static (dyn Allocator + Sync)) -> Self {
+        assert!(
+            self.is_empty(),
+            "the allocator can only change in an empty tree"
+        );
+        self.alloc = NodeAlloc::new(alloc);
+        self.root = NodeRef::new(Node::default(), self.alloc);
+        self
+    }
+
+    // Caps the number of levels the tree may grow to. Inserts that would need
+    // another level fail with `Error::DepthLimitExceeded` instead.
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    // Paranoid mode, for deployments that must handle digest collisions
+    // explicitly rather than rely on their odds. Wherever equal digests would
+    // be taken as equal values, the values' bytes are compared too: a write
+    // whose value hashes like the stored one but differs fails with
+    // `Error::CollisionDetected` instead of being dropped as a no-op, and so
+    // does `try_diff` on such a pair, walking every leaf instead of skipping
+    // subtrees whose hashes match. Imports and patches return the error;
+    // sync sessions report it as a `FaultKind::Collision` peer fault.
+    pub fn with_collision_checks(mut self) -> Self {
+        self.collision_checks = true;
+        self
+    }
+
+    // Sets what `insert` and `try_insert` do when the key is already
+    // present; the default overwrites. Replays of recorded changes, such as
+    // patches, op logs and sync sessions, always overwrite, or replicas
+    // would stop converging.
+    pub fn with_duplicate_policy(mut self, policy: DuplicatePolicy<V>) -> Self {
+        self.duplicate_policy = policy;
+        self
+    }
+
+    // A copy of the tree that shares every node with it. Taking one is O(1);
+    // afterwards each insert copies only the nodes on its own path, so the
+    // two trees diverge without affecting each other.
+    pub fn fork(&self) -> Self {
+        MerkleSearchTree {
+            root: self.root.clone(),
+            alloc: self.alloc,
+            fanout: self.fanout,
+            depth: self.depth,
+            max_depth: self.max_depth,
+            collision_checks: self.collision_checks,
+            duplicate_policy: self.duplicate_policy,
+            generation: self.generation,
+            last_work: Work::default(),
+            total_work: Work::default(),
+            content_digest: self.content_digest.clone(),
+            quota: self.quota,
+            soft_limits: self.soft_limits,
+            churn: Churn::default(),
+            recent: self.recent.clone(),
+            root_chain: self.root_chain.clone(),
+            #[cfg(feature = "crdt")]
+            op_log: None,
+            watch: None,
+            #[cfg(feature = "structure-log")]
+            structure_log: self.structure_log.clone(),
+            #[cfg(feature = "compression")]
+            compress_above: self.compress_above,
+        }
+    }
+
+    // The work done by the most recent successful mutation.
+    pub fn last_work(&self) -> Work {
+        self.last_work
+    }
+
+    // The work accumulated since construction or the last `take_work`.
+    pub fn total_work(&self) -> Work {
+        self.total_work
+    }
+
+    // Returns the accumulated work and resets the counter, e.g. once per batch.
+    pub fn take_work(&mut self) -> Work {
+        std::mem::take(&mut self.total_work)
+    }
+
+    pub fn fanout_policy(&self) -> FanoutPolicy {
+        match self.fanout {
+            Fanout::Children(max_children) => FanoutPolicy::Children(max_children as u32),
+            Fanout::Bytes { target, .. } => FanoutPolicy::NodeBytes(target as u32),
+        }
+    }
+
+    // The number of internal levels, counting the root.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    // The root hash: the XOR of every leaf hash, or `EMPTY_ROOT` (at this
+    // width) for an empty tree.
+    pub fn hash(&self) -> &NodeHash<N> {
+        if self.is_empty() {
+            &NodeHash::EMPTY
+        } else {
+            self.root.hash()
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.leaf(key).and_then(|leaf| leaf.value())
+    }
+
+    pub fn get_key_value(&self, key: &K) -> Option<(&K, &V)> {
+        match self.leaf(key)? {
+            Node::Leaf { key, value, .. } => Some((key, value)),
+            Node::Internal { .. } => None,
+        }
+    }
+
+    // The stored value of `key`, copied first if a fork shares it, and the
+    // internal nodes above it. Hashes are left for the caller to fix, see
+    // `ValueGuard`.
+    pub(crate) fn value_mut(&mut self, key: &K) -> Option<&mut V>
+    where
+        V: Clone,
+    {
+        let mut node = &mut self.root;
+        loop {
+            let Node::Internal { children, .. } = Node::make_mut(node) else {
+                unreachable!("the walk never lands on a leaf");
+            };
+            if children.is_empty() || !children[0].is_internal() {
+                let index = children
+                    .binary_search_by(|child| child.key().cmp(key))
+                    .ok()?;
+                return Some(Node::make_leaf_mut(&mut children[index]).0);
+            }
+            let index = children.partition_point(|child| child.key() < key);
+            node = children.get_mut(index)?;
+        }
+    }
+
+    fn leaf(&self, key: &K) -> Option<&Node<K, V, N>> {
+        self.root.find_leaf(key)
+    }
+
+    // The number of entries.
+    pub fn len(&self) -> usize {
+        self.root.leaf_count()
+    }
+
+    // The number of keys smaller than `key`.
+    pub fn rank(&self, key: &K) -> usize {
+        let mut rank = 0;
+        let mut node: &Node<K, V, N> = &self.root;
+        loop {
+            let Node::Internal { children, .. } = node else {
+                unreachable!("the walk never lands on a leaf");
+            };
+            let index = children.partition_point(|child| child.key() < key);
+            if node.are_children_leaves() {
+                return rank + index;
+            }
+            rank += children[..index]
+                .iter()
+                .map(|child| child.leaf_count())
+                .sum::<usize>();
+            match children.get(index) {
+                Some(child) => node = child,
+                None => return rank,
+            }
+        }
+    }
+
+    // The entry at position `n` in key order, counting from 0.
+    pub fn select(&self, mut n: usize) -> Option<(&K, &V)> {
+        if n >= self.len() {
+            return None;
+        }
+        let mut node: &Node<K, V, N> = &self.root;
+        loop {
+            let Node::Internal { children, .. } = node else {
+                unreachable!("the walk never lands on a leaf");
+            };
+            if node.are_children_leaves() {
+                let leaf = &children[n];
+                return Some((leaf.key(), leaf.value()?));
+            }
+            for child in children {
+                if n < child.leaf_count() {
+                    node = child;
+                    break;
+                }
+                n -= child.leaf_count();
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        matches!(&*self.root, Node::Internal { children, .. } if children.is_empty())
+    }
+}
+
+// Dropping nodes recursively could overflow the stack on very deep trees,
+// so the children are detached and dropped one node at a time.
+// Nodes still shared with a fork are left to the fork.
+impl<K, V, const N: usize> Drop for MerkleSearchTree<K, V, N> {
+    fn drop(&mut self) {
+        let mut pending = Vec::new();
+        if let Some(Node::Internal { children, .. }) = NodeRef::get_mut(&mut self.root) {
+            pending.append(children);
+        }
+        while let Some(node) = pending.pop() {
+            if let Ok(Node::Internal { mut children, .. }) = NodeRef::try_unwrap(node) {
+                pending.append(&mut children);
+            }
+        }
+    }
+}
+
+impl<V> Clone for DuplicatePolicy<V> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<V> Copy for DuplicatePolicy<V> {}
+
+impl<V> fmt::Debug for DuplicatePolicy<V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DuplicatePolicy::Overwrite => write!(f, "Overwrite"),
+            DuplicatePolicy::Reject => write!(f, "Reject"),
+            DuplicatePolicy::KeepExisting => write!(f, "KeepExisting"),
+            DuplicatePolicy::Resolve(_) => write!(f, "Resolve"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rank_and_select() {
+        let mut tree = MerkleSearchTree::new(3);
+        // Even keys only, inserted out of order.
+        for i in 0..500u32 {
+            let key = (i * 7919) % 500 * 2;
+            tree.insert(key, format!("v{key}"));
+        }
+        tree.insert(10, "replaced".to_string());
+        assert_eq!(tree.len(), 500);
+
+        assert_eq!(tree.rank(&0), 0);
+        assert_eq!(tree.rank(&11), 6);
+        assert_eq!(tree.rank(&12), 6);
+        assert_eq!(tree.rank(&5000), 500);
+        assert_eq!(tree.select(0), Some((&0, &"v0".to_string())));
+        assert_eq!(tree.select(5), Some((&10, &"replaced".to_string())));
+        assert_eq!(tree.select(499).map(|(key, _)| *key), Some(998));
+        assert_eq!(tree.select(500), None);
+
+        for key in (0..1000).step_by(4) {
+            tree.remove(&key);
+        }
+        assert_eq!(tree.len(), 250);
+        for (n, (key, _)) in tree.iter().enumerate() {
+            assert_eq!(tree.rank(key), n);
+            assert_eq!(tree.select(n).map(|(key, _)| key), Some(key));
+        }
+    }
+}
@@ -0,0 +1,563 @@
+// The tree's nodes, and the fanout that decides when one is too big.
+//
+// Nodes are edited in place where no fork shares them and copied where one
+// does. `recalculate` rebuilds an internal node's hash, max key and usage
+// from its children, so an edit only has to fix the nodes on its path.
+
+use std::cmp::Ordering;
+
+use crate::core::alloc::NodeRef;
+use crate::core::codec::Encode;
+use crate::core::hash::NodeHash;
+use crate::core::quota::Usage;
+
+// Decides when a node is too big and must split.
+// Levels come from these splits, not from key hashes, and only a split root
+// adds a level. A node over `Children(max_children)` splits in half, and as
+// `MaxChildren` is at least 3 both halves keep two or more children, so the
+// depth stays logarithmic in the most entries the tree has held, whatever
+// keys are chosen. `Bytes` gives the same bound as long as two entries fit
+// in `target`; larger ones split off into single-child nodes. There are no
+// probabilistic spines to bound, at the price that the shape depends on the
+// insert order; the hashes don't.
+pub(super) enum Fanout<K> {
+    // At most this many children per node.
+    Children(usize),
+    // Keep each node's estimated serialized size around `target` bytes, so
+    // store-backed pages stay evenly sized whatever the key and value lengths.
+    Bytes {
+        target: usize,
+        key_len: fn(&K) -> usize,
+    },
+}
+
+// The internal and leaf nodes of the tree.
+// Children are reference counted so that forks share every node they haven't
+// modified; a mutation copies only the nodes on its path.
+pub(crate) enum Node<K, V, const N: usize = 32> {
+    Internal {
+        hash: NodeHash<N>,
+        children: Vec<NodeRef<Node<K, V, N>>>,
+        max_key: K,
+        // The entries and value bytes below, for order statistics and quotas.
+        usage: Usage,
+    },
+    Leaf {
+        key: K,
+        value: V,
+        hash: NodeHash<N>,
+    },
+}
+
+impl<K: Default, V, const N: usize> Default for Node<K, V, N> {
+    fn default() -> Self {
+        Node::Internal {
+            hash: NodeHash::default(),
+            children: vec![],
+            max_key: K::default(),
+            usage: Usage::default(),
+        }
+    }
+}
+
+impl<K: Ord + Clone + Default + Encode, V: AsRef<[u8]>, const N: usize> Node<K, V, N> {
+    pub(crate) fn key(&self) -> &K {
+        match self {
+            Node::Internal { max_key, .. } => max_key,
+            Node::Leaf { key, .. } => key,
+        }
+    }
+
+    pub(crate) fn children(&self) -> &[NodeRef<Node<K, V, N>>] {
+        match self {
+            Node::Internal { children, .. } => children,
+            Node::Leaf { .. } => &[],
+        }
+    }
+
+    // The number of leaves in the subtree; a leaf counts itself.
+    pub(crate) fn leaf_count(&self) -> usize {
+        match self {
+            Node::Internal { usage, .. } => usage.entries,
+            Node::Leaf { .. } => 1,
+        }
+    }
+
+    pub(crate) fn usage(&self) -> Usage {
+        match self {
+            Node::Internal { usage, .. } => *usage,
+            Node::Leaf { value, .. } => Usage {
+                entries: 1,
+                bytes: value.as_ref().len() as u64,
+            },
+        }
+    }
+
+    pub(crate) fn hash(&self) -> &NodeHash<N> {
+        match self {
+            Node::Internal { hash, .. } => hash,
+            Node::Leaf { hash, .. } => hash,
+        }
+    }
+
+    pub(super) fn value(&self) -> Option<&V> {
+        match self {
+            Node::Internal { .. } => None,
+            Node::Leaf { value, .. } => Some(value),
+        }
+    }
+
+    // An internal node over `children`, or None if there are none.
+    pub(super) fn from_children(
+        children: Vec<NodeRef<Node<K, V, N>>>,
+    ) -> Option<NodeRef<Node<K, V, N>>> {
+        let alloc = children.first()?.alloc();
+        let mut node = Node::Internal {
+            hash: Default::default(),
+            children,
+            max_key: K::default(),
+            usage: Usage::default(),
+        };
+        node.recalculate();
+        Some(NodeRef::new(node, alloc))
+    }
+
+    pub(crate) fn is_internal(&self) -> bool {
+        matches!(self, Node::Internal { .. })
+    }
+
+    pub(crate) fn recalculate(&mut self) {
+        if let Node::Internal {
+            children,
+            hash,
+            max_key,
+            usage,
+        } = self
+        {
+            *hash = Default::default();
+            *usage = children.iter().map(|child| child.usage()).sum();
+            if let Some(last_child) = children.last() {
+                *max_key = last_child.key().clone();
+                for child in children {
+                    hash.xor(child.hash());
+                }
+            }
+        }
+    }
+
+    // The leaf for `key` below this node, if there is one.
+    pub(crate) fn find_leaf(&self, key: &K) -> Option<&Node<K, V, N>> {
+        let mut node = self;
+        loop {
+            let Node::Internal { children, .. } = node else {
+                unreachable!("the walk never lands on a leaf");
+            };
+            if children.is_empty() || !children[0].is_internal() {
+                return children
+                    .binary_search_by(|child| child.key().cmp(key))
+                    .ok()
+                    .map(|index| &*children[index]);
+            }
+            // Keys beyond the last max_key are not in the tree.
+            let index = children.partition_point(|child| child.key() < key);
+            node = children.get(index)?;
+        }
+    }
+
+    // Returns the index of the child to descend into for `key`.
+    // Keys larger than every child are routed to the last one.
+    pub(super) fn route(children: &[NodeRef<Node<K, V, N>>], key: &K) -> usize {
+        let index = children.partition_point(|child| child.key() < key);
+        index.min(children.len().saturating_sub(1))
+    }
+
+    pub(crate) fn are_children_leaves(&self) -> bool {
+        match self {
+            Node::Internal { children, .. } => children.is_empty() || !children[0].is_internal(),
+            Node::Leaf { .. } => false,
+        }
+    }
+
+    // Like `Arc::make_mut`, but without requiring `V: Clone`: only internal
+    // nodes are ever mutated, and copying one just shares its children.
+    pub(super) fn make_mut(node: &mut NodeRef<Node<K, V, N>>) -> &mut Node<K, V, N> {
+        if NodeRef::get_mut(node).is_none() {
+            let Node::Internal {
+                hash,
+                children,
+                max_key,
+                usage,
+            } = &**node
+            else {
+                unreachable!("leaves are replaced, never mutated")
+            };
+            let copy = Node::Internal {
+                hash: *hash,
+                children: children.clone(),
+                max_key: max_key.clone(),
+                usage: *usage,
+            };
+            *node = NodeRef::new(copy, node.alloc());
+        }
+        NodeRef::get_mut(node).expect("the node was just made unique")
+    }
+
+    // `make_mut` for a leaf, which has to copy the value if a fork shares it.
+    pub(super) fn make_leaf_mut(node: &mut NodeRef<Node<K, V, N>>) -> (&mut V, &mut NodeHash<N>)
+    where
+        V: Clone,
+    {
+        if NodeRef::get_mut(node).is_none() {
+            let Node::Leaf { key, value, hash } = &**node else {
+                unreachable!("internal nodes are copied with make_mut")
+            };
+            let copy = Node::Leaf {
+                key: key.clone(),
+                value: value.clone(),
+                hash: *hash,
+            };
+            *node = NodeRef::new(copy, node.alloc());
+        }
+        match NodeRef::get_mut(node) {
+            Some(Node::Leaf { value, hash, .. }) => (value, hash),
+            _ => unreachable!("the leaf was just made unique"),
+        }
+    }
+
+    // Inserts or replaces a leaf in a node whose children are leaves.
+    // Returns the leaf it replaced, if any.
+    pub(super) fn upsert_leaf(
+        &mut self,
+        new_node: NodeRef<Node<K, V, N>>,
+    ) -> Option<NodeRef<Node<K, V, N>>> {
+        let Node::Internal {
+            hash,
+            children,
+            usage,
+            ..
+        } = self
+        else {
+            unreachable!("leaves are inserted into their parent")
+        };
+
+        hash.xor(new_node.hash());
+        *usage += new_node.usage();
+        match children.binary_search(&new_node) {
+            Ok(index) => {
+                hash.xor(children[index].hash());
+                *usage -= children[index].usage();
+                Some(std::mem::replace(&mut children[index], new_node))
+            }
+            Err(index) => {
+                // Key not found. Insert the new leaf.
+                children.insert(index, new_node);
+                None
+            }
+        }
+    }
+
+    // Removes the leaf for `key` from a node whose children are leaves.
+    pub(super) fn remove_leaf(&mut self, key: &K) {
+        let Node::Internal {
+            hash,
+            children,
+            max_key,
+            usage,
+        } = self
+        else {
+            unreachable!("leaves are removed from their parent")
+        };
+
+        if let Ok(index) = children.binary_search_by(|child| child.key().cmp(key)) {
+            let removed = children.remove(index);
+            hash.xor(removed.hash());
+            *usage -= removed.usage();
+        }
+        if let Some(last) = children.last() {
+            *max_key = last.key().clone();
+        }
+    }
+
+    // Puts a descended child back at `index` after a removal below it, or
+    // drops it if it was left empty.
+    pub(super) fn reattach_shrunk(
+        &mut self,
+        index: usize,
+        old_child_hash: &NodeHash<N>,
+        child: NodeRef<Node<K, V, N>>,
+    ) {
+        let Node::Internal {
+            hash,
+            children,
+            max_key,
+            usage,
+        } = self
+        else {
+            unreachable!("children are reattached to internal nodes")
+        };
+
+        hash.xor(old_child_hash);
+        if matches!(&*child, Node::Internal { children, .. } if children.is_empty()) {
+            children.remove(index);
+        } else {
+            hash.xor(child.hash());
+            children[index] = child;
+        }
+        *usage = children.iter().map(|child| child.usage()).sum();
+        if let Some(last) = children.last() {
+            *max_key = last.key().clone();
+        }
+    }
+
+    // Puts a descended child back at `index`, along with the sibling it split off (if any).
+    pub(super) fn reattach(
+        &mut self,
+        index: usize,
+        old_child_hash: &NodeHash<N>,
+        child: NodeRef<Node<K, V, N>>,
+        sibling: Option<NodeRef<Node<K, V, N>>>,
+    ) {
+        let Node::Internal {
+            hash,
+            children,
+            usage,
+            ..
+        } = self
+        else {
+            unreachable!("children are reattached to internal nodes")
+        };
+
+        hash.xor(old_child_hash);
+        hash.xor(child.hash());
+        children[index] = child;
+
+        // If the child split, add its new sibling to our children list.
+        if let Some(new_sibling) = sibling {
+            hash.xor(new_sibling.hash());
+            children.insert(index + 1, new_sibling);
+        }
+        *usage = children.iter().map(|child| child.usage()).sum();
+    }
+}
+
+impl<K: Ord + Clone + Default + Encode, V: AsRef<[u8]>, const N: usize> Node<K, V, N> {
+    // Changes the value of `key`'s leaf, in a node whose children are
+    // leaves, and rehashes it. The leaf is copied first if a fork shares it.
+    // Returns the new value's length, or None if the key is absent.
+    pub(super) fn update_leaf(&mut self, key: &K, update: impl FnOnce(&mut V)) -> Option<usize>
+    where
+        V: Clone,
+    {
+        let Node::Internal {
+            hash,
+            children,
+            usage,
+            ..
+        } = self
+        else {
+            unreachable!("leaves are updated through their parent")
+        };
+
+        let index = children
+            .binary_search_by(|child| child.key().cmp(key))
+            .ok()?;
+        let leaf = &mut children[index];
+        hash.xor(leaf.hash());
+        let (value, leaf_hash) = Node::make_leaf_mut(leaf);
+        update(value);
+        *leaf_hash = NodeHash::leaf(key, value.as_ref());
+        let len = value.as_ref().len();
+        hash.xor(leaf.hash());
+        // Summed over again: the value may have changed before this call,
+        // under a `ValueGuard`, so the old length isn't known.
+        *usage = children.iter().map(|child| child.usage()).sum();
+        Some(len)
+    }
+
+    // Splits the node in two if the fanout policy says it's too big.
+    // Returns the new right sibling if it split.
+    pub(super) fn split_if_needed(&mut self, fanout: &Fanout<K>) -> Option<NodeRef<Node<K, V, N>>> {
+        let Node::Internal {
+            hash,
+            children,
+            max_key,
+            usage,
+        } = self
+        else {
+            return None;
+        };
+
+        if let Some(mid) = fanout.split_point(children) {
+            let alloc = children[mid].alloc();
+            let sibling_children = children.split_off(mid);
+            let mut new_sibling = Node::Internal {
+                hash: Default::default(),
+                children: sibling_children,
+                max_key: K::default(), // will be recalculated
+                usage: Usage::default(),
+            };
+            new_sibling.recalculate();
+
+            hash.xor(new_sibling.hash());
+            *usage -= new_sibling.usage();
+            if let Some(last) = children.last() {
+                *max_key = last.key().clone();
+            }
+
+            Some(NodeRef::new(new_sibling, alloc))
+        } else {
+            if let Some(last) = children.last() {
+                *max_key = last.key().clone();
+            }
+            None
+        }
+    }
+}
+
+// Derived impls would require `K: Copy`.
+impl<K> Clone for Fanout<K> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<K> Copy for Fanout<K> {}
+
+impl<K> Fanout<K> {
+    // The estimated encoded size of a node's entry for `child`.
+    fn entry_bytes<const N: usize>(key_len: fn(&K) -> usize, key: &K, value_len: usize) -> usize {
+        key_len(key) + value_len + N
+    }
+
+    fn child_bytes<V: AsRef<[u8]>, const N: usize>(
+        key_len: fn(&K) -> usize,
+        child: &Node<K, V, N>,
+    ) -> usize {
+        match child {
+            Node::Internal { max_key, .. } => Self::entry_bytes::<N>(key_len, max_key, 0),
+            Node::Leaf { key, value, .. } => {
+                Self::entry_bytes::<N>(key_len, key, value.as_ref().len())
+            }
+        }
+    }
+
+    // Where to split `children`, or None if the node still fits.
+    fn split_point<V: AsRef<[u8]>, const N: usize>(
+        &self,
+        children: &[NodeRef<Node<K, V, N>>],
+    ) -> Option<usize> {
+        match self {
+            Fanout::Children(max_children) => {
+                (children.len() > *max_children).then_some(children.len() / 2)
+            }
+            Fanout::Bytes { target, key_len } => {
+                if children.len() < 2 {
+                    return None;
+                }
+                let sizes: Vec<usize> = children
+                    .iter()
+                    .map(|child| Self::child_bytes(*key_len, child))
+                    .collect();
+                let total: usize = sizes.iter().sum();
+                if total <= *target {
+                    return None;
+                }
+                // Split where the left half reaches half of the bytes.
+                let mut prefix = 0;
+                let mid = sizes.iter().take_while(|size| {
+                    prefix += *size;
+                    prefix * 2 < total
+                });
+                Some((mid.count() + 1).clamp(1, children.len() - 1))
+            }
+        }
+    }
+
+    // Whether `children` would overflow after adding an entry for `key`
+    // (replacing `existing` if given). A `value_len` of 0 stands for a child pointer.
+    pub(super) fn overflows<V: AsRef<[u8]>, const N: usize>(
+        &self,
+        children: &[NodeRef<Node<K, V, N>>],
+        key: &K,
+        value_len: usize,
+        existing: Option<&Node<K, V, N>>,
+    ) -> bool {
+        match self {
+            Fanout::Children(max_children) => existing.is_none() && children.len() >= *max_children,
+            Fanout::Bytes { target, key_len } => {
+                let count = children.len() + usize::from(existing.is_none());
+                let total: usize = children
+                    .iter()
+                    .map(|child| Self::child_bytes(*key_len, child))
+                    .sum::<usize>()
+                    + Self::entry_bytes::<N>(*key_len, key, value_len)
+                    - existing.map_or(0, |child| Self::child_bytes(*key_len, child));
+                count >= 2 && total > *target
+            }
+        }
+    }
+}
+
+// These are needed for sorting and comparing
+impl<K: Ord + Clone + Default + Encode, V: AsRef<[u8]>, const N: usize> PartialEq
+    for Node<K, V, N>
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+impl<K: Ord + Clone + Default + Encode, V: AsRef<[u8]>, const N: usize> Eq for Node<K, V, N> {}
+
+impl<K: Ord + Clone + Default + Encode, V: AsRef<[u8]>, const N: usize> PartialOrd
+    for Node<K, V, N>
+{
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl<K: Ord + Clone + Default + Encode, V: AsRef<[u8]>, const N: usize> Ord for Node<K, V, N> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(other.key())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::tree::MerkleSearchTree;
+
+    #[test]
+    fn test_target_node_bytes() {
+        let mut tree = MerkleSearchTree::new(usize::MAX).with_target_node_bytes(1024);
+        for i in 0..400u32 {
+            // Small values for most keys, a few large ones.
+            let len = if i % 50 == 0 { 600 } else { 10 };
+            tree.insert(i, "x".repeat(len));
+        }
+        assert!(tree.depth() > 1);
+
+        let key_len = |key: &u32| key.encoded_len();
+        let mut stack = vec![&tree.root];
+        while let Some(node) = stack.pop() {
+            let Node::Internal { children, .. } = &**node else {
+                continue;
+            };
+            let bytes: usize = children
+                .iter()
+                .map(|child| Fanout::child_bytes(key_len, child))
+                .sum();
+            assert!(
+                children.len() == 1 || bytes <= 1024,
+                "node of {bytes} bytes"
+            );
+            stack.extend(children.iter());
+        }
+
+        // Same entries in a tree sized by count still hash the same.
+        let mut by_count = MerkleSearchTree::new(8);
+        for (k, v) in tree.iter() {
+            by_count.insert(*k, v.clone());
+        }
+        assert_eq!(by_count.hash(), tree.hash());
+    }
+}
@@ -0,0 +1,353 @@
+// Moving whole ranges of entries between trees.
+//
+// `split_where` and `concat` only rebuild the nodes along the boundary
+// between the two parts; everything to either side moves over as it is,
+// hashes included.
+
+use std::ops::{Bound, RangeBounds};
+use std::sync::OnceLock;
+
+use crate::core::alloc::NodeRef;
+use crate::core::codec::Encode;
+use crate::core::error::Error;
+#[cfg(feature = "crdt")]
+use crate::core::hash::NodeHash;
+use crate::core::limits::Churn;
+use crate::core::metrics::Work;
+#[cfg(feature = "structure-log")]
+use crate::core::structure::StructureLog;
+
+use super::write::Detached;
+use super::{MerkleSearchTree, Node};
+
+impl<K: Ord + Clone + Default + Encode, V: AsRef<[u8]>, const N: usize> MerkleSearchTree<K, V, N> {
+    // Removes the entries whose keys fall into `range` and returns them as a
+    // tree of their own, with the same configuration. Only the nodes along
+    // the two edges of the range are rebuilt; the subtrees between them move
+    // over as they are, hashes included.
+    pub fn extract_subtree<R: RangeBounds<K>>(&mut self, range: R) -> Self {
+        let mut work = Work::default();
+        let depth_before = self.depth;
+        #[cfg(feature = "crdt")]
+        let mut root = *self.root.hash();
+        let mut extracted = self.split_where(|key| Self::is_before_start(&range, key), &mut work);
+        let after = extracted.split_where(
+            |key| match range.end_bound() {
+                Bound::Included(end) => key <= end,
+                Bound::Excluded(end) => key < end,
+                Bound::Unbounded => true,
+            },
+            &mut work,
+        );
+        self.concat(after, &mut work);
+
+        self.finish(work, depth_before);
+        #[cfg(feature = "crdt")]
+        if let Some(log) = &mut self.op_log {
+            let mut entries = self.root.leaf_count() + extracted.len();
+            for (key, hash) in extracted.leaf_hashes() {
+                root.xor(hash);
+                entries -= 1;
+                log.delete(key, &NodeHash::root_of(root, entries));
+            }
+        }
+        self.reset_changes();
+        extracted.reset_changes();
+        extracted
+    }
+
+    // Removes the entries whose keys fall into `range`, returning how many
+    // there were. Subtrees inside the range are dropped whole; only the nodes
+    // on its two edges are rebuilt.
+    pub fn remove_range<R: RangeBounds<K>>(&mut self, range: R) -> usize {
+        self.extract_subtree(range).len()
+    }
+
+    // Moves every entry of `subtree` into this tree by attaching its nodes
+    // as they are, rather than inserting key by key. Its keys must all fall
+    // between two adjacent keys of ours, or beyond either end; otherwise
+    // nothing changes and it fails with `Error::GraftOverlap`.
+    pub fn graft(&mut self, subtree: Self) -> Result<(), Error> {
+        let Some((first, _)) = subtree.iter().next() else {
+            return Ok(());
+        };
+        if self.range(first..=subtree.root.key()).next().is_some() {
+            return Err(Error::GraftOverlap);
+        }
+
+        #[cfg(feature = "crdt")]
+        if let Some(log) = &mut self.op_log {
+            let mut root = *self.root.hash();
+            for ((key, value), (_, hash)) in subtree.iter().zip(subtree.leaf_hashes()) {
+                root.xor(hash);
+                log.put(key, value, &root);
+            }
+        }
+
+        let mut work = Work::default();
+        let depth_before = self.depth;
+        let first = first.clone();
+        let after = self.split_where(|key| *key < first, &mut work);
+        self.concat(subtree, &mut work);
+        self.concat(after, &mut work);
+
+        self.finish(work, depth_before);
+        self.reset_changes();
+        Ok(())
+    }
+
+    // Moves the entries whose keys fail `goes_left` into a new tree and
+    // returns it. `goes_left` must hold for a prefix of the keys. Only the
+    // nodes on the path to the first key that fails it are rebuilt.
+    fn split_where(&mut self, goes_left: impl Fn(&K) -> bool, work: &mut Work) -> Self {
+        // The children left and right of the path, level by level.
+        let mut levels = Vec::with_capacity(self.depth);
+        let mut node: &Node<K, V, N> = &self.root;
+        loop {
+            let children = node.children();
+            let index = children.partition_point(|child| goes_left(child.key()));
+            match children.get(index) {
+                Some(child) if child.is_internal() => {
+                    levels.push((children[..index].to_vec(), children[index + 1..].to_vec()));
+                    node = child;
+                }
+                _ => {
+                    levels.push((children[..index].to_vec(), children[index..].to_vec()));
+                    break;
+                }
+            }
+        }
+
+        // Rebuild both halves bottom-up. A half left empty is dropped from
+        // its parent.
+        let (mut left, mut right) = (None, None);
+        while let Some((mut left_children, right_children)) = levels.pop() {
+            left_children.extend(left);
+            let right_children = right.into_iter().chain(right_children).collect();
+            left = Node::from_children(left_children);
+            right = Node::from_children(right_children);
+            work.nodes_touched += 2;
+        }
+
+        let mut split = MerkleSearchTree {
+            root: right.unwrap_or_else(|| NodeRef::new(Node::default(), self.alloc)),
+            alloc: self.alloc,
+            fanout: self.fanout,
+            depth: self.depth,
+            max_depth: self.max_depth,
+            collision_checks: self.collision_checks,
+            duplicate_policy: self.duplicate_policy,
+            generation: 0,
+            last_work: Work::default(),
+            total_work: Work::default(),
+            content_digest: OnceLock::new(),
+            quota: self.quota,
+            soft_limits: self.soft_limits,
+            churn: Churn::default(),
+            recent: self.recent.clone(),
+            root_chain: None,
+            #[cfg(feature = "crdt")]
+            op_log: None,
+            watch: None,
+            #[cfg(feature = "structure-log")]
+            structure_log: StructureLog::default(),
+            #[cfg(feature = "compression")]
+            compress_above: self.compress_above,
+        };
+        split.collapse_root();
+        self.root = left.unwrap_or_else(|| NodeRef::new(Node::default(), self.alloc));
+        self.collapse_root();
+        split
+    }
+
+    // Appends the entries of `other`, whose keys must all be larger than
+    // ours. The shorter tree's root children join the node at the same
+    // height on the taller one's facing edge, and the nodes that overflow
+    // split on the way back up.
+    fn concat(&mut self, other: Self, work: &mut Work) {
+        if other.is_empty() {
+            return;
+        }
+        if self.is_empty() {
+            self.root = other.root.clone();
+            self.depth = other.depth;
+            return;
+        }
+
+        let append = self.depth >= other.depth;
+        let (mut node, short) = if append {
+            (std::mem::take(&mut self.root), other.root.clone())
+        } else {
+            (other.root.clone(), std::mem::take(&mut self.root))
+        };
+        let levels = self.depth.abs_diff(other.depth);
+        self.depth = self.depth.max(other.depth);
+
+        // Walk down the facing edge, detaching nodes as `try_insert` does.
+        let placeholder: NodeRef<Node<K, V, N>> = NodeRef::default();
+        let mut path: Vec<Detached<K, V, N>> = Vec::with_capacity(levels);
+        for _ in 0..levels {
+            let Node::Internal { children, .. } = Node::make_mut(&mut node) else {
+                unreachable!("only internal nodes are walked");
+            };
+            let index = if append { children.len() - 1 } else { 0 };
+            let child = std::mem::replace(&mut children[index], placeholder.clone());
+            path.push((node, index, *child.hash()));
+            node = child;
+        }
+
+        let unique = Node::make_mut(&mut node);
+        if let Node::Internal { children, .. } = &mut *unique {
+            let joined = short.children().iter().cloned();
+            if append {
+                children.extend(joined);
+            } else {
+                children.splice(0..0, joined);
+            }
+        }
+        unique.recalculate();
+        let sibling = unique.split_if_needed(&self.fanout);
+        work.count_node(sibling.is_some());
+        self.log_split(path.len(), &node, &sibling);
+        self.climb(path, node, sibling, work);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::hash::NodeHash;
+
+    // Checks hashes, usage, max keys, key order and that every leaf sits at
+    // the tree's depth.
+    fn check_structure(tree: &MerkleSearchTree<u32>) {
+        let mut keys = Vec::new();
+        let mut pending = vec![(&*tree.root, 1)];
+        while let Some((node, level)) = pending.pop() {
+            let children = node.children();
+            assert!(!children.is_empty() || level == 1);
+            let mut sum = NodeHash::default();
+            for child in children {
+                sum.xor(child.hash());
+                if child.is_internal() {
+                    pending.push((child, level + 1));
+                } else {
+                    assert_eq!(level, tree.depth());
+                    keys.push(*child.key());
+                }
+            }
+            assert_eq!(&sum, node.hash());
+            assert_eq!(node.usage(), children.iter().map(|c| c.usage()).sum());
+            if let Some(last) = children.last() {
+                assert_eq!(node.key(), last.key());
+            }
+        }
+        keys.sort();
+        assert!(tree.iter().map(|(key, _)| *key).eq(keys));
+    }
+
+    #[test]
+    fn test_extract_and_graft() {
+        let mut tree = MerkleSearchTree::new(3);
+        for i in 0..500u32 {
+            tree.insert(i, format!("v{i}"));
+        }
+        let original = tree.fork();
+
+        let extracted = tree.extract_subtree(100..250);
+        check_structure(&tree);
+        check_structure(&extracted);
+        assert_eq!(extracted.len(), 150);
+        assert_eq!(tree.len(), 350);
+        assert_eq!(*extracted.hash(), original.range_hash(100..250));
+        assert_eq!(tree.get(&99), Some(&"v99".to_string()));
+        assert_eq!(tree.get(&100), None);
+        assert_eq!(tree.get(&250), Some(&"v250".to_string()));
+        assert!(extracted.iter().map(|(key, _)| *key).eq(100..250));
+
+        // Grafting the range back restores the content, and the tree keeps
+        // working as usual.
+        tree.graft(extracted).unwrap();
+        check_structure(&tree);
+        assert_eq!(tree.hash(), original.hash());
+        assert!(tree.iter().eq(original.iter()));
+        tree.insert(175, "again".to_string());
+        tree.remove(&176);
+        check_structure(&tree);
+
+        // Edges and empty ranges.
+        let mut tree = original.fork();
+        assert!(tree.extract_subtree(600..).is_empty());
+        let head = tree.extract_subtree(..=0);
+        assert_eq!(head.len(), 1);
+        let all = tree.extract_subtree(..);
+        check_structure(&all);
+        assert!(tree.is_empty());
+        assert_eq!(tree.depth(), 1);
+        assert_eq!(all.len(), 499);
+    }
+
+    #[test]
+    fn test_remove_range() {
+        let mut tree = MerkleSearchTree::new(4);
+        for i in 0..5000u32 {
+            tree.insert(i, format!("v{i}"));
+        }
+        let original = tree.fork();
+
+        // Only the two edges are touched, not every removed key's path.
+        assert_eq!(tree.remove_range(1000..4000), 3000);
+        check_structure(&tree);
+        assert!(tree.last_work().nodes_touched < 100);
+        assert!(
+            tree.iter()
+                .map(|(key, _)| *key)
+                .eq((0..1000).chain(4000..5000))
+        );
+        let mut expected = original.range_hash(..1000);
+        expected.xor(&original.range_hash(4000..));
+        assert_eq!(*tree.hash(), expected);
+
+        assert_eq!(tree.remove_range(1000..4000), 0);
+        assert_eq!(tree.remove_range(..=10), 11);
+        assert_eq!(tree.remove_range(..), 1989);
+        assert!(tree.is_empty());
+    }
+
+    #[test]
+    fn test_graft_shapes() {
+        let mut tree = MerkleSearchTree::new(3);
+        let mut expected = MerkleSearchTree::new(3);
+        for i in (0..300u32).filter(|i| !(100..120).contains(i)) {
+            tree.insert(i, format!("v{i}"));
+            expected.insert(i, format!("v{i}"));
+        }
+
+        // A shallow tree into the middle of a deep one.
+        let mut small = MerkleSearchTree::new(3);
+        for i in 100..103u32 {
+            small.insert(i, format!("v{i}"));
+            expected.insert(i, format!("v{i}"));
+        }
+        tree.graft(small).unwrap();
+        check_structure(&tree);
+
+        // A deep tree below a shallow one.
+        let mut shallow = MerkleSearchTree::new(3);
+        shallow.insert(1000, "v1000".to_string());
+        expected.insert(1000, "v1000".to_string());
+        shallow.graft(tree).unwrap();
+        check_structure(&shallow);
+        assert_eq!(shallow.hash(), expected.hash());
+        assert!(shallow.iter().eq(expected.iter()));
+
+        let mut overlapping = MerkleSearchTree::new(3);
+        overlapping.insert(105, "v105".to_string());
+        overlapping.insert(150, "v150".to_string());
+        assert!(matches!(
+            shallow.graft(overlapping),
+            Err(Error::GraftOverlap)
+        ));
+        assert_eq!(shallow.hash(), expected.hash());
+    }
+}
@@ -0,0 +1,1014 @@
+// Inserts, removals and in-place updates.
+//
+// A write detaches the nodes on its path, edits the bottom one and climbs
+// back up, reattaching and splitting full parents on the way. Afterwards
+// `finish` does the bookkeeping every mutation shares: work counters, soft
+// limits, the root chain and root watchers.
+
+use std::sync::OnceLock;
+use std::time::Instant;
+
+use crate::core::alloc::NodeRef;
+use crate::core::chain::RootChain;
+use crate::core::codec::Encode;
+use crate::core::error::Error;
+use crate::core::hash::NodeHash;
+use crate::core::metrics::Work;
+use crate::core::quota::Usage;
+use crate::core::recent::RecentChanges;
+use crate::core::structure::StructureEvent;
+#[cfg(feature = "structure-log")]
+use crate::core::structure::StructureLog;
+use crate::core::watch::RootWatch;
+#[cfg(feature = "crdt")]
+use crate::crdt::ops::OpLog;
+
+use super::{DuplicatePolicy, InsertOutcome, MerkleSearchTree, Node};
+
+// A node taken off the tree during an insert, with the slot of its detached
+// child and that child's old hash.
+pub(super) type Detached<K, V, const N: usize> = (NodeRef<Node<K, V, N>>, usize, NodeHash<N>);
+
+// An insert's outcome with the replaced leaf in place of its value.
+pub(crate) type LeafOutcome<K, V, const N: usize> = InsertOutcome<NodeRef<Node<K, V, N>>>;
+
+// What a batch of writes puts back if it fails, and the op log and watch it
+// holds back until it succeeds; see `begin_batch`.
+pub(crate) struct Batch<K, V, const N: usize> {
+    root: NodeRef<Node<K, V, N>>,
+    depth: usize,
+    generation: u64,
+    content_digest: OnceLock<NodeHash<N>>,
+    recent: Option<RecentChanges<K, N>>,
+    root_chain: Option<RootChain<N>>,
+    #[cfg(feature = "structure-log")]
+    structure_log: StructureLog<K>,
+    #[cfg(feature = "crdt")]
+    op_log: Option<OpLog<K, V, N>>,
+    watch: Option<RootWatch<N>>,
+}
+
+impl<K: Ord + Clone + Default + Encode, V: AsRef<[u8]>, const N: usize> MerkleSearchTree<K, V, N> {
+    // Inserts or updates `key`. The replaced value is cloned out only if a
+    // fork still shares it.
+    //
+    // Panics if the write fails: if a depth limit or quota is configured and
+    // the insert would exceed it, if the duplicate policy rejects it, or if
+    // collision checks catch one. A tree with none of these configured never
+    // panics here. Anything writing data it didn't produce itself, such as
+    // entries from a peer or a file, should use `try_insert`.
+    pub fn insert(&mut self, key: K, value: V) -> InsertOutcome<V>
+    where
+        V: Clone,
+    {
+        match self.try_insert(key, value) {
+            Ok(outcome) => outcome,
+            Err(err) => panic!("{err}"),
+        }
+    }
+
+    // Inserts or updates `key` as the duplicate policy says, failing instead
+    // of growing the tree past its depth limit or its tenant past its quota.
+    pub fn try_insert(&mut self, key: K, value: V) -> Result<InsertOutcome<V>, Error>
+    where
+        V: Clone,
+    {
+        self.insert_with_policy(key, value, self.duplicate_policy)
+    }
+
+    // `try_insert` with `policy` in place of the tree's own. The policy is
+    // applied during the insert's own lookup, so it costs no extra read.
+    pub fn insert_with_policy(
+        &mut self,
+        key: K,
+        value: V,
+        policy: DuplicatePolicy<V>,
+    ) -> Result<InsertOutcome<V>, Error>
+    where
+        V: Clone,
+    {
+        self.try_insert_entry(
+            key,
+            |stored| match (stored, policy) {
+                (None, _) | (Some(_), DuplicatePolicy::Overwrite) => Ok(Some(value)),
+                (Some(_), DuplicatePolicy::Reject) => Err(Error::DuplicateKey),
+                (Some(_), DuplicatePolicy::KeepExisting) => Ok(None),
+                (Some(stored), DuplicatePolicy::Resolve(resolve)) => {
+                    Ok(Some(resolve(stored, value)))
+                }
+            },
+            true,
+        )
+        .map(Self::value_outcome)
+    }
+
+    // Inserts `value`, or if `key` is already present, `merge(stored, value)`,
+    // e.g. to sum counters. The merge happens during the insert's own lookup
+    // and before anything is hashed, so it costs no extra read.
+    pub fn upsert_with<F>(&mut self, key: K, value: V, merge: F) -> Result<InsertOutcome<V>, Error>
+    where
+        V: Clone,
+        F: FnOnce(&V, V) -> V,
+    {
+        self.try_insert_entry(
+            key,
+            |stored| {
+                Ok(Some(match stored {
+                    Some(stored) => merge(stored, value),
+                    None => value,
+                }))
+            },
+            true,
+        )
+        .map(Self::value_outcome)
+    }
+
+    // `try_insert` for writers that don't need the replaced value, which is
+    // returned as its leaf.
+    pub(crate) fn try_write(&mut self, key: K, value: V) -> Result<LeafOutcome<K, V, N>, Error> {
+        self.try_insert_entry(key, |_| Ok(Some(value)), true)
+    }
+
+    // Inserts an entry received from a replica. Quotas only apply to local
+    // writes: rejecting replicated entries would keep replicas from converging.
+    // The depth limit and collision checks still apply, and fail the write.
+    #[cfg_attr(not(feature = "sync"), allow(dead_code))]
+    pub(crate) fn insert_replicated(&mut self, key: K, value: V) -> Result<(), Error> {
+        self.try_insert_entry(key, |_| Ok(Some(value)), false)
+            .map(|_| ())
+    }
+
+    // Takes the value out of a replaced leaf, cloning it if a fork shares it.
+    fn value_outcome(outcome: LeafOutcome<K, V, N>) -> InsertOutcome<V>
+    where
+        V: Clone,
+    {
+        match outcome {
+            InsertOutcome::Updated(old) => InsertOutcome::Updated(match NodeRef::try_unwrap(old) {
+                Ok(Node::Leaf { value, .. }) => value,
+                Ok(Node::Internal { .. }) => unreachable!("only leaves are replaced"),
+                Err(shared) => shared.value().expect("replaced a leaf").clone(),
+            }),
+            InsertOutcome::Inserted => InsertOutcome::Inserted,
+            InsertOutcome::Unchanged => InsertOutcome::Unchanged,
+        }
+    }
+
+    // Writes the value `make` returns given the stored one, checking the
+    // quota first if `quota` is set. `make` returns None to keep the stored
+    // value.
+    fn try_insert_entry(
+        &mut self,
+        key: K,
+        make: impl FnOnce(Option<&V>) -> Result<Option<V>, Error>,
+        quota: bool,
+    ) -> Result<LeafOutcome<K, V, N>, Error> {
+        let (value, existing) = match self.leaf(&key) {
+            Some(leaf) => match make(leaf.value())? {
+                Some(value) => (value, Some((*leaf.hash(), leaf.usage().bytes))),
+                None => {
+                    self.last_work = Work::default();
+                    return Ok(InsertOutcome::Unchanged);
+                }
+            },
+            None => match make(None)? {
+                Some(value) => (value, None),
+                None => return Ok(InsertOutcome::Unchanged),
+            },
+        };
+        if quota {
+            let old_len = existing.map(|(_, bytes)| bytes as usize);
+            self.check_quota(&key, old_len, value.as_ref().len())?;
+        }
+        let hash = NodeHash::leaf(&key, value.as_ref());
+        let work = Work {
+            bytes_hashed: value.as_ref().len() as u64,
+            ..Default::default()
+        };
+        // Rewriting a value leaves every hash as it was: skip the walk.
+        if existing.is_some_and(|(old, _)| old == hash) {
+            if self.collision_checks
+                && self
+                    .leaf(&key)
+                    .and_then(|leaf| leaf.value())
+                    .is_some_and(|old| old.as_ref() != value.as_ref())
+            {
+                return Err(Error::CollisionDetected(hash.to_string()));
+            }
+            self.last_work = work;
+            self.total_work += work;
+            return Ok(InsertOutcome::Unchanged);
+        }
+        if let Some(limit) = self.max_depth
+            && self.depth >= limit
+            && self.would_grow(&key, value.as_ref().len())
+        {
+            return Err(Error::DepthLimitExceeded { limit });
+        }
+        let depth_before = self.depth;
+
+        let mut work = work;
+        let changed = (self.recent.is_some() || self.logs_ops()).then(|| key.clone());
+        let leaf = NodeRef::new(Node::Leaf { key, value, hash }, self.alloc);
+
+        // Walk down to the bottom internal node, detaching each node on the way
+        // so it can be mutated without recursion. `path` remembers the parents
+        // and the slot (and old hash) of the child taken out of each. Nodes
+        // shared with a fork are copied before they are touched.
+        let placeholder: NodeRef<Node<K, V, N>> = NodeRef::default();
+        let mut path: Vec<Detached<K, V, N>> = Vec::with_capacity(self.depth);
+        let mut node = std::mem::replace(&mut self.root, placeholder.clone());
+        while !node.are_children_leaves() {
+            let Node::Internal { children, .. } = Node::make_mut(&mut node) else {
+                unreachable!("only internal nodes are walked");
+            };
+            let index = Node::route(children, leaf.key());
+            let child = std::mem::replace(&mut children[index], placeholder.clone());
+            path.push((node, index, *child.hash()));
+            node = child;
+        }
+
+        let entries = node.children().len();
+        let replaced = Node::make_mut(&mut node).upsert_leaf(leaf);
+        self.check_growth(entries, &node);
+        let sibling = Node::make_mut(&mut node).split_if_needed(&self.fanout);
+        work.count_node(sibling.is_some());
+        self.log_split(path.len(), &node, &sibling);
+        self.climb(path, node, sibling, &mut work);
+
+        self.finish(work, depth_before);
+        if let Some(key) = changed {
+            self.log_put(&key);
+            self.record_change(key);
+        }
+        Ok(match replaced {
+            Some(old) => InsertOutcome::Updated(old),
+            None => InsertOutcome::Inserted,
+        })
+    }
+
+    // Removes `key`, returning whether it was present. Nodes left empty are
+    // dropped and a root with a single internal child is collapsed, but
+    // underfull nodes are not merged.
+    pub fn remove(&mut self, key: &K) -> bool {
+        if self.get(key).is_none() {
+            return false;
+        }
+        let mut work = Work::default();
+        let depth_before = self.depth;
+
+        // Same detach-and-climb walk as `try_insert`.
+        let placeholder: NodeRef<Node<K, V, N>> = NodeRef::default();
+        let mut path: Vec<Detached<K, V, N>> = Vec::with_capacity(self.depth);
+        let mut node = std::mem::replace(&mut self.root, placeholder.clone());
+        while !node.are_children_leaves() {
+            let Node::Internal { children, .. } = Node::make_mut(&mut node) else {
+                unreachable!("only internal nodes are walked");
+            };
+            let index = Node::route(children, key);
+            let child = std::mem::replace(&mut children[index], placeholder.clone());
+            path.push((node, index, *child.hash()));
+            node = child;
+        }
+
+        Node::make_mut(&mut node).remove_leaf(key);
+        work.count_node(false);
+        while let Some((mut parent, index, old_child_hash)) = path.pop() {
+            if node.children().is_empty() {
+                let level = path.len() + 1;
+                self.log(|| StructureEvent::NodeDropped {
+                    level,
+                    key: key.clone(),
+                });
+            }
+            Node::make_mut(&mut parent).reattach_shrunk(index, &old_child_hash, node);
+            work.count_node(false);
+            node = parent;
+        }
+
+        self.root = node;
+        self.collapse_root();
+
+        self.finish(work, depth_before);
+        #[cfg(feature = "crdt")]
+        if let Some(mut log) = self.op_log.take() {
+            log.delete(key, self.hash());
+            self.op_log = Some(log);
+        }
+        if self.recent.is_some() {
+            self.record_change(key.clone());
+        }
+        true
+    }
+
+    // Changes the parts of `key`'s value its hash doesn't cover, such as the
+    // local tags of a `Tagged` value, without touching any hash. Returns
+    // whether the key exists. Fails with `Error::HashChanged`, leaving the
+    // value as it was, if `update` changed any hashed byte.
+    pub fn update_unhashed<F>(&mut self, key: &K, update: F) -> Result<bool, Error>
+    where
+        V: Clone,
+        F: FnOnce(&mut V),
+    {
+        let Some(Node::Leaf { value, hash, .. }) = self.leaf(key) else {
+            return Ok(false);
+        };
+        let (mut value, hash) = (value.clone(), *hash);
+        update(&mut value);
+        if NodeHash::<N>::leaf(key, value.as_ref()) != hash {
+            return Err(Error::HashChanged);
+        }
+
+        let leaf = NodeRef::new(
+            Node::Leaf {
+                key: key.clone(),
+                value,
+                hash,
+            },
+            self.alloc,
+        );
+        let mut node = &mut self.root;
+        loop {
+            let Node::Internal { children, .. } = Node::make_mut(node) else {
+                unreachable!("only internal nodes are walked");
+            };
+            let index = Node::route(children, key);
+            if !children[index].is_internal() {
+                children[index] = leaf;
+                break;
+            }
+            node = &mut children[index];
+        }
+        self.log_put(key);
+        Ok(true)
+    }
+
+    // Changes `key`'s value in place and rehashes only its leaf and the path
+    // above it, so a large value isn't cloned through `insert`; it is copied
+    // only if a fork still shares it. Returns whether the key exists. As the
+    // change happens in place, quotas aren't checked.
+    pub fn update<F>(&mut self, key: &K, update: F) -> bool
+    where
+        V: Clone,
+        F: FnOnce(&mut V),
+    {
+        // Look the key up first, so a miss doesn't copy a path a fork shares.
+        if self.leaf(key).is_none() {
+            return false;
+        }
+        let depth_before = self.depth;
+
+        // Same detach-and-climb walk as `try_insert`.
+        let placeholder: NodeRef<Node<K, V, N>> = NodeRef::default();
+        let mut path: Vec<Detached<K, V, N>> = Vec::with_capacity(self.depth);
+        let mut node = std::mem::replace(&mut self.root, placeholder.clone());
+        while !node.are_children_leaves() {
+            let Node::Internal { children, .. } = Node::make_mut(&mut node) else {
+                unreachable!("only internal nodes are walked");
+            };
+            let index = Node::route(children, key);
+            let child = std::mem::replace(&mut children[index], placeholder.clone());
+            path.push((node, index, *child.hash()));
+            node = child;
+        }
+
+        let updated = Node::make_mut(&mut node).update_leaf(key, update);
+        let mut work = Work {
+            bytes_hashed: updated.unwrap_or_default() as u64,
+            ..Default::default()
+        };
+        work.count_node(false);
+        while let Some((mut parent, index, old_child_hash)) = path.pop() {
+            Node::make_mut(&mut parent).reattach(index, &old_child_hash, node, None);
+            work.count_node(false);
+            node = parent;
+        }
+        self.root = node;
+
+        self.finish(work, depth_before);
+        self.log_put(key);
+        if self.recent.is_some() {
+            self.record_change(key.clone());
+        }
+        true
+    }
+
+    // Climbs back up `path` from `node`, reattaching children and splitting
+    // full parents, and makes the top the root.
+    pub(super) fn climb(
+        &mut self,
+        mut path: Vec<Detached<K, V, N>>,
+        mut node: NodeRef<Node<K, V, N>>,
+        mut sibling: Option<NodeRef<Node<K, V, N>>>,
+        work: &mut Work,
+    ) {
+        while let Some((mut parent, index, old_child_hash)) = path.pop() {
+            let unique = Node::make_mut(&mut parent);
+            let entries = unique.children().len();
+            unique.reattach(index, &old_child_hash, node, sibling);
+            self.check_growth(entries, unique);
+            sibling = unique.split_if_needed(&self.fanout);
+            work.count_node(sibling.is_some());
+            self.log_split(path.len(), &parent, &sibling);
+            node = parent;
+        }
+
+        self.root = match sibling {
+            Some(new_sibling) => {
+                // The root split, so we need to create a new root.
+                let mut new_root = Node::Internal {
+                    hash: Default::default(),
+                    children: vec![node, new_sibling],
+                    max_key: K::default(), // Will be set by recalculate
+                    usage: Usage::default(),
+                };
+                new_root.recalculate();
+                self.depth += 1;
+                work.nodes_touched += 1;
+                let depth = self.depth;
+                self.log(|| StructureEvent::RootGrew { depth });
+                NodeRef::new(new_root, self.alloc)
+            }
+            None => node,
+        };
+    }
+
+    // Records a mutation that changed the tree, and reports the soft limits
+    // it crossed.
+    pub(super) fn finish(&mut self, work: Work, depth_before: usize) {
+        let work = Work {
+            mutations: 1,
+            ..work
+        };
+        self.last_work = work;
+        self.total_work += work;
+        self.generation += 1;
+        self.content_digest = OnceLock::new();
+        if let Some(limits) = &self.soft_limits {
+            limits.check_depth(depth_before, self.depth);
+            limits.check_churn(&mut self.churn, Instant::now());
+        }
+        self.link_root();
+        self.notify_watch();
+    }
+
+    // Swaps in a whole new tree, e.g. one loaded from a snapshot. Like other
+    // bulk changes, this clears the change history.
+    #[cfg(any(feature = "store", feature = "crdt"))]
+    pub(crate) fn replace_root(&mut self, root: NodeRef<Node<K, V, N>>, depth: usize) {
+        self.root = root;
+        self.depth = depth;
+        self.generation += 1;
+        self.content_digest = OnceLock::new();
+        self.reset_changes();
+        self.link_root();
+        self.notify_watch();
+    }
+
+    // Starts a batch of writes that must apply whole, such as a patch, for
+    // `commit_batch` or `rollback_batch` to end. Until then the writes log
+    // no ops and notify no watch, so a failed batch leaves no trace outside
+    // the tree.
+    pub(crate) fn begin_batch(&mut self) -> Batch<K, V, N> {
+        Batch {
+            root: self.root.clone(),
+            depth: self.depth,
+            generation: self.generation,
+            content_digest: self.content_digest.clone(),
+            recent: self.recent.clone(),
+            root_chain: self.root_chain.clone(),
+            #[cfg(feature = "structure-log")]
+            structure_log: self.structure_log.clone(),
+            #[cfg(feature = "crdt")]
+            op_log: self.op_log.take(),
+            watch: self.watch.take(),
+        }
+    }
+
+    // Ends a batch that succeeded. The op log gets the writes to `keys`, and
+    // the watch the new root.
+    pub(crate) fn commit_batch<'k>(
+        &mut self,
+        batch: Batch<K, V, N>,
+        keys: impl IntoIterator<Item = &'k K>,
+    ) where
+        K: 'k,
+    {
+        #[cfg(feature = "crdt")]
+        {
+            self.op_log = batch.op_log;
+        }
+        self.log_batch(&batch.root, keys);
+        self.watch = batch.watch;
+        if batch.root.hash() != self.root.hash() {
+            self.notify_watch();
+        }
+    }
+
+    // Ends a batch that failed, putting back the tree it started from. The
+    // work counters keep the failed writes' work.
+    pub(crate) fn rollback_batch(&mut self, batch: Batch<K, V, N>) {
+        self.root = batch.root;
+        self.depth = batch.depth;
+        self.generation = batch.generation;
+        self.content_digest = batch.content_digest;
+        self.recent = batch.recent;
+        self.root_chain = batch.root_chain;
+        #[cfg(feature = "structure-log")]
+        {
+            self.structure_log = batch.structure_log;
+        }
+        #[cfg(feature = "crdt")]
+        {
+            self.op_log = batch.op_log;
+        }
+        self.watch = batch.watch;
+    }
+
+    // Reports a node grown from `entries` children past the soft limit.
+    fn check_growth(&self, entries: usize, node: &Node<K, V, N>) {
+        if let Some(limits) = &self.soft_limits
+            && node.children().len() > entries
+        {
+            limits.check_node(node.children().len());
+        }
+    }
+
+    // Records a split of the node `level` levels below the root, if it split.
+    pub(super) fn log_split(
+        &mut self,
+        level: usize,
+        node: &Node<K, V, N>,
+        sibling: &Option<NodeRef<Node<K, V, N>>>,
+    ) {
+        if sibling.is_some() {
+            self.log(|| StructureEvent::Split {
+                level,
+                at: node.key().clone(),
+            });
+        }
+    }
+
+    #[cfg(feature = "structure-log")]
+    fn log(&mut self, event: impl FnOnce() -> StructureEvent<K>) {
+        let event = event();
+        self.structure_log.push(event);
+    }
+
+    #[cfg(not(feature = "structure-log"))]
+    fn log(&mut self, _event: impl FnOnce() -> StructureEvent<K>) {}
+
+    // Without the `crdt` feature there is no op log to write to; see `ops`.
+    #[cfg(not(feature = "crdt"))]
+    fn logs_ops(&self) -> bool {
+        false
+    }
+
+    #[cfg(not(feature = "crdt"))]
+    fn log_put(&mut self, _key: &K) {}
+
+    #[cfg(not(feature = "crdt"))]
+    fn log_batch<'k>(&mut self, _before: &Node<K, V, N>, _keys: impl IntoIterator<Item = &'k K>)
+    where
+        K: 'k,
+    {
+    }
+
+    // The structural decisions made so far; see `StructureLog`.
+    #[cfg(feature = "structure-log")]
+    pub fn structure_log(&self) -> &StructureLog<K> {
+        &self.structure_log
+    }
+
+    // Returns the log and starts a new one.
+    #[cfg(feature = "structure-log")]
+    pub fn take_structure_log(&mut self) -> StructureLog<K> {
+        std::mem::take(&mut self.structure_log)
+    }
+
+    // Drops root levels with a single internal child.
+    pub(super) fn collapse_root(&mut self) {
+        while let Node::Internal { children, .. } = &*self.root
+            && children.len() == 1
+            && children[0].is_internal()
+        {
+            let only_child = children[0].clone();
+            self.root = only_child;
+            self.depth -= 1;
+            let depth = self.depth;
+            self.log(|| StructureEvent::RootCollapsed { depth });
+        }
+        if self.is_empty() {
+            self.depth = 1;
+        }
+    }
+
+    // Whether inserting `key` would split the root: only when every node on
+    // its path would overflow.
+    fn would_grow(&self, key: &K, value_len: usize) -> bool {
+        let mut node: &Node<K, V, N> = &self.root;
+        loop {
+            let Node::Internal { children, .. } = node else {
+                unreachable!("the walk never lands on a leaf");
+            };
+            if children.is_empty() || !children[0].is_internal() {
+                let existing = children.binary_search_by(|child| child.key().cmp(key)).ok();
+                return self.fanout.overflows(
+                    children,
+                    key,
+                    value_len,
+                    existing.map(|i| &*children[i]),
+                );
+            }
+            // A split below adds one child pointer here.
+            if !self.fanout.overflows(children, key, 0, None) {
+                return false;
+            }
+            node = &children[Node::route(children, key)];
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_simple_insert() {
+        // insert the first three leaves:
+        // 1. insert("key1"): The root has 1 child: [Leaf("key1")]. This is less than 10, so no split.
+        // 2. insert("key3"): The root has 2 children: [Leaf("key1"), Leaf("key3")]. This is less than 10, so no split.
+        // 3. insert("key2"): The root has 3 children: [Leaf("key1"), Leaf("key2"), Leaf("key3")]. This is still less than 10, so no split.
+
+        let mut tree = MerkleSearchTree::<String>::new(10);
+        tree.insert("key1".to_string(), "value1".to_string());
+        tree.insert("key3".to_string(), "value3".to_string());
+        tree.insert("key2".to_string(), "value2".to_string());
+
+        if let Node::Internal { children, .. } = &*tree.root {
+            assert_eq!(children.len(), 3);
+            assert_eq!(children[0].key(), "key1");
+            assert_eq!(children[1].key(), "key2");
+            assert_eq!(children[2].key(), "key3");
+        } else {
+            panic!("Root should be an internal node");
+        }
+    }
+
+    // The keys of the leaves under each child of `node`.
+    fn child_keys(node: &Node<String, String>) -> Vec<Vec<&str>> {
+        node.children()
+            .iter()
+            .map(|child| {
+                child
+                    .children()
+                    .iter()
+                    .map(|leaf| leaf.key().as_str())
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_cascading_split() {
+        let mut tree = MerkleSearchTree::<String>::new(3);
+        // The fourth insert splits the root (height: 1 -> 2).
+        for key in ["10", "20", "30", "40"] {
+            tree.insert(key.to_string(), format!("v{key}"));
+        }
+        // These split the left child in two, leaving the root with three.
+        tree.insert("05".to_string(), "v05".to_string());
+        tree.insert("15".to_string(), "v15".to_string());
+        assert_eq!(tree.depth(), 2);
+
+        // This splits the right child, which propagates up and splits the
+        // root again (height: 2 -> 3).
+        tree.insert("35".to_string(), "v35".to_string());
+        tree.insert("45".to_string(), "v45".to_string());
+        assert_eq!(tree.depth(), 3);
+
+        let root = tree.root.children();
+        assert_eq!(root.len(), 2);
+        assert_eq!(
+            child_keys(&root[0]),
+            vec![vec!["05", "10"], vec!["15", "20"]]
+        );
+        assert_eq!(
+            child_keys(&root[1]),
+            vec![vec!["30", "35"], vec!["40", "45"]]
+        );
+    }
+
+    #[test]
+    fn test_root_split() {
+        let mut tree = MerkleSearchTree::new(3);
+        tree.insert("10".to_string(), "v1".to_string());
+        tree.insert("20".to_string(), "v2".to_string());
+        tree.insert("30".to_string(), "v3".to_string());
+        // The root's children list is now [ L("10"), L("20"), L("30") ].
+        assert_eq!(tree.depth(), 1);
+
+        // Triggers a root split into two groups of two.
+        tree.insert("40".to_string(), "v4".to_string());
+        assert_eq!(tree.depth(), 2);
+        assert!(tree.root.children().iter().all(|child| child.is_internal()));
+        assert_eq!(
+            child_keys(&tree.root),
+            vec![vec!["10", "20"], vec!["30", "40"]]
+        );
+    }
+
+    #[test]
+    fn test_hash_changes() {
+        let mut tree = MerkleSearchTree::<String>::new(10);
+        let initial_hash = *tree.hash();
+
+        tree.insert("key1".to_string(), "value1".to_string());
+        let hash_after_1 = *tree.hash();
+        assert_ne!(initial_hash, hash_after_1);
+
+        tree.insert("key2".to_string(), "value2".to_string());
+        let hash_after_2 = *tree.hash();
+        assert_ne!(hash_after_1, hash_after_2);
+    }
+
+    #[test]
+    fn test_update_existing_key() {
+        let mut tree = MerkleSearchTree::new(4);
+
+        tree.insert(1, "version_1".to_string());
+        let hash_v1 = *tree.hash();
+
+        tree.insert(1, "version_2".to_string()); // Update the value for key 1
+        let hash_v2 = *tree.hash();
+
+        assert_ne!(hash_v1, hash_v2, "Updating a value should change the hash");
+    }
+
+    #[test]
+    fn test_insert_largest_key_fix() {
+        // This test specifically targets the panic we fixed:
+        // "index out of bounds" when inserting a key larger than all current children.
+        let mut tree = MerkleSearchTree::new(3);
+
+        // 1. Insert base keys
+        tree.insert(10, "v10".to_string());
+        tree.insert(20, "v20".to_string());
+
+        // 2. Force a split (max_children = 2), creating a deeper tree
+        // The tree should now have Internal nodes.
+        tree.insert(30, "v30".to_string());
+
+        // 3. Insert a key strictly larger than the current max_key (30)
+        // If the `partition_point` fix is missing, this lines panics.
+        tree.insert(40, "v40".to_string());
+
+        // Verify no panic and structure is sound
+        assert_ne!(tree.hash(), &Default::default());
+    }
+
+    #[test]
+    fn test_depth_limit() {
+        let mut tree = MerkleSearchTree::new(3).with_max_depth(3);
+        let mut inserted = 0;
+        while tree.try_insert(inserted, "v".to_string()).is_ok() {
+            inserted += 1;
+        }
+        assert_eq!(tree.depth(), 3);
+        assert!(matches!(
+            tree.try_insert(inserted, "v".to_string()),
+            Err(Error::DepthLimitExceeded { limit: 3 })
+        ));
+
+        // The rejected insert left the tree untouched, and updates still work.
+        let hash = *tree.hash();
+        assert_eq!(tree.iter().count(), inserted as usize);
+        assert!(tree.try_insert(0, "updated".to_string()).is_ok());
+        assert_ne!(tree.hash(), &hash);
+    }
+
+    #[test]
+    fn test_insert_outcome() {
+        let mut tree = MerkleSearchTree::new(4);
+        for i in 0..50 {
+            assert_eq!(tree.insert(i, format!("v{i}")), InsertOutcome::Inserted);
+        }
+        let fork = tree.fork();
+        let digest = tree.content_digest();
+
+        // Re-ingesting the same data copies no node of the fork's.
+        for i in 0..50 {
+            assert_eq!(tree.insert(i, format!("v{i}")), InsertOutcome::Unchanged);
+        }
+        assert!(NodeRef::ptr_eq(&tree.root, &fork.root));
+        assert_eq!(tree.last_work().nodes_touched, 0);
+        assert_eq!(tree.content_digest(), digest);
+
+        // The fork still shares the old value, so it is cloned out.
+        assert_eq!(
+            tree.insert(7, "new".to_string()),
+            InsertOutcome::Updated("v7".to_string())
+        );
+        assert_ne!(tree.hash(), fork.hash());
+        let previous = tree.insert(7, "newer".to_string()).into_previous();
+        assert_eq!(previous.as_deref(), Some("new"));
+    }
+
+    #[test]
+    fn test_upsert_with() {
+        let sum = |stored: &String, added: String| {
+            (stored.parse::<u64>().unwrap() + added.parse::<u64>().unwrap()).to_string()
+        };
+        let mut tree = MerkleSearchTree::new(4);
+        for i in 0..100u32 {
+            tree.upsert_with(i % 10, "1".to_string(), sum).unwrap();
+        }
+        assert_eq!(tree.len(), 10);
+        assert!(tree.iter().all(|(_, count)| count == "10"));
+
+        let mut expected = MerkleSearchTree::new(4);
+        for i in 0..10u32 {
+            expected.insert(i, "10".to_string());
+        }
+        assert_eq!(tree.hash(), expected.hash());
+        assert_eq!(
+            tree.upsert_with(3, "0".to_string(), sum).unwrap(),
+            InsertOutcome::Unchanged
+        );
+        assert_eq!(
+            tree.upsert_with(3, "5".to_string(), sum).unwrap(),
+            InsertOutcome::Updated("10".to_string())
+        );
+    }
+
+    #[test]
+    fn test_duplicate_policy() {
+        let mut tree =
+            MerkleSearchTree::<u32>::new(4).with_duplicate_policy(DuplicatePolicy::Reject);
+        assert_eq!(tree.insert(1, "a".to_string()), InsertOutcome::Inserted);
+        assert!(matches!(
+            tree.try_insert(1, "b".to_string()),
+            Err(Error::DuplicateKey)
+        ));
+        assert_eq!(
+            tree.insert_with_policy(1, "c".to_string(), DuplicatePolicy::KeepExisting)
+                .unwrap(),
+            InsertOutcome::Unchanged
+        );
+        let longest = |stored: &String, new: String| {
+            if new.len() > stored.len() {
+                new
+            } else {
+                stored.clone()
+            }
+        };
+        assert_eq!(
+            tree.insert_with_policy(1, "dd".to_string(), DuplicatePolicy::Resolve(longest))
+                .unwrap(),
+            InsertOutcome::Updated("a".to_string())
+        );
+        assert_eq!(
+            tree.insert_with_policy(1, "e".to_string(), DuplicatePolicy::Resolve(longest))
+                .unwrap(),
+            InsertOutcome::Unchanged
+        );
+        assert_eq!(tree.get(&1).map(String::as_str), Some("dd"));
+
+        // Forks keep the policy; replays still overwrite.
+        let mut fork = tree.fork();
+        assert!(fork.try_insert(1, "f".to_string()).is_err());
+        fork.insert_replicated(1, "f".to_string()).unwrap();
+        assert_eq!(fork.get(&1).map(String::as_str), Some("f"));
+    }
+
+    #[test]
+    fn test_update_in_place() {
+        let mut tree = MerkleSearchTree::<u32, Vec<u8>>::new(4);
+        for i in 0..200 {
+            tree.insert(i, vec![i as u8; 100]);
+        }
+        let before = tree.fork();
+        // A miss leaves the nodes shared with the fork.
+        assert!(!tree.update(&500, |value| value.clear()));
+        assert!(NodeRef::ptr_eq(&tree.root, &before.root));
+        assert!(tree.update(&42, |value| value.extend_from_slice(b"more")));
+        assert!(!NodeRef::ptr_eq(&tree.root, &before.root));
+
+        let mut expected = before.fork();
+        let mut value = vec![42; 100];
+        value.extend_from_slice(b"more");
+        expected.insert(42, value);
+        assert_eq!(tree.hash(), expected.hash());
+        assert_eq!(tree.usage(..), expected.usage(..));
+        // The fork sharing the leaf kept the old value.
+        assert_eq!(before.get(&42).unwrap().len(), 100);
+        assert_eq!(tree.get(&42).unwrap().len(), 104);
+    }
+
+    #[test]
+    fn test_depth_independent_of_keys() {
+        // Ascending, descending and clustered keys, all with one value hash:
+        // none of them grows the tree past the split bound.
+        let orders: [Vec<u64>; 3] = [
+            (0..4096).collect(),
+            (0..4096).rev().collect(),
+            (0..4096).map(|i| (i % 64) << 32 | i).collect(),
+        ];
+        for max_children in [3, 4] {
+            for keys in &orders {
+                let mut tree = MerkleSearchTree::<u64>::new(max_children);
+                for key in keys {
+                    tree.insert(*key, "same".to_string());
+                }
+                // Nodes split in half hold at least 2 children, so 4096
+                // entries need at most 12 levels below the root.
+                assert!(tree.depth() <= 13, "depth {}", tree.depth());
+            }
+        }
+
+        // A fanout of 2 would split 3 children into 1 and 2, and ascending
+        // keys would chain the single-child halves into a spine.
+        assert!(MerkleSearchTree::<u64>::try_new(2).is_err());
+    }
+
+    #[test]
+    fn test_deep_tree_insert() {
+        // max_children = 3 on many keys yields a deep tree; the iterative insert
+        // must keep every level's hash consistent.
+        let mut tree = MerkleSearchTree::new(3);
+        for i in 0..5000 {
+            tree.insert(i * 7919 % 5000, format!("v{i}"));
+        }
+        assert!(tree.depth() > 7);
+        let mut expected = NodeHash::default();
+        for (key, value) in tree.iter() {
+            expected.xor(&NodeHash::leaf(key, value.as_bytes()));
+        }
+        assert_eq!(tree.hash(), &expected);
+        assert_eq!(tree.iter().count(), 5000);
+    }
+
+    #[test]
+    fn test_work_accounting() {
+        let mut tree = MerkleSearchTree::new(4);
+        tree.insert(1, "abc".to_string());
+        assert_eq!(
+            tree.last_work(),
+            Work {
+                bytes_hashed: 3,
+                nodes_touched: 1,
+                splits: 0,
+                mutations: 1,
+            }
+        );
+
+        for i in 2..=100 {
+            tree.insert(i, "abcd".to_string());
+        }
+        let total = tree.take_work();
+        assert_eq!(total.bytes_hashed, 3 + 99 * 4);
+        assert_eq!(total.mutations, 100);
+        assert!(total.splits > 0);
+        // Every insert touches at least one node per level.
+        assert!(total.nodes_touched >= 100);
+        assert_eq!(tree.total_work(), Work::default());
+
+        // A deep insert touches the whole path.
+        tree.insert(50, "x".to_string());
+        assert_eq!(tree.last_work().nodes_touched, tree.depth() as u64);
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut tree = MerkleSearchTree::new(3);
+        let mut expected = MerkleSearchTree::new(3);
+        for i in 0..200u32 {
+            tree.insert(i, format!("v{i}"));
+            if i % 3 != 0 {
+                expected.insert(i, format!("v{i}"));
+            }
+        }
+        for i in (0..200).step_by(3) {
+            assert!(tree.remove(&i));
+        }
+        assert!(!tree.remove(&0));
+        assert!(!tree.remove(&1000));
+        assert_eq!(tree.hash(), expected.hash());
+        assert_eq!(tree.get(&3), None);
+        assert_eq!(tree.get(&4), Some(&"v4".to_string()));
+        assert!(
+            tree.iter()
+                .map(|(key, _)| key)
+                .eq(expected.iter().map(|(key, _)| key))
+        );
+
+        for i in 0..200 {
+            tree.remove(&i);
+        }
+        assert!(tree.is_empty());
+        assert_eq!(tree.depth(), 1);
+        assert_eq!(*tree.hash(), NodeHash::empty_root());
+        tree.insert(7, "again".to_string());
+        assert_eq!(tree.get(&7), Some(&"again".to_string()));
+    }
+}
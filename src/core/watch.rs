@@ -0,0 +1,237 @@
+// Waiting for a tree to go quiet, e.g. to snapshot it only between bursts
+// of writes.
+//
+// A `RootWatch` is a handle that sees every root change of the tree it came
+// from, including from another thread, without borrowing the tree. It can
+// block until no change has happened for a window, or return a future that
+// resolves then. The future needs no particular runtime: it arms a timer
+// thread for the end of the current window, and runs under tokio or any
+// other executor alike.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::core::codec::Encode;
+use crate::core::hash::NodeHash;
+use crate::core::tree::MerkleSearchTree;
+
+#[derive(Clone)]
+pub struct RootWatch<const N: usize = 32> {
+    state: Arc<Mutex<WatchState<N>>>,
+}
+
+struct WatchState<const N: usize> {
+    root: NodeHash<N>,
+    changed_at: Instant,
+}
+
+impl<const N: usize> RootWatch<N> {
+    // The tree's current root.
+    pub fn root(&self) -> NodeHash<N> {
+        self.lock().root
+    }
+
+    // The time since the last root change, or since the watch was created.
+    pub fn idle(&self) -> Duration {
+        self.lock().changed_at.elapsed()
+    }
+
+    // Blocks until the root hasn't changed for `window`, and returns it.
+    pub fn wait_quiescent(&self, window: Duration) -> NodeHash<N> {
+        loop {
+            match self.quiescent(window) {
+                Ok(root) => return root,
+                Err(remaining) => thread::sleep(remaining),
+            }
+        }
+    }
+
+    // Resolves once the root hasn't changed for `window`, to that root.
+    pub fn await_quiescence(&self, window: Duration) -> Quiescence<N> {
+        Quiescence {
+            watch: self.clone(),
+            window,
+        }
+    }
+
+    // The stable root, or the time left until it could be.
+    fn quiescent(&self, window: Duration) -> Result<NodeHash<N>, Duration> {
+        let state = self.lock();
+        let idle = state.changed_at.elapsed();
+        if idle >= window {
+            Ok(state.root)
+        } else {
+            Err(window - idle)
+        }
+    }
+
+    pub(crate) fn record(&self, root: NodeHash<N>) {
+        let mut state = self.lock();
+        state.root = root;
+        state.changed_at = Instant::now();
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, WatchState<N>> {
+        // The state is two plain fields; a panicking writer can't leave it torn.
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+pub struct Quiescence<const N: usize = 32> {
+    watch: RootWatch<N>,
+    window: Duration,
+}
+
+impl<const N: usize> Future for Quiescence<N> {
+    type Output = NodeHash<N>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<NodeHash<N>> {
+        match self.watch.quiescent(self.window) {
+            Ok(root) => Poll::Ready(root),
+            Err(remaining) => {
+                // Writes only push the end of the window back, so waking at
+                // the current end and checking again is enough.
+                let waker = cx.waker().clone();
+                thread::spawn(move || {
+                    thread::sleep(remaining);
+                    waker.wake();
+                });
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<K: Ord + Clone + Default + Encode, V: AsRef<[u8]>, const N: usize> MerkleSearchTree<K, V, N> {
+    // A watch on this tree's root. All watches taken from a tree share its
+    // state; forks don't report to them. Batches that apply whole, such as
+    // patches, report their final root once, or nothing if they fail.
+    pub fn watch(&mut self) -> RootWatch<N> {
+        let root = *self.hash();
+        self.watch
+            .get_or_insert_with(|| RootWatch {
+                state: Arc::new(Mutex::new(WatchState {
+                    root,
+                    changed_at: Instant::now(),
+                })),
+            })
+            .clone()
+    }
+
+    pub(crate) fn notify_watch(&self) {
+        if let Some(watch) = &self.watch {
+            watch.record(*self.hash());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::buffer::WriteBuffer;
+    use crate::core::hash::EMPTY_ROOT;
+    use crate::core::range::KeyRange;
+    use crate::core::transfer::PendingImport;
+    use std::task::{Wake, Waker};
+
+    struct Unpark(thread::Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = std::pin::pin!(future);
+        let waker = Waker::from(Arc::new(Unpark(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    fn write_burst(mut tree: MerkleSearchTree<u32>) -> thread::JoinHandle<NodeHash> {
+        thread::spawn(move || {
+            for i in 0..5 {
+                tree.insert(i, format!("v{i}"));
+                thread::sleep(Duration::from_millis(10));
+            }
+            *tree.hash()
+        })
+    }
+
+    #[test]
+    fn test_wait_quiescent() {
+        let mut tree = MerkleSearchTree::<u32>::new(4);
+        let watch = tree.watch();
+        assert_eq!(watch.root(), EMPTY_ROOT);
+
+        let started = Instant::now();
+        let writer = write_burst(tree);
+        thread::sleep(Duration::from_millis(5));
+        let root = watch.wait_quiescent(Duration::from_millis(100));
+        assert_eq!(root, writer.join().unwrap());
+        assert!(started.elapsed() >= Duration::from_millis(140));
+        assert!(watch.idle() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_watch_survives_batches() {
+        let mut tree = MerkleSearchTree::<u32>::new(3).with_max_depth(2);
+        let watch = tree.watch();
+        tree.insert(100, "kept".to_string());
+
+        // Batches that fail are rolled back unseen, and the watch stays.
+        let mut buffer = WriteBuffer::new();
+        for i in 0..20 {
+            buffer.insert(i, format!("v{i}"));
+        }
+        assert!(buffer.flush_into(&mut tree).is_err());
+        #[cfg(feature = "crdt")]
+        {
+            let mut to = tree.fork();
+            to.insert(1, "patched".to_string());
+            let mut patch = crate::crdt::patch::create_patch(&tree, &to);
+            patch.post_root = NodeHash::default();
+            assert!(tree.apply_patch(patch).is_err());
+        }
+        assert_eq!(tree.len(), 1);
+        assert_eq!(watch.root(), *tree.hash());
+        tree.insert(101, "later".to_string());
+        assert_eq!(watch.root(), *tree.hash());
+
+        // One that succeeds reports its root, and the writes after it theirs.
+        let mut source = MerkleSearchTree::<u32>::new(4);
+        for i in 0..5 {
+            source.insert(i, format!("v{i}"));
+        }
+        let mut import = PendingImport::new(KeyRange::full());
+        for chunk in source.export_stream(KeyRange::full(), 64) {
+            import.add(chunk).unwrap();
+        }
+        let root = import.commit(&mut tree).unwrap();
+        assert_eq!(watch.root(), root);
+        tree.insert(7, "after".to_string());
+        assert_eq!(watch.root(), *tree.hash());
+    }
+
+    #[test]
+    fn test_await_quiescence() {
+        let mut tree = MerkleSearchTree::<u32>::new(4);
+        let watch = tree.watch();
+        let writer = write_burst(tree);
+        thread::sleep(Duration::from_millis(5));
+        let root = block_on(watch.await_quiescence(Duration::from_millis(100)));
+        assert_eq!(root, writer.join().unwrap());
+    }
+}
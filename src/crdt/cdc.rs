@@ -0,0 +1,183 @@
+// Applying Debezium-style change events from a stream such as a Kafka topic,
+// so a pipeline can keep a verifiable digest of a sink table.
+//
+// An event carries its operation code, the row's key bytes and, for creates,
+// updates and snapshot reads, the row after the change. The consumer
+// deserializes events with whatever its Kafka client uses and hands them
+// over in batches; each batch yields the root after it, to publish next to
+// the batch's last offset. Events at or below the last applied offset are
+// skipped, so a batch redelivered after a consumer restart is harmless, and
+// so are the tombstones Kafka compaction leaves after deletes.
+
+use crate::core::error::Error;
+use crate::core::hash::NodeHash;
+use crate::core::tree::MerkleSearchTree;
+use crate::crdt::materialize::ChangeEvent;
+
+type ByteChange = ChangeEvent<Vec<u8>, Vec<u8>>;
+
+// Debezium's `op` field.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CdcOp {
+    Create,
+    Update,
+    Delete,
+    // A row read by the initial snapshot.
+    Read,
+    // The empty message following a delete on a compacted topic.
+    Tombstone,
+}
+
+impl CdcOp {
+    // Parses Debezium's one-letter codes.
+    pub fn from_code(code: &str) -> Result<Self, Error> {
+        match code {
+            "c" => Ok(CdcOp::Create),
+            "u" => Ok(CdcOp::Update),
+            "d" => Ok(CdcOp::Delete),
+            "r" => Ok(CdcOp::Read),
+            code => Err(Error::Malformed(format!("unknown change op {code:?}"))),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CdcEvent {
+    // The position in the stream, e.g. the Kafka offset.
+    pub offset: u64,
+    pub op: CdcOp,
+    pub key: Vec<u8>,
+    // The row after the change; None for deletes and tombstones.
+    pub after: Option<Vec<u8>>,
+}
+
+impl CdcEvent {
+    fn change(self) -> Result<Option<ByteChange>, Error> {
+        let key = self.key;
+        match (self.op, self.after) {
+            (CdcOp::Create | CdcOp::Update | CdcOp::Read, Some(value)) => {
+                Ok(Some(ChangeEvent::Upsert { key, value }))
+            }
+            (CdcOp::Create | CdcOp::Update | CdcOp::Read, None) => Err(Error::Malformed(format!(
+                "{:?} event at offset {} without a row",
+                self.op, self.offset
+            ))),
+            (CdcOp::Delete, _) => Ok(Some(ChangeEvent::Delete { key })),
+            (CdcOp::Tombstone, _) => Ok(None),
+        }
+    }
+}
+
+// What one batch did.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BatchRoot {
+    // The offset of the batch's last event, applied or skipped.
+    pub offset: Option<u64>,
+    pub root: NodeHash,
+    pub applied: usize,
+    pub skipped: usize,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CdcAdapter {
+    last_offset: Option<u64>,
+}
+
+impl CdcAdapter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Resumes after `offset`, e.g. the one published with the root a tree
+    // was restored to.
+    pub fn resume_after(offset: u64) -> Self {
+        CdcAdapter {
+            last_offset: Some(offset),
+        }
+    }
+
+    pub fn last_offset(&self) -> Option<u64> {
+        self.last_offset
+    }
+
+    // Applies a batch in order. A malformed event fails the batch with the
+    // events before it applied and the offset left at the last of them.
+    pub fn apply_batch(
+        &mut self,
+        tree: &mut MerkleSearchTree<Vec<u8>, Vec<u8>>,
+        events: impl IntoIterator<Item = CdcEvent>,
+    ) -> Result<BatchRoot, Error> {
+        let (mut applied, mut skipped, mut offset) = (0, 0, None);
+        for event in events {
+            offset = Some(event.offset);
+            if self.last_offset.is_some_and(|last| event.offset <= last) {
+                skipped += 1;
+                continue;
+            }
+            let event_offset = event.offset;
+            match event.change()? {
+                Some(change) => {
+                    tree.apply_cdc(change)?;
+                    applied += 1;
+                }
+                None => skipped += 1,
+            }
+            self.last_offset = Some(event_offset);
+        }
+        Ok(BatchRoot {
+            offset,
+            root: tree.root_hash(),
+            applied,
+            skipped,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn event(offset: u64, op: &str, key: &str, after: Option<&str>) -> CdcEvent {
+        CdcEvent {
+            offset,
+            op: CdcOp::from_code(op).unwrap(),
+            key: key.as_bytes().to_vec(),
+            after: after.map(|row| row.as_bytes().to_vec()),
+        }
+    }
+
+    #[test]
+    fn test_apply_batches() {
+        let mut tree = MerkleSearchTree::new(8);
+        let mut adapter = CdcAdapter::new();
+        let first = vec![
+            event(0, "r", "1", Some("{\"name\":\"a\"}")),
+            event(1, "c", "2", Some("{\"name\":\"b\"}")),
+            event(2, "u", "1", Some("{\"name\":\"c\"}")),
+        ];
+        let batch = adapter.apply_batch(&mut tree, first.clone()).unwrap();
+        assert_eq!(
+            (batch.offset, batch.applied, batch.skipped),
+            (Some(2), 3, 0)
+        );
+        assert_eq!(batch.root, tree.root_hash());
+
+        // A redelivered batch changes nothing.
+        let again = adapter.apply_batch(&mut tree, first).unwrap();
+        assert_eq!((again.applied, again.skipped), (0, 3));
+        assert_eq!(again.root, batch.root);
+
+        let mut tombstone = event(4, "d", "2", None);
+        tombstone.op = CdcOp::Tombstone;
+        let second = vec![event(3, "d", "2", Some("ignored")), tombstone];
+        let batch = adapter.apply_batch(&mut tree, second).unwrap();
+        assert_eq!((batch.applied, batch.skipped), (1, 1));
+        assert_eq!(tree.len(), 1);
+        assert_eq!(adapter.last_offset(), Some(4));
+
+        let bad = adapter.apply_batch(&mut tree, [event(5, "c", "3", None)]);
+        assert!(matches!(bad, Err(Error::Malformed(_))));
+        assert_eq!(adapter.last_offset(), Some(4));
+        assert!(CdcOp::from_code("t").is_err());
+    }
+}
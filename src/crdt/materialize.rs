@@ -0,0 +1,146 @@
+// Trees kept as a verification index over an external source of truth, such
+// as a SQL table or a Kafka topic.
+//
+// A `Materializer` reads the whole source; `rebuild_from` builds the tree
+// from it on the side and swaps it in only once the scan succeeded, so a
+// failed rebuild leaves the old contents in place. The scan returns the
+// source's position at that point, e.g. a log offset or a transaction id;
+// change events after it are then applied one by one with `apply_cdc`.
+// Sources report their own failures as `Error::Io`, e.g. through
+// `io::Error::other`.
+
+use crate::core::codec::Encode;
+use crate::core::error::Error;
+use crate::core::tree::{InsertOutcome, MerkleSearchTree};
+
+pub trait Materializer<K, V> {
+    // Feeds every row of the source to `row`, in any order, and returns the
+    // position of the source the rows reflect.
+    fn scan(&mut self, row: &mut dyn FnMut(K, V)) -> Result<u64, Error>;
+}
+
+// One row change in the source.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChangeEvent<K, V> {
+    // The row was inserted or updated.
+    Upsert { key: K, value: V },
+    Delete { key: K },
+}
+
+impl<K: Ord + Clone + Default + Encode, V: AsRef<[u8]>, const N: usize> MerkleSearchTree<K, V, N> {
+    // Replaces the contents with the rows of `source`, returning the
+    // position of the source they reflect. The tree keeps its settings.
+    pub fn rebuild_from(&mut self, source: &mut impl Materializer<K, V>) -> Result<u64, Error>
+    where
+        V: Clone,
+    {
+        let mut fresh = self.fork();
+        fresh.replace_root(Default::default(), 1);
+        let mut failed = None;
+        let position = source.scan(&mut |key, value| {
+            if failed.is_none()
+                && let Err(error) = fresh.try_insert(key, value)
+            {
+                failed = Some(error);
+            }
+        })?;
+        if let Some(error) = failed {
+            return Err(error);
+        }
+        self.replace_root(fresh.root.clone(), fresh.depth);
+        Ok(position)
+    }
+
+    // Applies one change from the source. Returns whether the tree changed.
+    pub fn apply_cdc(&mut self, event: ChangeEvent<K, V>) -> Result<bool, Error>
+    where
+        V: Clone,
+    {
+        match event {
+            ChangeEvent::Upsert { key, value } => self
+                .try_insert(key, value)
+                .map(|outcome| !matches!(outcome, InsertOutcome::Unchanged)),
+            ChangeEvent::Delete { key } => Ok(self.remove(&key)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+    use std::io;
+
+    // A table with a change log, standing in for a database.
+    #[derive(Default)]
+    struct Table {
+        rows: HashMap<u32, String>,
+        log: Vec<ChangeEvent<u32, String>>,
+        offline: bool,
+    }
+
+    impl Table {
+        fn write(&mut self, event: ChangeEvent<u32, String>) {
+            match &event {
+                ChangeEvent::Upsert { key, value } => {
+                    self.rows.insert(*key, value.clone());
+                }
+                ChangeEvent::Delete { key } => {
+                    self.rows.remove(key);
+                }
+            }
+            self.log.push(event);
+        }
+    }
+
+    impl Materializer<u32, String> for Table {
+        fn scan(&mut self, row: &mut dyn FnMut(u32, String)) -> Result<u64, Error> {
+            if self.offline {
+                return Err(io::Error::other("connection refused").into());
+            }
+            for (key, value) in &self.rows {
+                row(*key, value.clone());
+            }
+            Ok(self.log.len() as u64)
+        }
+    }
+
+    #[test]
+    fn test_rebuild_and_follow() {
+        let mut table = Table::default();
+        for i in 0..100 {
+            table.write(ChangeEvent::Upsert {
+                key: i,
+                value: format!("row {i}"),
+            });
+        }
+        let mut index = MerkleSearchTree::<u32>::new(8).with_recent_changes(4);
+        index.insert(1000, "stale".to_string());
+        let position = index.rebuild_from(&mut table).unwrap();
+        assert_eq!(position, 100);
+        assert_eq!(index.len(), 100);
+        assert!(index.recent_changes().unwrap().is_empty());
+
+        table.write(ChangeEvent::Delete { key: 5 });
+        table.write(ChangeEvent::Upsert {
+            key: 7,
+            value: "updated".to_string(),
+        });
+        table.write(ChangeEvent::Delete { key: 5000 });
+        let changed: Vec<bool> = table.log[position as usize..]
+            .iter()
+            .map(|event| index.apply_cdc(event.clone()).unwrap())
+            .collect();
+        assert_eq!(changed, [true, true, false]);
+
+        let mut rebuilt = MerkleSearchTree::<u32>::new(16);
+        rebuilt.rebuild_from(&mut table).unwrap();
+        assert_eq!(rebuilt.hash(), index.hash());
+
+        // A failed scan leaves the tree as it was.
+        table.offline = true;
+        let root = *index.hash();
+        assert!(matches!(index.rebuild_from(&mut table), Err(Error::Io(_))));
+        assert_eq!(*index.hash(), root);
+    }
+}
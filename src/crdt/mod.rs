@@ -0,0 +1,9 @@
+// Trees as replicated data: writes logged as ops, diffs shipped as patches,
+// and trees kept in step with an external change stream. Behind the `crdt`
+// feature.
+
+#[cfg(feature = "cdc")]
+pub mod cdc;
+pub mod materialize;
+pub mod ops;
+pub mod patch;
@@ -0,0 +1,357 @@
+// Building a tree by replaying a log of operations, for event-sourced
+// systems that use the root to check their state, and producing such a log
+// from a tree's own writes.
+//
+// Replaying the same log always gives the same root: the root only depends
+// on the final entries, and the node layout only on the order of the writes,
+// which a log fixes. Metadata is carried along for the application and
+// never hashed, so two logs with the same writes but different sequence
+// numbers or timestamps still agree on the root.
+//
+// A tree with an `OpSink` appends an op for every write that changes it,
+// with the root the write left behind, so replicas replaying the log can
+// check their root after each op. Bulk moves are logged key by key: their
+// intermediate roots follow from the XOR of the leaf hashes. So are batches
+// that apply whole, such as patches, once they succeed; a failed one logs
+// nothing. `restore`
+// replaces the content wholesale and isn't logged; start a new log after it.
+
+use std::collections::BTreeSet;
+use std::sync::mpsc::Sender;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::core::codec::{Decode, Encode};
+use crate::core::config::MaxChildren;
+use crate::core::error::Error;
+use crate::core::hash::NodeHash;
+use crate::core::tree::{MerkleSearchTree, Node};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct OpMeta {
+    // The op's position in its log.
+    pub seq: u64,
+    // When the op was issued, in Unix milliseconds.
+    pub timestamp_ms: u64,
+    // The writer that issued it.
+    pub origin: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Op<K, V> {
+    Put { key: K, value: V, meta: OpMeta },
+    Delete { key: K, meta: OpMeta },
+}
+
+impl<K, V> Op<K, V> {
+    pub fn key(&self) -> &K {
+        match self {
+            Op::Put { key, .. } | Op::Delete { key, .. } => key,
+        }
+    }
+
+    pub fn meta(&self) -> &OpMeta {
+        match self {
+            Op::Put { meta, .. } | Op::Delete { meta, .. } => meta,
+        }
+    }
+}
+
+impl<K: Clone, V: Clone> Op<&K, &V> {
+    pub fn cloned(&self) -> Op<K, V> {
+        match *self {
+            Op::Put { key, value, meta } => Op::Put {
+                key: key.clone(),
+                value: value.clone(),
+                meta,
+            },
+            Op::Delete { key, meta } => Op::Delete {
+                key: key.clone(),
+                meta,
+            },
+        }
+    }
+}
+
+// Receives the ops a tree applies. `op` borrows from the tree, which has
+// already moved to `root`.
+pub trait OpSink<K, V, const N: usize = 32>: Send {
+    fn append(&mut self, op: Op<&K, &V>, root: &NodeHash<N>);
+}
+
+// Sends each op with its root down a channel. Once the receiver is dropped,
+// ops are discarded.
+impl<K, V, const N: usize> OpSink<K, V, N> for Sender<(Op<K, V>, NodeHash<N>)>
+where
+    K: Clone + Send,
+    V: Clone + Send,
+{
+    fn append(&mut self, op: Op<&K, &V>, root: &NodeHash<N>) {
+        let _ = self.send((op.cloned(), *root));
+    }
+}
+
+// A tree's sink, with the metadata for its next op.
+pub(crate) struct OpLog<K, V, const N: usize> {
+    sink: Box<dyn OpSink<K, V, N>>,
+    origin: u64,
+    next_seq: u64,
+}
+
+impl<K, V, const N: usize> OpLog<K, V, N> {
+    fn meta(&mut self) -> OpMeta {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+        let meta = OpMeta {
+            seq: self.next_seq,
+            timestamp_ms,
+            origin: self.origin,
+        };
+        self.next_seq += 1;
+        meta
+    }
+
+    pub(crate) fn put(&mut self, key: &K, value: &V, root: &NodeHash<N>) {
+        let meta = self.meta();
+        self.sink.append(Op::Put { key, value, meta }, root);
+    }
+
+    pub(crate) fn delete(&mut self, key: &K, root: &NodeHash<N>) {
+        let meta = self.meta();
+        self.sink.append(Op::Delete { key, meta }, root);
+    }
+}
+
+impl<K: Ord + Clone + Default + Encode, V: AsRef<[u8]>> MerkleSearchTree<K, V> {
+    // Builds a tree with the default fanout by applying `ops` in order,
+    // failing on the first op the tree refuses.
+    pub fn replay_ops(ops: impl Iterator<Item = Op<K, V>>) -> Result<Self, Error> {
+        let mut tree = MerkleSearchTree::new(MaxChildren::DEFAULT.get());
+        for op in ops {
+            tree.apply_op(op)?;
+        }
+        Ok(tree)
+    }
+}
+
+impl<K: Ord + Clone + Default + Encode, V: AsRef<[u8]>, const N: usize> MerkleSearchTree<K, V, N> {
+    // Appends an op to `sink` for every write from now on, stamped with
+    // `origin` and sequence numbers from 0. Forks don't inherit the sink.
+    pub fn with_op_sink(mut self, origin: u64, sink: impl OpSink<K, V, N> + 'static) -> Self {
+        self.op_log = Some(OpLog {
+            sink: Box::new(sink),
+            origin,
+            next_seq: 0,
+        });
+        self
+    }
+
+    // Stops logging ops.
+    pub fn clear_op_sink(&mut self) {
+        self.op_log = None;
+    }
+
+    pub(crate) fn logs_ops(&self) -> bool {
+        self.op_log.is_some()
+    }
+
+    // Logs the write of `key`, which is in the tree.
+    pub(crate) fn log_put(&mut self, key: &K) {
+        if let Some(mut log) = self.op_log.take() {
+            let value = self.get(key).expect("the key was just written");
+            log.put(key, value, self.hash());
+            self.op_log = Some(log);
+        }
+    }
+
+    // Logs a batch of writes to `keys` that succeeded, one op for each key
+    // it changed from `before`. The roots follow key by key, as for bulk
+    // moves, and only a key's last write shows.
+    pub(crate) fn log_batch<'k>(
+        &mut self,
+        before: &Node<K, V, N>,
+        keys: impl IntoIterator<Item = &'k K>,
+    ) where
+        K: 'k,
+    {
+        let Some(mut log) = self.op_log.take() else {
+            return;
+        };
+        let (mut root, mut entries) = (*before.hash(), before.leaf_count());
+        let mut seen = BTreeSet::new();
+        for key in keys {
+            if !seen.insert(key) {
+                continue;
+            }
+            let (old, new) = (before.find_leaf(key), self.root.find_leaf(key));
+            if old.map(Node::hash) == new.map(Node::hash) {
+                continue;
+            }
+            for leaf in old.into_iter().chain(new) {
+                root.xor(leaf.hash());
+            }
+            entries = entries + usize::from(new.is_some()) - usize::from(old.is_some());
+            let shown = NodeHash::root_of(root, entries);
+            match new {
+                Some(Node::Leaf { value, .. }) => log.put(key, value, &shown),
+                _ => log.delete(key, &shown),
+            }
+        }
+        self.op_log = Some(log);
+    }
+
+    // Applies one op. Fails like `try_insert` for puts the tree's depth limit
+    // or quota rejects; deleting a missing key is not an error.
+    pub fn apply_op(&mut self, op: Op<K, V>) -> Result<(), Error> {
+        match op {
+            Op::Put { key, value, .. } => self.try_write(key, value).map(|_| ()),
+            Op::Delete { key, .. } => {
+                self.remove(&key);
+                Ok(())
+            }
+        }
+    }
+}
+
+const DELETE: u8 = 0;
+const PUT: u8 = 1;
+
+impl Encode for OpMeta {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.seq.encode(out);
+        self.timestamp_ms.encode(out);
+        self.origin.encode(out);
+    }
+
+    fn encoded_len(&self) -> usize {
+        24
+    }
+}
+
+impl Decode for OpMeta {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        Ok(OpMeta {
+            seq: u64::decode(input)?,
+            timestamp_ms: u64::decode(input)?,
+            origin: u64::decode(input)?,
+        })
+    }
+}
+
+impl<K: Encode, V: Encode> Encode for Op<K, V> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Op::Put { key, value, meta } => {
+                PUT.encode(out);
+                meta.encode(out);
+                key.encode(out);
+                value.encode(out);
+            }
+            Op::Delete { key, meta } => {
+                DELETE.encode(out);
+                meta.encode(out);
+                key.encode(out);
+            }
+        }
+    }
+}
+
+impl<K: Decode, V: Decode> Decode for Op<K, V> {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        let tag = u8::decode(input)?;
+        let meta = OpMeta::decode(input)?;
+        let key = K::decode(input)?;
+        match tag {
+            PUT => Ok(Op::Put {
+                key,
+                value: V::decode(input)?,
+                meta,
+            }),
+            DELETE => Ok(Op::Delete { key, meta }),
+            tag => Err(Error::Malformed(format!("unknown op tag {tag}"))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn log(origin: u64) -> Vec<Op<u32, String>> {
+        let mut ops = Vec::new();
+        for i in 0..200u64 {
+            let meta = OpMeta {
+                seq: i,
+                timestamp_ms: 1_700_000_000_000 + i,
+                origin,
+            };
+            let key = (i * 7 % 100) as u32;
+            ops.push(if i % 5 == 4 {
+                Op::Delete { key, meta }
+            } else {
+                Op::Put {
+                    key,
+                    value: format!("v{i}"),
+                    meta,
+                }
+            });
+        }
+        ops
+    }
+
+    #[test]
+    fn test_replay_is_deterministic() {
+        let a = MerkleSearchTree::replay_ops(log(1).into_iter()).unwrap();
+        let b = MerkleSearchTree::replay_ops(log(1).into_iter()).unwrap();
+        assert_eq!(a.hash(), b.hash());
+        assert_eq!(a.level_digests(), b.level_digests());
+
+        // Metadata isn't hashed.
+        let c = MerkleSearchTree::replay_ops(log(2).into_iter()).unwrap();
+        assert_eq!(a.hash(), c.hash());
+
+        let mut expected = std::collections::BTreeMap::new();
+        for op in log(1) {
+            match op {
+                Op::Put { key, value, .. } => expected.insert(key, value),
+                Op::Delete { key, .. } => expected.remove(&key),
+            };
+        }
+        assert_eq!(a.into_btree_map(), expected);
+    }
+
+    #[test]
+    fn test_op_sink() {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let mut tree = MerkleSearchTree::<u32>::new(4).with_op_sink(7, sender);
+        for i in 0..20 {
+            tree.insert(i, format!("v{i}"));
+        }
+        tree.insert(3, "v3".to_string());
+        tree.remove(&4);
+        let moved = tree.extract_subtree(15..);
+        tree.graft(moved).unwrap();
+        tree.fork().insert(100, "unlogged".to_string());
+
+        // Replaying the log passes through every root the tree reported.
+        let mut replica = MerkleSearchTree::new(16);
+        let ops: Vec<_> = receiver.try_iter().collect();
+        assert_eq!(ops.len(), 20 + 1 + 5 + 5);
+        for (seq, (op, root)) in ops.into_iter().enumerate() {
+            assert_eq!((op.meta().seq, op.meta().origin), (seq as u64, 7));
+            replica.apply_op(op).unwrap();
+            assert_eq!(*replica.hash(), root);
+        }
+        assert_eq!(replica.hash(), tree.hash());
+    }
+
+    #[test]
+    fn test_op_encoding() {
+        for op in log(3).into_iter().take(10) {
+            let mut bytes = Vec::new();
+            op.encode(&mut bytes);
+            assert_eq!(Op::decode(&mut bytes.as_slice()).unwrap(), op);
+        }
+        assert!(Op::<u32, String>::decode(&mut [9u8; 40].as_slice()).is_err());
+    }
+}
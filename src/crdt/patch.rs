@@ -0,0 +1,410 @@
+// Patches: the entries that changed between two versions of a tree, plus the
+// root hashes before and after, so a replica that already mostly agrees can
+// catch up by applying a short log instead of syncing.
+//
+// Each run of changed keys also carries the hashes of its range before and
+// after. A replica that has moved past the pre-root can still apply the patch
+// as long as none of those ranges changed locally; otherwise the ranges that
+// no longer match are reported as conflicts.
+//
+// Patches are persisted and exchanged with their 32-byte roots and range
+// hashes, so they are made from and applied to trees of the default width
+// only.
+
+use crate::core::branch::Diff;
+use std::fmt;
+use std::ops::{Bound, RangeInclusive};
+
+use crate::core::codec::{Decode, Encode};
+use crate::core::error::Error;
+use crate::core::hash::NodeHash;
+use crate::core::tree::MerkleSearchTree;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Patch<K, V> {
+    // The root the patch applies to.
+    pub pre_root: NodeHash,
+    // The root after applying it.
+    pub post_root: NodeHash,
+    // New values in key order; None removes the key.
+    pub changes: Vec<(K, Option<V>)>,
+    // The runs of changed keys, with no unchanged key of the old tree in between.
+    pub ranges: Vec<PatchRange<K>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PatchRange<K> {
+    pub range: RangeInclusive<K>,
+    pub pre_hash: NodeHash,
+    pub post_hash: NodeHash,
+}
+
+// A range whose local hash isn't the one the patch expected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RangeConflict<K> {
+    pub range: RangeInclusive<K>,
+    pub expected: NodeHash,
+    pub actual: NodeHash,
+}
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum PatchError<K> {
+    // The tree changed under the patch in these ranges. Nothing was applied.
+    Conflict(Vec<RangeConflict<K>>),
+    // Applying failed, or didn't produce the promised hashes. Nothing was applied.
+    Failed(Error),
+}
+
+impl<K> From<Error> for PatchError<K> {
+    fn from(err: Error) -> Self {
+        PatchError::Failed(err)
+    }
+}
+
+impl<K> fmt::Display for PatchError<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PatchError::Conflict(ranges) => {
+                write!(f, "patch conflicts with {} changed range(s)", ranges.len())
+            }
+            PatchError::Failed(err) => write!(f, "patch failed: {err}"),
+        }
+    }
+}
+
+impl<K: fmt::Debug> std::error::Error for PatchError<K> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PatchError::Conflict(_) => None,
+            PatchError::Failed(err) => Some(err),
+        }
+    }
+}
+
+// The patch that turns `from` into `to`.
+pub fn create_patch<K, V>(from: &MerkleSearchTree<K, V>, to: &MerkleSearchTree<K, V>) -> Patch<K, V>
+where
+    K: Ord + Clone + Default + Encode,
+    V: AsRef<[u8]> + Clone,
+{
+    let changes: Vec<(K, Option<V>)> = from
+        .diff(to)
+        .into_iter()
+        .map(|diff| match diff {
+            Diff::Added(key, value)
+            | Diff::Changed {
+                key, theirs: value, ..
+            } => (key.clone(), Some(value.clone())),
+            Diff::Removed(key, _) => (key.clone(), None),
+        })
+        .collect();
+
+    let mut runs: Vec<RangeInclusive<K>> = Vec::new();
+    for (key, _) in &changes {
+        if let Some(run) = runs.last_mut() {
+            let gap = (Bound::Excluded(run.end()), Bound::Excluded(key));
+            if from.range(gap).next().is_none() {
+                *run = run.start().clone()..=key.clone();
+                continue;
+            }
+        }
+        runs.push(key.clone()..=key.clone());
+    }
+    let ranges = runs
+        .into_iter()
+        .map(|range| PatchRange {
+            pre_hash: from.range_hash(range.clone()),
+            post_hash: to.range_hash(range.clone()),
+            range,
+        })
+        .collect();
+
+    Patch {
+        pre_root: from.root_hash(),
+        post_root: to.root_hash(),
+        changes,
+        ranges,
+    }
+}
+
+impl<K: Ord + Clone + Default + Encode, V: AsRef<[u8]> + Clone> Patch<K, V> {
+    // The patch that undoes this one: it restores the values `pre_state`
+    // held and re-adds the keys this removed. `pre_state` must be at the
+    // pre-root, or it fails with `Error::RootMismatch`. The runs of changed
+    // keys are the same in both directions, so only their hashes swap.
+    pub fn invert(&self, pre_state: &MerkleSearchTree<K, V>) -> Result<Patch<K, V>, Error> {
+        if pre_state.root_hash() != self.pre_root {
+            return Err(Error::RootMismatch {
+                expected: self.pre_root,
+                actual: pre_state.root_hash(),
+            });
+        }
+        Ok(Patch {
+            pre_root: self.post_root,
+            post_root: self.pre_root,
+            changes: self
+                .changes
+                .iter()
+                .map(|(key, _)| (key.clone(), pre_state.get(key).cloned()))
+                .collect(),
+            ranges: self
+                .ranges
+                .iter()
+                .map(|range| PatchRange {
+                    range: range.range.clone(),
+                    pre_hash: range.post_hash,
+                    post_hash: range.pre_hash,
+                })
+                .collect(),
+        })
+    }
+}
+
+impl<K: Ord + Clone + Default + Encode, V: AsRef<[u8]>> MerkleSearchTree<K, V> {
+    // Applies `patch` and returns the new root. At the patch's pre-root the
+    // result must be its post-root. Elsewhere every patched range must still
+    // hash as it did, and must end up as promised. On any error the tree is
+    // left as it was, and its op sink and watch see none of the patch.
+    pub fn apply_patch(&mut self, patch: Patch<K, V>) -> Result<NodeHash, PatchError<K>> {
+        let rebased = self.root_hash() != patch.pre_root;
+        if rebased {
+            let conflicts: Vec<RangeConflict<K>> = patch
+                .ranges
+                .iter()
+                .filter_map(|range| {
+                    let actual = self.range_hash(range.range.clone());
+                    (actual != range.pre_hash).then(|| RangeConflict {
+                        range: range.range.clone(),
+                        expected: range.pre_hash,
+                        actual,
+                    })
+                })
+                .collect();
+            if !conflicts.is_empty() {
+                return Err(PatchError::Conflict(conflicts));
+            }
+        }
+
+        let batch = self.begin_batch();
+        let keys: Vec<K> = patch.changes.iter().map(|(key, _)| key.clone()).collect();
+        let result = patch
+            .changes
+            .into_iter()
+            .try_for_each(|(key, value)| match value {
+                Some(value) => self.try_write(key, value).map(|_| ()),
+                None => {
+                    self.remove(&key);
+                    Ok(())
+                }
+            })
+            .and_then(|()| {
+                let expected = if rebased {
+                    patch.ranges.iter().find_map(|range| {
+                        let actual = self.range_hash(range.range.clone());
+                        (actual != range.post_hash).then_some((range.post_hash, actual))
+                    })
+                } else {
+                    Some((patch.post_root, self.root_hash())).filter(|(post, root)| post != root)
+                };
+                match expected {
+                    Some((expected, actual)) => Err(Error::RootMismatch { expected, actual }),
+                    None => Ok(self.root_hash()),
+                }
+            });
+        match result {
+            Ok(_) => self.commit_batch(batch, &keys),
+            Err(_) => self.rollback_batch(batch),
+        }
+        Ok(result?)
+    }
+}
+
+const REMOVED: u8 = 0;
+const UPSERTED: u8 = 1;
+
+impl<K: Encode, V: Encode> Encode for Patch<K, V> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.pre_root.encode(out);
+        self.post_root.encode(out);
+        (self.changes.len() as u64).encode(out);
+        for (key, value) in &self.changes {
+            key.encode(out);
+            match value {
+                Some(value) => {
+                    UPSERTED.encode(out);
+                    value.encode(out);
+                }
+                None => REMOVED.encode(out),
+            }
+        }
+        (self.ranges.len() as u64).encode(out);
+        for range in &self.ranges {
+            range.range.start().encode(out);
+            range.range.end().encode(out);
+            range.pre_hash.encode(out);
+            range.post_hash.encode(out);
+        }
+    }
+}
+
+impl<K: Decode, V: Decode> Decode for Patch<K, V> {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        let pre_root = NodeHash::decode(input)?;
+        let post_root = NodeHash::decode(input)?;
+        let count = u64::decode(input)?;
+        let changes = (0..count)
+            .map(|_| {
+                let key = K::decode(input)?;
+                let value = match u8::decode(input)? {
+                    REMOVED => None,
+                    UPSERTED => Some(V::decode(input)?),
+                    tag => return Err(Error::Malformed(format!("unknown change tag {tag}"))),
+                };
+                Ok((key, value))
+            })
+            .collect::<Result<_, _>>()?;
+        let count = u64::decode(input)?;
+        let ranges = (0..count)
+            .map(|_| {
+                Ok(PatchRange {
+                    range: K::decode(input)?..=K::decode(input)?,
+                    pre_hash: NodeHash::decode(input)?,
+                    post_hash: NodeHash::decode(input)?,
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(Patch {
+            pre_root,
+            post_root,
+            changes,
+            ranges,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tree(n: u32) -> MerkleSearchTree<u32> {
+        let mut tree = MerkleSearchTree::new(4);
+        for i in 0..n {
+            tree.insert(i, format!("v{i}"));
+        }
+        tree
+    }
+
+    #[test]
+    fn test_patch_round_trip() {
+        let from = tree(300);
+        let mut to = from.fork();
+        to.insert(3, "changed".to_string());
+        to.insert(400, "added".to_string());
+        to.remove(&150);
+
+        let patch = create_patch(&from, &to);
+        assert_eq!(patch.changes.len(), 3);
+        let mut bytes = Vec::new();
+        patch.encode(&mut bytes);
+        let decoded = Patch::decode(&mut bytes.as_slice()).unwrap();
+        assert_eq!(decoded, patch);
+
+        let mut replica = tree(300);
+        assert_eq!(replica.apply_patch(decoded).unwrap(), *to.hash());
+        assert_eq!(replica.get(&150), None);
+        assert_eq!(replica.get(&400), Some(&"added".to_string()));
+    }
+
+    #[test]
+    fn test_invert() {
+        let from = tree(300);
+        let mut to = from.fork();
+        to.insert(3, "changed".to_string());
+        to.insert(400, "added".to_string());
+        to.remove(&150);
+        let patch = create_patch(&from, &to);
+        let undo = patch.invert(&from).unwrap();
+        assert_eq!(undo, create_patch(&to, &from));
+
+        let mut replica = tree(300);
+        replica.apply_patch(patch.clone()).unwrap();
+        assert_eq!(replica.apply_patch(undo).unwrap(), *from.hash());
+        assert!(replica.iter().eq(from.iter()));
+
+        assert!(matches!(patch.invert(&to), Err(Error::RootMismatch { .. })));
+    }
+
+    #[test]
+    fn test_patch_rejects_tampered_changes() {
+        let from = tree(50);
+        let mut to = from.fork();
+        to.insert(1, "changed".to_string());
+        let mut tampered = create_patch(&from, &to);
+        tampered.changes[0].1 = Some("forged".to_string());
+
+        // The failed patch logs nothing, and the sink stays attached.
+        let (sender, ops) = std::sync::mpsc::channel();
+        let mut replica = tree(50).with_op_sink(1, sender);
+        assert!(matches!(
+            replica.apply_patch(tampered.clone()),
+            Err(PatchError::Failed(Error::RootMismatch { .. }))
+        ));
+        assert_eq!(replica.hash(), from.hash());
+        assert!(ops.try_recv().is_err());
+
+        // Also when applied away from the pre-root.
+        replica.insert(40, "local".to_string());
+        let before = *replica.hash();
+        assert!(matches!(
+            replica.apply_patch(tampered),
+            Err(PatchError::Failed(Error::RootMismatch { .. }))
+        ));
+        assert_eq!(*replica.hash(), before);
+        let (op, _) = ops.try_recv().unwrap();
+        assert_eq!((op.key(), op.meta().seq), (&40, 0));
+        assert!(ops.try_recv().is_err());
+
+        // A patch that applies logs its changes once it has.
+        let root = replica.apply_patch(create_patch(&from, &to)).unwrap();
+        let (op, op_root) = ops.try_recv().unwrap();
+        assert_eq!((op.key(), op.meta().seq, op_root), (&1, 1, root));
+        assert!(ops.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_patch_conflicts() {
+        let from = tree(100);
+        let mut to = from.fork();
+        for key in [10, 11, 12, 60] {
+            to.insert(key, "patched".to_string());
+        }
+        let patch = create_patch(&from, &to);
+        assert_eq!(
+            patch
+                .ranges
+                .iter()
+                .map(|r| r.range.clone())
+                .collect::<Vec<_>>(),
+            vec![10..=12, 60..=60]
+        );
+
+        // An unrelated local change doesn't conflict.
+        let mut replica = tree(100);
+        replica.insert(90, "local".to_string());
+        replica.apply_patch(patch.clone()).unwrap();
+        assert_eq!(replica.get(&11), Some(&"patched".to_string()));
+        assert_eq!(replica.get(&90), Some(&"local".to_string()));
+
+        // A local change inside a patched range does.
+        let mut replica = tree(100);
+        replica.insert(11, "local".to_string());
+        let before = *replica.hash();
+        let Err(PatchError::Conflict(conflicts)) = replica.apply_patch(patch) else {
+            panic!("expected a conflict");
+        };
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].range, 10..=12);
+        assert_eq!(*replica.hash(), before);
+    }
+}
@@ -0,0 +1,111 @@
+// Bad peer input is an error, never a panic; see `adversarial`.
+//
+// The crate is layered: `core` holds the tree and is always built, and
+// `store`, `sync`, `proof` and `crdt` build on it behind features of the
+// same name, all on by default. A layer depends on `core` and on the layers
+// its feature enables, never on a layer above it.
+#![forbid(unsafe_code)]
+
+#[cfg(all(
+    test,
+    feature = "store",
+    feature = "sync",
+    feature = "proof",
+    feature = "crdt"
+))]
+mod adversarial;
+pub mod core;
+#[cfg(feature = "crdt")]
+pub mod crdt;
+pub mod prelude;
+#[cfg(feature = "proof")]
+pub mod proof;
+#[cfg(feature = "store")]
+pub mod store;
+#[cfg(feature = "sync")]
+pub mod sync;
+
+pub use crate::core::branch::{Branches, Diff, DiffCursor};
+pub use crate::core::budget::{Budget, Progress};
+pub use crate::core::buffer::WriteBuffer;
+pub use crate::core::chain::RootChain;
+pub use crate::core::checkpoint::{Checkpoint, NoteSignature};
+#[cfg(feature = "bincode")]
+pub use crate::core::coded::BincodeCodec;
+#[cfg(feature = "serde_json")]
+pub use crate::core::coded::JsonCodec;
+pub use crate::core::coded::{BinaryCodec, Coded, RawCodec, Utf8Codec, ValueCodec};
+pub use crate::core::composite::CompositeRoot;
+pub use crate::core::config::{FanoutPolicy, HashScheme, MaxChildren, TreeConfig};
+pub use crate::core::dedup::{SharedValue, ValuePool};
+pub use crate::core::error::Error;
+pub use crate::core::guard::ValueGuard;
+pub use crate::core::hash::{EMPTY_ROOT, NodeHash, is_empty_root};
+pub use crate::core::hashed::HashedTree;
+pub use crate::core::interned::{InternedKey, Interner};
+pub use crate::core::inventory::BucketSummary;
+pub use crate::core::keys::{DecimalKey, PrefixKey, TimestampKey, UuidKey};
+pub use crate::core::limits::{LimitEvent, SoftLimits};
+pub use crate::core::locks::{RangeGuard, RangeLocks};
+pub use crate::core::metrics::Work;
+pub use crate::core::quota::Usage;
+pub use crate::core::range::KeyRange;
+pub use crate::core::recent::RecentChanges;
+pub use crate::core::report::{DiffReport, diff_report};
+pub use crate::core::ring::{OwnerId, Ring, TokenRing};
+pub use crate::core::scan::Scan;
+pub use crate::core::scoped::ScopedTreeView;
+#[cfg(feature = "zeroize")]
+pub use crate::core::secret::SecretKey;
+pub use crate::core::session::SessionToken;
+pub use crate::core::structure::{StructureEvent, StructureLog};
+pub use crate::core::table::{Row, Schema, Table};
+pub use crate::core::tagged::Tagged;
+pub use crate::core::transfer::{ExportChunk, PendingImport};
+pub use crate::core::tree::{DuplicatePolicy, InsertOutcome, MerkleSearchTree};
+pub use crate::core::watch::{Quiescence, RootWatch};
+
+#[cfg(all(feature = "store", feature = "crdt"))]
+pub use crate::store::versions::{Retention, Version, VersionedStore};
+#[cfg(feature = "store")]
+pub use crate::store::{
+    Store,
+    amplification::{AmplificationSample, FanoutHint, WriteAmplification},
+    cache::CachedStore,
+    gc::gc,
+    kv::MstKv,
+    maintenance::{JobReport, Maintenance, MaintenanceHandle},
+    mapped::MappedTree,
+    repair::{CorruptNode, DeepCursor},
+    snapshot::Manifest,
+    stream::SnapshotStream,
+    verify::Verifier,
+};
+
+#[cfg(feature = "proof")]
+pub use crate::proof::{
+    PROOF_VERSION, Proof,
+    commitment::TreeParams,
+    embedded::{PageKey, ProofError, verify_proof},
+    range::RangeProof,
+    sparse::SparseMerkleSearchTree,
+};
+
+#[cfg(feature = "quic")]
+pub use crate::sync::quic::QuicStream;
+#[cfg(feature = "sync")]
+pub use crate::sync::{
+    DivergentRange, FaultKind, PeerFault, SyncPlan, SyncStrategy,
+    gossip::{Gossip, GossipMetrics, PeerId, PeerSelection, PeerStatus, RetryPolicy, SyncReport},
+    handshake::{Agreement, Hello},
+    packet::{DIGEST_PACKET_SIZE, DIGEST_PACKET_VERSION, DigestComparison},
+    transport::{FramedStream, SyncTransport},
+    wire::WireFormat,
+};
+
+#[cfg(feature = "crdt")]
+pub use crate::crdt::{
+    materialize::{ChangeEvent, Materializer},
+    ops::{Op, OpMeta, OpSink},
+    patch::{Patch, PatchError, create_patch},
+};
@@ -1,6 +0,0 @@
-mod hash;
-mod tree;
-
-fn main() {
-    println!("Hello, world!");
-}
@@ -0,0 +1,51 @@
+// The types most users need, for a single glob import:
+// `use mst_rs::prelude::*;`.
+//
+// Each layer's types are exported only when its feature is on: `core` (the
+// tree, `config`, `hash` and `error`) always, `store` and `proof` for
+// persisting and proving, and `sync` for reconciling replicas.
+//
+// Enums that grow as the crate does, errors and events, are
+// `#[non_exhaustive]`, so adding a variant isn't a breaking change.
+
+pub use crate::core::config::{MaxChildren, TreeConfig};
+pub use crate::core::error::Error;
+pub use crate::core::hash::{EMPTY_ROOT, NodeHash};
+pub use crate::core::range::KeyRange;
+pub use crate::core::tree::{DuplicatePolicy, InsertOutcome, MerkleSearchTree};
+#[cfg(feature = "proof")]
+pub use crate::proof::{Proof, range::RangeProof};
+#[cfg(feature = "store")]
+pub use crate::store::{MemoryStore, Store, snapshot::Manifest};
+#[cfg(feature = "sync")]
+pub use crate::sync::{Message, PeerFault, Reconciler, wire::WireFormat};
+
+#[cfg(all(test, feature = "store", feature = "sync"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_prelude_covers_a_sync() {
+        let mut ours = MerkleSearchTree::<u32>::new(8);
+        let mut theirs = MerkleSearchTree::<u32>::new(8);
+        for i in 0..100 {
+            ours.insert(i, format!("v{i}"));
+        }
+        let mut store = MemoryStore::new();
+        let (manifest, _) = ours.write_snapshot(&mut store).unwrap();
+        let mut restored = MerkleSearchTree::<u32>::new(8);
+        restored.restore(&store, &manifest).unwrap();
+        assert_eq!(restored.hash(), ours.hash());
+
+        let reconciler =
+            Reconciler::new(|local: &String, remote: &String| local.max(remote).clone());
+        let mut pending = vec![(true, reconciler.start(&ours))];
+        while let Some((to_theirs, message)) = pending.pop() {
+            let tree = if to_theirs { &mut theirs } else { &mut ours };
+            for reply in reconciler.handle(tree, message) {
+                pending.push((!to_theirs, reply));
+            }
+        }
+        assert_eq!(theirs.hash(), ours.hash());
+    }
+}
@@ -0,0 +1,210 @@
+// Roots that commit to the parameters of the tree they come from.
+//
+// A root hash says nothing about how its tree hashes, splits nodes or
+// encodes keys, so a root or proof taken from one tree could be presented
+// as one from a tree built differently, where the same bytes mean other
+// keys. A committed root is `SHA-256(tag || params || root_page ||
+// root_hash)` instead: the encoded `TreeParams`, the snapshot's root page
+// id and the structural root, all 32 bytes like any snapshot's. Whatever
+// hash scheme the params name, the commitment itself is SHA-256. The page
+// id is a plain digest of the keys and subtree hashes in the root page, so
+// the commitment doesn't rest on the XOR root alone, and a verifier never
+// takes the root page on trust. Advertising committed roots, and checking
+// proofs against them with `verify_committed`, binds both to the
+// parameters.
+//
+// The key encoding isn't visible to the tree, so callers name it, e.g.
+// "u32-be" or "utf8"; any string both sides agree on will do.
+
+use crate::core::codec::{Decode, Encode};
+use crate::core::config::{FanoutPolicy, HashScheme};
+use crate::core::error::Error;
+use crate::core::hash::NodeHash;
+use crate::core::tree::MerkleSearchTree;
+use crate::proof::Proof;
+use crate::store::snapshot::Manifest;
+
+// Separates committed roots from any other digest over similar bytes.
+const COMMITMENT_TAG: &[u8] = b"merkle-search-tree root commitment v2";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TreeParams {
+    pub hash_scheme: HashScheme,
+    pub fanout_policy: FanoutPolicy,
+    pub key_encoding: String,
+}
+
+impl TreeParams {
+    // The parameters of `tree`, which like any snapshot source has the
+    // default 32-byte hashes.
+    pub fn of<K, V>(tree: &MerkleSearchTree<K, V>, key_encoding: &str) -> Self
+    where
+        K: Ord + Clone + Default + Encode,
+        V: AsRef<[u8]>,
+    {
+        TreeParams {
+            hash_scheme: HashScheme::of::<32>(),
+            fanout_policy: tree.fanout_policy(),
+            key_encoding: key_encoding.to_string(),
+        }
+    }
+
+    // A snapshot's root page and root hash committed to these parameters:
+    // the SHA-256 of the tag and all three, a 32-byte hash like its inputs.
+    pub fn commit(&self, root_page: &NodeHash, root_hash: &NodeHash) -> NodeHash {
+        let mut bytes = COMMITMENT_TAG.to_vec();
+        self.encode(&mut bytes);
+        root_page.encode(&mut bytes);
+        root_hash.encode(&mut bytes);
+        NodeHash::digest(&bytes)
+    }
+}
+
+impl Encode for TreeParams {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.hash_scheme.algorithm.encode(out);
+        self.hash_scheme.width.encode(out);
+        match self.fanout_policy {
+            FanoutPolicy::Children(max_children) => {
+                0u8.encode(out);
+                max_children.encode(out);
+            }
+            FanoutPolicy::NodeBytes(target) => {
+                1u8.encode(out);
+                target.encode(out);
+            }
+        }
+        self.key_encoding.encode(out);
+    }
+}
+
+impl Decode for TreeParams {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        Ok(TreeParams {
+            hash_scheme: HashScheme {
+                algorithm: String::decode(input)?,
+                width: u16::decode(input)?,
+            },
+            fanout_policy: match u8::decode(input)? {
+                0 => FanoutPolicy::Children(u32::decode(input)?),
+                1 => FanoutPolicy::NodeBytes(u32::decode(input)?),
+                tag => return Err(Error::Malformed(format!("unknown fanout policy {tag}"))),
+            },
+            key_encoding: String::decode(input)?,
+        })
+    }
+}
+
+impl Manifest {
+    // The snapshot's root committed to the parameters of the tree written.
+    pub fn committed_root(&self, params: &TreeParams) -> NodeHash {
+        params.commit(&self.root_page, &self.root_hash)
+    }
+}
+
+impl Proof {
+    // As `verify`, once `root_page` and `root_hash` are shown to be the
+    // roots `committed` commits to under `params`.
+    pub fn verify_committed<K, V>(
+        &self,
+        params: &TreeParams,
+        committed: &NodeHash,
+        root_page: &NodeHash,
+        root_hash: &NodeHash,
+        key: &K,
+    ) -> Result<Option<V>, Error>
+    where
+        K: Ord + Clone + Default + Encode + Decode,
+        V: AsRef<[u8]> + Decode,
+    {
+        let actual = params.commit(root_page, root_hash);
+        if actual != *committed {
+            return Err(Error::HashMismatch {
+                expected: *committed,
+                actual,
+            });
+        }
+        self.verify(root_page, root_hash, key)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    fn build(entries: &[(u32, String)], max_children: usize) -> MerkleSearchTree<u32> {
+        let mut tree = MerkleSearchTree::new(max_children);
+        for (key, value) in entries {
+            tree.insert(*key, value.clone());
+        }
+        tree
+    }
+
+    fn snapshot(tree: &MerkleSearchTree<u32>, store: &mut MemoryStore) -> (Manifest, NodeHash) {
+        let (manifest, _) = tree.write_snapshot(store).unwrap();
+        let committed = manifest.committed_root(&TreeParams::of(tree, "u32-be"));
+        (manifest, committed)
+    }
+
+    #[test]
+    fn test_commitment_binds_params() {
+        let entries: Vec<_> = (0..100).map(|i| (i, format!("v{i}"))).collect();
+        let tree = build(&entries, 4);
+        let wider = build(&entries, 8);
+        let mut store = MemoryStore::new();
+        let (manifest, committed) = snapshot(&tree, &mut store);
+        let (wider_manifest, wider_committed) = snapshot(&wider, &mut MemoryStore::new());
+        // Same entries, same structural root, different commitments.
+        assert_eq!(manifest.root_hash, wider_manifest.root_hash);
+        assert_ne!(committed, wider_committed);
+        let params = TreeParams::of(&tree, "u32-be");
+        assert_ne!(
+            committed,
+            manifest.committed_root(&TreeParams::of(&tree, "u32-le"))
+        );
+
+        let mut bytes = Vec::new();
+        params.encode(&mut bytes);
+        assert_eq!(TreeParams::decode(&mut bytes.as_slice()).unwrap(), params);
+
+        let proof = manifest.prove::<u32, String, _>(&store, &7).unwrap();
+        let (root_page, root_hash) = (manifest.root_page, manifest.root_hash);
+        let value =
+            proof.verify_committed::<u32, String>(&params, &committed, &root_page, &root_hash, &7);
+        assert_eq!(value.unwrap(), Some("v7".to_string()));
+        // Replayed against a tree advertised with other parameters.
+        let other = TreeParams::of(&wider, "u32-be");
+        assert!(
+            proof
+                .verify_committed::<u32, String>(&other, &committed, &root_page, &root_hash, &7)
+                .is_err()
+        );
+    }
+
+    // A proof from a tree holding the same values under swapped keys is
+    // refused, whichever of its roots it comes with.
+    #[test]
+    fn test_swapped_values_replay() {
+        let honest = build(&[(1, "a".to_string()), (2, "b".to_string())], 4);
+        let forged = build(&[(1, "b".to_string()), (2, "a".to_string())], 4);
+        let params = TreeParams::of(&honest, "u32-be");
+        let (manifest, committed) = snapshot(&honest, &mut MemoryStore::new());
+        let mut store = MemoryStore::new();
+        let (forged_manifest, _) = forged.write_snapshot(&mut store).unwrap();
+        let proof = forged_manifest.prove::<u32, String, _>(&store, &1).unwrap();
+        for (root_page, root_hash) in [
+            (forged_manifest.root_page, forged_manifest.root_hash),
+            (forged_manifest.root_page, manifest.root_hash),
+            (manifest.root_page, manifest.root_hash),
+        ] {
+            assert!(
+                proof
+                    .verify_committed::<u32, String>(
+                        &params, &committed, &root_page, &root_hash, &1
+                    )
+                    .is_err()
+            );
+        }
+    }
+}
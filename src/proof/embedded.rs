@@ -0,0 +1,288 @@
+// Inclusion proof verification for constrained devices.
+//
+// `verify_proof` checks a proof in its binary form, as `Proof::to_bytes`
+// writes it, against a trusted root, reading the pages in place: nothing is
+// decoded into owned keys or values, and nothing is allocated. It uses only
+// `core`, the crate's CRC-32C and the SHA-256 of `NodeHash`, so it can be
+// carried to a `no_std` target such as an HSM or a microcontroller, which
+// then needs a buffer the size of the largest proof it accepts and nothing
+// more.
+//
+// Without decoding, keys are compared as they are encoded in pages, which
+// `PageKey` describes for the integer and string key types. Values must be
+// length-prefixed byte strings, as `String` and `Vec<u8>` encode, and their
+// pages uncompressed.
+
+use core::cmp::Ordering;
+
+use crate::core::codec::crc32c;
+use crate::core::hash::NodeHash;
+use crate::proof::PROOF_VERSION;
+use crate::store::snapshot::{COMPRESSED_LEAF_PAGE, INTERNAL_PAGE, LEAF_PAGE};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ProofError {
+    // The proof or one of its pages doesn't parse, or the path is too short
+    // or too long.
+    Malformed,
+    // A page fails its checksum, or isn't the page its parent points to.
+    PageMismatch,
+    // A page's subtree doesn't hash to what its parent recorded.
+    HashMismatch,
+    // A leaf page with compressed values, which need allocation to read.
+    Compressed,
+}
+
+// How keys of a type sit in pages.
+pub trait PageKey {
+    // The length of the encoded key at the start of `input`.
+    fn encoded_len(input: &[u8]) -> Option<usize>;
+
+    // Orders two encoded keys as the type orders the keys.
+    fn compare(a: &[u8], b: &[u8]) -> Ordering;
+}
+
+// Integers are big-endian with the sign bit flipped, so order bytewise.
+macro_rules! page_key_int {
+    ($($ty:ty),*) => {$(
+        impl PageKey for $ty {
+            fn encoded_len(input: &[u8]) -> Option<usize> {
+                let len = core::mem::size_of::<$ty>();
+                (input.len() >= len).then_some(len)
+            }
+
+            fn compare(a: &[u8], b: &[u8]) -> Ordering {
+                a.cmp(b)
+            }
+        }
+    )*};
+}
+
+page_key_int!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128);
+
+// Strings and byte strings are length-prefixed, and order by their bytes.
+macro_rules! page_key_bytes {
+    ($($ty:ty),*) => {$(
+        impl PageKey for $ty {
+            fn encoded_len(input: &[u8]) -> Option<usize> {
+                let prefix: [u8; 4] = input.get(..4)?.try_into().ok()?;
+                let len = 4 + u32::from_be_bytes(prefix) as usize;
+                (input.len() >= len).then_some(len)
+            }
+
+            fn compare(a: &[u8], b: &[u8]) -> Ordering {
+                a[4..].cmp(&b[4..])
+            }
+        }
+    )*};
+}
+
+page_key_bytes!(String, Vec<u8>);
+
+// The value the proof in `proof` shows for the key encoded as `key`, as it
+// is stored without its length prefix, or None if it shows the key absent.
+pub fn verify_proof<'a, K: PageKey>(
+    proof: &'a [u8],
+    root_page: &NodeHash,
+    root_hash: &NodeHash,
+    key: &[u8],
+) -> Result<Option<&'a [u8]>, ProofError> {
+    if K::encoded_len(key) != Some(key.len()) {
+        return Err(ProofError::Malformed);
+    }
+    let mut input = Cursor(proof);
+    if input.u8()? != PROOF_VERSION {
+        return Err(ProofError::Malformed);
+    }
+    let count = input.u32()?;
+    let (mut id, mut hash) = (*root_page, *root_hash);
+    for depth in 0..count {
+        let len = input.u32()? as usize;
+        let page = input.take(len)?;
+        let last = depth + 1 == count;
+        match step::<K>(&id, &hash, page, key)? {
+            Step::Child(child_hash, child) if !last => (hash, id) = (child_hash, child),
+            Step::Leaf(value) if last && input.0.is_empty() => return Ok(value),
+            _ => return Err(ProofError::Malformed),
+        }
+    }
+    Err(ProofError::Malformed)
+}
+
+enum Step<'a> {
+    Child(NodeHash, NodeHash),
+    Leaf(Option<&'a [u8]>),
+}
+
+// Checks page `id` against its id and `hash`, and takes one step on `key`'s
+// path, as `snapshot::step` does.
+fn step<'a, K: PageKey>(
+    id: &NodeHash,
+    hash: &NodeHash,
+    page: &'a [u8],
+    key: &[u8],
+) -> Result<Step<'a>, ProofError> {
+    let Some(split) = page.len().checked_sub(4) else {
+        return Err(ProofError::PageMismatch);
+    };
+    let (body, checksum) = page.split_at(split);
+    if Cursor(checksum).u32()? != crc32c(body) || NodeHash::digest(page) != *id {
+        return Err(ProofError::PageMismatch);
+    }
+
+    let mut input = Cursor(body);
+    let tag = input.u8()?;
+    let count = input.u32()? as usize;
+    let mut sum = NodeHash::default();
+    let step = match tag {
+        LEAF_PAGE => {
+            let (mut previous, mut found) = (None, None);
+            for _ in 0..count {
+                let entry_key = input.key::<K>()?;
+                if previous.is_some_and(|previous| K::compare(previous, entry_key).is_ge()) {
+                    return Err(ProofError::Malformed);
+                }
+                previous = Some(entry_key);
+                let len = input.u32()? as usize;
+                let value = input.take(len)?;
+                sum.xor(&NodeHash::leaf_encoded(entry_key, value));
+                if K::compare(entry_key, key).is_eq() {
+                    found = Some(value);
+                }
+            }
+            sum = NodeHash::root_of(sum, count);
+            Step::Leaf(found)
+        }
+        INTERNAL_PAGE => {
+            if count == 0 {
+                return Err(ProofError::Malformed);
+            }
+            // A subtree hash and a page id per child, then the max keys.
+            let children = input.take(count.checked_mul(64).ok_or(ProofError::Malformed)?)?;
+            for child in children.chunks(64) {
+                sum.xor(&Cursor(child).hash()?);
+            }
+            let mut index = None;
+            for i in 0..count {
+                let child_key = input.key::<K>()?;
+                if index.is_none() && K::compare(child_key, key).is_ge() {
+                    index = Some(i);
+                }
+            }
+            let mut child = Cursor(&children[index.unwrap_or(count - 1) * 64..]);
+            Step::Child(child.hash()?, child.hash()?)
+        }
+        COMPRESSED_LEAF_PAGE => return Err(ProofError::Compressed),
+        _ => return Err(ProofError::Malformed),
+    };
+    if !input.0.is_empty() {
+        return Err(ProofError::Malformed);
+    }
+    if sum != *hash {
+        return Err(ProofError::HashMismatch);
+    }
+    Ok(step)
+}
+
+struct Cursor<'a>(&'a [u8]);
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ProofError> {
+        if self.0.len() < len {
+            return Err(ProofError::Malformed);
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, ProofError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, ProofError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn hash(&mut self) -> Result<NodeHash, ProofError> {
+        let mut hash = NodeHash::default();
+        hash.0.copy_from_slice(self.take(32)?);
+        Ok(hash)
+    }
+
+    fn key<K: PageKey>(&mut self) -> Result<&'a [u8], ProofError> {
+        let len = K::encoded_len(self.0).ok_or(ProofError::Malformed)?;
+        self.take(len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::codec::Encode;
+    use crate::core::tree::MerkleSearchTree;
+    use crate::store::MemoryStore;
+
+    #[test]
+    fn test_verify_in_place() {
+        let mut tree = MerkleSearchTree::<u32>::new(4);
+        for i in 0..300 {
+            tree.insert(i * 2, format!("v{i}"));
+        }
+        let mut store = MemoryStore::new();
+        let (manifest, _) = tree.write_snapshot(&mut store).unwrap();
+        let (root_page, root_hash) = (manifest.root_page, manifest.root_hash);
+        let proof = |key: u32| {
+            let proof = manifest.prove::<u32, String, _>(&store, &key).unwrap();
+            proof.to_bytes()
+        };
+
+        let present = proof(20);
+        let value = verify_proof::<u32>(&present, &root_page, &root_hash, &20u32.to_be_bytes());
+        assert_eq!(value, Ok(Some(&b"v10"[..])));
+        let absent = proof(21);
+        let value = verify_proof::<u32>(&absent, &root_page, &root_hash, &21u32.to_be_bytes());
+        assert_eq!(value, Ok(None));
+
+        let far = proof(500);
+        assert!(verify_proof::<u32>(&far, &root_page, &root_hash, &21u32.to_be_bytes()).is_err());
+        let forged = NodeHash::digest(b"forged");
+        assert_eq!(
+            verify_proof::<u32>(&present, &root_page, &forged, &20u32.to_be_bytes()),
+            Err(ProofError::HashMismatch)
+        );
+        assert_eq!(
+            verify_proof::<u32>(
+                &present[..present.len() - 1],
+                &root_page,
+                &root_hash,
+                &20u32.to_be_bytes()
+            ),
+            Err(ProofError::Malformed)
+        );
+    }
+
+    #[test]
+    fn test_string_keys() {
+        let mut tree = MerkleSearchTree::<String>::new(4);
+        for i in 0..200 {
+            tree.insert(format!("key {i}"), format!("v{i}"));
+        }
+        let mut store = MemoryStore::new();
+        let (manifest, _) = tree.write_snapshot(&mut store).unwrap();
+        for key in ["key 7", "key 70", "key 700"] {
+            let proof = manifest
+                .prove::<String, String, _>(&store, &key.to_string())
+                .unwrap()
+                .to_bytes();
+            let mut encoded = Vec::new();
+            key.encode(&mut encoded);
+            let value =
+                verify_proof::<String>(&proof, &manifest.root_page, &manifest.root_hash, &encoded);
+            let expected = tree.get(&key.to_string()).map(String::as_bytes);
+            assert_eq!(value, Ok(expected));
+        }
+    }
+}
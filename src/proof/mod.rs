@@ -0,0 +1,533 @@
+// Inclusion and absence proofs over snapshot pages.
+//
+// Internal hashes are the XOR of their children, so a root hash alone can't
+// back a proof: anyone can pick sibling hashes that XOR to it. Page ids can:
+// each is the SHA-256 of its page, and a page holds its children's ids, so
+// the pages from the root page down to a leaf page commit to that leaf
+// page's entries. A proof is that path. It shows a key's value or, since it
+// is the path the key routes along, that the key is absent.
+//
+// Proofs of many keys share their upper pages. `verify_batch` opens each
+// distinct page once, so checking thousands of proofs costs about as many
+// page hashes as there are distinct pages among them.
+//
+// A `RangeProof`, in `range`, shows every entry between two bounds: it
+// carries the pages of every subtree the range overlaps, down to the run of
+// leaf pages between the bounds, so no entry can be left out.
+//
+// Proofs of both kinds cross service boundaries as compact binary or as
+// JSON, both carrying `PROOF_VERSION`. Either is parsed only up to a
+// caller-given size, since a proof is the one thing a verifier accepts from
+// anyone.
+//
+// Proofs are made of snapshot pages, so like snapshots they only exist for
+// trees with the default 32-byte hashes; see `store`.
+
+pub mod commitment;
+pub mod embedded;
+pub mod range;
+pub mod sparse;
+
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::fmt::Write;
+
+use crate::core::codec::{Decode, Encode, take};
+use crate::core::error::Error;
+use crate::core::hash::NodeHash;
+use crate::core::tree::Node;
+use crate::store::Store;
+use crate::store::snapshot::{DecodedPage, Manifest, Step, child_index, open_page, step};
+
+// The version serialized proofs carry. Version 2 hashes leaves over their
+// keys.
+pub const PROOF_VERSION: u8 = 2;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Proof {
+    // The pages on a key's path, from the root page down to a leaf page.
+    pub pages: Vec<Vec<u8>>,
+}
+
+impl Proof {
+    // The value the proof shows for `key`, None if it shows the key is
+    // absent. Fails if the pages don't chain up to `root_page` and
+    // `root_hash`, or aren't the path `key` routes along.
+    pub fn verify<K, V>(
+        &self,
+        root_page: &NodeHash,
+        root_hash: &NodeHash,
+        key: &K,
+    ) -> Result<Option<V>, Error>
+    where
+        K: Ord + Clone + Default + Encode + Decode,
+        V: AsRef<[u8]> + Decode,
+    {
+        let (mut id, mut hash) = (*root_page, *root_hash);
+        for (depth, page) in self.pages.iter().enumerate() {
+            match step::<K, V>(&id, &hash, page, key)? {
+                Step::Child(child, child_hash) => (id, hash) = (child, child_hash),
+                Step::Leaf(value) if depth + 1 == self.pages.len() => return Ok(value),
+                Step::Leaf(_) => {
+                    return Err(Error::Malformed(
+                        "proof continues below a leaf page".to_string(),
+                    ));
+                }
+            }
+        }
+        Err(Error::Malformed(
+            "proof ends above the leaf pages".to_string(),
+        ))
+    }
+
+    // Verifies each `(key, proof)` pair as `verify` would, returning the
+    // values in order, or the first failure.
+    pub fn verify_batch<K, V>(
+        root_page: &NodeHash,
+        root_hash: &NodeHash,
+        proofs: &[(K, Proof)],
+    ) -> Result<Vec<Option<V>>, Error>
+    where
+        K: Ord + Clone + Default + Encode + Decode,
+        V: AsRef<[u8]> + Clone + Decode,
+    {
+        // Opened pages by id, with their subtree hashes. The id fixes the
+        // page, so a page seen before needs only its hash compared.
+        let mut opened: HashMap<NodeHash, (NodeHash, DecodedPage<K, V>)> = HashMap::new();
+        let mut values = Vec::with_capacity(proofs.len());
+        'proofs: for (key, proof) in proofs {
+            let (mut id, mut hash) = (*root_page, *root_hash);
+            for (depth, page) in proof.pages.iter().enumerate() {
+                let (actual, decoded) = match opened.entry(id) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => {
+                        let decoded = open_page::<K, V>(&id, &hash, page)?;
+                        entry.insert((hash, decoded))
+                    }
+                };
+                if *actual != hash {
+                    return Err(Error::HashMismatch {
+                        expected: hash,
+                        actual: *actual,
+                    });
+                }
+                match decoded {
+                    DecodedPage::Internal(children, keys) => {
+                        (hash, id) = children[child_index(children.len(), keys, key)?];
+                    }
+                    DecodedPage::Leaf(_) if depth + 1 < proof.pages.len() => {
+                        return Err(Error::Malformed(
+                            "proof continues below a leaf page".to_string(),
+                        ));
+                    }
+                    DecodedPage::Leaf(node) => {
+                        let children = node.children();
+                        let value = children
+                            .binary_search_by(|child| child.key().cmp(key))
+                            .ok()
+                            .and_then(|index| match &*children[index] {
+                                Node::Leaf { value, .. } => Some(value.clone()),
+                                Node::Internal { .. } => None,
+                            });
+                        values.push(value);
+                        continue 'proofs;
+                    }
+                }
+            }
+            return Err(Error::Malformed(
+                "proof ends above the leaf pages".to_string(),
+            ));
+        }
+        Ok(values)
+    }
+}
+
+impl Proof {
+    // The binary form: the version, the page count, then each page
+    // length-prefixed.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode(&mut out);
+        out
+    }
+
+    // Fails with `Error::MessageTooLarge` if `bytes` is over `max_size`.
+    pub fn from_bytes(bytes: &[u8], max_size: usize) -> Result<Proof, Error> {
+        let pages = pages_from_bytes(bytes, max_size)?;
+        Ok(Proof { pages })
+    }
+
+    // `{"version":2,"pages":["<hex>",...]}`.
+    pub fn to_json(&self) -> String {
+        pages_to_json(&self.pages)
+    }
+
+    // Parses the form `to_json` writes, with any whitespace between tokens.
+    // Fails with `Error::MessageTooLarge` if `json` is over `max_size`.
+    pub fn from_json(json: &str, max_size: usize) -> Result<Proof, Error> {
+        let pages = pages_from_json(json, max_size)?;
+        Ok(Proof { pages })
+    }
+}
+
+impl Encode for Proof {
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_pages(&self.pages, out);
+    }
+}
+
+impl Decode for Proof {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        let pages = decode_pages(input)?;
+        Ok(Proof { pages })
+    }
+}
+
+// Proofs of either kind serialize as their list of pages.
+fn encode_pages(pages: &[Vec<u8>], out: &mut Vec<u8>) {
+    PROOF_VERSION.encode(out);
+    (pages.len() as u32).encode(out);
+    for page in pages {
+        page.encode(out);
+    }
+}
+
+fn decode_pages(input: &mut &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+    check_version(u8::decode(input)? as u64)?;
+    let count = u32::decode(input)? as usize;
+    // Each page takes at least its length prefix.
+    if count > input.len() / 4 {
+        return Err(Error::Malformed(format!(
+            "{count} pages in {} bytes",
+            input.len()
+        )));
+    }
+    (0..count).map(|_| Vec::decode(input)).collect()
+}
+
+fn pages_from_bytes(bytes: &[u8], max_size: usize) -> Result<Vec<Vec<u8>>, Error> {
+    check_size(bytes.len(), max_size)?;
+    let mut input = bytes;
+    let pages = decode_pages(&mut input)?;
+    if !input.is_empty() {
+        return Err(Error::Malformed(format!(
+            "{} bytes after the proof",
+            input.len()
+        )));
+    }
+    Ok(pages)
+}
+
+fn pages_to_json(pages: &[Vec<u8>]) -> String {
+    let mut out = format!("{{\"version\":{PROOF_VERSION},\"pages\":[");
+    for (i, page) in pages.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        out.push('"');
+        for byte in page {
+            let _ = write!(out, "{byte:02x}");
+        }
+        out.push('"');
+    }
+    out.push_str("]}");
+    out
+}
+
+fn pages_from_json(json: &str, max_size: usize) -> Result<Vec<Vec<u8>>, Error> {
+    check_size(json.len(), max_size)?;
+    let mut parser = JsonParser(json.as_bytes());
+    let (mut version, mut pages) = (None, None);
+    parser.expect(b'{')?;
+    loop {
+        match parser.string()?.as_slice() {
+            b"version" => {
+                parser.expect(b':')?;
+                version = Some(parser.number()?);
+            }
+            b"pages" => {
+                parser.expect(b':')?;
+                pages = Some(parser.pages()?);
+            }
+            field => {
+                return Err(Error::Malformed(format!(
+                    "unknown proof field {:?}",
+                    String::from_utf8_lossy(field)
+                )));
+            }
+        }
+        if !parser.next_item(b'}')? {
+            break;
+        }
+    }
+    parser.end()?;
+    check_version(version.ok_or_else(|| missing("version"))?)?;
+    pages.ok_or_else(|| missing("pages"))
+}
+
+fn check_size(size: usize, max_size: usize) -> Result<(), Error> {
+    if size > max_size {
+        return Err(Error::MessageTooLarge {
+            size: size as u64,
+            limit: max_size as u64,
+        });
+    }
+    Ok(())
+}
+
+fn check_version(version: u64) -> Result<(), Error> {
+    if version != PROOF_VERSION as u64 {
+        return Err(Error::Malformed(format!(
+            "unsupported proof version {version}"
+        )));
+    }
+    Ok(())
+}
+
+fn missing(field: &str) -> Error {
+    Error::Malformed(format!("proof without {field}"))
+}
+
+// Just enough JSON for a serialized proof: objects, arrays, unsigned
+// numbers and strings without escapes.
+struct JsonParser<'a>(&'a [u8]);
+
+impl JsonParser<'_> {
+    fn skip_whitespace(&mut self) {
+        while let [b' ' | b'\t' | b'\n' | b'\r', rest @ ..] = self.0 {
+            self.0 = rest;
+        }
+    }
+
+    fn expect(&mut self, token: u8) -> Result<(), Error> {
+        self.skip_whitespace();
+        match self.0 {
+            [first, rest @ ..] if *first == token => {
+                self.0 = rest;
+                Ok(())
+            }
+            _ => Err(Error::Malformed(format!(
+                "expected {:?} in proof json",
+                token as char
+            ))),
+        }
+    }
+
+    // After an item: true if a comma follows, false if `close` does.
+    fn next_item(&mut self, close: u8) -> Result<bool, Error> {
+        self.skip_whitespace();
+        if self.0.first() == Some(&b',') {
+            self.0 = &self.0[1..];
+            return Ok(true);
+        }
+        self.expect(close)?;
+        Ok(false)
+    }
+
+    fn end(&mut self) -> Result<(), Error> {
+        self.skip_whitespace();
+        if !self.0.is_empty() {
+            return Err(Error::Malformed(
+                "trailing data after proof json".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn string(&mut self) -> Result<Vec<u8>, Error> {
+        self.expect(b'"')?;
+        let len = self
+            .0
+            .iter()
+            .position(|&byte| byte == b'"' || byte == b'\\')
+            .ok_or_else(|| Error::Malformed("unterminated string in proof json".to_string()))?;
+        let string = take(&mut self.0, len)?.to_vec();
+        self.expect(b'"')?;
+        Ok(string)
+    }
+
+    fn number(&mut self) -> Result<u64, Error> {
+        self.skip_whitespace();
+        let len = self
+            .0
+            .iter()
+            .take_while(|byte| byte.is_ascii_digit())
+            .count();
+        std::str::from_utf8(take(&mut self.0, len)?)
+            .ok()
+            .and_then(|digits| digits.parse().ok())
+            .ok_or_else(|| Error::Malformed("expected a number in proof json".to_string()))
+    }
+
+    fn pages(&mut self) -> Result<Vec<Vec<u8>>, Error> {
+        self.expect(b'[')?;
+        let mut pages = Vec::new();
+        self.skip_whitespace();
+        if self.0.first() == Some(&b']') {
+            self.0 = &self.0[1..];
+            return Ok(pages);
+        }
+        loop {
+            pages.push(decode_hex(&self.string()?)?);
+            if !self.next_item(b']')? {
+                return Ok(pages);
+            }
+        }
+    }
+}
+
+fn decode_hex(hex: &[u8]) -> Result<Vec<u8>, Error> {
+    let digit = |byte: u8| match byte {
+        b'0'..=b'9' => Ok(byte - b'0'),
+        b'a'..=b'f' => Ok(byte - b'a' + 10),
+        _ => Err(Error::Malformed(format!(
+            "invalid hex digit {:?} in proof json",
+            byte as char
+        ))),
+    };
+    if !hex.len().is_multiple_of(2) {
+        return Err(Error::Malformed("odd-length hex in proof json".to_string()));
+    }
+    hex.chunks(2)
+        .map(|pair| Ok(digit(pair[0])? << 4 | digit(pair[1])?))
+        .collect()
+}
+
+impl Manifest {
+    // A proof of `key`'s value or absence in this snapshot.
+    pub fn prove<K, V, S>(&self, store: &S, key: &K) -> Result<Proof, Error>
+    where
+        K: Ord + Clone + Default + Encode + Decode,
+        V: AsRef<[u8]> + Decode,
+        S: Store,
+    {
+        let mut proof = Proof::default();
+        let (mut id, mut hash) = (self.root_page, self.root_hash);
+        loop {
+            let page = store.get(&id)?.ok_or(Error::MissingPage(id))?;
+            let next = step::<K, V>(&id, &hash, &page, key)?;
+            proof.pages.push(page);
+            match next {
+                Step::Child(child, child_hash) => (id, hash) = (child, child_hash),
+                Step::Leaf(_) => return Ok(proof),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::tree::MerkleSearchTree;
+    use crate::store::MemoryStore;
+
+    #[test]
+    fn test_inclusion_and_absence() {
+        let mut tree = MerkleSearchTree::<u32>::new(4);
+        for i in 0..300 {
+            tree.insert(i * 2, format!("v{i}"));
+        }
+        let mut store = MemoryStore::new();
+        let (manifest, _) = tree.write_snapshot(&mut store).unwrap();
+        let (root_page, root_hash) = (manifest.root_page, manifest.root_hash);
+
+        let proof = manifest.prove::<u32, String, _>(&store, &20).unwrap();
+        assert_eq!(proof.pages.len(), tree.depth());
+        let value = proof.verify::<u32, String>(&root_page, &root_hash, &20);
+        assert_eq!(value.unwrap(), Some("v10".to_string()));
+        let absent = manifest.prove::<u32, String, _>(&store, &21).unwrap();
+        let value = absent.verify::<u32, String>(&root_page, &root_hash, &21);
+        assert_eq!(value.unwrap(), None);
+
+        // The path of another key doesn't prove this one absent.
+        let far = manifest.prove::<u32, String, _>(&store, &500).unwrap();
+        assert!(
+            far.verify::<u32, String>(&root_page, &root_hash, &21)
+                .is_err()
+        );
+        let mut short = proof.clone();
+        short.pages.pop();
+        assert!(
+            short
+                .verify::<u32, String>(&root_page, &root_hash, &20)
+                .is_err()
+        );
+        let forged = NodeHash::digest(b"forged");
+        assert!(
+            proof
+                .verify::<u32, String>(&root_page, &forged, &20)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_verify_batch() {
+        let mut tree = MerkleSearchTree::<u32>::new(4);
+        for i in 0..300 {
+            tree.insert(i * 2, format!("v{i}"));
+        }
+        let mut store = MemoryStore::new();
+        let (manifest, _) = tree.write_snapshot(&mut store).unwrap();
+        let (root_page, root_hash) = (manifest.root_page, manifest.root_hash);
+        let mut proofs: Vec<_> = (0..100)
+            .map(|key| (key, manifest.prove::<u32, String, _>(&store, &key).unwrap()))
+            .collect();
+
+        let values = Proof::verify_batch::<u32, String>(&root_page, &root_hash, &proofs).unwrap();
+        for ((key, proof), value) in proofs.iter().zip(&values) {
+            let single = proof.verify::<u32, String>(&root_page, &root_hash, key);
+            assert_eq!(single.unwrap(), *value);
+        }
+        assert_eq!(values[20], Some("v10".to_string()));
+        assert_eq!(values[21], None);
+
+        // One bad proof fails the batch, even when its pages were seen.
+        proofs[50].0 = 500;
+        assert!(Proof::verify_batch::<u32, String>(&root_page, &root_hash, &proofs).is_err());
+        proofs[50] = (51, proofs[51].1.clone());
+        proofs[51].1.pages.pop();
+        assert!(Proof::verify_batch::<u32, String>(&root_page, &root_hash, &proofs).is_err());
+        let forged = NodeHash::digest(b"forged");
+        assert!(Proof::verify_batch::<u32, String>(&root_page, &forged, &proofs).is_err());
+    }
+
+    #[test]
+    fn test_serialization() {
+        let mut tree = MerkleSearchTree::<u32>::new(4);
+        for i in 0..300 {
+            tree.insert(i, format!("v{i}"));
+        }
+        let mut store = MemoryStore::new();
+        let (manifest, _) = tree.write_snapshot(&mut store).unwrap();
+        let proof = manifest.prove::<u32, String, _>(&store, &42).unwrap();
+
+        let bytes = proof.to_bytes();
+        assert_eq!(Proof::from_bytes(&bytes, bytes.len()).unwrap(), proof);
+        let json = proof.to_json();
+        assert_eq!(Proof::from_json(&json, json.len()).unwrap(), proof);
+        let spaced = json.replace(',', " ,\n ").replace(':', " : ");
+        assert_eq!(Proof::from_json(&spaced, usize::MAX).unwrap(), proof);
+        let empty = Proof::default();
+        assert_eq!(Proof::from_json(&empty.to_json(), 100).unwrap(), empty);
+
+        // Oversized, truncated, other versions and stray data are refused.
+        assert!(matches!(
+            Proof::from_bytes(&bytes, bytes.len() - 1),
+            Err(Error::MessageTooLarge { .. })
+        ));
+        assert!(matches!(
+            Proof::from_json(&json, 10),
+            Err(Error::MessageTooLarge { .. })
+        ));
+        assert!(Proof::from_bytes(&bytes[..bytes.len() - 1], usize::MAX).is_err());
+        let mut newer = bytes.clone();
+        newer[0] = PROOF_VERSION + 1;
+        assert!(Proof::from_bytes(&newer, usize::MAX).is_err());
+        assert!(
+            Proof::from_json(&json.replace("\"version\":2", "\"version\":3"), usize::MAX).is_err()
+        );
+        assert!(Proof::from_json(&format!("{json}x"), usize::MAX).is_err());
+        let bad_digit = format!("{}g\"]}}", &json[..json.len() - 4]);
+        assert!(Proof::from_json(&bad_digit, usize::MAX).is_err());
+    }
+}
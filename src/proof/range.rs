@@ -0,0 +1,248 @@
+// Range proofs: every entry between two bounds, shown to be complete.
+//
+// A range proof holds the page of every subtree whose keys may fall in the
+// range, from the root page down and then depth-first in key order, so it
+// ends in the contiguous run of leaf pages between the bounds. Verifying
+// walks the same subtrees and expects exactly their pages, in that order:
+// a page left out or swapped for another fails its parent's id, and a leaf
+// page with an entry left out fails its own.
+
+use std::ops::RangeBounds;
+
+use crate::core::alloc::NodeRef;
+use crate::core::codec::{Decode, Encode};
+use crate::core::error::Error;
+use crate::core::hash::NodeHash;
+use crate::core::range::KeyRange;
+use crate::core::tree::Node;
+use crate::proof::{decode_pages, encode_pages, pages_from_bytes, pages_from_json, pages_to_json};
+use crate::store::Store;
+use crate::store::snapshot::{DecodedPage, Manifest, open_page};
+use crate::store::stream::wanted_children;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RangeProof {
+    // The pages of the subtrees overlapping the range, depth-first.
+    pub pages: Vec<Vec<u8>>,
+}
+
+impl RangeProof {
+    // The entries the proof shows in `range`, in key order. Fails if the
+    // pages don't chain up to `root_page` and `root_hash`, or aren't
+    // exactly the pages of the subtrees `range` overlaps.
+    pub fn verify<K, V>(
+        &self,
+        root_page: &NodeHash,
+        root_hash: &NodeHash,
+        range: &KeyRange<K>,
+    ) -> Result<Vec<(K, V)>, Error>
+    where
+        K: Ord + Clone + Default + Encode + Decode,
+        V: AsRef<[u8]> + Decode,
+    {
+        let mut entries = Vec::new();
+        let mut pages = self.pages.iter();
+        let mut pending = vec![(*root_page, *root_hash)];
+        while let Some((id, hash)) = pending.pop() {
+            let page = pages.next().ok_or_else(|| {
+                Error::Malformed("range proof ends before the range does".to_string())
+            })?;
+            match open_page::<K, V>(&id, &hash, page)? {
+                DecodedPage::Internal(children, keys) => {
+                    for (child_hash, child) in children[wanted_children(&keys, range)].iter().rev()
+                    {
+                        pending.push((*child, *child_hash));
+                    }
+                }
+                DecodedPage::Leaf(Node::Internal { children, .. }) => {
+                    // A decoded page's leaves aren't shared yet.
+                    entries.extend(children.into_iter().filter_map(
+                        |child| match NodeRef::try_unwrap(child) {
+                            Ok(Node::Leaf { key, value, .. }) if range.contains(&key) => {
+                                Some((key, value))
+                            }
+                            _ => None,
+                        },
+                    ));
+                }
+                DecodedPage::Leaf(_) => {
+                    return Err(Error::Malformed("leaf page without entries".to_string()));
+                }
+            }
+        }
+        if pages.next().is_some() {
+            return Err(Error::Malformed(
+                "range proof continues past the range".to_string(),
+            ));
+        }
+        Ok(entries)
+    }
+
+    // The binary form, laid out as `Proof::to_bytes`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode(&mut out);
+        out
+    }
+
+    // Fails with `Error::MessageTooLarge` if `bytes` is over `max_size`.
+    pub fn from_bytes(bytes: &[u8], max_size: usize) -> Result<RangeProof, Error> {
+        let pages = pages_from_bytes(bytes, max_size)?;
+        Ok(RangeProof { pages })
+    }
+
+    // The JSON form, laid out as `Proof::to_json`.
+    pub fn to_json(&self) -> String {
+        pages_to_json(&self.pages)
+    }
+
+    // Fails with `Error::MessageTooLarge` if `json` is over `max_size`.
+    pub fn from_json(json: &str, max_size: usize) -> Result<RangeProof, Error> {
+        let pages = pages_from_json(json, max_size)?;
+        Ok(RangeProof { pages })
+    }
+}
+
+impl Encode for RangeProof {
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_pages(&self.pages, out);
+    }
+}
+
+impl Decode for RangeProof {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        let pages = decode_pages(input)?;
+        Ok(RangeProof { pages })
+    }
+}
+
+impl Manifest {
+    // A proof of every entry of this snapshot in `range`.
+    pub fn prove_range<K, V, S>(&self, store: &S, range: &KeyRange<K>) -> Result<RangeProof, Error>
+    where
+        K: Ord + Clone + Default + Encode + Decode,
+        V: AsRef<[u8]> + Decode,
+        S: Store,
+    {
+        let mut proof = RangeProof::default();
+        let mut pending = vec![(self.root_page, self.root_hash)];
+        while let Some((id, hash)) = pending.pop() {
+            let page = store.get(&id)?.ok_or(Error::MissingPage(id))?;
+            if let DecodedPage::Internal(children, keys) = open_page::<K, V>(&id, &hash, &page)? {
+                for (child_hash, child) in children[wanted_children(&keys, range)].iter().rev() {
+                    pending.push((*child, *child_hash));
+                }
+            }
+            proof.pages.push(page);
+        }
+        Ok(proof)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::tree::MerkleSearchTree;
+    use crate::store::MemoryStore;
+
+    fn range(start: u32, end: u32) -> KeyRange<u32> {
+        KeyRange {
+            start: Some(start),
+            end: Some(end),
+        }
+    }
+
+    #[test]
+    fn test_range_proof() {
+        let mut tree = MerkleSearchTree::<u32>::new(4);
+        for i in 0..300 {
+            tree.insert(i, format!("v{i}"));
+        }
+        let mut store = MemoryStore::new();
+        let (manifest, _) = tree.write_snapshot(&mut store).unwrap();
+        let (root_page, root_hash) = (manifest.root_page, manifest.root_hash);
+
+        let proof = manifest
+            .prove_range::<u32, String, _>(&store, &range(100, 150))
+            .unwrap();
+        assert!(proof.pages.len() < manifest.pages.len() / 4);
+        let entries = proof
+            .verify::<u32, String>(&root_page, &root_hash, &range(100, 150))
+            .unwrap();
+        let expected: Vec<_> = tree.range(100..150).map(|(k, v)| (*k, v.clone())).collect();
+        assert_eq!(entries, expected);
+
+        // A proof of a narrower range, or of the same one with a page
+        // dropped, doesn't show the whole range.
+        let narrow = manifest
+            .prove_range::<u32, String, _>(&store, &range(100, 120))
+            .unwrap();
+        assert!(
+            narrow
+                .verify::<u32, String>(&root_page, &root_hash, &range(100, 150))
+                .is_err()
+        );
+        let mut dropped = proof.clone();
+        dropped.pages.remove(dropped.pages.len() / 2);
+        assert!(
+            dropped
+                .verify::<u32, String>(&root_page, &root_hash, &range(100, 150))
+                .is_err()
+        );
+
+        // Nor does one whose leaf page leaves a key out.
+        let mut omitted = tree.fork();
+        omitted.remove(&120);
+        let (other, _) = omitted.write_snapshot(&mut store).unwrap();
+        let other = other
+            .prove_range::<u32, String, _>(&store, &range(100, 150))
+            .unwrap();
+        let mut forged = proof.clone();
+        let (index, page) = other
+            .pages
+            .iter()
+            .enumerate()
+            .rfind(|(i, page)| proof.pages.get(*i) != Some(*page))
+            .unwrap();
+        forged.pages[index] = page.clone();
+        assert!(
+            forged
+                .verify::<u32, String>(&root_page, &root_hash, &range(100, 150))
+                .is_err()
+        );
+        assert!(
+            other
+                .verify::<u32, String>(&root_page, &root_hash, &range(100, 150))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_range_proof_serialization() {
+        let mut tree = MerkleSearchTree::<u32>::new(4);
+        for i in 0..300 {
+            tree.insert(i, format!("v{i}"));
+        }
+        let mut store = MemoryStore::new();
+        let (manifest, _) = tree.write_snapshot(&mut store).unwrap();
+        let proof = manifest
+            .prove_range::<u32, String, _>(&store, &range(40, 60))
+            .unwrap();
+
+        let bytes = proof.to_bytes();
+        assert_eq!(RangeProof::from_bytes(&bytes, bytes.len()).unwrap(), proof);
+        let json = proof.to_json();
+        assert_eq!(RangeProof::from_json(&json, json.len()).unwrap(), proof);
+        assert!(matches!(
+            RangeProof::from_bytes(&bytes, bytes.len() - 1),
+            Err(Error::MessageTooLarge { .. })
+        ));
+        assert!(matches!(
+            RangeProof::from_json(&json, json.len() - 1),
+            Err(Error::MessageTooLarge { .. })
+        ));
+        let mut newer = bytes.clone();
+        newer[0] += 1;
+        assert!(RangeProof::from_bytes(&newer, usize::MAX).is_err());
+    }
+}
@@ -0,0 +1,240 @@
+// A tree that holds only some of its subtrees, for light clients and shard
+// routers that don't keep the full data.
+//
+// A sparse tree starts from a snapshot's root page and hash and loads the
+// pages of the key ranges it cares about. Every page is checked against the
+// id and hash its parent recorded, so what it holds is exactly the
+// snapshot's content. Lookups inside the loaded ranges are answered
+// locally; elsewhere they fail with `Error::MissingPage`, and a `Proof` from
+// a full replica answers them instead. The parents of missing subtrees
+// still record their hashes, which `missing_subtrees` lists.
+
+use std::collections::BTreeMap;
+use std::collections::btree_map::Entry;
+use std::marker::PhantomData;
+use std::ops::Bound;
+
+use crate::core::codec::{Decode, Encode};
+use crate::core::error::Error;
+use crate::core::hash::NodeHash;
+use crate::core::range::KeyRange;
+use crate::proof::Proof;
+use crate::store::Store;
+use crate::store::snapshot::{DecodedPage, Manifest, Step, open_page, step};
+
+// The keys a child page covers: above its left neighbour's max key, up to
+// its own. The first child is unbounded below and the last one above.
+type ChildBounds<K> = (Bound<K>, Bound<K>);
+
+pub struct SparseMerkleSearchTree<K, V = String> {
+    root_page: NodeHash,
+    root_hash: NodeHash,
+    // The loaded pages, each checked on arrival.
+    pages: BTreeMap<NodeHash, Vec<u8>>,
+    entries: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K: Ord + Clone + Default + Encode + Decode, V: AsRef<[u8]> + Decode>
+    SparseMerkleSearchTree<K, V>
+{
+    // Holds nothing yet.
+    pub fn new(root_page: NodeHash, root_hash: NodeHash) -> Self {
+        SparseMerkleSearchTree {
+            root_page,
+            root_hash,
+            pages: BTreeMap::new(),
+            entries: PhantomData,
+        }
+    }
+
+    pub fn from_manifest(manifest: &Manifest) -> Self {
+        Self::new(manifest.root_page, manifest.root_hash)
+    }
+
+    pub fn root_page(&self) -> &NodeHash {
+        &self.root_page
+    }
+
+    pub fn root_hash(&self) -> &NodeHash {
+        &self.root_hash
+    }
+
+    pub fn held_pages(&self) -> usize {
+        self.pages.len()
+    }
+
+    // Loads from `store` every page whose subtree may hold keys in `range`,
+    // returning how many weren't held yet.
+    pub fn materialize<S: Store>(&mut self, store: &S, range: KeyRange<K>) -> Result<usize, Error> {
+        let mut loaded = 0;
+        let mut pending = vec![(self.root_page, self.root_hash)];
+        while let Some((id, hash)) = pending.pop() {
+            if let Entry::Vacant(entry) = self.pages.entry(id) {
+                let page = store.get(&id)?.ok_or(Error::MissingPage(id))?;
+                open_page::<K, V>(&id, &hash, &page)?;
+                entry.insert(page);
+                loaded += 1;
+            }
+            let DecodedPage::Internal(children, keys) = self.open(&id, &hash)? else {
+                continue;
+            };
+            for ((child_hash, child), bounds) in children.into_iter().zip(child_bounds(keys)) {
+                if overlaps(&bounds, &range) {
+                    pending.push((child, child_hash));
+                }
+            }
+        }
+        Ok(loaded)
+    }
+
+    // `key`'s value, if the pages on its path are held. Fails with
+    // `Error::MissingPage` at the first page that isn't.
+    pub fn get(&self, key: &K) -> Result<Option<V>, Error> {
+        let (mut id, mut hash) = (self.root_page, self.root_hash);
+        loop {
+            let page = self.pages.get(&id).ok_or(Error::MissingPage(id))?;
+            match step::<K, V>(&id, &hash, page, key)? {
+                Step::Child(child, child_hash) => (id, hash) = (child, child_hash),
+                Step::Leaf(value) => return Ok(value),
+            }
+        }
+    }
+
+    // Whether `get` can answer for `key` without a proof.
+    pub fn is_materialized(&self, key: &K) -> bool {
+        !matches!(self.get(key), Err(Error::MissingPage(_)))
+    }
+
+    // `key`'s value or absence as shown by `proof`, checked against this
+    // tree's root.
+    pub fn verify_proof(&self, key: &K, proof: &Proof) -> Result<Option<V>, Error> {
+        proof.verify(&self.root_page, &self.root_hash, key)
+    }
+
+    // Like `verify_proof`, and keeps the proof's pages, so later lookups
+    // along the same path are answered locally.
+    pub fn absorb(&mut self, key: &K, proof: Proof) -> Result<Option<V>, Error> {
+        let value = self.verify_proof(key, &proof)?;
+        for page in proof.pages {
+            self.pages.insert(NodeHash::digest(&page), page);
+        }
+        Ok(value)
+    }
+
+    // The key bounds and hash of every subtree below a held page that isn't
+    // held itself. With the held leaves, they make up the whole tree.
+    pub fn missing_subtrees(&self) -> Result<Vec<(ChildBounds<K>, NodeHash)>, Error> {
+        let mut missing = Vec::new();
+        let root_bounds = (Bound::Unbounded, Bound::Unbounded);
+        let mut pending = vec![(self.root_page, self.root_hash, root_bounds)];
+        while let Some((id, hash, bounds)) = pending.pop() {
+            if !self.pages.contains_key(&id) {
+                missing.push((bounds, hash));
+                continue;
+            }
+            let DecodedPage::Internal(children, keys) = self.open(&id, &hash)? else {
+                continue;
+            };
+            for ((child_hash, child), (lower, upper)) in
+                children.into_iter().zip(child_bounds(keys))
+            {
+                // A child's bounds can't reach past its parent's.
+                let lower = if matches!(lower, Bound::Unbounded) {
+                    bounds.0.clone()
+                } else {
+                    lower
+                };
+                let upper = if matches!(upper, Bound::Unbounded) {
+                    bounds.1.clone()
+                } else {
+                    upper
+                };
+                pending.push((child, child_hash, (lower, upper)));
+            }
+        }
+        missing.reverse();
+        Ok(missing)
+    }
+
+    fn open(&self, id: &NodeHash, hash: &NodeHash) -> Result<DecodedPage<K, V>, Error> {
+        let page = self.pages.get(id).ok_or(Error::MissingPage(*id))?;
+        open_page(id, hash, page)
+    }
+}
+
+fn child_bounds<K: Clone>(keys: Vec<K>) -> Vec<ChildBounds<K>> {
+    let count = keys.len();
+    let mut lower = Bound::Unbounded;
+    keys.into_iter()
+        .enumerate()
+        .map(|(i, key)| {
+            let upper = if i + 1 == count {
+                Bound::Unbounded
+            } else {
+                Bound::Included(key.clone())
+            };
+            (std::mem::replace(&mut lower, Bound::Excluded(key)), upper)
+        })
+        .collect()
+}
+
+fn overlaps<K: Ord>((lower, upper): &ChildBounds<K>, range: &KeyRange<K>) -> bool {
+    let above_start = match (upper, &range.start) {
+        (Bound::Included(upper), Some(start)) => upper >= start,
+        _ => true,
+    };
+    let below_end = match (lower, &range.end) {
+        (Bound::Excluded(lower), Some(end)) => lower < end,
+        _ => true,
+    };
+    above_start && below_end
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::tree::MerkleSearchTree;
+    use crate::store::MemoryStore;
+
+    #[test]
+    fn test_sparse_tree() {
+        let mut tree = MerkleSearchTree::<u32>::new(4);
+        for i in 0..300 {
+            tree.insert(i, format!("v{i}"));
+        }
+        let mut store = MemoryStore::new();
+        let (manifest, _) = tree.write_snapshot(&mut store).unwrap();
+
+        let mut sparse = SparseMerkleSearchTree::<u32>::from_manifest(&manifest);
+        let range = KeyRange {
+            start: Some(100),
+            end: Some(120),
+        };
+        let loaded = sparse.materialize(&store, range).unwrap();
+        assert!(loaded < manifest.pages.len() / 4);
+        for i in 100..120 {
+            assert_eq!(sparse.get(&i).unwrap(), Some(format!("v{i}")));
+        }
+        assert!(!sparse.is_materialized(&250));
+        assert!(matches!(sparse.get(&250), Err(Error::MissingPage(_))));
+
+        // Every missing subtree's hash matches the full tree's over its bounds.
+        let missing = sparse.missing_subtrees().unwrap();
+        assert!(!missing.is_empty());
+        for (bounds, hash) in &missing {
+            assert_eq!(tree.range_hash(*bounds), *hash);
+        }
+
+        // Proofs from a full replica answer the rest.
+        let proof = manifest.prove::<u32, String, _>(&store, &250).unwrap();
+        let mut forged = proof.clone();
+        *forged.pages.last_mut().unwrap() = store.get(&manifest.root_page).unwrap().unwrap();
+        assert!(sparse.verify_proof(&250, &forged).is_err());
+        assert_eq!(
+            sparse.absorb(&250, proof).unwrap(),
+            Some("v250".to_string())
+        );
+        assert_eq!(sparse.get(&250).unwrap(), Some("v250".to_string()));
+        assert!(sparse.missing_subtrees().unwrap().len() > missing.len());
+    }
+}
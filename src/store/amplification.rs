@@ -0,0 +1,173 @@
+// Write amplification of trees persisted with delta snapshots.
+//
+// A delta snapshot rewrites every page on the path of each changed leaf,
+// and a page is rewritten whole, so one small write costs about `depth`
+// pages of up to `max_children` entries each. A bad fanout can make a store
+// write ten times more than expected without anything failing. Feeding each
+// snapshot's stats and the tree's work since the last one into a
+// `WriteAmplification` keeps rolling ratios over the last few snapshots,
+// and `hint` estimates whether another fanout would write less.
+
+use std::collections::VecDeque;
+
+use crate::core::metrics::Work;
+use crate::store::snapshot::SnapshotStats;
+
+// What one snapshot persisted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AmplificationSample {
+    pub mutations: u64,
+    // The value bytes the mutations wrote.
+    pub value_bytes: u64,
+    pub pages_written: usize,
+    pub bytes_written: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FanoutHint {
+    pub max_children: usize,
+    // Estimated bytes written per mutation, including `page_cost`.
+    pub current_cost: f64,
+    pub suggested_cost: f64,
+}
+
+#[derive(Clone, Debug)]
+pub struct WriteAmplification {
+    window: usize,
+    samples: VecDeque<AmplificationSample>,
+}
+
+impl WriteAmplification {
+    // Fanouts `hint` considers.
+    const CANDIDATES: [usize; 7] = [4, 8, 16, 32, 64, 128, 256];
+
+    // Keeps the last `window` snapshots.
+    pub fn new(window: usize) -> Self {
+        WriteAmplification {
+            window: window.max(1),
+            samples: VecDeque::new(),
+        }
+    }
+
+    // Records a snapshot. `work` is what the tree did since the previous
+    // one, e.g. from `take_work`.
+    pub fn record(&mut self, work: Work, stats: &SnapshotStats) {
+        if self.samples.len() == self.window {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(AmplificationSample {
+            mutations: work.mutations,
+            value_bytes: work.bytes_hashed,
+            pages_written: stats.pages_written,
+            bytes_written: stats.bytes_written,
+        });
+    }
+
+    pub fn samples(&self) -> impl Iterator<Item = &AmplificationSample> {
+        self.samples.iter()
+    }
+
+    // The sum over the window.
+    pub fn total(&self) -> AmplificationSample {
+        let mut total = AmplificationSample::default();
+        for sample in &self.samples {
+            total.mutations += sample.mutations;
+            total.value_bytes += sample.value_bytes;
+            total.pages_written += sample.pages_written;
+            total.bytes_written += sample.bytes_written;
+        }
+        total
+    }
+
+    // Pages written per mutation, None before any mutation.
+    pub fn pages_per_mutation(&self) -> Option<f64> {
+        let total = self.total();
+        (total.mutations > 0).then(|| total.pages_written as f64 / total.mutations as f64)
+    }
+
+    // Bytes written to the store per value byte written to the tree.
+    pub fn amplification(&self) -> Option<f64> {
+        let total = self.total();
+        (total.value_bytes > 0).then(|| total.bytes_written as f64 / total.value_bytes as f64)
+    }
+
+    // The fanout that would write the fewest bytes per mutation for a tree
+    // of `entries` entries now using `max_children`, if it beats the current
+    // one by a fifth. Each page write is charged `page_cost` bytes on top of
+    // its size, e.g. a disk block for a store that pays per write; with a
+    // cost of zero, narrow nodes always win.
+    //
+    // The estimate scales the measured pages: a mutation rewrites one page
+    // per level, and a page's entries, not its fixed header, grow with the
+    // fanout.
+    pub fn hint(
+        &self,
+        entries: usize,
+        max_children: usize,
+        page_cost: usize,
+    ) -> Option<FanoutHint> {
+        let total = self.total();
+        if total.mutations == 0 || total.pages_written == 0 {
+            return None;
+        }
+        let page_bytes = total.bytes_written as f64 / total.pages_written as f64;
+        let entry_bytes = page_bytes / max_children as f64;
+        let levels = |fanout: usize| ((entries.max(2) as f64).ln() / (fanout as f64).ln()).ceil();
+        let cost =
+            |fanout: usize| levels(fanout) * (page_cost as f64 + entry_bytes * fanout as f64);
+
+        let current_cost = cost(max_children);
+        let (best, suggested_cost) = Self::CANDIDATES
+            .iter()
+            .map(|fanout| (*fanout, cost(*fanout)))
+            .min_by(|a, b| a.1.total_cmp(&b.1))?;
+        (suggested_cost < current_cost * 0.8).then_some(FanoutHint {
+            max_children: best,
+            current_cost,
+            suggested_cost,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::tree::MerkleSearchTree;
+    use crate::store::MemoryStore;
+
+    fn measure(max_children: usize) -> (WriteAmplification, usize) {
+        let mut tree = MerkleSearchTree::<u32>::new(max_children);
+        for i in 0..5000 {
+            tree.insert(i, format!("value {i}"));
+        }
+        let mut store = MemoryStore::new();
+        let (mut manifest, _) = tree.write_snapshot(&mut store).unwrap();
+        tree.take_work();
+
+        let mut amplification = WriteAmplification::new(4);
+        for round in 0..6 {
+            tree.insert(round * 997, format!("changed {round}"));
+            let (next, stats) = tree.write_delta_snapshot(&mut store, &manifest).unwrap();
+            amplification.record(tree.take_work(), &stats);
+            manifest = next;
+        }
+        (amplification, tree.depth())
+    }
+
+    #[test]
+    fn test_write_amplification() {
+        let (wide, depth) = measure(256);
+        assert_eq!(wide.samples().count(), 4);
+        assert_eq!(wide.total().mutations, 4);
+        assert_eq!(wide.pages_per_mutation(), Some(depth as f64));
+        let (narrow, _) = measure(8);
+        assert!(wide.amplification().unwrap() > 2.0 * narrow.amplification().unwrap());
+
+        // Without a per-page cost, the wide tree should narrow; with a
+        // large one, the narrow tree should widen.
+        let hint = wide.hint(5000, 256, 0).unwrap();
+        assert!(hint.max_children < 256 && hint.suggested_cost < hint.current_cost);
+        assert!(narrow.hint(5000, 8, 1 << 20).unwrap().max_children > 8);
+        assert_eq!(narrow.hint(5000, 8, 64), None);
+    }
+}
@@ -0,0 +1,108 @@
+// Seeding a new replica from an untrusted mirror.
+//
+// The download walks the remote snapshot one level at a time, asking the
+// mirror for each level's pages in a single batch. Pages are content-
+// addressed and each parent records its children's hashes, so every page is
+// checked as it arrives: against its id, and its subtree against the hash
+// its parent recorded. A mirror that serves anything else is caught at the
+// first bad page, before the rest of the tree is fetched.
+
+use std::collections::BTreeSet;
+
+use crate::core::codec::{Decode, Encode};
+use crate::core::config::MaxChildren;
+use crate::core::error::Error;
+use crate::core::hash::NodeHash;
+use crate::core::tree::MerkleSearchTree;
+use crate::store::repair::check_page;
+use crate::store::snapshot::Manifest;
+use crate::store::{MemoryStore, Store};
+
+impl<K, V> MerkleSearchTree<K, V>
+where
+    K: Ord + Clone + Default + Encode + Decode,
+    V: AsRef<[u8]> + Encode + Decode,
+{
+    // Downloads the snapshot `remote` describes into a tree with the default
+    // fanout. `fetch_nodes` returns the mirror's pages for a batch of ids, in
+    // the same order. Pages that don't match fail with `Error::Malformed`.
+    pub fn bootstrap<F>(remote: &Manifest, mut fetch_nodes: F) -> Result<Self, Error>
+    where
+        F: FnMut(&[NodeHash]) -> Result<Vec<Vec<u8>>, Error>,
+    {
+        let mut store = MemoryStore::new();
+        let mut seen = BTreeSet::from([remote.root_page]);
+        let mut level = vec![(remote.root_page, remote.root_hash)];
+        while !level.is_empty() {
+            let ids: Vec<NodeHash> = level.iter().map(|(page, _)| *page).collect();
+            let pages = fetch_nodes(&ids)?;
+            if pages.len() != ids.len() {
+                return Err(Error::Malformed(format!(
+                    "asked the mirror for {} pages, got {}",
+                    ids.len(),
+                    pages.len()
+                )));
+            }
+
+            let mut next = Vec::new();
+            for ((page, hash), bytes) in level.into_iter().zip(pages) {
+                let children = check_page::<K, V>(&bytes, page, hash).ok_or_else(|| {
+                    Error::Malformed(format!("mirror page {page} does not match its hash"))
+                })?;
+                store.put(page, &bytes)?;
+                next.extend(
+                    children
+                        .into_iter()
+                        .filter(|(child, _)| seen.insert(*child)),
+                );
+            }
+            level = next;
+        }
+
+        let mut tree = MerkleSearchTree::new(MaxChildren::DEFAULT.get());
+        tree.restore(&store, remote)?;
+        Ok(tree)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bootstrap() {
+        let mut source = MerkleSearchTree::<u32>::new(4);
+        for i in 0..300 {
+            source.insert(i, format!("v{i}"));
+        }
+        let mut mirror = MemoryStore::new();
+        let (manifest, _) = source.write_snapshot(&mut mirror).unwrap();
+        let fetch = |ids: &[NodeHash]| -> Result<Vec<Vec<u8>>, Error> {
+            ids.iter()
+                .map(|id| mirror.get(id)?.ok_or(Error::MissingPage(*id)))
+                .collect()
+        };
+
+        let mut batches = 0;
+        let tree = MerkleSearchTree::<u32>::bootstrap(&manifest, |ids| {
+            batches += 1;
+            fetch(ids)
+        })
+        .unwrap();
+        assert_eq!(tree.hash(), source.hash());
+        assert_eq!(batches, source.depth());
+
+        // A forged page stops the download at its level.
+        let mut batches = 0;
+        let result = MerkleSearchTree::<u32>::bootstrap(&manifest, |ids| {
+            batches += 1;
+            let mut pages = fetch(ids)?;
+            if batches == 2 {
+                pages[1][20] ^= 1;
+            }
+            Ok(pages)
+        });
+        assert!(matches!(result, Err(Error::Malformed(_))));
+        assert_eq!(batches, 2);
+    }
+}
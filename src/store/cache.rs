@@ -0,0 +1,255 @@
+// A page cache in front of a store, for trees served straight from storage.
+//
+// Pages are content-addressed, so a cached page never goes stale; the cache
+// only has to bound its size, and drops the page loaded longest ago first.
+// For predictable sequential workloads, `prefetch_range` loads the pages a
+// scan of a key range will read on a background thread, so the scan itself
+// finds them in memory. A range with more pages than the cache holds only
+// stays partly cached.
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::thread::{self, JoinHandle};
+
+use crate::core::codec::{Decode, Encode};
+use crate::core::error::Error;
+use crate::core::hash::NodeHash;
+use crate::core::range::KeyRange;
+use crate::store::Store;
+use crate::store::snapshot::{DecodedPage, Manifest, open_page};
+use crate::store::stream::wanted_children;
+
+// Clones share the cache and the store, e.g. with a prefetch thread.
+pub struct CachedStore<S> {
+    shared: Arc<Shared<S>>,
+}
+
+struct Shared<S> {
+    store: RwLock<S>,
+    cache: Mutex<PageCache>,
+}
+
+struct PageCache {
+    capacity: usize,
+    pages: HashMap<NodeHash, Vec<u8>>,
+    // Cached ids, oldest first.
+    order: VecDeque<NodeHash>,
+    hits: u64,
+    misses: u64,
+}
+
+impl<S: Store> CachedStore<S> {
+    // Caches up to `capacity` pages.
+    pub fn new(store: S, capacity: usize) -> Self {
+        CachedStore {
+            shared: Arc::new(Shared {
+                store: RwLock::new(store),
+                cache: Mutex::new(PageCache {
+                    capacity,
+                    pages: HashMap::new(),
+                    order: VecDeque::new(),
+                    hits: 0,
+                    misses: 0,
+                }),
+            }),
+        }
+    }
+
+    pub fn cached_pages(&self) -> usize {
+        self.cache().pages.len()
+    }
+
+    // Reads served from the cache and from the store.
+    pub fn hits(&self) -> u64 {
+        self.cache().hits
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.cache().misses
+    }
+
+    fn cache(&self) -> MutexGuard<'_, PageCache> {
+        // Every update leaves the map and the queue in step.
+        self.shared
+            .cache
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl<S: Store + Send + Sync + 'static> CachedStore<S> {
+    // Loads the pages of `manifest`'s snapshot that a scan of `range` reads,
+    // in scan order, on a background thread. The thread returns how many
+    // pages it loaded, or the first page it couldn't.
+    pub fn prefetch_range<K, V>(
+        &self,
+        manifest: &Manifest,
+        range: KeyRange<K>,
+    ) -> JoinHandle<Result<usize, Error>>
+    where
+        K: Ord + Clone + Default + Encode + Decode + Send + 'static,
+        V: AsRef<[u8]> + Decode,
+    {
+        let store = self.clone();
+        let root = (manifest.root_page, manifest.root_hash);
+        thread::spawn(move || {
+            let (mut pending, mut loaded) = (vec![root], 0);
+            while let Some((id, hash)) = pending.pop() {
+                let page = store.get(&id)?.ok_or(Error::MissingPage(id))?;
+                loaded += 1;
+                if let DecodedPage::Internal(children, keys) = open_page::<K, V>(&id, &hash, &page)?
+                {
+                    for (child_hash, child) in children[wanted_children(&keys, &range)].iter().rev()
+                    {
+                        pending.push((*child, *child_hash));
+                    }
+                }
+            }
+            Ok(loaded)
+        })
+    }
+}
+
+impl<S> Clone for CachedStore<S> {
+    fn clone(&self) -> Self {
+        CachedStore {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<S: Store> Store for CachedStore<S> {
+    fn get(&self, id: &NodeHash) -> io::Result<Option<Vec<u8>>> {
+        {
+            let mut cache = self.cache();
+            if let Some(page) = cache.pages.get(id).cloned() {
+                cache.hits += 1;
+                return Ok(Some(page));
+            }
+            cache.misses += 1;
+        }
+        // Read without holding the cache, so hits go on meanwhile.
+        let page = read(&self.shared.store).get(id)?;
+        if let Some(page) = &page {
+            self.cache().insert(*id, page.clone());
+        }
+        Ok(page)
+    }
+
+    fn put(&mut self, id: NodeHash, page: &[u8]) -> io::Result<()> {
+        write(&self.shared.store).put(id, page)
+    }
+
+    fn contains(&self, id: &NodeHash) -> io::Result<bool> {
+        if self.cache().pages.contains_key(id) {
+            return Ok(true);
+        }
+        read(&self.shared.store).contains(id)
+    }
+
+    fn delete(&mut self, id: &NodeHash) -> io::Result<bool> {
+        self.cache().remove(id);
+        write(&self.shared.store).delete(id)
+    }
+
+    fn ids(&self) -> io::Result<Vec<NodeHash>> {
+        read(&self.shared.store).ids()
+    }
+}
+
+impl PageCache {
+    fn insert(&mut self, id: NodeHash, page: Vec<u8>) {
+        if self.capacity == 0 || self.pages.insert(id, page).is_some() {
+            return;
+        }
+        self.order.push_back(id);
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.pages.remove(&oldest);
+            }
+        }
+    }
+
+    fn remove(&mut self, id: &NodeHash) {
+        if self.pages.remove(id).is_some() {
+            self.order.retain(|cached| cached != id);
+        }
+    }
+}
+
+// A store call that panicked left the store as its own code did.
+fn read<S>(store: &RwLock<S>) -> std::sync::RwLockReadGuard<'_, S> {
+    store
+        .read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn write<S>(store: &RwLock<S>) -> std::sync::RwLockWriteGuard<'_, S> {
+    store
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::tree::MerkleSearchTree;
+    use crate::store::MemoryStore;
+
+    #[test]
+    fn test_prefetch_range() {
+        let mut tree = MerkleSearchTree::<u32>::new(4);
+        for i in 0..2000 {
+            tree.insert(i, format!("v{i}"));
+        }
+        let mut store = CachedStore::new(MemoryStore::new(), 10_000);
+        let (manifest, _) = tree.write_snapshot(&mut store).unwrap();
+        let range = KeyRange {
+            start: Some(500),
+            end: Some(700),
+        };
+        let loaded = store
+            .prefetch_range::<u32, String>(&manifest, range.clone())
+            .join()
+            .unwrap()
+            .unwrap();
+        assert!(loaded > 0 && loaded < manifest.pages.len());
+        assert_eq!(store.cached_pages(), loaded);
+        assert_eq!(store.misses() as usize, loaded);
+
+        // Reads in the range find every page cached.
+        for key in 500u32..700 {
+            let value: Option<String> = manifest.get(&store, &key).unwrap();
+            assert_eq!(value, Some(format!("v{key}")));
+        }
+        assert_eq!(store.misses() as usize, loaded);
+        assert!(store.hits() > 0);
+    }
+
+    #[test]
+    fn test_capacity() {
+        let mut tree = MerkleSearchTree::<u32>::new(4);
+        for i in 0..500 {
+            tree.insert(i, format!("v{i}"));
+        }
+        let mut store = CachedStore::new(MemoryStore::new(), 8);
+        let (manifest, _) = tree.write_snapshot(&mut store).unwrap();
+        let loaded = store
+            .prefetch_range::<u32, String>(&manifest, KeyRange::full())
+            .join()
+            .unwrap()
+            .unwrap();
+        assert_eq!(loaded, manifest.pages.len());
+        assert_eq!(store.cached_pages(), 8);
+        let evicted = manifest.root_page;
+        store.delete(&evicted).unwrap();
+        assert!(
+            store
+                .prefetch_range::<u32, String>(&manifest, KeyRange::full())
+                .join()
+                .unwrap()
+                .is_err()
+        );
+    }
+}
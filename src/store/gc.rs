@@ -0,0 +1,113 @@
+// Garbage collection of store pages.
+//
+// Snapshots share unchanged pages, so pages can't be deleted along with a
+// manifest. Instead, everything reachable from the roots still in use is
+// marked, and every other page in the store is swept.
+
+use std::collections::BTreeSet;
+
+use crate::core::error::Error;
+use crate::core::hash::NodeHash;
+use crate::store::Store;
+use crate::store::snapshot::child_pages;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GcReport {
+    pub pages_live: usize,
+    pub pages_reclaimed: usize,
+    pub bytes_reclaimed: u64,
+}
+
+// Deletes every page not reachable from `live_roots` (root page ids, as in
+// `Manifest::root_page`). Nothing is deleted if marking fails, e.g. because
+// a live root references a missing page.
+pub fn gc<S: Store>(store: &mut S, live_roots: &[NodeHash]) -> Result<GcReport, Error> {
+    let live = mark(store, live_roots)?;
+
+    let mut report = GcReport {
+        pages_live: live.len(),
+        ..Default::default()
+    };
+    for id in store.ids()? {
+        if live.contains(&id) {
+            continue;
+        }
+        let size = store.size(&id)?.unwrap_or(0);
+        if store.delete(&id)? {
+            report.pages_reclaimed += 1;
+            report.bytes_reclaimed += size;
+        }
+    }
+    Ok(report)
+}
+
+// Every page reachable from `roots`.
+pub(crate) fn mark<S: Store>(store: &S, roots: &[NodeHash]) -> Result<BTreeSet<NodeHash>, Error> {
+    let mut live = BTreeSet::new();
+    let mut pending = roots.to_vec();
+    while let Some(id) = pending.pop() {
+        if !live.insert(id) {
+            continue;
+        }
+        let page = store.get(&id)?.ok_or(Error::MissingPage(id))?;
+        pending.extend(child_pages(&id, &page)?);
+    }
+    Ok(live)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::tree::MerkleSearchTree;
+    use crate::store::MemoryStore;
+
+    #[test]
+    fn test_gc_keeps_live_snapshots() {
+        let mut tree = MerkleSearchTree::<u32>::new(4);
+        for i in 0..300 {
+            tree.insert(i, format!("v{i}"));
+        }
+        let mut store = MemoryStore::new();
+        let (old, _) = tree.write_snapshot(&mut store).unwrap();
+        tree.insert(7, "changed".to_string());
+        let (new, delta) = tree.write_delta_snapshot(&mut store, &old).unwrap();
+
+        // Keeping both roots reclaims nothing.
+        let report = gc(&mut store, &[old.root_page, new.root_page]).unwrap();
+        assert_eq!(report.pages_reclaimed, 0);
+        assert_eq!(report.pages_live, store.len());
+
+        // Dropping the old root reclaims exactly the pages it didn't share.
+        let report = gc(&mut store, &[new.root_page]).unwrap();
+        assert_eq!(report.pages_reclaimed, delta.pages_written);
+        assert!(report.bytes_reclaimed > 0);
+        assert_eq!(store.len(), new.pages.len());
+
+        let mut restored = MerkleSearchTree::<u32>::new(4);
+        restored.restore(&store, &new).unwrap();
+        assert_eq!(restored.hash(), tree.hash());
+    }
+
+    #[test]
+    fn test_gc_aborts_on_missing_page() {
+        let mut tree = MerkleSearchTree::<u32>::new(4);
+        for i in 0..100 {
+            tree.insert(i, format!("v{i}"));
+        }
+        let mut store = MemoryStore::new();
+        let (manifest, _) = tree.write_snapshot(&mut store).unwrap();
+        let leaf = *manifest
+            .pages
+            .iter()
+            .find(|id| **id != manifest.root_page)
+            .unwrap();
+        store.delete(&leaf).unwrap();
+        store.put(NodeHash::digest(b"garbage"), b"garbage").unwrap();
+
+        assert!(matches!(
+            gc(&mut store, &[manifest.root_page]),
+            Err(Error::MissingPage(_))
+        ));
+        assert_eq!(store.len(), manifest.pages.len());
+    }
+}
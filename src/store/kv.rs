@@ -0,0 +1,182 @@
+// A byte-string key-value store over a tree and a page store, for
+// applications that want a verified KV store without assembling one.
+//
+// Writes go to an in-memory tree; `commit` persists what changed since the
+// last commit as a delta snapshot and returns its manifest, which is all the
+// application has to keep to reopen the store later. Proofs are made against
+// the last commit, and `collect_garbage` drops the pages older commits no
+// longer share with it.
+
+use std::ops::RangeBounds;
+
+use crate::core::error::Error;
+use crate::core::hash::NodeHash;
+use crate::core::tree::MerkleSearchTree;
+#[cfg(feature = "proof")]
+use crate::proof::Proof;
+use crate::store::Store;
+use crate::store::gc::{GcReport, gc};
+use crate::store::snapshot::Manifest;
+
+const MAX_CHILDREN: usize = 32;
+
+pub struct MstKv<S> {
+    tree: MerkleSearchTree<Vec<u8>, Vec<u8>>,
+    store: S,
+    // The last commit, None before the first one.
+    manifest: Option<Manifest>,
+}
+
+impl<S: Store> MstKv<S> {
+    // An empty store. Pages already in `store` are left alone.
+    pub fn new(store: S) -> Self {
+        MstKv {
+            tree: MerkleSearchTree::new(MAX_CHILDREN),
+            store,
+            manifest: None,
+        }
+    }
+
+    // Reopens the store at the commit `manifest` describes.
+    pub fn open(store: S, manifest: Manifest) -> Result<Self, Error> {
+        let mut tree = MerkleSearchTree::new(MAX_CHILDREN);
+        tree.restore(&store, &manifest)?;
+        Ok(MstKv {
+            tree,
+            store,
+            manifest: Some(manifest),
+        })
+    }
+
+    pub fn put(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) {
+        self.tree.insert(key.into(), value.into());
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        self.tree.get(&key.to_vec()).map(Vec::as_slice)
+    }
+
+    // Returns whether the key was there.
+    pub fn delete(&mut self, key: &[u8]) -> bool {
+        self.tree.remove(&key.to_vec())
+    }
+
+    // The entries with keys in `range`, in key order.
+    pub fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R) -> impl Iterator<Item = (&[u8], &[u8])> {
+        self.tree
+            .range(range)
+            .map(|(key, value)| (key.as_slice(), value.as_slice()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    // The root of the current contents, committed or not.
+    pub fn root_hash(&self) -> NodeHash {
+        self.tree.root_hash()
+    }
+
+    // Persists the changes since the last commit.
+    pub fn commit(&mut self) -> Result<&Manifest, Error> {
+        let (manifest, _) = match &self.manifest {
+            Some(prev) => self.tree.write_delta_snapshot(&mut self.store, prev)?,
+            None => self.tree.write_snapshot(&mut self.store)?,
+        };
+        Ok(self.manifest.insert(manifest))
+    }
+
+    pub fn manifest(&self) -> Option<&Manifest> {
+        self.manifest.as_ref()
+    }
+
+    // Whether there are changes `commit` would persist.
+    pub fn has_uncommitted(&self) -> bool {
+        self.manifest
+            .as_ref()
+            .map_or(!self.tree.is_empty(), |manifest| {
+                manifest.root_hash != *self.tree.hash()
+            })
+    }
+
+    // A proof of `key`'s value, or its absence, at the last commit.
+    #[cfg(feature = "proof")]
+    pub fn prove(&self, key: &[u8]) -> Result<Proof, Error> {
+        let manifest = self
+            .manifest
+            .as_ref()
+            .ok_or_else(|| Error::Malformed("nothing committed to prove against".to_string()))?;
+        manifest.prove::<Vec<u8>, Vec<u8>, _>(&self.store, &key.to_vec())
+    }
+
+    // Deletes the pages the last commit doesn't use.
+    pub fn collect_garbage(&mut self) -> Result<GcReport, Error> {
+        let live: Vec<NodeHash> = self
+            .manifest
+            .iter()
+            .map(|manifest| manifest.root_page)
+            .collect();
+        gc(&mut self.store, &live)
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    pub fn into_store(self) -> S {
+        self.store
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    #[test]
+    fn test_kv_round_trip() {
+        let mut kv = MstKv::new(MemoryStore::new());
+        for i in 0..100u32 {
+            kv.put(format!("user/{i:03}"), i.to_be_bytes());
+        }
+        kv.put("config", "on");
+        assert!(kv.delete(b"user/050"));
+        assert!(!kv.delete(b"user/050"));
+        assert_eq!(kv.get(b"config"), Some(&b"on"[..]));
+        let users: Vec<&[u8]> = kv
+            .scan(b"user/010".to_vec()..b"user/013".to_vec())
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(users, [b"user/010", b"user/011", b"user/012"]);
+
+        assert!(kv.has_uncommitted());
+        kv.commit().unwrap();
+        assert!(!kv.has_uncommitted());
+        #[cfg(feature = "proof")]
+        {
+            let manifest = kv.manifest().unwrap();
+            let proof = kv.prove(b"config").unwrap();
+            let value = proof.verify::<Vec<u8>, Vec<u8>>(
+                &manifest.root_page,
+                &manifest.root_hash,
+                &b"config".to_vec(),
+            );
+            assert_eq!(value.unwrap(), Some(b"on".to_vec()));
+        }
+
+        kv.put("config", "off");
+        kv.commit().unwrap();
+        assert!(kv.collect_garbage().unwrap().pages_reclaimed > 0);
+
+        let root = kv.root_hash();
+        let manifest = kv.manifest().unwrap().clone();
+        let reopened = MstKv::open(kv.into_store(), manifest).unwrap();
+        assert_eq!(reopened.root_hash(), root);
+        assert_eq!(reopened.len(), 100);
+        assert_eq!(reopened.get(b"config"), Some(&b"off"[..]));
+    }
+}
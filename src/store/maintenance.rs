@@ -0,0 +1,279 @@
+// Periodic background upkeep: snapshotting, store compaction and cache
+// trimming on a schedule, with a handle to stop it.
+//
+// Each job runs on its own interval on one background thread. Jobs reach the
+// tree, store and caches they look after through `Arc<Mutex<_>>`, holding a
+// lock only while they run, so writers on other threads wait at most one job.
+// A failing job is counted and retried at its next turn; it doesn't stop the
+// others.
+//
+// The schedule is a plain thread, like the timer behind `Quiescence`, so it
+// works the same under tokio, another runtime or none; there is no tokio
+// feature to enable. Trees keep no tombstones, so there is nothing to purge:
+// a removal drops its leaf at once.
+
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::core::codec::{Decode, Encode};
+use crate::core::dedup::ValuePool;
+use crate::core::error::Error;
+use crate::core::tree::MerkleSearchTree;
+use crate::store::Store;
+use crate::store::gc::gc;
+use crate::store::snapshot::Manifest;
+
+type Task = Box<dyn FnMut() -> Result<(), Error> + Send>;
+
+struct Job {
+    every: Duration,
+    next: Instant,
+    task: Task,
+}
+
+// How a job has fared so far.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct JobReport {
+    pub name: String,
+    pub runs: u64,
+    pub failures: u64,
+    pub last_error: Option<String>,
+}
+
+#[derive(Default)]
+pub struct Maintenance {
+    jobs: Vec<Job>,
+    reports: Vec<JobReport>,
+}
+
+pub struct MaintenanceHandle {
+    stopped: Arc<(Mutex<bool>, Condvar)>,
+    reports: Arc<Mutex<Vec<JobReport>>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Maintenance {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Runs `task` every `every`, first after one interval.
+    pub fn with_job(
+        mut self,
+        name: &str,
+        every: Duration,
+        task: impl FnMut() -> Result<(), Error> + Send + 'static,
+    ) -> Self {
+        self.jobs.push(Job {
+            every,
+            next: Instant::now() + every,
+            task: Box::new(task),
+        });
+        self.reports.push(JobReport {
+            name: name.to_string(),
+            ..JobReport::default()
+        });
+        self
+    }
+
+    // Snapshots `tree` into `store`, writing only what changed since the
+    // manifest in `published`, and publishes the new manifest there. Skips
+    // a turn when the tree hasn't changed.
+    pub fn with_snapshots<K, V, S>(
+        self,
+        every: Duration,
+        tree: Arc<Mutex<MerkleSearchTree<K, V>>>,
+        store: Arc<Mutex<S>>,
+        published: Arc<Mutex<Option<Manifest>>>,
+    ) -> Self
+    where
+        K: Ord + Clone + Default + Encode + Decode + Send + Sync + 'static,
+        V: AsRef<[u8]> + Encode + Decode + Send + Sync + 'static,
+        S: Store + Send + 'static,
+    {
+        self.with_job("snapshot", every, move || {
+            let tree = lock(&tree);
+            let mut published = lock(&published);
+            let manifest = match &*published {
+                Some(prev) if prev.root_hash == *tree.hash() => return Ok(()),
+                Some(prev) => tree.write_delta_snapshot(&mut *lock(&store), prev)?.0,
+                None => tree.write_snapshot(&mut *lock(&store))?.0,
+            };
+            *published = Some(manifest);
+            Ok(())
+        })
+    }
+
+    // Compacts `store`, deleting every page the manifest in `published`
+    // doesn't reach. Pages of older manifests the application still uses
+    // must be kept some other way, e.g. in a `SharedStore`.
+    pub fn with_compaction<S>(
+        self,
+        every: Duration,
+        store: Arc<Mutex<S>>,
+        published: Arc<Mutex<Option<Manifest>>>,
+    ) -> Self
+    where
+        S: Store + Send + 'static,
+    {
+        self.with_job("compaction", every, move || {
+            // Taken before the store, as snapshots do.
+            let published = lock(&published);
+            let Some(manifest) = &*published else {
+                return Ok(());
+            };
+            gc(&mut *lock(&store), &[manifest.root_page])?;
+            Ok(())
+        })
+    }
+
+    // Drops the pooled values no tree uses any more.
+    pub fn with_pool_trimming<V>(self, every: Duration, pool: Arc<Mutex<ValuePool<V>>>) -> Self
+    where
+        V: AsRef<[u8]> + Send + Sync + 'static,
+    {
+        self.with_job("pool trimming", every, move || {
+            lock(&pool).release_unused();
+            Ok(())
+        })
+    }
+
+    pub fn start(self) -> MaintenanceHandle {
+        let stopped = Arc::new((Mutex::new(false), Condvar::new()));
+        let reports = Arc::new(Mutex::new(self.reports));
+        let thread = {
+            let (stopped, reports) = (stopped.clone(), reports.clone());
+            let mut jobs = self.jobs;
+            thread::spawn(move || run(&mut jobs, &stopped, &reports))
+        };
+        MaintenanceHandle {
+            stopped,
+            reports,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl MaintenanceHandle {
+    pub fn reports(&self) -> Vec<JobReport> {
+        lock(&self.reports).clone()
+    }
+
+    // Stops the schedule, waiting for a running job to finish.
+    pub fn stop(mut self) -> Vec<JobReport> {
+        self.shut_down();
+        self.reports()
+    }
+
+    fn shut_down(&mut self) {
+        let (stopped, wake) = &*self.stopped;
+        *lock(stopped) = true;
+        wake.notify_all();
+        if let Some(thread) = self.thread.take() {
+            // A job that panicked ended the schedule; the panic was printed then.
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for MaintenanceHandle {
+    fn drop(&mut self) {
+        self.shut_down();
+    }
+}
+
+fn run(jobs: &mut [Job], stopped: &(Mutex<bool>, Condvar), reports: &Mutex<Vec<JobReport>>) {
+    let (stopped, wake) = stopped;
+    loop {
+        let now = Instant::now();
+        for (job, index) in jobs.iter_mut().zip(0..) {
+            if job.next > now {
+                continue;
+            }
+            let result = (job.task)();
+            job.next = Instant::now() + job.every;
+            let mut reports = lock(reports);
+            let report = &mut reports[index];
+            report.runs += 1;
+            if let Err(error) = result {
+                report.failures += 1;
+                report.last_error = Some(error.to_string());
+            }
+        }
+        let Some(next) = jobs.iter().map(|job| job.next).min() else {
+            return;
+        };
+        let mut guard = lock(stopped);
+        while !*guard {
+            let Some(timeout) = next.checked_duration_since(Instant::now()) else {
+                break;
+            };
+            guard = wake
+                .wait_timeout(guard, timeout)
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .0;
+        }
+        if *guard {
+            return;
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    // Jobs replace whole values; a panicking one leaves nothing half-written.
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::dedup::SharedValue;
+    use crate::store::MemoryStore;
+
+    #[test]
+    fn test_maintenance_schedule() {
+        let tree = Arc::new(Mutex::new(MerkleSearchTree::<u32>::new(8)));
+        let store = Arc::new(Mutex::new(MemoryStore::new()));
+        let published = Arc::new(Mutex::new(None));
+        let every = Duration::from_millis(5);
+        let handle = Maintenance::new()
+            .with_snapshots(every, tree.clone(), store.clone(), published.clone())
+            .with_compaction(every * 2, store.clone(), published.clone())
+            .with_job("failing", every, || Err(Error::HashChanged))
+            .start();
+
+        for i in 0..200 {
+            lock(&tree).insert(i % 50, format!("v{i}"));
+            thread::sleep(Duration::from_millis(1));
+        }
+        thread::sleep(every * 4);
+        let reports = handle.stop();
+        assert_eq!(reports.len(), 3);
+        assert!(reports.iter().all(|report| report.runs > 0));
+        assert_eq!(reports[2].failures, reports[2].runs);
+        assert!(reports[2].last_error.is_some());
+
+        // The last snapshot holds the final tree, and compaction left its pages.
+        let manifest = lock(&published).clone().unwrap();
+        let mut restored = MerkleSearchTree::<u32>::new(8);
+        restored.restore(&*lock(&store), &manifest).unwrap();
+        assert_eq!(restored.hash(), lock(&tree).hash());
+    }
+
+    #[test]
+    fn test_pool_trimming() {
+        let pool = Arc::new(Mutex::new(ValuePool::new()));
+        let kept: SharedValue<String> = lock(&pool).value("kept".to_string());
+        drop(lock(&pool).value("dropped".to_string()));
+        let handle = Maintenance::new()
+            .with_pool_trimming(Duration::from_millis(1), pool.clone())
+            .start();
+        thread::sleep(Duration::from_millis(20));
+        drop(handle);
+        assert_eq!(lock(&pool).len(), 1);
+        assert_eq!(*kept, "kept");
+    }
+}
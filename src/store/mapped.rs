@@ -0,0 +1,227 @@
+// A read-only tree over a tree's canonical bytes, answering reads with
+// borrowed slices of the buffer instead of decoded copies.
+//
+// The buffer is anything that derefs to bytes. A replica serving mostly
+// reads of large values can map a file written from `canonical_bytes` with
+// the memory-map crate of its choice and hand the map over; a `Vec<u8>` or
+// `Arc<[u8]>` works just as well. Opening indexes where each entry starts,
+// once; after that, lookups and iteration allocate nothing. Slices borrow
+// the `MappedTree`, so the mapping can't go away while one is in use.
+//
+// Keys and values are returned in their encoded form without the length
+// prefix: a `String` or `Vec<u8>` as its bytes, an integer key big-endian.
+// Encoded keys sort like the keys, so lookups compare bytes. Values must
+// encode with a length prefix, as byte strings do.
+
+use std::ops::Deref;
+
+use crate::core::codec::{Decode, Encode, take};
+use crate::core::error::Error;
+use crate::core::hash::NodeHash;
+use crate::store::snapshot::CANONICAL_FORMAT;
+
+pub struct MappedTree<B> {
+    bytes: B,
+    root: NodeHash,
+    // The width of fixed-size keys, None for length-prefixed ones.
+    key_width: Option<usize>,
+    // Where each entry starts, in key order.
+    offsets: Vec<usize>,
+}
+
+impl<B: Deref<Target = [u8]>> MappedTree<B> {
+    // Opens the bytes of a tree with length-prefixed keys, such as `String`
+    // or `Vec<u8>` keys.
+    pub fn open(bytes: B) -> Result<Self, Error> {
+        Self::index(bytes, None)
+    }
+
+    // Opens the bytes of a tree with fixed-size keys of `key_width` bytes,
+    // such as integer or `UuidKey` keys.
+    pub fn open_fixed(bytes: B, key_width: usize) -> Result<Self, Error> {
+        Self::index(bytes, Some(key_width))
+    }
+
+    fn index(bytes: B, key_width: Option<usize>) -> Result<Self, Error> {
+        let mut input = &bytes[..];
+        if u8::decode(&mut input)? != CANONICAL_FORMAT {
+            return Err(Error::Malformed("not a canonical tree image".to_string()));
+        }
+        let root = NodeHash::decode(&mut input)?;
+        let count = u64::decode(&mut input)?;
+        let mut offsets = Vec::new();
+        let mut offset = 1 + root.len() + 8;
+        let mut previous: Option<&[u8]> = None;
+        for _ in 0..count {
+            let (key, _, next) = read(&bytes, key_width, offset)?;
+            if previous.is_some_and(|previous| previous >= key) {
+                return Err(Error::Malformed(
+                    "tree image keys are out of order".to_string(),
+                ));
+            }
+            previous = Some(key);
+            offsets.push(offset);
+            offset = next;
+        }
+        if offset != bytes.len() {
+            return Err(Error::Malformed(
+                "trailing bytes after the last entry".to_string(),
+            ));
+        }
+        Ok(MappedTree {
+            bytes,
+            root,
+            key_width,
+            offsets,
+        })
+    }
+
+    // The root recorded in the image; see `verify`.
+    pub fn root(&self) -> &NodeHash {
+        &self.root
+    }
+
+    pub fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.offsets.is_empty()
+    }
+
+    // The value of the key with these encoded bytes.
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        let index = self
+            .offsets
+            .binary_search_by(|offset| self.entry_at(*offset).0.cmp(key))
+            .ok()?;
+        Some(self.entry_at(self.offsets[index]).1)
+    }
+
+    // `get` for a typed key, encoding it first.
+    pub fn get_key<K: Encode>(&self, key: &K) -> Option<&[u8]> {
+        let mut bytes = Vec::new();
+        key.encode(&mut bytes);
+        match self.key_width {
+            Some(_) => self.get(&bytes),
+            None => self.get(bytes.get(4..)?),
+        }
+    }
+
+    // The entries in key order.
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], &[u8])> {
+        self.offsets.iter().map(|offset| self.entry_at(*offset))
+    }
+
+    // The entries from the first key not below `key` on.
+    pub fn iter_from(&self, key: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+        let start = self
+            .offsets
+            .partition_point(|offset| self.entry_at(*offset).0 < key);
+        self.offsets[start..]
+            .iter()
+            .map(|offset| self.entry_at(*offset))
+    }
+
+    // Checks the values against the recorded root. Only the root is checked:
+    // run this once after mapping a file from an untrusted source.
+    pub fn verify(&self) -> Result<(), Error> {
+        let mut actual = NodeHash::default();
+        for (key, value) in self.iter() {
+            // Variable-width keys are stored without their length prefix.
+            actual.xor(&match self.key_width {
+                Some(_) => NodeHash::leaf_encoded(key, value),
+                None => NodeHash::leaf(key, value),
+            });
+        }
+        let actual = NodeHash::root_of(actual, self.len());
+        if actual != self.root {
+            return Err(Error::RootMismatch {
+                expected: self.root,
+                actual,
+            });
+        }
+        Ok(())
+    }
+
+    fn entry_at(&self, offset: usize) -> (&[u8], &[u8]) {
+        let Ok((key, value, _)) = read(&self.bytes, self.key_width, offset) else {
+            unreachable!("entries are checked when the image is opened");
+        };
+        (key, value)
+    }
+}
+
+// The key and value at `offset`, and where the next entry starts.
+fn read(
+    bytes: &[u8],
+    key_width: Option<usize>,
+    offset: usize,
+) -> Result<(&[u8], &[u8], usize), Error> {
+    let mut input = bytes.get(offset..).unwrap_or_default();
+    let before = input.len();
+    let key = match key_width {
+        Some(width) => take(&mut input, width)?,
+        None => {
+            let len = u32::decode(&mut input)? as usize;
+            take(&mut input, len)?
+        }
+    };
+    let len = u32::decode(&mut input)? as usize;
+    let value = take(&mut input, len)?;
+    Ok((key, value, offset + before - input.len()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::tree::MerkleSearchTree;
+
+    #[test]
+    fn test_string_keys() {
+        let mut tree = MerkleSearchTree::<String>::new(8);
+        for i in 0..500 {
+            tree.insert(format!("key-{i:04}"), "x".repeat(i));
+        }
+        let mapped = MappedTree::open(tree.canonical_bytes()).unwrap();
+        assert_eq!(mapped.len(), 500);
+        assert_eq!(mapped.root(), tree.hash());
+        mapped.verify().unwrap();
+        assert_eq!(mapped.get(b"key-0042"), Some("x".repeat(42).as_bytes()));
+        assert_eq!(mapped.get(b"key-9999"), None);
+        assert_eq!(
+            mapped.get_key(&"key-0007".to_string()),
+            Some("x".repeat(7).as_bytes())
+        );
+        let from: Vec<&[u8]> = mapped.iter_from(b"key-0498").map(|(key, _)| key).collect();
+        assert_eq!(from, [b"key-0498", b"key-0499"]);
+
+        // Values point into the buffer.
+        let value = mapped.get(b"key-0100").unwrap();
+        assert!(mapped.bytes.as_ptr_range().contains(&value.as_ptr()));
+    }
+
+    #[test]
+    fn test_fixed_keys() {
+        let mut tree = MerkleSearchTree::<u32>::new(8);
+        for i in 0..300 {
+            tree.insert(i * 3, format!("v{i}"));
+        }
+        let bytes: std::sync::Arc<[u8]> = tree.canonical_bytes().into();
+        let mapped = MappedTree::open_fixed(bytes.clone(), 4).unwrap();
+        assert_eq!(mapped.get(&9u32.to_be_bytes()), Some(&b"v3"[..]));
+        assert_eq!(mapped.get_key(&10u32), None);
+        assert!(
+            mapped
+                .iter()
+                .map(|(_, value)| value)
+                .eq(tree.iter().map(|(_, v)| v.as_bytes()))
+        );
+
+        let mut forged = bytes.to_vec();
+        *forged.last_mut().unwrap() ^= 1;
+        let forged = MappedTree::open_fixed(forged, 4).unwrap();
+        assert!(matches!(forged.verify(), Err(Error::RootMismatch { .. })));
+        assert!(MappedTree::open(bytes).is_err());
+    }
+}
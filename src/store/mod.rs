@@ -0,0 +1,189 @@
+// Persisting trees as content-addressed pages, and what is built on those
+// pages: caching, garbage collection, versions, repair and verification.
+// Behind the `store` feature.
+//
+// Only trees with the default 32-byte hashes are persisted. Page ids are
+// SHA-256 digests and the pages record their children's hashes beside
+// them, so snapshots, and everything here, are implemented for
+// `MerkleSearchTree<K, V>` alone. A tree of another width has to be copied
+// into a default one, entry by entry, to be stored.
+
+pub mod amplification;
+pub mod bootstrap;
+pub mod cache;
+pub mod gc;
+pub mod kv;
+pub mod maintenance;
+pub mod mapped;
+pub mod repair;
+pub mod shared;
+pub mod snapshot;
+pub mod stream;
+pub mod verify;
+#[cfg(feature = "crdt")]
+pub mod versions;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::core::hash::NodeHash;
+
+// Content-addressed page storage: every page is keyed by the SHA-256 of its bytes.
+// Identical pages are therefore stored once, whichever snapshot wrote them.
+pub trait Store {
+    fn get(&self, id: &NodeHash) -> io::Result<Option<Vec<u8>>>;
+
+    fn put(&mut self, id: NodeHash, page: &[u8]) -> io::Result<()>;
+
+    fn contains(&self, id: &NodeHash) -> io::Result<bool> {
+        Ok(self.get(id)?.is_some())
+    }
+
+    // The size of a page in bytes, if present.
+    fn size(&self, id: &NodeHash) -> io::Result<Option<u64>> {
+        Ok(self.get(id)?.map(|page| page.len() as u64))
+    }
+
+    // Returns whether the page was present.
+    fn delete(&mut self, id: &NodeHash) -> io::Result<bool>;
+
+    // Every page id in the store, in ascending order.
+    fn ids(&self) -> io::Result<Vec<NodeHash>>;
+}
+
+#[derive(Default)]
+pub struct MemoryStore {
+    pages: BTreeMap<NodeHash, Vec<u8>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.pages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pages.is_empty()
+    }
+}
+
+impl Store for MemoryStore {
+    fn get(&self, id: &NodeHash) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.pages.get(id).cloned())
+    }
+
+    fn put(&mut self, id: NodeHash, page: &[u8]) -> io::Result<()> {
+        self.pages.insert(id, page.to_vec());
+        Ok(())
+    }
+
+    fn contains(&self, id: &NodeHash) -> io::Result<bool> {
+        Ok(self.pages.contains_key(id))
+    }
+
+    fn delete(&mut self, id: &NodeHash) -> io::Result<bool> {
+        Ok(self.pages.remove(id).is_some())
+    }
+
+    fn ids(&self) -> io::Result<Vec<NodeHash>> {
+        Ok(self.pages.keys().copied().collect())
+    }
+}
+
+// One file per page, named after the hex page id.
+pub struct DirStore {
+    dir: PathBuf,
+}
+
+impl DirStore {
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<Self> {
+        fs::create_dir_all(dir.as_ref())?;
+        Ok(DirStore {
+            dir: dir.as_ref().to_path_buf(),
+        })
+    }
+
+    fn path(&self, id: &NodeHash) -> PathBuf {
+        self.dir.join(format!("{id}.page"))
+    }
+}
+
+impl Store for DirStore {
+    fn get(&self, id: &NodeHash) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(self.path(id)) {
+            Ok(page) => Ok(Some(page)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn put(&mut self, id: NodeHash, page: &[u8]) -> io::Result<()> {
+        // Write then rename, so a crash never leaves a torn page under its final name.
+        let path = self.path(&id);
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, page)?;
+        fs::rename(tmp, path)
+    }
+
+    fn contains(&self, id: &NodeHash) -> io::Result<bool> {
+        self.path(id).try_exists()
+    }
+
+    fn size(&self, id: &NodeHash) -> io::Result<Option<u64>> {
+        match fs::metadata(self.path(id)) {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn delete(&mut self, id: &NodeHash) -> io::Result<bool> {
+        match fs::remove_file(self.path(id)) {
+            Ok(()) => Ok(true),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn ids(&self) -> io::Result<Vec<NodeHash>> {
+        let mut ids = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let name = entry?.file_name();
+            let Some(hex) = name.to_str().and_then(|name| name.strip_suffix(".page")) else {
+                continue;
+            };
+            if let Some(id) = NodeHash::from_hex(hex) {
+                ids.push(id);
+            }
+        }
+        ids.sort();
+        Ok(ids)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_dir_store_round_trip() {
+        let dir = std::env::temp_dir().join(format!("mst-dir-store-{}", std::process::id()));
+        let mut store = DirStore::open(&dir).unwrap();
+        let id = NodeHash::digest(b"page");
+
+        assert_eq!(store.get(&id).unwrap(), None);
+        store.put(id, b"page").unwrap();
+        assert!(store.contains(&id).unwrap());
+        assert_eq!(store.get(&id).unwrap(), Some(b"page".to_vec()));
+        assert_eq!(store.ids().unwrap(), vec![id]);
+        assert!(store.delete(&id).unwrap());
+        assert!(!store.delete(&id).unwrap());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
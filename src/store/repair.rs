@@ -0,0 +1,246 @@
+// Corruption detection and repair for snapshots in a store.
+//
+// A deep verification reloads every page of a snapshot, recomputes the leaf
+// hashes from the stored values and checks each subtree against the hash its
+// parent recorded, bottom-up to the manifest's root hash. A page that is
+// missing, doesn't match its id, doesn't decode or doesn't add up to its
+// recorded hash is corrupt. Pages are content-addressed, so a corrupt subtree
+// is repaired by fetching the pages with the same ids from a peer holding the
+// same snapshot; the parents stay valid as they are.
+
+use std::collections::BTreeSet;
+
+use crate::core::budget::{Budget, Progress};
+use crate::core::codec::{Decode, Encode};
+use crate::core::error::Error;
+use crate::core::hash::NodeHash;
+use crate::store::Store;
+use crate::store::snapshot::{DecodedPage, Manifest, decode_page, page_body};
+
+// A page whose subtree doesn't hold what its parent recorded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CorruptNode {
+    pub page: NodeHash,
+    // The subtree hash recorded by the parent, or the manifest for the root.
+    pub hash: NodeHash,
+}
+
+// Where a budgeted deep verification stopped.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeepCursor {
+    // Pages still to check, with the hash their parent recorded.
+    pending: Vec<(NodeHash, NodeHash)>,
+    seen: BTreeSet<NodeHash>,
+}
+
+impl Manifest {
+    // Every corrupt page reachable from the root. The subtrees
+    // below a corrupt page can't be reached and aren't checked.
+    pub fn verify_deep<K, V, S>(&self, store: &S) -> Result<Vec<CorruptNode>, Error>
+    where
+        K: Ord + Clone + Default + Encode + Decode,
+        V: AsRef<[u8]> + Decode,
+        S: Store,
+    {
+        let progress =
+            self.verify_deep_with_budget::<K, V, S>(store, None, &mut Budget::unlimited())?;
+        Ok(progress.result)
+    }
+
+    // `verify_deep`, checking pages until `budget` runs out, one unit per
+    // page. Each call returns the corrupt pages it found.
+    pub fn verify_deep_with_budget<K, V, S>(
+        &self,
+        store: &S,
+        resume: Option<DeepCursor>,
+        budget: &mut Budget,
+    ) -> Result<Progress<Vec<CorruptNode>, DeepCursor>, Error>
+    where
+        K: Ord + Clone + Default + Encode + Decode,
+        V: AsRef<[u8]> + Decode,
+        S: Store,
+    {
+        let mut corrupt = Vec::new();
+        let DeepCursor {
+            mut pending,
+            mut seen,
+        } = resume.unwrap_or_else(|| DeepCursor {
+            pending: vec![(self.root_page, self.root_hash)],
+            seen: BTreeSet::new(),
+        });
+        let mut first = true;
+        while let Some((page, hash)) = pending.pop() {
+            // Unchanged subtrees are shared between parents; check them once.
+            if !seen.insert(page) {
+                continue;
+            }
+            if !std::mem::take(&mut first) && !budget.spend() {
+                seen.remove(&page);
+                pending.push((page, hash));
+                return Ok(Progress {
+                    result: corrupt,
+                    resume: Some(DeepCursor { pending, seen }),
+                });
+            }
+            let checked = store
+                .get(&page)?
+                .and_then(|bytes| check_page::<K, V>(&bytes, page, hash));
+            match checked {
+                Some(children) => pending.extend(children),
+                None => corrupt.push(CorruptNode { page, hash }),
+            }
+        }
+        Ok(Progress {
+            result: corrupt,
+            resume: None,
+        })
+    }
+
+    // Replaces the `corrupt` subtrees with pages from a peer, and returns the
+    // number of pages written. `fetch` returns the peer's page with an id.
+    // Fetched pages are checked like stored ones, so a faulty peer can't
+    // introduce other content; its pages are rejected with `Error::Malformed`.
+    pub fn repair_from_peer<K, V, S, F>(
+        &self,
+        store: &mut S,
+        corrupt: &[CorruptNode],
+        mut fetch: F,
+    ) -> Result<usize, Error>
+    where
+        K: Ord + Clone + Default + Encode + Decode,
+        V: AsRef<[u8]> + Decode,
+        S: Store,
+        F: FnMut(&NodeHash) -> Option<Vec<u8>>,
+    {
+        let mut repaired = 0;
+        let mut pending: Vec<_> = corrupt.iter().map(|node| (node.page, node.hash)).collect();
+        while let Some((page, hash)) = pending.pop() {
+            let stored = store
+                .get(&page)?
+                .and_then(|bytes| check_page::<K, V>(&bytes, page, hash));
+            if let Some(children) = stored {
+                pending.extend(children);
+                continue;
+            }
+
+            // Below a corrupt page, any subtree may be damaged or missing too.
+            let bytes = fetch(&page).ok_or(Error::MissingPage(page))?;
+            let children = check_page::<K, V>(&bytes, page, hash).ok_or_else(|| {
+                Error::Malformed(format!("peer page {page} does not match its hash"))
+            })?;
+            store.put(page, &bytes)?;
+            repaired += 1;
+            pending.extend(children);
+        }
+        Ok(repaired)
+    }
+}
+
+// The (page id, subtree hash) of the children of a sound page, or None if
+// the page is corrupt.
+pub(crate) fn check_page<K, V>(
+    bytes: &[u8],
+    page: NodeHash,
+    hash: NodeHash,
+) -> Option<Vec<(NodeHash, NodeHash)>>
+where
+    K: Ord + Clone + Default + Encode + Decode,
+    V: AsRef<[u8]> + Decode,
+{
+    let body = page_body(&page, bytes).ok()?;
+    if NodeHash::digest(bytes) != page {
+        return None;
+    }
+    match decode_page::<K, V>(body).ok()? {
+        DecodedPage::Leaf(node) => {
+            (NodeHash::root_of(*node.hash(), node.leaf_count()) == hash).then(Vec::new)
+        }
+        DecodedPage::Internal(children, _) => {
+            let mut sum = NodeHash::default();
+            for (child_hash, _) in &children {
+                sum.xor(child_hash);
+            }
+            (sum == hash).then(|| children.into_iter().map(|(hash, id)| (id, hash)).collect())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::tree::MerkleSearchTree;
+    use crate::store::MemoryStore;
+
+    fn snapshot() -> (Manifest, MemoryStore) {
+        let mut tree = MerkleSearchTree::<u32>::new(4);
+        for i in 0..300 {
+            tree.insert(i, format!("v{i}"));
+        }
+        let mut store = MemoryStore::new();
+        let (manifest, _) = tree.write_snapshot(&mut store).unwrap();
+        (manifest, store)
+    }
+
+    #[test]
+    fn test_verify_deep_finds_rot() {
+        let (manifest, mut store) = snapshot();
+        assert!(
+            manifest
+                .verify_deep::<u32, String, _>(&store)
+                .unwrap()
+                .is_empty()
+        );
+
+        // Flip one bit in a page, and drop another.
+        let ids: Vec<_> = manifest.pages.iter().copied().collect();
+        let mut page = store.get(&ids[3]).unwrap().unwrap();
+        *page.last_mut().unwrap() ^= 1;
+        store.put(ids[3], &page).unwrap();
+        store.delete(&ids[7]).unwrap();
+
+        let corrupt = manifest.verify_deep::<u32, String, _>(&store).unwrap();
+        let pages: BTreeSet<_> = corrupt.iter().map(|node| node.page).collect();
+        assert_eq!(pages, BTreeSet::from([ids[3], ids[7]]));
+    }
+
+    #[test]
+    fn test_repair_from_peer() {
+        let (manifest, peer) = snapshot();
+        let mut store = MemoryStore::new();
+        for id in &manifest.pages {
+            store.put(*id, &peer.get(id).unwrap().unwrap()).unwrap();
+        }
+        // A rotten root hides the missing page below it.
+        store.put(manifest.root_page, b"rotten").unwrap();
+        let victim = *manifest
+            .pages
+            .iter()
+            .find(|id| **id != manifest.root_page)
+            .unwrap();
+        store.delete(&victim).unwrap();
+
+        let corrupt = manifest.verify_deep::<u32, String, _>(&store).unwrap();
+        assert_eq!(corrupt.len(), 1);
+
+        // A peer serving other content is rejected.
+        let result = manifest.repair_from_peer::<u32, String, _, _>(&mut store, &corrupt, |_| {
+            Some(b"forged".to_vec())
+        });
+        assert!(matches!(result, Err(Error::Malformed(_))));
+
+        let repaired = manifest
+            .repair_from_peer::<u32, String, _, _>(&mut store, &corrupt, |id| peer.get(id).unwrap())
+            .unwrap();
+        assert_eq!(repaired, 2);
+        assert!(
+            manifest
+                .verify_deep::<u32, String, _>(&store)
+                .unwrap()
+                .is_empty()
+        );
+
+        let mut restored = MerkleSearchTree::<u32>::new(4);
+        restored.restore(&store, &manifest).unwrap();
+        assert_eq!(restored.hash(), &manifest.root_hash);
+    }
+}
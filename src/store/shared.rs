@@ -0,0 +1,192 @@
+// A store shared by several roots (versions, branches, the live tree), with a
+// reference count per page.
+//
+// A page is referenced once by each page pointing at it and once by each
+// retained root. Releasing a root frees the pages whose count drops to zero,
+// so only the nodes unique to that root are deleted. Unlike `gc`, this never
+// has to walk the pages that are still shared.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+
+use crate::core::error::Error;
+use crate::core::hash::NodeHash;
+use crate::store::Store;
+use crate::store::gc::GcReport;
+use crate::store::snapshot::child_pages;
+
+pub struct SharedStore<S> {
+    inner: S,
+    refs: BTreeMap<NodeHash, u64>,
+}
+
+impl<S: Store> SharedStore<S> {
+    // Wraps an empty store.
+    pub fn new(inner: S) -> Self {
+        SharedStore {
+            inner,
+            refs: BTreeMap::new(),
+        }
+    }
+
+    // Wraps a store that already holds pages, rebuilding the counts from the
+    // roots still in use. Pages not reachable from `roots` are left untouched;
+    // run `gc` to remove them.
+    pub fn open(inner: S, roots: &[NodeHash]) -> Result<Self, Error> {
+        let mut store = SharedStore::new(inner);
+        let mut pending = roots.to_vec();
+        for root in roots {
+            *store.refs.entry(*root).or_default() += 1;
+        }
+        let mut seen = BTreeSet::new();
+        while let Some(id) = pending.pop() {
+            if !seen.insert(id) {
+                continue;
+            }
+            let page = store.inner.get(&id)?.ok_or(Error::MissingPage(id))?;
+            for child in child_pages(&id, &page)? {
+                *store.refs.entry(child).or_default() += 1;
+                pending.push(child);
+            }
+        }
+        Ok(store)
+    }
+
+    pub fn ref_count(&self, id: &NodeHash) -> u64 {
+        self.refs.get(id).copied().unwrap_or(0)
+    }
+
+    // Adds a root reference, e.g. after writing a snapshot that must be kept.
+    pub fn retain(&mut self, root: NodeHash) -> Result<(), Error> {
+        if !self.inner.contains(&root)? {
+            return Err(Error::MissingPage(root));
+        }
+        *self.refs.entry(root).or_default() += 1;
+        Ok(())
+    }
+
+    // Drops a root reference and frees every page that is no longer referenced.
+    pub fn release(&mut self, root: NodeHash) -> Result<GcReport, Error> {
+        let mut report = GcReport::default();
+        let mut pending = vec![root];
+        while let Some(id) = pending.pop() {
+            let Some(count) = self.refs.get_mut(&id) else {
+                continue;
+            };
+            *count -= 1;
+            if *count > 0 {
+                continue;
+            }
+            self.refs.remove(&id);
+
+            let Some(page) = self.inner.get(&id)? else {
+                continue;
+            };
+            pending.extend(child_pages(&id, &page)?);
+            if self.inner.delete(&id)? {
+                report.pages_reclaimed += 1;
+                report.bytes_reclaimed += page.len() as u64;
+            }
+        }
+        report.pages_live = self.refs.len();
+        Ok(report)
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: Store> Store for SharedStore<S> {
+    fn get(&self, id: &NodeHash) -> io::Result<Option<Vec<u8>>> {
+        self.inner.get(id)
+    }
+
+    // A page that is new to the store adds a reference to each of its children.
+    // Writing a page that is already present is a no-op.
+    fn put(&mut self, id: NodeHash, page: &[u8]) -> io::Result<()> {
+        if self.inner.contains(&id)? {
+            return Ok(());
+        }
+        let children = child_pages(&id, page)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        self.inner.put(id, page)?;
+        for child in children {
+            *self.refs.entry(child).or_default() += 1;
+        }
+        Ok(())
+    }
+
+    fn contains(&self, id: &NodeHash) -> io::Result<bool> {
+        self.inner.contains(id)
+    }
+
+    fn size(&self, id: &NodeHash) -> io::Result<Option<u64>> {
+        self.inner.size(id)
+    }
+
+    // Deletes the page regardless of its count.
+    fn delete(&mut self, id: &NodeHash) -> io::Result<bool> {
+        self.refs.remove(id);
+        self.inner.delete(id)
+    }
+
+    fn ids(&self) -> io::Result<Vec<NodeHash>> {
+        self.inner.ids()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::tree::MerkleSearchTree;
+    use crate::store::MemoryStore;
+
+    #[test]
+    fn test_release_frees_unique_pages_only() {
+        let mut tree = MerkleSearchTree::<u32>::new(4);
+        for i in 0..300 {
+            tree.insert(i, format!("v{i}"));
+        }
+        let mut store = SharedStore::new(MemoryStore::new());
+        let (v1, _) = tree.write_snapshot(&mut store).unwrap();
+        store.retain(v1.root_page).unwrap();
+
+        tree.insert(42, "changed".to_string());
+        let (v2, delta) = tree.write_delta_snapshot(&mut store, &v1).unwrap();
+        store.retain(v2.root_page).unwrap();
+
+        let report = store.release(v1.root_page).unwrap();
+        assert_eq!(report.pages_reclaimed, delta.pages_written);
+        assert_eq!(store.ids().unwrap().len(), v2.pages.len());
+
+        let mut restored = MerkleSearchTree::<u32>::new(4);
+        restored.restore(&store, &v2).unwrap();
+        assert_eq!(restored.hash(), tree.hash());
+
+        store.release(v2.root_page).unwrap();
+        assert!(store.ids().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_open_rebuilds_counts() {
+        let mut tree = MerkleSearchTree::<u32>::new(4);
+        for i in 0..100 {
+            tree.insert(i, format!("v{i}"));
+        }
+        let mut inner = MemoryStore::new();
+        let (v1, _) = tree.write_snapshot(&mut inner).unwrap();
+        tree.insert(1, "changed".to_string());
+        let (v2, _) = tree.write_delta_snapshot(&mut inner, &v1).unwrap();
+
+        let mut store = SharedStore::open(inner, &[v1.root_page, v2.root_page]).unwrap();
+        assert_eq!(store.ref_count(&v1.root_page), 1);
+        assert!(v1.pages.union(&v2.pages).all(|id| store.ref_count(id) >= 1));
+
+        let report = store.release(v2.root_page).unwrap();
+        assert_eq!(
+            report.pages_reclaimed,
+            v2.pages.difference(&v1.pages).count()
+        );
+    }
+}
@@ -0,0 +1,681 @@
+// Snapshots of a tree as content-addressed pages in a `Store`.
+//
+// Every node becomes one page. A node whose children are leaves is written as
+// its entries; any other node as a (hash, page id) pair per child followed by
+// the children's max keys. Keeping the ids up front lets tools such as the
+// garbage collector follow pages without knowing the key type.
+// Page ids are the SHA-256 of the page bytes, so an unchanged subtree yields
+// the same pages, and a delta snapshot only writes the pages that are new.
+// Every page ends with a CRC-32C of the bytes before it. It is checked first
+// on load, so bytes damaged at rest (`Error::ChecksumMismatch`) are told apart
+// from intact pages that don't hash as recorded (`Error::HashMismatch`).
+//
+// Encodings are canonical: fixed field order, fixed-width big-endian
+// integers and lengths, and sets written in key order, never in hash-map
+// order. Pages depend on the node layout, so they are only stable for a
+// given insert history; `canonical_bytes` depends on the content alone. An
+// existing format never changes across versions or platforms: a new layout
+// would get a new tag or format version, so stored digests stay valid.
+
+use std::collections::BTreeSet;
+
+use crate::core::alloc::{NodeAlloc, NodeRef};
+use crate::core::codec::{Decode, Encode, crc32c};
+use crate::core::error::Error;
+use crate::core::hash::NodeHash;
+use crate::core::quota::Usage;
+use crate::core::tree::{MerkleSearchTree, Node};
+use crate::store::Store;
+
+pub(crate) const LEAF_PAGE: u8 = 0;
+pub(crate) const INTERNAL_PAGE: u8 = 1;
+// A leaf page whose values carry a compression flag; see `compress`.
+pub(crate) const COMPRESSED_LEAF_PAGE: u8 = 2;
+// The first byte of `canonical_bytes`.
+pub(crate) const CANONICAL_FORMAT: u8 = 1;
+
+// Everything needed to restore a snapshot from a store.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Manifest {
+    pub root_page: NodeHash,
+    // The tree's `hash()`, all zeros when empty. Unlike a bare root, this
+    // isn't ambiguous: `root_page` commits to the content.
+    pub root_hash: NodeHash,
+    // Every page reachable from `root_page`.
+    pub pages: BTreeSet<NodeHash>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SnapshotStats {
+    pub pages_written: usize,
+    pub pages_reused: usize,
+    pub bytes_written: usize,
+}
+
+impl Encode for Manifest {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.root_page.encode(out);
+        self.root_hash.encode(out);
+        (self.pages.len() as u64).encode(out);
+        for page in &self.pages {
+            page.encode(out);
+        }
+    }
+}
+
+impl Decode for Manifest {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        let root_page = NodeHash::decode(input)?;
+        let root_hash = NodeHash::decode(input)?;
+        let count = u64::decode(input)?;
+        let pages = (0..count)
+            .map(|_| NodeHash::decode(input))
+            .collect::<Result<_, _>>()?;
+        Ok(Manifest {
+            root_page,
+            root_hash,
+            pages,
+        })
+    }
+}
+
+enum Visit<'a, T> {
+    Enter(&'a T),
+    Exit(&'a T),
+}
+
+impl<K, V> MerkleSearchTree<K, V>
+where
+    K: Ord + Clone + Default + Encode + Decode,
+    V: AsRef<[u8]> + Encode + Decode,
+{
+    // Writes every page of the tree to `store`.
+    pub fn write_snapshot<S: Store>(
+        &self,
+        store: &mut S,
+    ) -> Result<(Manifest, SnapshotStats), Error> {
+        self.write_pages(store, None)
+    }
+
+    // Writes only the pages that `prev` doesn't already reference.
+    // The store must still hold the pages of `prev`.
+    pub fn write_delta_snapshot<S: Store>(
+        &self,
+        store: &mut S,
+        prev: &Manifest,
+    ) -> Result<(Manifest, SnapshotStats), Error> {
+        self.write_pages(store, Some(prev))
+    }
+
+    fn write_pages<S: Store>(
+        &self,
+        store: &mut S,
+        prev: Option<&Manifest>,
+    ) -> Result<(Manifest, SnapshotStats), Error> {
+        let mut pages = BTreeSet::new();
+        let mut stats = SnapshotStats::default();
+        #[cfg(feature = "compression")]
+        let compress_above = self.compress_above;
+        #[cfg(not(feature = "compression"))]
+        let compress_above = None;
+
+        // Post-order walk: a node's page needs the ids of its children's pages,
+        // which pile up on `ids` in child order.
+        let mut ids: Vec<NodeHash> = Vec::new();
+        let mut visits = vec![Visit::Enter(&*self.root)];
+        while let Some(visit) = visits.pop() {
+            let mut page = match visit {
+                Visit::Enter(node) if !node.are_children_leaves() => {
+                    visits.push(Visit::Exit(node));
+                    if let Node::Internal { children, .. } = node {
+                        visits.extend(children.iter().rev().map(|child| Visit::Enter(&**child)));
+                    }
+                    continue;
+                }
+                Visit::Enter(node) => encode_leaf_page(node, compress_above),
+                Visit::Exit(node) => {
+                    let Node::Internal { children, .. } = node else {
+                        unreachable!("only internal nodes are exited");
+                    };
+                    let child_ids = ids.split_off(ids.len() - children.len());
+                    encode_internal_page(children, &child_ids)
+                }
+            };
+            crc32c(&page).encode(&mut page);
+
+            let id = NodeHash::digest(&page);
+            ids.push(id);
+            if !pages.insert(id) {
+                continue;
+            }
+            if prev.is_some_and(|prev| prev.pages.contains(&id)) {
+                stats.pages_reused += 1;
+            } else {
+                store.put(id, &page)?;
+                stats.pages_written += 1;
+                stats.bytes_written += page.len();
+            }
+        }
+
+        let manifest = Manifest {
+            root_page: ids.pop().expect("the root page is written last"),
+            root_hash: *self.hash(),
+            pages,
+        };
+        Ok((manifest, stats))
+    }
+
+    // Replaces the contents of this tree with the snapshot described by `manifest`.
+    // Every page is checked against its id and every subtree against the hash
+    // its parent recorded. The tree keeps its own fanout settings.
+    pub fn restore<S: Store>(&mut self, store: &S, manifest: &Manifest) -> Result<(), Error> {
+        // Pages still to load, and decoded internal pages waiting for their children.
+        enum Pending {
+            Load(NodeHash),
+            Assemble(Vec<NodeHash>),
+        }
+
+        let mut nodes: Vec<Node<K, V>> = Vec::new();
+        let mut depth = 0;
+        let mut pending = vec![Pending::Load(manifest.root_page)];
+        while let Some(next) = pending.pop() {
+            match next {
+                Pending::Load(id) => {
+                    let page = store.get(&id)?.ok_or(Error::MissingPage(id))?;
+                    let body = page_body(&id, &page)?;
+                    let actual = NodeHash::digest(&page);
+                    if actual != id {
+                        return Err(Error::HashMismatch {
+                            expected: id,
+                            actual,
+                        });
+                    }
+                    match decode_page_in::<K, V>(body, self.alloc)? {
+                        DecodedPage::Leaf(node) => {
+                            if depth == 0 {
+                                // The first leaf page reached is at the bottom of the leftmost path.
+                                depth = pending
+                                    .iter()
+                                    .filter(|p| matches!(p, Pending::Assemble(_)))
+                                    .count()
+                                    + 1;
+                            }
+                            nodes.push(node)
+                        }
+                        DecodedPage::Internal(children, _) => {
+                            let hashes = children.iter().map(|(hash, _)| *hash).collect();
+                            pending.push(Pending::Assemble(hashes));
+                            pending.extend(children.iter().rev().map(|(_, id)| Pending::Load(*id)));
+                        }
+                    }
+                }
+                Pending::Assemble(hashes) => {
+                    let children: Vec<_> = nodes
+                        .split_off(nodes.len() - hashes.len())
+                        .into_iter()
+                        .map(|node| NodeRef::new(node, self.alloc))
+                        .collect();
+                    for (child, expected) in children.iter().zip(&hashes) {
+                        if child.hash() != expected {
+                            return Err(Error::HashMismatch {
+                                expected: *expected,
+                                actual: *child.hash(),
+                            });
+                        }
+                    }
+                    let mut node = Node::Internal {
+                        hash: NodeHash::default(),
+                        children,
+                        max_key: K::default(),
+                        usage: Usage::default(),
+                    };
+                    node.recalculate();
+                    nodes.push(node);
+                }
+            }
+        }
+
+        let root = nodes.pop().expect("the root is assembled last");
+        let actual = NodeHash::root_of(*root.hash(), root.leaf_count());
+        if actual != manifest.root_hash {
+            return Err(Error::HashMismatch {
+                expected: manifest.root_hash,
+                actual,
+            });
+        }
+        self.replace_root(NodeRef::new(root, self.alloc), depth);
+        Ok(())
+    }
+}
+
+impl<K, V, const N: usize> MerkleSearchTree<K, V, N>
+where
+    K: Ord + Clone + Default + Encode,
+    V: AsRef<[u8]> + Encode,
+{
+    // The content in a form that doesn't depend on the node layout: the
+    // format version, the root hash, the entry count as a u64, then each key
+    // and value in key order. Trees with the same entries produce the same
+    // bytes whatever their fanout or insert order.
+    pub fn canonical_bytes(&self) -> Vec<u8> {
+        let mut out = vec![CANONICAL_FORMAT];
+        self.hash().encode(&mut out);
+        (self.len() as u64).encode(&mut out);
+        for (key, value) in self.iter() {
+            key.encode(&mut out);
+            value.encode(&mut out);
+        }
+        out
+    }
+}
+
+impl Manifest {
+    // Looks `key` up in the snapshot, loading only the pages on its path.
+    pub fn get<K, V, S>(&self, store: &S, key: &K) -> Result<Option<V>, Error>
+    where
+        K: Ord + Clone + Default + Encode + Decode,
+        V: AsRef<[u8]> + Decode,
+        S: Store,
+    {
+        let (mut id, mut hash) = (self.root_page, self.root_hash);
+        loop {
+            let page = store.get(&id)?.ok_or(Error::MissingPage(id))?;
+            match step::<K, V>(&id, &hash, &page, key)? {
+                Step::Child(child, child_hash) => (id, hash) = (child, child_hash),
+                Step::Leaf(value) => return Ok(value),
+            }
+        }
+    }
+}
+
+fn encode_leaf_page<K: Encode, V: Encode>(
+    node: &Node<K, V>,
+    compress_above: Option<usize>,
+) -> Vec<u8> {
+    let Node::Internal { children, .. } = node else {
+        unreachable!("pages are written for internal nodes");
+    };
+    let tag = match compress_above {
+        Some(_) => COMPRESSED_LEAF_PAGE,
+        None => LEAF_PAGE,
+    };
+    let mut page = vec![tag];
+    (children.len() as u32).encode(&mut page);
+    for child in children {
+        if let Node::Leaf { key, value, .. } = &**child {
+            key.encode(&mut page);
+            #[cfg(feature = "compression")]
+            if let Some(threshold) = compress_above {
+                crate::core::compress::encode_value(value, threshold, &mut page);
+                continue;
+            }
+            value.encode(&mut page);
+        }
+    }
+    page
+}
+
+#[cfg(feature = "compression")]
+fn decode_value<V: Decode>(input: &mut &[u8], compressed: bool) -> Result<V, Error> {
+    if compressed {
+        crate::core::compress::decode_value(input)
+    } else {
+        V::decode(input)
+    }
+}
+
+#[cfg(not(feature = "compression"))]
+fn decode_value<V: Decode>(input: &mut &[u8], compressed: bool) -> Result<V, Error> {
+    if compressed {
+        return Err(Error::Malformed(
+            "compressed pages need the compression feature".to_string(),
+        ));
+    }
+    V::decode(input)
+}
+
+fn encode_internal_page<K: Ord + Clone + Default + Encode, V: AsRef<[u8]>>(
+    children: &[NodeRef<Node<K, V>>],
+    ids: &[NodeHash],
+) -> Vec<u8> {
+    let mut page = vec![INTERNAL_PAGE];
+    (children.len() as u32).encode(&mut page);
+    for (child, id) in children.iter().zip(ids) {
+        child.hash().encode(&mut page);
+        id.encode(&mut page);
+    }
+    for child in children {
+        child.key().encode(&mut page);
+    }
+    page
+}
+
+pub(crate) enum DecodedPage<K, V> {
+    Leaf(Node<K, V>),
+    // (subtree hash, page id) per child, and the children's max keys.
+    Internal(Vec<(NodeHash, NodeHash)>, Vec<K>),
+}
+
+// The bytes of page `id` without its checksum, if they pass it.
+pub(crate) fn page_body<'a>(id: &NodeHash, page: &'a [u8]) -> Result<&'a [u8], Error> {
+    if page.len() < 4 {
+        return Err(Error::ChecksumMismatch(*id));
+    }
+    let (body, mut checksum) = page.split_at(page.len() - 4);
+    if u32::decode(&mut checksum)? != crc32c(body) {
+        return Err(Error::ChecksumMismatch(*id));
+    }
+    Ok(body)
+}
+
+// The ids of the pages page `id` points to. Leaf pages point nowhere.
+pub(crate) fn child_pages(id: &NodeHash, page: &[u8]) -> Result<Vec<NodeHash>, Error> {
+    let mut input = page_body(id, page)?;
+    let tag = u8::decode(&mut input)?;
+    let count = u32::decode(&mut input)?;
+    match tag {
+        LEAF_PAGE | COMPRESSED_LEAF_PAGE => Ok(vec![]),
+        INTERNAL_PAGE => (0..count)
+            .map(|_| {
+                <NodeHash>::decode(&mut input)?;
+                NodeHash::decode(&mut input)
+            })
+            .collect(),
+        tag => Err(Error::Malformed(format!("unknown page tag {tag}"))),
+    }
+}
+
+// Decodes a page body, as returned by `page_body`.
+pub(crate) fn decode_page<K, V>(body: &[u8]) -> Result<DecodedPage<K, V>, Error>
+where
+    K: Ord + Clone + Default + Encode + Decode,
+    V: AsRef<[u8]> + Decode,
+{
+    decode_page_in(body, NodeAlloc::default())
+}
+
+// `decode_page`, placing the decoded leaves in `alloc`.
+fn decode_page_in<K, V>(body: &[u8], alloc: NodeAlloc) -> Result<DecodedPage<K, V>, Error>
+where
+    K: Ord + Clone + Default + Encode + Decode,
+    V: AsRef<[u8]> + Decode,
+{
+    let mut input = body;
+    let tag = u8::decode(&mut input)?;
+    let count = u32::decode(&mut input)?;
+    let decoded = match tag {
+        LEAF_PAGE | COMPRESSED_LEAF_PAGE => {
+            let mut children = Vec::with_capacity(count as usize);
+            for _ in 0..count {
+                let key = K::decode(&mut input)?;
+                let value = decode_value::<V>(&mut input, tag == COMPRESSED_LEAF_PAGE)?;
+                let hash = NodeHash::leaf(&key, value.as_ref());
+                children.push(NodeRef::new(Node::Leaf { key, value, hash }, alloc));
+            }
+            if !children.is_sorted_by(|a, b| a.key() < b.key()) {
+                return Err(Error::Malformed(
+                    "leaf page keys are out of order".to_string(),
+                ));
+            }
+            let mut node = Node::Internal {
+                hash: NodeHash::default(),
+                children,
+                max_key: K::default(),
+                usage: Usage::default(),
+            };
+            node.recalculate();
+            DecodedPage::Leaf(node)
+        }
+        INTERNAL_PAGE => {
+            let mut children = Vec::with_capacity(count as usize);
+            for _ in 0..count {
+                children.push((NodeHash::decode(&mut input)?, NodeHash::decode(&mut input)?));
+            }
+            let keys = (0..count)
+                .map(|_| K::decode(&mut input))
+                .collect::<Result<_, _>>()?;
+            DecodedPage::Internal(children, keys)
+        }
+        tag => return Err(Error::Malformed(format!("unknown page tag {tag}"))),
+    };
+    if !input.is_empty() {
+        return Err(Error::Malformed("trailing bytes after page".to_string()));
+    }
+    Ok(decoded)
+}
+
+pub(crate) enum Step<V> {
+    // The id and subtree hash of the child page `key` routes to.
+    Child(NodeHash, NodeHash),
+    // `key`'s value in a leaf page.
+    Leaf(Option<V>),
+}
+
+// Decodes page `id`, checking it against its id and its subtree against
+// `hash`, the hash its parent recorded.
+pub(crate) fn open_page<K, V>(
+    id: &NodeHash,
+    hash: &NodeHash,
+    page: &[u8],
+) -> Result<DecodedPage<K, V>, Error>
+where
+    K: Ord + Clone + Default + Encode + Decode,
+    V: AsRef<[u8]> + Decode,
+{
+    let body = page_body(id, page)?;
+    let actual = NodeHash::digest(page);
+    if actual != *id {
+        return Err(Error::HashMismatch {
+            expected: *id,
+            actual,
+        });
+    }
+    let decoded = decode_page::<K, V>(body)?;
+    let actual = match &decoded {
+        // Only an empty tree's root page is empty.
+        DecodedPage::Leaf(node) => NodeHash::root_of(*node.hash(), node.leaf_count()),
+        DecodedPage::Internal(children, _) => {
+            let mut sum = NodeHash::default();
+            for (child_hash, _) in children {
+                sum.xor(child_hash);
+            }
+            sum
+        }
+    };
+    if actual != *hash {
+        return Err(Error::HashMismatch {
+            expected: *hash,
+            actual,
+        });
+    }
+    Ok(decoded)
+}
+
+// Opens page `id` and takes one step on `key`'s path.
+pub(crate) fn step<K, V>(
+    id: &NodeHash,
+    hash: &NodeHash,
+    page: &[u8],
+    key: &K,
+) -> Result<Step<V>, Error>
+where
+    K: Ord + Clone + Default + Encode + Decode,
+    V: AsRef<[u8]> + Decode,
+{
+    match open_page::<K, V>(id, hash, page)? {
+        DecodedPage::Leaf(node) => {
+            let children = node.children();
+            let Ok(index) = children.binary_search_by(|child| child.key().cmp(key)) else {
+                return Ok(Step::Leaf(None));
+            };
+            // A leaf page decodes to an internal node of unshared leaves.
+            let Node::Internal { mut children, .. } = node else {
+                return Err(Error::Malformed("leaf page without entries".to_string()));
+            };
+            match NodeRef::try_unwrap(children.swap_remove(index)) {
+                Ok(Node::Leaf { value, .. }) => Ok(Step::Leaf(Some(value))),
+                _ => Err(Error::Malformed("leaf page entry isn't a leaf".to_string())),
+            }
+        }
+        DecodedPage::Internal(children, keys) => {
+            let (child_hash, child) = children[child_index(children.len(), &keys, key)?];
+            Ok(Step::Child(child, child_hash))
+        }
+    }
+}
+
+// Routes like the tree does: to the first child whose key isn't below
+// `key`, or to the last one.
+pub(crate) fn child_index<K: Ord>(children: usize, keys: &[K], key: &K) -> Result<usize, Error> {
+    if children == 0 {
+        return Err(Error::Malformed(
+            "internal page without children".to_string(),
+        ));
+    }
+    Ok(keys
+        .partition_point(|child_key| child_key < key)
+        .min(children - 1))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::hash::EMPTY_ROOT;
+    use crate::store::MemoryStore;
+
+    fn tree(keys: std::ops::Range<u32>) -> MerkleSearchTree<u32> {
+        let mut tree = MerkleSearchTree::new(4);
+        for i in keys {
+            tree.insert(i, format!("v{i}"));
+        }
+        tree
+    }
+
+    #[test]
+    fn test_canonical_bytes() {
+        let mut narrow = MerkleSearchTree::<u32>::new(3);
+        let mut wide = MerkleSearchTree::<u32>::new(16);
+        let entries = [(1, "a"), (2, "bc")];
+        for (key, value) in entries {
+            narrow.insert(key, value.to_string());
+        }
+        for (key, value) in entries.into_iter().rev() {
+            wide.insert(key, value.to_string());
+        }
+        let bytes = narrow.canonical_bytes();
+        assert_eq!(bytes, wide.canonical_bytes());
+
+        let mut expected = vec![CANONICAL_FORMAT];
+        expected.extend_from_slice(&narrow.hash().0);
+        expected.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 2]);
+        expected.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 1, b'a']);
+        expected.extend_from_slice(&[0, 0, 0, 2, 0, 0, 0, 2, b'b', b'c']);
+        assert_eq!(bytes, expected);
+    }
+
+    // Pins the page and canonical encodings: if this fails, stored snapshot
+    // digests would no longer match, and the format needs a new version.
+    #[test]
+    fn test_encodings_are_stable() {
+        let tree = tree(0..100);
+        let (manifest, _) = tree.write_snapshot(&mut MemoryStore::new()).unwrap();
+        assert_eq!(
+            manifest.root_page.to_string(),
+            "21071cc0d36912bab861ef12e3899bd7549edf4a22fa9aef6f1923a2e87a73ad"
+        );
+        assert_eq!(
+            <NodeHash>::digest(&tree.canonical_bytes()).to_string(),
+            "9266a0298e8a816921c9172a42b14593612d5021708bce584a98580da1739023"
+        );
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let original = tree(0..200);
+        let mut store = MemoryStore::new();
+        let (manifest, stats) = original.write_snapshot(&mut store).unwrap();
+        assert_eq!(stats.pages_written, store.len());
+
+        let mut restored = MerkleSearchTree::<u32>::new(4);
+        restored.restore(&store, &manifest).unwrap();
+        assert_eq!(restored.hash(), original.hash());
+        assert_eq!(restored.depth(), original.depth());
+        assert!(restored.iter().eq(original.iter()));
+
+        // The restored tree keeps working.
+        restored.insert(500, "v500".to_string());
+        assert_eq!(restored.get(&500), Some(&"v500".to_string()));
+
+        let mut bytes = Vec::new();
+        manifest.encode(&mut bytes);
+        assert_eq!(Manifest::decode(&mut bytes.as_slice()).unwrap(), manifest);
+
+        // An empty tree is shown by `EMPTY_ROOT`, and restores as such.
+        let empty = tree(0..0);
+        let (manifest, _) = empty.write_snapshot(&mut store).unwrap();
+        assert_eq!(manifest.root_hash, EMPTY_ROOT);
+        restored.restore(&store, &manifest).unwrap();
+        assert!(restored.is_empty());
+        assert_eq!(*restored.hash(), EMPTY_ROOT);
+    }
+
+    #[test]
+    fn test_delta_snapshot_writes_changed_pages_only() {
+        let mut tree = tree(0..1000);
+        let mut store = MemoryStore::new();
+        let (first, full) = tree.write_snapshot(&mut store).unwrap();
+
+        tree.insert(10, "changed".to_string());
+        let (second, delta) = tree.write_delta_snapshot(&mut store, &first).unwrap();
+
+        // Only the path from the changed leaf to the root is rewritten.
+        assert_eq!(delta.pages_written, tree.depth());
+        assert_eq!(delta.pages_written + delta.pages_reused, second.pages.len());
+        assert!(delta.bytes_written < full.bytes_written / 10);
+
+        let mut restored = MerkleSearchTree::<u32>::new(4);
+        restored.restore(&store, &second).unwrap();
+        assert_eq!(restored.get(&10), Some(&"changed".to_string()));
+    }
+
+    #[test]
+    fn test_restore_detects_tampering() {
+        let tree = tree(0..50);
+        let mut store = MemoryStore::new();
+        let (manifest, _) = tree.write_snapshot(&mut store).unwrap();
+
+        let mut restored = MerkleSearchTree::<u32>::new(4);
+        let mut tampered = manifest.clone();
+        tampered.root_hash = NodeHash::digest(b"something else");
+        assert!(matches!(
+            restored.restore(&store, &tampered),
+            Err(Error::HashMismatch { .. })
+        ));
+
+        // Damaged bytes fail the checksum; an intact page under the wrong id
+        // fails the hash.
+        let mut pages = manifest.pages.iter().copied();
+        let (victim, other) = (pages.next().unwrap(), pages.next().unwrap());
+        let original = store.get(&victim).unwrap().unwrap();
+        let mut flipped = original.clone();
+        flipped[1] ^= 0x10;
+        store.put(victim, &flipped).unwrap();
+        assert!(matches!(
+            restored.restore(&store, &manifest),
+            Err(Error::ChecksumMismatch(id)) if id == victim
+        ));
+        store
+            .put(victim, &store.get(&other).unwrap().unwrap())
+            .unwrap();
+        assert!(matches!(
+            restored.restore(&store, &manifest),
+            Err(Error::HashMismatch { expected, .. }) if expected == victim
+        ));
+
+        store.delete(&victim).unwrap();
+        assert!(matches!(
+            restored.restore(&store, &manifest),
+            Err(Error::MissingPage(_))
+        ));
+    }
+}
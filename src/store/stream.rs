@@ -0,0 +1,312 @@
+// Async scans of a snapshot in a store, for services that read trees
+// straight from storage on an async executor.
+//
+// A stream walks the snapshot's pages in key order on a thread of its own,
+// checking each page like `restore` does, and queues the entries of each
+// leaf page as one batch. Up to `prefetch` batches are read ahead, so the
+// store is busy while the consumer works through the current batch; a
+// consumer that falls behind holds the reader back rather than letting the
+// queue grow. Dropping the stream stops the reader at its next batch.
+//
+// `poll_next` has the shape of the `futures` crate's `Stream::poll_next`,
+// so implementing that trait is a single forwarding call; `next_entry`
+// returns a future for use without it. Like `Quiescence`, it runs under any
+// executor.
+
+use std::future::Future;
+use std::ops::RangeBounds;
+use std::pin::Pin;
+use std::sync::mpsc::{self, Receiver, SyncSender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+use crate::core::alloc::NodeRef;
+use crate::core::codec::{Decode, Encode};
+use crate::core::error::Error;
+use crate::core::hash::NodeHash;
+use crate::core::range::KeyRange;
+use crate::core::tree::Node;
+use crate::store::Store;
+use crate::store::snapshot::{DecodedPage, Manifest, open_page};
+
+type Batch<K, V> = Result<Vec<(K, V)>, Error>;
+
+pub struct SnapshotStream<K, V> {
+    batches: Receiver<Batch<K, V>>,
+    // The task waiting for the next batch.
+    waker: Arc<Mutex<Option<Waker>>>,
+    current: std::vec::IntoIter<(K, V)>,
+    done: bool,
+}
+
+// Nothing in the stream is pinned in place.
+impl<K, V> Unpin for SnapshotStream<K, V> {}
+
+impl Manifest {
+    // Streams every entry of the snapshot in key order.
+    pub fn iter_stream<K, V, S>(&self, store: Arc<S>, prefetch: usize) -> SnapshotStream<K, V>
+    where
+        K: Ord + Clone + Default + Encode + Decode + Send + 'static,
+        V: AsRef<[u8]> + Decode + Send + 'static,
+        S: Store + Send + Sync + 'static,
+    {
+        self.range_stream(store, KeyRange::full(), prefetch)
+    }
+
+    // Streams the entries in `range` in key order, reading only the pages
+    // that can hold them.
+    pub fn range_stream<K, V, S>(
+        &self,
+        store: Arc<S>,
+        range: KeyRange<K>,
+        prefetch: usize,
+    ) -> SnapshotStream<K, V>
+    where
+        K: Ord + Clone + Default + Encode + Decode + Send + 'static,
+        V: AsRef<[u8]> + Decode + Send + 'static,
+        S: Store + Send + Sync + 'static,
+    {
+        let (sender, batches) = mpsc::sync_channel(prefetch.max(1));
+        let waker = Arc::new(Mutex::new(None::<Waker>));
+        let root = (self.root_page, self.root_hash);
+        let wake = waker.clone();
+        thread::spawn(move || {
+            read_ahead(&*store, root, &range, &sender, &wake);
+            // Wake the consumer once more to see the end.
+            drop(sender);
+            wake_consumer(&wake);
+        });
+        SnapshotStream {
+            batches,
+            waker,
+            current: Vec::new().into_iter(),
+            done: false,
+        }
+    }
+}
+
+impl<K, V> SnapshotStream<K, V> {
+    // The next entry, None after the last one. A page that is missing or
+    // fails its checks yields one error and ends the stream.
+    pub fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<(K, V), Error>>> {
+        let this = self.get_mut();
+        let mut registered = false;
+        loop {
+            if let Some(entry) = this.current.next() {
+                return Poll::Ready(Some(Ok(entry)));
+            }
+            if this.done {
+                return Poll::Ready(None);
+            }
+            match this.batches.try_recv() {
+                Ok(Ok(batch)) => this.current = batch.into_iter(),
+                Ok(Err(error)) => {
+                    this.done = true;
+                    return Poll::Ready(Some(Err(error)));
+                }
+                Err(TryRecvError::Disconnected) => {
+                    this.done = true;
+                    return Poll::Ready(None);
+                }
+                Err(TryRecvError::Empty) if registered => return Poll::Pending,
+                Err(TryRecvError::Empty) => {
+                    // Look again once the waker is in place, in case a batch
+                    // landed in between.
+                    *lock(&this.waker) = Some(cx.waker().clone());
+                    registered = true;
+                }
+            }
+        }
+    }
+
+    pub fn next_entry(&mut self) -> Next<'_, K, V> {
+        Next { stream: self }
+    }
+}
+
+pub struct Next<'a, K, V> {
+    stream: &'a mut SnapshotStream<K, V>,
+}
+
+impl<K, V> Future for Next<'_, K, V> {
+    type Output = Option<Result<(K, V), Error>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut *self.stream).poll_next(cx)
+    }
+}
+
+// Walks the pages depth first, sending each leaf page's entries in `range`.
+fn read_ahead<K, V, S>(
+    store: &S,
+    root: (NodeHash, NodeHash),
+    range: &KeyRange<K>,
+    sender: &SyncSender<Batch<K, V>>,
+    waker: &Mutex<Option<Waker>>,
+) where
+    K: Ord + Clone + Default + Encode + Decode,
+    V: AsRef<[u8]> + Decode,
+    S: Store,
+{
+    let mut pending = vec![root];
+    while let Some((id, hash)) = pending.pop() {
+        let batch = store
+            .get(&id)
+            .map_err(Error::from)
+            .and_then(|page| page.ok_or(Error::MissingPage(id)))
+            .and_then(|page| open_page::<K, V>(&id, &hash, &page));
+        let batch = match batch {
+            Ok(DecodedPage::Internal(children, keys)) => {
+                for (child_hash, child) in children[wanted_children(&keys, range)].iter().rev() {
+                    pending.push((*child, *child_hash));
+                }
+                continue;
+            }
+            Ok(DecodedPage::Leaf(Node::Internal { children, .. })) => Ok(children
+                .into_iter()
+                .filter_map(|child| match NodeRef::try_unwrap(child) {
+                    Ok(Node::Leaf { key, value, .. }) if range.contains(&key) => Some((key, value)),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()),
+            Ok(DecodedPage::Leaf(_)) => {
+                Err(Error::Malformed("leaf page without entries".to_string()))
+            }
+            Err(error) => Err(error),
+        };
+        let failed = batch.is_err();
+        if matches!(&batch, Ok(entries) if entries.is_empty()) {
+            continue;
+        }
+        // Blocks while `prefetch` batches wait; fails once the stream is dropped.
+        if sender.send(batch).is_err() {
+            return;
+        }
+        wake_consumer(waker);
+        if failed {
+            return;
+        }
+    }
+}
+
+// The children of an internal page that can hold keys in `range`, given
+// their max keys. A child holds the keys up to its max key, so children
+// wholly below the range are skipped, and none is needed after the first
+// one reaching its end.
+pub(crate) fn wanted_children<K: Ord>(keys: &[K], range: &KeyRange<K>) -> std::ops::Range<usize> {
+    let below = keys.partition_point(|key| range.start.as_ref().is_some_and(|start| key < start));
+    let through = match &range.end {
+        Some(end) => keys.partition_point(|key| key < end) + 1,
+        None => keys.len(),
+    };
+    below..through.min(keys.len())
+}
+
+fn wake_consumer(waker: &Mutex<Option<Waker>>) {
+    if let Some(waker) = lock(waker).take() {
+        waker.wake();
+    }
+}
+
+fn lock(waker: &Mutex<Option<Waker>>) -> std::sync::MutexGuard<'_, Option<Waker>> {
+    // Only ever holds a whole waker or none.
+    waker
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::tree::MerkleSearchTree;
+    use crate::store::MemoryStore;
+    use crate::store::snapshot::child_pages;
+    use std::task::Wake;
+
+    struct Unpark(thread::Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = std::pin::pin!(future);
+        let waker = Waker::from(Arc::new(Unpark(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    fn collect(mut stream: SnapshotStream<u32, String>) -> Result<Vec<(u32, String)>, Error> {
+        block_on(async {
+            let mut entries = Vec::new();
+            while let Some(entry) = stream.next_entry().await {
+                entries.push(entry?);
+            }
+            Ok(entries)
+        })
+    }
+
+    #[test]
+    fn test_streams_match_the_tree() {
+        let mut tree = MerkleSearchTree::<u32>::new(4);
+        for i in 0..2000 {
+            tree.insert(i * 2, format!("v{i}"));
+        }
+        let mut store = MemoryStore::new();
+        let (manifest, _) = tree.write_snapshot(&mut store).unwrap();
+        let store = Arc::new(store);
+
+        let all = collect(manifest.iter_stream(store.clone(), 2)).unwrap();
+        let expected: Vec<_> = tree.iter().map(|(k, v)| (*k, v.clone())).collect();
+        assert_eq!(all, expected);
+
+        for (start, end) in [(0, 1), (101, 999), (3990, 5000), (5000, 6000)] {
+            let range = KeyRange {
+                start: Some(start),
+                end: Some(end),
+            };
+            let entries = collect(manifest.range_stream(store.clone(), range, 1)).unwrap();
+            let expected: Vec<_> = tree
+                .range(start..end)
+                .map(|(k, v)| (*k, v.clone()))
+                .collect();
+            assert_eq!(entries, expected);
+        }
+
+        // An unread stream can go away while the reader waits on it.
+        drop(manifest.iter_stream::<u32, String, _>(store, 1));
+    }
+
+    #[test]
+    fn test_missing_page_ends_the_stream() {
+        let mut tree = MerkleSearchTree::<u32>::new(4);
+        for i in 0..500 {
+            tree.insert(i, format!("v{i}"));
+        }
+        let mut store = MemoryStore::new();
+        let (manifest, _) = tree.write_snapshot(&mut store).unwrap();
+        let leaf = manifest
+            .pages
+            .iter()
+            .find(|id| {
+                let page = store.get(id).unwrap().unwrap();
+                child_pages(id, &page).unwrap().is_empty()
+            })
+            .copied()
+            .unwrap();
+        store.delete(&leaf).unwrap();
+        let result = collect(manifest.iter_stream(Arc::new(store), 2));
+        assert!(matches!(result, Err(Error::MissingPage(id)) if id == leaf));
+    }
+}
@@ -0,0 +1,219 @@
+// Incremental verification of a stream of entries against a root hash, for
+// bulk restores that should abort on corrupted input instead of loading
+// everything first.
+//
+// The root hash alone can only be checked once the stream ends, as it is the
+// XOR of every value's hash. Checkpoints split the key space into ranges
+// with their expected hashes, e.g. the top-level pages of a snapshot; each
+// range is checked as soon as the stream moves past it, so a bad entry is
+// reported about one page later.
+
+use std::ops::Bound;
+
+use crate::core::codec::{Decode, Encode};
+use crate::core::error::Error;
+use crate::core::hash::NodeHash;
+use crate::core::range::KeyRange;
+use crate::store::Store;
+use crate::store::snapshot::{DecodedPage, Manifest, decode_page, page_body};
+
+pub struct Verifier<K> {
+    root: NodeHash,
+    // The expected hash of each range, in key order, by the range's upper
+    // bound. The current range is the first one.
+    checkpoints: Vec<(Bound<K>, NodeHash)>,
+    next: usize,
+    // The hashes of the current range and of the whole stream so far.
+    range_hash: NodeHash,
+    total_hash: NodeHash,
+    last_key: Option<K>,
+    entries: usize,
+}
+
+impl<K: Ord + Clone + Encode> Verifier<K> {
+    // Checks only the root, when the stream ends.
+    pub fn new(root: NodeHash) -> Self {
+        Verifier {
+            root,
+            checkpoints: Vec::new(),
+            next: 0,
+            range_hash: NodeHash::default(),
+            total_hash: NodeHash::default(),
+            last_key: None,
+            entries: 0,
+        }
+    }
+
+    // Checkpoints covering the key space in order, such as the output of
+    // `subtree_roots_at_depth`.
+    pub fn with_ranges(mut self, ranges: Vec<(KeyRange<K>, NodeHash)>) -> Self {
+        self.checkpoints = ranges
+            .into_iter()
+            .map(|(range, hash)| {
+                let end = range.end.map_or(Bound::Unbounded, Bound::Excluded);
+                (end, hash)
+            })
+            .collect();
+        self
+    }
+
+    // Adds the next entry, failing as soon as the stream can no longer match:
+    // `Error::HashMismatch` if it completes a range with the wrong hash, or
+    // `Error::Malformed` if it is out of order or past the last range.
+    pub fn push<V: AsRef<[u8]>>(&mut self, key: &K, value: &V) -> Result<(), Error> {
+        if self.last_key.as_ref().is_some_and(|last| last >= key) {
+            return Err(Error::Malformed("entries are out of key order".to_string()));
+        }
+        while self.next < self.checkpoints.len()
+            && !Self::below(key, &self.checkpoints[self.next].0)
+        {
+            self.close_range()?;
+        }
+        if !self.checkpoints.is_empty() && self.next == self.checkpoints.len() {
+            return Err(Error::Malformed(
+                "entry is past the last checkpoint".to_string(),
+            ));
+        }
+        let hash = NodeHash::leaf(key, value.as_ref());
+        self.range_hash.xor(&hash);
+        self.total_hash.xor(&hash);
+        self.last_key = Some(key.clone());
+        self.entries += 1;
+        Ok(())
+    }
+
+    // Checks the remaining ranges and the root once the stream has ended.
+    pub fn finish(mut self) -> Result<usize, Error> {
+        while self.next < self.checkpoints.len() {
+            self.close_range()?;
+        }
+        let actual = NodeHash::root_of(self.total_hash, self.entries);
+        if actual != self.root {
+            return Err(Error::RootMismatch {
+                expected: self.root,
+                actual,
+            });
+        }
+        Ok(self.entries)
+    }
+
+    // The number of ranges checked so far.
+    pub fn checked(&self) -> usize {
+        self.next
+    }
+
+    fn close_range(&mut self) -> Result<(), Error> {
+        let expected = self.checkpoints[self.next].1;
+        if self.range_hash != expected {
+            return Err(Error::HashMismatch {
+                expected,
+                actual: self.range_hash,
+            });
+        }
+        self.range_hash = NodeHash::default();
+        self.next += 1;
+        Ok(())
+    }
+
+    fn below(key: &K, end: &Bound<K>) -> bool {
+        match end {
+            Bound::Included(end) => key <= end,
+            Bound::Excluded(end) => key < end,
+            Bound::Unbounded => true,
+        }
+    }
+}
+
+impl Manifest {
+    // A verifier for this snapshot's content, with a checkpoint for each
+    // child of the root page. Only the root page is read.
+    pub fn verifier<K, V, S>(&self, store: &S) -> Result<Verifier<K>, Error>
+    where
+        K: Ord + Clone + Default + Encode + Decode,
+        V: AsRef<[u8]> + Decode,
+        S: Store,
+    {
+        let mut verifier = Verifier::new(self.root_hash);
+        let bytes = store
+            .get(&self.root_page)?
+            .ok_or(Error::MissingPage(self.root_page))?;
+        if let DecodedPage::Internal(children, max_keys) =
+            decode_page::<K, V>(page_body(&self.root_page, &bytes)?)?
+        {
+            verifier.checkpoints = children
+                .into_iter()
+                .zip(max_keys)
+                .map(|((hash, _), max_key)| (Bound::Included(max_key), hash))
+                .collect();
+        }
+        Ok(verifier)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::tree::MerkleSearchTree;
+    use crate::store::MemoryStore;
+
+    fn tree() -> MerkleSearchTree<u32> {
+        let mut tree = MerkleSearchTree::new(4);
+        for i in 0..200 {
+            tree.insert(i, format!("v{i}"));
+        }
+        tree
+    }
+
+    #[test]
+    fn test_verifier_ranges() {
+        let tree = tree();
+        let ranges = tree.subtree_roots_at_depth(1);
+        let mut verifier = Verifier::new(*tree.hash()).with_ranges(ranges.clone());
+        for (key, value) in tree.iter() {
+            verifier.push(key, value).unwrap();
+        }
+        assert_eq!(verifier.checked(), ranges.len() - 1);
+        assert_eq!(verifier.finish().unwrap(), 200);
+
+        // A corrupted value fails at the end of its range, not of the stream.
+        let mut verifier = Verifier::new(*tree.hash()).with_ranges(ranges);
+        let failed = tree.iter().position(|(key, value)| {
+            let value = if *key == 3 { "bad" } else { value.as_str() };
+            verifier.push(key, &value).is_err()
+        });
+        assert!(matches!(failed, Some(at) if at < 100));
+
+        let mut verifier = Verifier::new(*tree.hash());
+        verifier.push(&5, &"v5").unwrap();
+        assert!(matches!(verifier.push(&5, &"v5"), Err(Error::Malformed(_))));
+        assert!(matches!(verifier.finish(), Err(Error::RootMismatch { .. })));
+    }
+
+    #[test]
+    fn test_verifier_from_manifest() {
+        let tree = tree();
+        let mut store = MemoryStore::new();
+        let (manifest, _) = tree.write_snapshot(&mut store).unwrap();
+        let verifier = || manifest.verifier::<u32, String, _>(&store).unwrap();
+
+        let mut good = verifier();
+        for (key, value) in tree.iter() {
+            good.push(key, value).unwrap();
+        }
+        assert_eq!(good.finish().unwrap(), 200);
+
+        // A missing entry is caught when the next range starts.
+        let mut missing = verifier();
+        let failed = tree
+            .iter()
+            .filter(|(key, _)| **key != 0)
+            .position(|(key, value)| missing.push(key, value).is_err());
+        assert!(matches!(failed, Some(at) if at < 100));
+
+        let mut extra = verifier();
+        for (key, value) in tree.iter() {
+            extra.push(key, value).unwrap();
+        }
+        assert!(extra.push(&500, &"v500").is_err());
+    }
+}
@@ -0,0 +1,345 @@
+// Versioned persistence: every commit writes a delta snapshot of a tree to a
+// shared store and records it as the next version, so any past version can
+// be opened, queried or diffed straight from the store.
+//
+// The version list is kept in memory; it encodes with `Encode` so it can be
+// persisted next to the store and handed back to `VersionedStore::open`.
+// Old versions are pruned by a retention policy; releasing their roots frees
+// the pages no kept version shares.
+
+use crate::core::codec::{Decode, Encode};
+use crate::core::error::Error;
+use crate::core::tree::MerkleSearchTree;
+#[cfg(feature = "crdt")]
+use crate::crdt::patch::{Patch, create_patch};
+use crate::store::Store;
+use crate::store::gc::GcReport;
+use crate::store::shared::SharedStore;
+use crate::store::snapshot::Manifest;
+
+// The fanout of the trees restored to diff versions. It only shapes the
+// transient trees, not the result.
+#[cfg(feature = "crdt")]
+const DIFF_FANOUT: usize = 16;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Version {
+    pub number: u64,
+    // When the version was committed, in the caller's unit (e.g. Unix seconds).
+    pub timestamp: u64,
+    pub manifest: Manifest,
+}
+
+// Which versions `retain_versions` keeps. The latest version is always kept.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Retention {
+    keep_last: usize,
+    // (period, count): the latest version of each of the last `count` periods.
+    periodic: Vec<(u64, u64)>,
+}
+
+impl Retention {
+    // Keeps the latest `count` versions.
+    pub fn keep_last(count: usize) -> Self {
+        Retention {
+            keep_last: count,
+            periodic: Vec::new(),
+        }
+    }
+
+    // Also keeps the latest version of each of the last `count` periods of
+    // `period` length, counted back from `now`. Hourly for a week is
+    // `keep_every(3600, 24 * 7)` with timestamps in seconds.
+    pub fn keep_every(mut self, period: u64, count: u64) -> Self {
+        assert!(period > 0, "the period must be positive");
+        self.periodic.push((period, count));
+        self
+    }
+
+    fn keeps(&self, versions: &[Version], now: u64) -> Vec<bool> {
+        let mut keep = vec![false; versions.len()];
+        let recent = versions.len().saturating_sub(self.keep_last.max(1));
+        keep[recent..].iter_mut().for_each(|keep| *keep = true);
+        for &(period, count) in &self.periodic {
+            // Walking back from the latest, the first version seen in each
+            // period is that period's latest.
+            let mut last_period = None;
+            for (index, version) in versions.iter().enumerate().rev() {
+                let age = now.saturating_sub(version.timestamp) / period;
+                if age >= count {
+                    break;
+                }
+                if last_period != Some(age) {
+                    keep[index] = true;
+                    last_period = Some(age);
+                }
+            }
+        }
+        keep
+    }
+}
+
+pub struct VersionedStore<S> {
+    store: SharedStore<S>,
+    // In commit order, so numbers and timestamps ascend.
+    versions: Vec<Version>,
+}
+
+impl<S: Store> VersionedStore<S> {
+    pub fn new(inner: S) -> Self {
+        VersionedStore {
+            store: SharedStore::new(inner),
+            versions: Vec::new(),
+        }
+    }
+
+    // Reopens a store holding `versions`, as returned by `versions`.
+    pub fn open(inner: S, versions: Vec<Version>) -> Result<Self, Error> {
+        let roots: Vec<_> = versions.iter().map(|v| v.manifest.root_page).collect();
+        Ok(VersionedStore {
+            store: SharedStore::open(inner, &roots)?,
+            versions,
+        })
+    }
+
+    // Writes the pages of `tree` the latest version doesn't share, and
+    // returns the new version's number.
+    pub fn commit<K, V>(
+        &mut self,
+        tree: &MerkleSearchTree<K, V>,
+        timestamp: u64,
+    ) -> Result<u64, Error>
+    where
+        K: Ord + Clone + Default + Encode + Decode,
+        V: AsRef<[u8]> + Encode + Decode,
+    {
+        let (manifest, _) = match self.versions.last() {
+            Some(latest) => tree.write_delta_snapshot(&mut self.store, &latest.manifest)?,
+            None => tree.write_snapshot(&mut self.store)?,
+        };
+        self.store.retain(manifest.root_page)?;
+        let number = self.versions.last().map_or(1, |latest| latest.number + 1);
+        self.versions.push(Version {
+            number,
+            timestamp,
+            manifest,
+        });
+        Ok(number)
+    }
+
+    pub fn versions(&self) -> &[Version] {
+        &self.versions
+    }
+
+    pub fn version(&self, number: u64) -> Result<&Version, Error> {
+        self.versions
+            .binary_search_by_key(&number, |version| version.number)
+            .map(|index| &self.versions[index])
+            .map_err(|_| Error::UnknownVersion(number))
+    }
+
+    // The latest version committed at or before `timestamp`.
+    pub fn version_at(&self, timestamp: u64) -> Option<&Version> {
+        let count = self.versions.partition_point(|v| v.timestamp <= timestamp);
+        count.checked_sub(1).map(|index| &self.versions[index])
+    }
+
+    // The tree as of `version`.
+    pub fn open_at_version<K, V>(
+        &self,
+        version: u64,
+        max_children: usize,
+    ) -> Result<MerkleSearchTree<K, V>, Error>
+    where
+        K: Ord + Clone + Default + Encode + Decode,
+        V: AsRef<[u8]> + Encode + Decode,
+    {
+        let mut tree = MerkleSearchTree::new(max_children);
+        tree.restore(&self.store, &self.version(version)?.manifest)?;
+        Ok(tree)
+    }
+
+    // The value of `key` as of `version`, reading only the pages on its path.
+    pub fn get_at<K, V>(&self, key: &K, version: u64) -> Result<Option<V>, Error>
+    where
+        K: Ord + Clone + Default + Encode + Decode,
+        V: AsRef<[u8]> + Decode,
+    {
+        self.version(version)?.manifest.get(&self.store, key)
+    }
+
+    // The patch that turns version `from` into version `to`.
+    #[cfg(feature = "crdt")]
+    pub fn diff_versions<K, V>(&self, from: u64, to: u64) -> Result<Patch<K, V>, Error>
+    where
+        K: Ord + Clone + Default + Encode + Decode,
+        V: AsRef<[u8]> + Clone + Encode + Decode,
+    {
+        let from = self.open_at_version(from, DIFF_FANOUT)?;
+        let to = self.open_at_version(to, DIFF_FANOUT)?;
+        Ok(create_patch(&from, &to))
+    }
+
+    // Drops the versions `policy` doesn't keep, as of `now`, and frees the
+    // pages only they referenced.
+    pub fn retain_versions(&mut self, policy: &Retention, now: u64) -> Result<GcReport, Error> {
+        let keep = policy.keeps(&self.versions, now);
+        let mut report = GcReport {
+            pages_live: self.store.ids()?.len(),
+            ..Default::default()
+        };
+        let mut kept = Vec::new();
+        for (version, keep) in std::mem::take(&mut self.versions).into_iter().zip(keep) {
+            if keep {
+                kept.push(version);
+                continue;
+            }
+            let released = self.store.release(version.manifest.root_page)?;
+            report.pages_reclaimed += released.pages_reclaimed;
+            report.bytes_reclaimed += released.bytes_reclaimed;
+        }
+        report.pages_live -= report.pages_reclaimed;
+        self.versions = kept;
+        Ok(report)
+    }
+
+    pub fn store(&self) -> &SharedStore<S> {
+        &self.store
+    }
+}
+
+impl Encode for Version {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.number.encode(out);
+        self.timestamp.encode(out);
+        self.manifest.encode(out);
+    }
+}
+
+impl Decode for Version {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        Ok(Version {
+            number: u64::decode(input)?,
+            timestamp: u64::decode(input)?,
+            manifest: Manifest::decode(input)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    #[test]
+    fn test_time_travel() {
+        let mut store = VersionedStore::new(MemoryStore::new());
+        let mut tree = MerkleSearchTree::<u32>::new(4);
+        for i in 0..200 {
+            tree.insert(i, format!("v{i}"));
+        }
+        let first = store.commit(&tree, 100).unwrap();
+        tree.insert(7, "changed".to_string());
+        tree.remove(&8);
+        tree.insert(900, "new".to_string());
+        let second = store.commit(&tree, 200).unwrap();
+
+        assert_eq!(store.get_at(&7u32, first).unwrap(), Some("v7".to_string()));
+        assert_eq!(
+            store.get_at(&7u32, second).unwrap(),
+            Some("changed".to_string())
+        );
+        assert_eq!(store.get_at::<u32, String>(&8, second).unwrap(), None);
+        assert_eq!(store.get_at::<u32, String>(&900, first).unwrap(), None);
+        assert!(matches!(
+            store.get_at::<u32, String>(&7, 3),
+            Err(Error::UnknownVersion(3))
+        ));
+        assert_eq!(store.version_at(150).unwrap().number, first);
+        assert!(store.version_at(99).is_none());
+
+        let old = store.open_at_version::<u32, String>(first, 8).unwrap();
+        assert_eq!(old.len(), 200);
+        #[cfg(feature = "crdt")]
+        {
+            let patch = store.diff_versions::<u32, String>(first, second).unwrap();
+            assert_eq!(
+                patch.changes,
+                vec![
+                    (7, Some("changed".to_string())),
+                    (8, None),
+                    (900, Some("new".to_string())),
+                ]
+            );
+            assert_eq!(patch.post_root, *tree.hash());
+        }
+    }
+
+    #[test]
+    fn test_reopen() {
+        let mut store = VersionedStore::new(MemoryStore::new());
+        let mut tree = MerkleSearchTree::<u32>::new(4);
+        for i in 0..50 {
+            tree.insert(i, format!("v{i}"));
+            store.commit(&tree, i as u64).unwrap();
+        }
+
+        let mut bytes = Vec::new();
+        for version in store.versions() {
+            version.encode(&mut bytes);
+        }
+        let mut input = bytes.as_slice();
+        let versions = (0..50)
+            .map(|_| Version::decode(&mut input))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        let inner = store.store;
+        let reopened = VersionedStore::open(inner.into_inner(), versions).unwrap();
+        assert_eq!(
+            reopened.get_at(&10u32, 11).unwrap(),
+            Some("v10".to_string())
+        );
+        assert_eq!(reopened.get_at::<u32, String>(&10, 10).unwrap(), None);
+    }
+
+    #[test]
+    fn test_retain_versions() {
+        let mut store = VersionedStore::new(MemoryStore::new());
+        let mut tree = MerkleSearchTree::<u32>::new(4);
+        for i in 0..300 {
+            tree.insert(i, format!("v{i}"));
+        }
+        // A version every 10 minutes for two days.
+        for step in 0..288u64 {
+            tree.insert(step as u32, format!("step {step}"));
+            store.commit(&tree, step * 600).unwrap();
+        }
+        let now = 287 * 600;
+        let pages_before = store.store().ids().unwrap().len();
+
+        let policy = Retention::keep_last(3).keep_every(3600, 24);
+        let report = store.retain_versions(&policy, now).unwrap();
+        // The last 3, plus the latest of each hour of the last day; the
+        // current hour's is among the last 3.
+        assert_eq!(store.versions().len(), 3 + 24 - 1);
+        assert!(report.pages_reclaimed > 0);
+        assert_eq!(report.pages_live, pages_before - report.pages_reclaimed);
+        assert_eq!(report.pages_live, store.store().ids().unwrap().len());
+
+        // Every kept version is intact.
+        for version in store.versions() {
+            let step = version.timestamp / 600;
+            let value = store.get_at::<u32, String>(&(step as u32), version.number);
+            assert_eq!(value.unwrap(), Some(format!("step {step}")));
+        }
+
+        // Keeping only the latest frees everything else.
+        store
+            .retain_versions(&Retention::keep_last(0), now)
+            .unwrap();
+        assert_eq!(store.versions().len(), 1);
+        let latest = &store.versions()[0].manifest;
+        assert_eq!(store.store().ids().unwrap().len(), latest.pages.len());
+    }
+}
@@ -0,0 +1,579 @@
+// Anti-entropy across many peers, built on the pairwise sessions of `sync`.
+//
+// A `Gossip` picks the peer to sync with next, round-robin, at random or by
+// score, drives the session over a caller-supplied exchange function, and remembers
+// the root each peer was left at. Comparing those roots with the local one
+// tells how far the group is from convergence without contacting anyone.
+// Sessions that catch a peer contradicting its own hashes end early and
+// count as faults against it.
+//
+// Scoring favours the peers most likely to hold data we lack: those whose
+// last session carried the most keys, those left idle longest, and those
+// never synced. Faults count against a peer, and with a `RetryPolicy` a
+// faulting peer is also skipped for a backoff that doubles with each fault
+// in a row.
+//
+// Each session also yields a `SyncReport`, kept as the peer's `last_report`,
+// for alerting on peers whose sessions fail or keep growing.
+
+use std::time::{Duration, Instant};
+
+use crate::core::codec::Encode;
+use crate::core::hash::NodeHash;
+use crate::core::rng::Rng;
+use crate::core::tree::MerkleSearchTree;
+use crate::sync::{Message, PeerFault, Reconciler};
+
+pub type PeerId = u64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerSelection {
+    RoundRobin,
+    // Uniformly at random, from a seeded generator so runs can be replayed.
+    Random { seed: u64 },
+    // The peer with the highest `score`.
+    Scored,
+}
+
+// How long a peer that faulted is passed over: `initial` after one fault,
+// doubling with each further fault in a row, up to `max`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerStatus {
+    pub id: PeerId,
+    // The root both sides reached in the last completed session.
+    pub last_root: Option<NodeHash>,
+    pub last_synced: Option<Instant>,
+    pub sessions: u64,
+    pub messages: u64,
+    // Sessions cut short by a `PeerFault`.
+    pub faults: u64,
+    // Faults since the last completed session.
+    pub recent_faults: u32,
+    // Keys carried either way by the last completed session.
+    pub divergence: u64,
+    // The round of the last session, counting sessions with any peer.
+    pub last_round: Option<u64>,
+    // Until when the peer is passed over after a fault.
+    pub retry_at: Option<Instant>,
+    pub last_report: Option<SyncReport>,
+}
+
+impl PeerStatus {
+    // How likely the peer is to hold data we lack, given `round` sessions
+    // run so far. Never-synced peers score highest.
+    pub fn score(&self, round: u64) -> u64 {
+        let Some(last_round) = self.last_round else {
+            return u64::MAX;
+        };
+        let idle = round.saturating_sub(last_round);
+        (1 + self.divergence).saturating_mul(1 + idle) >> self.recent_faults.min(63)
+    }
+
+    fn ready(&self, now: Instant) -> bool {
+        self.retry_at.is_none_or(|retry_at| retry_at <= now)
+    }
+}
+
+// What one session took.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SyncReport {
+    // Messages delivered to the peer, each answered by a batch of replies.
+    pub rounds: u64,
+    pub messages_sent: u64,
+    pub messages_received: u64,
+    // As measured by the caller's size function; zero without one.
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    // Ranges found equal, or settled by an answering listing or delta.
+    pub ranges_resolved: u64,
+    pub keys_sent: u64,
+    pub keys_received: u64,
+    // Whether the session ended with both sides at the same root, rather
+    // than on a fault.
+    pub converged: bool,
+    pub elapsed: Duration,
+}
+
+impl SyncReport {
+    fn sent<K, V>(&mut self, message: &Message<K, V>, bytes: u64) {
+        self.messages_sent += 1;
+        self.bytes_sent += bytes;
+        self.keys_sent += keys_carried(message);
+        self.ranges_resolved += settles(message) as u64;
+    }
+
+    fn received<K, V>(&mut self, message: &Message<K, V>, bytes: u64) {
+        self.messages_received += 1;
+        self.bytes_received += bytes;
+        self.keys_received += keys_carried(message);
+        self.ranges_resolved += settles(message) as u64;
+    }
+}
+
+fn keys_carried<K, V>(message: &Message<K, V>) -> u64 {
+    match message {
+        Message::Entries { entries, .. } => entries.len() as u64,
+        Message::Delta { changes, .. } => changes.len() as u64,
+        _ => 0,
+    }
+}
+
+// Whether a message closes the exchange over its range.
+fn settles<K, V>(message: &Message<K, V>) -> bool {
+    matches!(
+        message,
+        Message::Entries { reply: false, .. } | Message::Delta { .. }
+    )
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GossipMetrics {
+    pub peers: usize,
+    // Peers last seen at the current local root.
+    pub in_sync: usize,
+    pub never_synced: usize,
+    pub sessions: u64,
+    pub messages: u64,
+    pub faults: u64,
+    // The time since the least recently synced peer was synced.
+    pub oldest_sync: Option<Duration>,
+}
+
+pub struct Gossip {
+    peers: Vec<PeerStatus>,
+    selection: PeerSelection,
+    // The next peer in round-robin order.
+    cursor: usize,
+    rng: Rng,
+    retry: Option<RetryPolicy>,
+    // Sessions run so far.
+    round: u64,
+}
+
+impl Gossip {
+    pub fn new(selection: PeerSelection) -> Self {
+        let seed = match selection {
+            PeerSelection::Random { seed } => seed,
+            PeerSelection::RoundRobin | PeerSelection::Scored => 0,
+        };
+        Gossip {
+            peers: Vec::new(),
+            selection,
+            cursor: 0,
+            rng: Rng(seed),
+            retry: None,
+            round: 0,
+        }
+    }
+
+    pub fn with_retry(mut self, policy: RetryPolicy) -> Self {
+        self.retry = Some(policy);
+        self
+    }
+
+    // Adds a peer, unless it is already known.
+    pub fn add_peer(&mut self, id: PeerId) {
+        if self.status(id).is_none() {
+            self.peers.push(PeerStatus {
+                id,
+                last_root: None,
+                last_synced: None,
+                sessions: 0,
+                messages: 0,
+                faults: 0,
+                recent_faults: 0,
+                divergence: 0,
+                last_round: None,
+                retry_at: None,
+                last_report: None,
+            });
+        }
+    }
+
+    pub fn remove_peer(&mut self, id: PeerId) -> bool {
+        let before = self.peers.len();
+        self.peers.retain(|peer| peer.id != id);
+        before != self.peers.len()
+    }
+
+    pub fn peers(&self) -> &[PeerStatus] {
+        &self.peers
+    }
+
+    pub fn status(&self, id: PeerId) -> Option<&PeerStatus> {
+        self.peers.iter().find(|peer| peer.id == id)
+    }
+
+    // The peer to sync with next, or None without peers or while every
+    // peer is backing off.
+    pub fn next_peer(&mut self) -> Option<PeerId> {
+        let now = Instant::now();
+        let ready: Vec<usize> = (0..self.peers.len())
+            .filter(|&index| self.peers[index].ready(now))
+            .collect();
+        if ready.is_empty() {
+            return None;
+        }
+        let index = match self.selection {
+            PeerSelection::RoundRobin => {
+                let len = self.peers.len();
+                let index = (0..len)
+                    .map(|offset| (self.cursor + offset) % len)
+                    .find(|index| ready.contains(index))?;
+                self.cursor = index + 1;
+                index
+            }
+            PeerSelection::Random { .. } => ready[self.rng.below(ready.len() as u64) as usize],
+            PeerSelection::Scored => {
+                // The first of the best, so ties go in insertion order.
+                let best = ready
+                    .iter()
+                    .map(|&index| self.peers[index].score(self.round))
+                    .max()?;
+                *ready
+                    .iter()
+                    .find(|&&index| self.peers[index].score(self.round) == best)?
+            }
+        };
+        Some(self.peers[index].id)
+    }
+
+    // Runs a session with `peer` to completion. `exchange` delivers a message
+    // to the peer and returns its replies. Returns the number of messages
+    // sent and received, or the fault that ended the session.
+    pub fn sync_with<K, V, F, X>(
+        &mut self,
+        tree: &mut MerkleSearchTree<K, V>,
+        reconciler: &Reconciler<F>,
+        peer: PeerId,
+        exchange: X,
+    ) -> Result<u64, PeerFault<K>>
+    where
+        K: Ord + Clone + Default + Encode,
+        V: AsRef<[u8]> + Clone + PartialEq,
+        F: Fn(&V, &V) -> V,
+        X: FnMut(Message<K, V>) -> Vec<Message<K, V>>,
+    {
+        let (report, result) = self.sync_reported(tree, reconciler, peer, exchange, |_| 0);
+        result.map(|()| report.messages_sent + report.messages_received)
+    }
+
+    // As `sync_with`, also returning the session's report whether or not it
+    // ended on a fault. `size` gives the bytes a message takes on the wire,
+    // e.g. its encoded length.
+    pub fn sync_reported<K, V, F, X, S>(
+        &mut self,
+        tree: &mut MerkleSearchTree<K, V>,
+        reconciler: &Reconciler<F>,
+        peer: PeerId,
+        mut exchange: X,
+        size: S,
+    ) -> (SyncReport, Result<(), PeerFault<K>>)
+    where
+        K: Ord + Clone + Default + Encode,
+        V: AsRef<[u8]> + Clone + PartialEq,
+        F: Fn(&V, &V) -> V,
+        X: FnMut(Message<K, V>) -> Vec<Message<K, V>>,
+        S: Fn(&Message<K, V>) -> u64,
+    {
+        let started = Instant::now();
+        let mut report = SyncReport::default();
+        let mut outgoing = vec![reconciler.start(tree)];
+        let mut result = Ok(());
+        'session: while let Some(message) = outgoing.pop() {
+            report.rounds += 1;
+            report.sent(&message, size(&message));
+            for reply in exchange(message) {
+                report.received(&reply, size(&reply));
+                let fingerprint = matches!(reply, Message::Fingerprint { .. });
+                match reconciler.handle_checked(tree, reply) {
+                    Ok(replies) => {
+                        // A fingerprint needing no answer matched ours.
+                        report.ranges_resolved += (fingerprint && replies.is_empty()) as u64;
+                        outgoing.extend(replies);
+                    }
+                    Err(fault) => {
+                        result = Err(fault);
+                        break 'session;
+                    }
+                }
+            }
+        }
+        report.converged = result.is_ok();
+        report.elapsed = started.elapsed();
+
+        self.add_peer(peer);
+        let status = self
+            .peers
+            .iter_mut()
+            .find(|status| status.id == peer)
+            .expect("the peer was just added");
+        status.sessions += 1;
+        status.messages += report.messages_sent + report.messages_received;
+        status.last_round = Some(self.round);
+        status.last_report = Some(report);
+        self.round += 1;
+        match result {
+            Ok(()) => {
+                status.last_root = Some(tree.root_hash());
+                status.last_synced = Some(Instant::now());
+                status.recent_faults = 0;
+                status.divergence = report.keys_sent + report.keys_received;
+                status.retry_at = None;
+            }
+            Err(_) => {
+                status.faults += 1;
+                status.recent_faults += 1;
+                if let Some(policy) = self.retry {
+                    let backoff = policy
+                        .initial
+                        .saturating_mul(1 << (status.recent_faults - 1).min(31))
+                        .min(policy.max);
+                    status.retry_at = Some(Instant::now() + backoff);
+                }
+            }
+        }
+        (report, result)
+    }
+
+    // One anti-entropy step: a session with the next peer. `exchange`
+    // delivers a message to the given peer and returns its replies. Returns
+    // the peer, or None if no peer is ready.
+    pub fn run_round<K, V, F, X>(
+        &mut self,
+        tree: &mut MerkleSearchTree<K, V>,
+        reconciler: &Reconciler<F>,
+        mut exchange: X,
+    ) -> Result<Option<PeerId>, PeerFault<K>>
+    where
+        K: Ord + Clone + Default + Encode,
+        V: AsRef<[u8]> + Clone + PartialEq,
+        F: Fn(&V, &V) -> V,
+        X: FnMut(PeerId, Message<K, V>) -> Vec<Message<K, V>>,
+    {
+        let Some(peer) = self.next_peer() else {
+            return Ok(None);
+        };
+        self.sync_with(tree, reconciler, peer, |message| exchange(peer, message))?;
+        Ok(Some(peer))
+    }
+
+    pub fn metrics(&self, local_root: &NodeHash) -> GossipMetrics {
+        let now = Instant::now();
+        GossipMetrics {
+            peers: self.peers.len(),
+            in_sync: self
+                .peers
+                .iter()
+                .filter(|peer| peer.last_root.as_ref() == Some(local_root))
+                .count(),
+            never_synced: self
+                .peers
+                .iter()
+                .filter(|peer| peer.last_synced.is_none())
+                .count(),
+            sessions: self.peers.iter().map(|peer| peer.sessions).sum(),
+            messages: self.peers.iter().map(|peer| peer.messages).sum(),
+            faults: self.peers.iter().map(|peer| peer.faults).sum(),
+            oldest_sync: self
+                .peers
+                .iter()
+                .filter_map(|peer| peer.last_synced)
+                .min()
+                .map(|oldest| now - oldest),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    type Merge = fn(&String, &String) -> String;
+
+    fn lww(local: &String, remote: &String) -> String {
+        local.max(remote).clone()
+    }
+
+    #[test]
+    fn test_gossip_converges() {
+        let reconciler = Reconciler::new(lww as Merge);
+        let mut replicas: Vec<MerkleSearchTree<u32>> = (0..4)
+            .map(|r| {
+                let mut tree = MerkleSearchTree::new(4);
+                for i in 0..50 {
+                    tree.insert(r * 100 + i, format!("{r}/{i}"));
+                }
+                tree
+            })
+            .collect();
+        let mut gossips: Vec<Gossip> = (0..4)
+            .map(|r| {
+                let mut gossip = Gossip::new(PeerSelection::Random { seed: r });
+                (0..4).filter(|p| *p != r).for_each(|p| gossip.add_peer(p));
+                gossip
+            })
+            .collect();
+        assert_eq!(gossips[0].metrics(replicas[0].hash()).never_synced, 3);
+
+        let converged = |replicas: &[MerkleSearchTree<u32>]| {
+            replicas
+                .iter()
+                .all(|replica| replica.hash() == replicas[0].hash())
+        };
+        let mut rounds = 0;
+        while !converged(&replicas) && rounds < 20 {
+            rounds += 1;
+            for r in 0..4 {
+                let mut tree = replicas[r].fork();
+                gossips[r]
+                    .run_round(&mut tree, &reconciler, |peer, message| {
+                        reconciler.handle(&mut replicas[peer as usize], message)
+                    })
+                    .unwrap();
+                replicas[r] = tree;
+            }
+        }
+        assert!(converged(&replicas));
+        assert_eq!(replicas[0].len(), 200);
+        let metrics = gossips[0].metrics(replicas[0].hash());
+        assert_eq!(metrics.sessions, rounds);
+        assert!(metrics.messages >= rounds && metrics.oldest_sync.is_some());
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let mut gossip = Gossip::new(PeerSelection::RoundRobin);
+        assert_eq!(gossip.next_peer(), None);
+        for peer in [7, 8, 9, 7] {
+            gossip.add_peer(peer);
+        }
+        let order: Vec<_> = (0..4).filter_map(|_| gossip.next_peer()).collect();
+        assert_eq!(order, [7, 8, 9, 7]);
+        assert!(gossip.remove_peer(8));
+        assert!(!gossip.remove_peer(8));
+        assert_eq!(gossip.peers().len(), 2);
+
+        // A session with an identical replica needs only the opening message.
+        let reconciler = Reconciler::new(lww as Merge);
+        let mut tree = MerkleSearchTree::<u32>::new(4);
+        tree.insert(1, "v".to_string());
+        let mut peer = tree.fork();
+        let sent = gossip.sync_with(&mut tree, &reconciler, 9, |message| {
+            reconciler.handle(&mut peer, message)
+        });
+        assert_eq!(sent, Ok(1));
+        let metrics = gossip.metrics(tree.hash());
+        assert_eq!((metrics.in_sync, metrics.never_synced), (1, 1));
+
+        // A peer whose listings don't match its hashes is a fault.
+        peer.insert(2, "other".to_string());
+        let result = gossip.sync_with(&mut tree, &reconciler, 7, |message| {
+            let mut replies = reconciler.handle(&mut peer, message);
+            if let Some(Message::Entries { hash, .. }) = replies.first_mut() {
+                *hash = NodeHash::default();
+            }
+            replies
+        });
+        assert!(result.is_err());
+        assert_eq!(gossip.status(7).unwrap().faults, 1);
+        assert_eq!(gossip.status(7).unwrap().last_root, None);
+    }
+
+    #[test]
+    fn test_sync_report() {
+        use crate::core::codec::Encode;
+
+        let reconciler = Reconciler::new(lww as Merge);
+        let mut tree = MerkleSearchTree::<u32>::new(4);
+        let mut peer = MerkleSearchTree::<u32>::new(4);
+        for i in 0..200 {
+            tree.insert(i, format!("v{i}"));
+            peer.insert(i, format!("v{i}"));
+        }
+        peer.insert(500, "new".to_string());
+        tree.insert(600, "mine".to_string());
+
+        let mut gossip = Gossip::new(PeerSelection::RoundRobin);
+        let (report, result) = gossip.sync_reported(
+            &mut tree,
+            &reconciler,
+            1,
+            |message| reconciler.handle(&mut peer, message),
+            |message| message.encoded_len() as u64,
+        );
+        assert!(result.is_ok() && report.converged);
+        assert_eq!(tree.hash(), peer.hash());
+        assert!(report.rounds >= 2 && report.ranges_resolved >= 1);
+        assert!(report.keys_sent >= 1 && report.keys_received >= 1);
+        assert!(report.bytes_sent > 0 && report.bytes_received > 0);
+        let status = gossip.status(1).unwrap();
+        assert_eq!(status.last_report, Some(report));
+        assert_eq!(
+            status.messages,
+            report.messages_sent + report.messages_received
+        );
+    }
+
+    #[test]
+    fn test_scored_selection_with_backoff() {
+        let reconciler = Reconciler::new(lww as Merge);
+        let mut tree = MerkleSearchTree::<u32>::new(4);
+        let mut peers: Vec<MerkleSearchTree<u32>> =
+            (0..3).map(|_| MerkleSearchTree::new(4)).collect();
+        let mut gossip = Gossip::new(PeerSelection::Scored).with_retry(RetryPolicy {
+            initial: Duration::from_secs(3600),
+            max: Duration::from_secs(7200),
+        });
+        (0..3).for_each(|peer| gossip.add_peer(peer));
+
+        // Peer 1 keeps receiving writes; peer 2 contradicts its hashes.
+        let mut round = |gossip: &mut Gossip, peers: &mut [MerkleSearchTree<u32>]| {
+            let peer = gossip.next_peer().unwrap();
+            let replica = &mut peers[peer as usize];
+            let _ = gossip.sync_with(&mut tree, &reconciler, peer, |message| {
+                let mut replies = reconciler.handle(replica, message);
+                for reply in replies.iter_mut().filter(|_| peer == 2) {
+                    if let Message::Entries { hash, .. } = reply {
+                        *hash = NodeHash::default();
+                    }
+                }
+                replies
+            });
+            peer
+        };
+        for (i, peer) in peers.iter_mut().enumerate() {
+            peer.insert(1000 + i as u32, format!("seed {i}"));
+        }
+        // Never-synced peers go first, in order.
+        let first: Vec<_> = (0..3).map(|_| round(&mut gossip, &mut peers)).collect();
+        assert_eq!(first, [0, 1, 2]);
+        assert_eq!(gossip.status(2).unwrap().recent_faults, 1);
+        assert!(gossip.status(2).unwrap().retry_at.is_some());
+
+        for i in 0..20 {
+            peers[1].insert(i, format!("v{i}"));
+            assert_ne!(round(&mut gossip, &mut peers), 2);
+        }
+        // The peer that keeps having new data is favoured, but the idle one
+        // still gets its turn.
+        let chosen = |peer| gossip.status(peer).unwrap().sessions;
+        assert!(chosen(1) > chosen(0) && chosen(0) > 1);
+        assert_eq!(chosen(2), 1);
+    }
+}
@@ -0,0 +1,216 @@
+// The opening exchange of a sync session: each side sends a `Hello`
+// describing what it speaks, and both derive the same `Agreement` from the
+// pair, or refuse.
+//
+// Versions: a side speaks every protocol version from its
+// `min_protocol_version` up to its `protocol_version`. The session uses the
+// highest version both speak, so a fleet rolls a change out by first
+// shipping nodes that speak the new version, then raising the minimum once
+// no old node is left; no flag day is needed. Sides with no common version
+// refuse, as do sides hashing differently, since none of their ranges would
+// ever compare equal.
+//
+// The wire format is negotiated as `WireFormat::negotiate` describes. The
+// fanout policy doesn't matter to range reconciliation, which only compares
+// entries, but snapshot pages can be shipped as they are only between trees
+// splitting nodes alike; `share_pages` says whether they do.
+//
+// A hello is encoded as a length-prefixed body. Later versions append their
+// fields to it, and older decoders skip what they don't know.
+
+use crate::core::codec::{Decode, Encode, take};
+pub use crate::core::config::{FanoutPolicy, HashScheme};
+use crate::core::error::Error;
+use crate::core::tree::MerkleSearchTree;
+use crate::sync::wire::WireFormat;
+
+// The version this build speaks, and the oldest it still accepts. Version 2
+// hashes leaves over their keys, so version 1 peers can't be reconciled with.
+pub const PROTOCOL_VERSION: u16 = 2;
+pub const MIN_PROTOCOL_VERSION: u16 = 2;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Hello {
+    pub protocol_version: u16,
+    pub min_protocol_version: u16,
+    pub hash_scheme: HashScheme,
+    pub fanout_policy: FanoutPolicy,
+    // The compression and size caps the sender accepts.
+    pub wire: WireFormat,
+}
+
+// What a session between two hellos runs with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Agreement {
+    pub protocol_version: u16,
+    pub wire: WireFormat,
+    // Whether snapshot pages can be shipped instead of entries.
+    pub share_pages: bool,
+}
+
+impl Hello {
+    // This build's hello for syncing `tree`.
+    pub fn for_tree<K, V, const N: usize>(
+        tree: &MerkleSearchTree<K, V, N>,
+        wire: WireFormat,
+    ) -> Self
+    where
+        K: Ord + Clone + Default + Encode,
+        V: AsRef<[u8]>,
+    {
+        Hello {
+            protocol_version: PROTOCOL_VERSION,
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+            hash_scheme: HashScheme::of::<N>(),
+            fanout_policy: tree.fanout_policy(),
+            wire,
+        }
+    }
+
+    // Fails with `Error::IncompatiblePeer` if the sides can't talk.
+    pub fn negotiate(&self, theirs: &Hello) -> Result<Agreement, Error> {
+        let version = self.protocol_version.min(theirs.protocol_version);
+        let oldest = self.min_protocol_version.max(theirs.min_protocol_version);
+        if version < oldest {
+            return Err(Error::IncompatiblePeer(format!(
+                "no common protocol version: we speak {}..={}, the peer {}..={}",
+                self.min_protocol_version,
+                self.protocol_version,
+                theirs.min_protocol_version,
+                theirs.protocol_version
+            )));
+        }
+        if self.hash_scheme != theirs.hash_scheme {
+            return Err(Error::IncompatiblePeer(format!(
+                "we hash with {} at {} bytes, the peer with {} at {}",
+                self.hash_scheme.algorithm,
+                self.hash_scheme.width,
+                theirs.hash_scheme.algorithm,
+                theirs.hash_scheme.width
+            )));
+        }
+        Ok(Agreement {
+            protocol_version: version,
+            wire: self.wire.negotiate(&theirs.wire),
+            share_pages: self.fanout_policy == theirs.fanout_policy,
+        })
+    }
+}
+
+impl Encode for Hello {
+    fn encode(&self, out: &mut Vec<u8>) {
+        let mut body = Vec::new();
+        self.protocol_version.encode(&mut body);
+        self.min_protocol_version.encode(&mut body);
+        self.hash_scheme.algorithm.encode(&mut body);
+        self.hash_scheme.width.encode(&mut body);
+        match self.fanout_policy {
+            FanoutPolicy::Children(max_children) => {
+                0u8.encode(&mut body);
+                max_children.encode(&mut body);
+            }
+            FanoutPolicy::NodeBytes(target) => {
+                1u8.encode(&mut body);
+                target.encode(&mut body);
+            }
+        }
+        self.wire.encode(&mut body);
+        body.encode(out);
+    }
+}
+
+impl Decode for Hello {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        let len = u32::decode(input)? as usize;
+        // Fields a later version appended stay unread in the body.
+        let mut body = take(input, len)?;
+        let body = &mut body;
+        Ok(Hello {
+            protocol_version: u16::decode(body)?,
+            min_protocol_version: u16::decode(body)?,
+            hash_scheme: HashScheme {
+                algorithm: String::decode(body)?,
+                width: u16::decode(body)?,
+            },
+            fanout_policy: match u8::decode(body)? {
+                0 => FanoutPolicy::Children(u32::decode(body)?),
+                1 => FanoutPolicy::NodeBytes(u32::decode(body)?),
+                tag => return Err(Error::Malformed(format!("unknown fanout policy {tag}"))),
+            },
+            wire: WireFormat::decode(body)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hello(tree: &MerkleSearchTree<u32>) -> Hello {
+        Hello::for_tree(tree, WireFormat::new())
+    }
+
+    #[test]
+    fn test_negotiation() {
+        let ours = hello(&MerkleSearchTree::new(8));
+        let same = hello(&MerkleSearchTree::new(8));
+        let agreed = ours.negotiate(&same).unwrap();
+        assert_eq!(agreed.protocol_version, PROTOCOL_VERSION);
+        assert!(agreed.share_pages);
+        assert!(
+            !ours
+                .negotiate(&hello(&MerkleSearchTree::new(16)))
+                .unwrap()
+                .share_pages
+        );
+
+        // A newer peer that still speaks our version talks at ours.
+        let newer = Hello {
+            protocol_version: PROTOCOL_VERSION + 2,
+            ..same.clone()
+        };
+        assert_eq!(
+            ours.negotiate(&newer).unwrap().protocol_version,
+            PROTOCOL_VERSION
+        );
+        assert_eq!(
+            newer.negotiate(&ours).unwrap().protocol_version,
+            PROTOCOL_VERSION
+        );
+        // One that dropped it, or hashes differently, is refused.
+        let dropped = Hello {
+            min_protocol_version: PROTOCOL_VERSION + 1,
+            ..newer
+        };
+        assert!(matches!(
+            ours.negotiate(&dropped),
+            Err(Error::IncompatiblePeer(_))
+        ));
+        let narrow = Hello::for_tree(
+            &MerkleSearchTree::<u32>::new(8).with_hash_width::<16>(),
+            WireFormat::new(),
+        );
+        assert!(matches!(
+            ours.negotiate(&narrow),
+            Err(Error::IncompatiblePeer(_))
+        ));
+    }
+
+    #[test]
+    fn test_hello_encoding_skips_later_fields() {
+        let ours = hello(&MerkleSearchTree::new(8));
+        let mut bytes = Vec::new();
+        ours.encode(&mut bytes);
+        assert_eq!(Hello::decode(&mut bytes.as_slice()).unwrap(), ours);
+
+        // A later version's hello with a field appended to the body.
+        let mut body = bytes[4..].to_vec();
+        body.extend_from_slice(b"new field");
+        let mut later = Vec::new();
+        body.encode(&mut later);
+        later.push(0xff);
+        let mut input = later.as_slice();
+        assert_eq!(Hello::decode(&mut input).unwrap(), ours);
+        assert_eq!(input, [0xff]);
+    }
+}
@@ -0,0 +1,627 @@
+// A reconciliation endpoint over HTTP, backed by a shared tree.
+//
+// `TreeEndpoint` answers four routes:
+//
+//   GET  /root          {"root":"<hex>","entries":<count>}
+//   GET  /pages?depth=d the subtree ranges at depth `d` (default 1) with
+//                       their hashes, as JSON, for comparing by eye or
+//                       picking ranges to fetch
+//   POST /diff          a `Message` frame; replies with the frames the tree
+//                       answers it with
+//   GET  /range?start=a&end=b
+//                       the entries in [a, b) as an `Entries` frame, which
+//                       the caller's reconciler answers like any listing
+//
+// Frames are in the endpoint's `WireFormat`; a response of several is each
+// one length-prefixed. Keys in queries and JSON use `FromStr` and `Display`.
+//
+// `handle` is independent of any server. With the `axum` feature, `router`
+// mounts it in an axum service, and so under hyper; elsewhere a handler
+// builds an `HttpRequest` from the method, path, query and body, and turns
+// the `HttpResponse` back into a response. For services without a server,
+// `serve` accepts HTTP/1.1 connections on a `TcpListener`, one thread per
+// connection, one request each. Since it faces the network directly, it
+// caps the connections it serves at once, the length and number of header
+// lines, and how long a socket may stall.
+
+use std::fmt;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+use crate::core::codec::{Decode, Encode};
+use crate::core::error::Error;
+use crate::core::report::write_json_bound;
+use crate::core::tree::MerkleSearchTree;
+use crate::sync::wire::WireFormat;
+use crate::sync::{KeyRange, Message, Reconciler};
+
+// Defaults for `serve`; see `with_timeout` and `with_max_connections`.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_MAX_CONNECTIONS: usize = 256;
+
+// Longer request and header lines, or more header lines, are refused.
+const MAX_LINE: usize = 8 << 10;
+const MAX_HEADERS: usize = 64;
+// What's read of a refused request before closing its connection.
+const MAX_LINGER: u64 = 64 << 10;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    // The query string without the `?`, percent-encoded.
+    pub query: String,
+    pub body: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    fn json(body: String) -> Self {
+        HttpResponse {
+            status: 200,
+            content_type: "application/json",
+            body: body.into_bytes(),
+        }
+    }
+
+    fn frames(body: Vec<u8>) -> Self {
+        HttpResponse {
+            status: 200,
+            content_type: "application/octet-stream",
+            body,
+        }
+    }
+
+    fn error(status: u16, reason: impl fmt::Display) -> Self {
+        HttpResponse {
+            status,
+            content_type: "text/plain",
+            body: reason.to_string().into_bytes(),
+        }
+    }
+}
+
+pub struct TreeEndpoint<K, V, F> {
+    tree: Arc<Mutex<MerkleSearchTree<K, V>>>,
+    reconciler: Reconciler<F>,
+    wire: WireFormat,
+    timeout: Duration,
+    max_connections: usize,
+    // Connections `serve` is answering.
+    active: Arc<AtomicUsize>,
+}
+
+impl<K, V, F> TreeEndpoint<K, V, F>
+where
+    K: Ord + Clone + Default + Encode + Decode + FromStr + fmt::Display,
+    V: AsRef<[u8]> + Clone + PartialEq + Encode + Decode,
+    F: Fn(&V, &V) -> V,
+{
+    pub fn new(tree: Arc<Mutex<MerkleSearchTree<K, V>>>, reconciler: Reconciler<F>) -> Self {
+        TreeEndpoint {
+            tree,
+            reconciler,
+            wire: WireFormat::new(),
+            timeout: DEFAULT_TIMEOUT,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            active: Arc::default(),
+        }
+    }
+
+    // The format of frames in bodies; its size cap also bounds requests.
+    pub fn with_wire(mut self, wire: WireFormat) -> Self {
+        self.wire = wire;
+        self
+    }
+
+    // How long `serve` waits on a socket read or write before dropping the
+    // connection. Defaults to 30 seconds.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    // How many connections `serve` answers at once; more are refused with
+    // a 503. Defaults to 256.
+    pub fn with_max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = max_connections;
+        self
+    }
+
+    pub fn handle(&self, request: &HttpRequest) -> HttpResponse {
+        let result = match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/root") => Ok(self.root()),
+            ("GET", "/pages") => self.pages(&request.query),
+            ("POST", "/diff") => self.diff(&request.body),
+            ("GET", "/range") => self.range(&request.query),
+            (_, "/root" | "/pages" | "/diff" | "/range") => {
+                return HttpResponse::error(405, "method not allowed");
+            }
+            _ => return HttpResponse::error(404, "not found"),
+        };
+        result.unwrap_or_else(|err| match err {
+            Error::MessageTooLarge { .. } => HttpResponse::error(413, err),
+            Error::Malformed(_) => HttpResponse::error(400, err),
+            err => HttpResponse::error(500, err),
+        })
+    }
+
+    fn root(&self) -> HttpResponse {
+        let tree = lock(&self.tree);
+        HttpResponse::json(format!(
+            "{{\"root\":\"{}\",\"entries\":{}}}",
+            tree.root_hash(),
+            tree.len()
+        ))
+    }
+
+    fn pages(&self, query: &str) -> Result<HttpResponse, Error> {
+        let depth = match param(query, "depth") {
+            Some(depth) => parse::<usize>(&depth, "depth")?,
+            None => 1,
+        };
+        let pages = lock(&self.tree).subtree_roots_at_depth(depth);
+        let mut out = String::from("[");
+        for (i, (range, hash)) in pages.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            out.push_str("{\"start\":");
+            let _ = write_json_bound(&mut out, range.start.as_ref());
+            out.push_str(",\"end\":");
+            let _ = write_json_bound(&mut out, range.end.as_ref());
+            let _ = write!(out, ",\"hash\":\"{hash}\"}}");
+        }
+        out.push(']');
+        Ok(HttpResponse::json(out))
+    }
+
+    fn diff(&self, body: &[u8]) -> Result<HttpResponse, Error> {
+        let message = self.wire.decode_frame::<Message<K, V>>(body)?;
+        let replies = self
+            .reconciler
+            .handle_checked(&mut lock(&self.tree), message)
+            .map_err(|fault| Error::Malformed(format!("{:?}", fault.kind)))?;
+        let mut out = Vec::new();
+        for reply in &replies {
+            self.wire.encode_frame(reply)?.encode(&mut out);
+        }
+        Ok(HttpResponse::frames(out))
+    }
+
+    fn range(&self, query: &str) -> Result<HttpResponse, Error> {
+        let bound = |name| {
+            param(query, name)
+                .map(|key| parse::<K>(&key, name))
+                .transpose()
+        };
+        let range = KeyRange {
+            start: bound("start")?,
+            end: bound("end")?,
+        };
+        let tree = lock(&self.tree);
+        let entries = tree
+            .range(range.clone())
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        let listing = Message::Entries {
+            hash: tree.range_hash(range.clone()),
+            range,
+            entries,
+            reply: true,
+        };
+        let mut out = Vec::new();
+        self.wire.encode_frame(&listing)?.encode(&mut out);
+        Ok(HttpResponse::frames(out))
+    }
+}
+
+impl<K, V, F> TreeEndpoint<K, V, F>
+where
+    K: Ord + Clone + Default + Encode + Decode + FromStr + fmt::Display + Send + Sync + 'static,
+    V: AsRef<[u8]> + Clone + PartialEq + Encode + Decode + Send + Sync + 'static,
+    F: Fn(&V, &V) -> V + Send + Sync + 'static,
+{
+    // Serves connections until accepting one fails.
+    pub fn serve(self: Arc<Self>, listener: TcpListener) -> io::Result<()> {
+        loop {
+            let (stream, _) = listener.accept()?;
+            if self.active.fetch_add(1, Ordering::AcqRel) >= self.max_connections {
+                self.active.fetch_sub(1, Ordering::AcqRel);
+                // Without blocking the accept loop on a client that doesn't
+                // read, and reading only what of its request has arrived.
+                let busy = HttpResponse::error(503, "too many connections");
+                let _ = stream
+                    .set_nonblocking(true)
+                    .and_then(|()| write_response(&stream, &busy))
+                    .and_then(|()| stream.shutdown(Shutdown::Write))
+                    .and_then(|()| io::copy(&mut (&stream).take(MAX_LINGER), &mut io::sink()));
+                continue;
+            }
+            let endpoint = self.clone();
+            thread::spawn(move || {
+                // A client that hangs up or stalls mid-request only loses
+                // its answer.
+                let _ = endpoint.serve_connection(stream);
+                endpoint.active.fetch_sub(1, Ordering::AcqRel);
+            });
+        }
+    }
+
+    fn serve_connection(&self, stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        match self.read_request(&mut reader)? {
+            Ok(request) => write_response(&stream, &self.handle(&request)),
+            Err(refusal) => {
+                write_response(&stream, &refusal)?;
+                // Closing with the rest of the request unread would reset
+                // the connection, and the client could lose the answer.
+                stream.shutdown(Shutdown::Write)?;
+                io::copy(&mut reader.take(MAX_LINGER), &mut io::sink())?;
+                Ok(())
+            }
+        }
+    }
+
+    fn read_request(
+        &self,
+        reader: &mut impl BufRead,
+    ) -> io::Result<Result<HttpRequest, HttpResponse>> {
+        let mut line = String::new();
+        if !read_line(reader, &mut line)? {
+            return Ok(Err(HttpResponse::error(431, "request line too long")));
+        }
+        let mut parts = line.split_whitespace();
+        let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+            return Ok(Err(HttpResponse::error(400, "bad request line")));
+        };
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let mut request = HttpRequest {
+            method: method.to_string(),
+            path: path.to_string(),
+            query: query.to_string(),
+            body: Vec::new(),
+        };
+
+        let mut length = 0u64;
+        for headers in 0.. {
+            line.clear();
+            if !read_line(reader, &mut line)? {
+                return Ok(Err(HttpResponse::error(431, "header line too long")));
+            }
+            if line.trim().is_empty() {
+                break;
+            }
+            if headers == MAX_HEADERS {
+                return Ok(Err(HttpResponse::error(431, "too many headers")));
+            }
+            if let Some((name, value)) = line.split_once(':')
+                && name.eq_ignore_ascii_case("content-length")
+            {
+                let Ok(value) = value.trim().parse() else {
+                    return Ok(Err(HttpResponse::error(400, "bad content length")));
+                };
+                length = value;
+            }
+        }
+        // Frames carry one byte of flag over the payload cap.
+        if length > self.wire.max_message() + 5 {
+            return Ok(Err(HttpResponse::error(413, "request body too large")));
+        }
+        reader.take(length).read_to_end(&mut request.body)?;
+        Ok(Ok(request))
+    }
+}
+
+#[cfg(feature = "axum")]
+impl<K, V, F> TreeEndpoint<K, V, F>
+where
+    K: Ord + Clone + Default + Encode + Decode + FromStr + fmt::Display + Send + Sync + 'static,
+    V: AsRef<[u8]> + Clone + PartialEq + Encode + Decode + Send + Sync + 'static,
+    F: Fn(&V, &V) -> V + Send + Sync + 'static,
+{
+    // An axum router answering the endpoint's routes, for services that
+    // run their own axum or hyper server. Request bodies are capped as
+    // `serve` caps them.
+    pub fn router(self: Arc<Self>) -> axum::Router {
+        let limit = self.wire.max_message() as usize + 5;
+        axum::Router::new()
+            .fallback(Self::handle_axum)
+            .layer(axum::extract::DefaultBodyLimit::max(limit))
+            .with_state(self)
+    }
+
+    async fn handle_axum(
+        axum::extract::State(endpoint): axum::extract::State<Arc<Self>>,
+        method: axum::http::Method,
+        uri: axum::http::Uri,
+        body: axum::body::Bytes,
+    ) -> axum::response::Response {
+        use axum::http::{StatusCode, header};
+        use axum::response::IntoResponse;
+
+        let request = HttpRequest {
+            method: method.to_string(),
+            path: uri.path().to_string(),
+            query: uri.query().unwrap_or_default().to_string(),
+            body: body.to_vec(),
+        };
+        // `handle` locks the tree, which sync code may hold for a while.
+        let response = tokio::task::spawn_blocking(move || endpoint.handle(&request))
+            .await
+            .unwrap_or_else(|_| HttpResponse::error(500, "handler panicked"));
+        let status =
+            StatusCode::from_u16(response.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        let content_type = [(header::CONTENT_TYPE, response.content_type)];
+        (status, content_type, response.body).into_response()
+    }
+}
+
+// Reads a line of up to `MAX_LINE` bytes into `line`. Returns false if it
+// was longer; an empty `line` means the client closed the connection.
+fn read_line(reader: &mut impl BufRead, line: &mut String) -> io::Result<bool> {
+    let read = reader.take(MAX_LINE as u64 + 1).read_line(line)?;
+    Ok(read <= MAX_LINE)
+}
+
+fn write_response(mut stream: &TcpStream, response: &HttpResponse) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason(response.status),
+        response.content_type,
+        response.body.len()
+    )?;
+    stream.write_all(&response.body)?;
+    stream.flush()
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    // Tree writes complete before they are visible, so a panicking writer
+    // leaves the last complete tree.
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// The decoded value of query parameter `name`.
+fn param(query: &str, name: &str) -> Option<String> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| percent_decode(value))
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match (escaped, bytes[i]) {
+            (Some(byte), _) => {
+                out.push(byte);
+                i += 3;
+            }
+            (None, b'+') => {
+                out.push(b' ');
+                i += 1;
+            }
+            (None, byte) => {
+                out.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn parse<T: FromStr>(value: &str, name: &str) -> Result<T, Error> {
+    value
+        .parse()
+        .map_err(|_| Error::Malformed(format!("bad {name} {value:?}")))
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        431 => "Request Header Fields Too Large",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    type Merge = fn(&String, &String) -> String;
+
+    fn lww(local: &String, remote: &String) -> String {
+        local.max(remote).clone()
+    }
+
+    fn endpoint(entries: u32) -> TreeEndpoint<u32, String, Merge> {
+        let mut tree = MerkleSearchTree::new(4);
+        for i in 0..entries {
+            tree.insert(i, format!("v{i}"));
+        }
+        TreeEndpoint::new(Arc::new(Mutex::new(tree)), Reconciler::new(lww as Merge))
+    }
+
+    fn get(path: &str, query: &str) -> HttpRequest {
+        HttpRequest {
+            method: "GET".to_string(),
+            path: path.to_string(),
+            query: query.to_string(),
+            body: Vec::new(),
+        }
+    }
+
+    fn frames(body: &[u8]) -> Vec<Message<u32, String>> {
+        let mut input = body;
+        let mut messages = Vec::new();
+        while !input.is_empty() {
+            let frame = Vec::<u8>::decode(&mut input).unwrap();
+            messages.push(WireFormat::new().decode_frame(&frame).unwrap());
+        }
+        messages
+    }
+
+    #[test]
+    fn test_routes() {
+        let server = endpoint(100);
+        let root = server.tree.lock().unwrap().root_hash();
+        let response = server.handle(&get("/root", ""));
+        let expected = format!("{{\"root\":\"{root}\",\"entries\":100}}");
+        assert_eq!(
+            (response.status, response.body),
+            (200, expected.into_bytes())
+        );
+        let pages = server.handle(&get("/pages", "depth=1"));
+        assert!(
+            String::from_utf8(pages.body)
+                .unwrap()
+                .starts_with("[{\"start\":null,")
+        );
+        assert_eq!(server.handle(&get("/pages", "depth=x")).status, 400);
+        assert_eq!(server.handle(&get("/nothing", "")).status, 404);
+        assert_eq!(server.handle(&get("/diff", "")).status, 405);
+
+        // A client with half the entries reconciles through /range and /diff.
+        let reconciler = Reconciler::new(lww as Merge);
+        let mut client = endpoint(50).tree.lock().unwrap().fork();
+        client.insert(500, "mine".to_string());
+        let listing = server.handle(&get("/range", "start=40&end=%36%30"));
+        let [listing] = frames(&listing.body).try_into().unwrap();
+        let Message::Entries { entries, .. } = &listing else {
+            panic!("expected a listing");
+        };
+        assert_eq!(entries.len(), 20);
+
+        let mut outgoing = vec![reconciler.start(&client)];
+        while let Some(message) = outgoing.pop() {
+            let response = server.handle(&HttpRequest {
+                method: "POST".to_string(),
+                path: "/diff".to_string(),
+                query: String::new(),
+                body: WireFormat::new().encode_frame(&message).unwrap(),
+            });
+            assert_eq!(response.status, 200);
+            for reply in frames(&response.body) {
+                outgoing.extend(reconciler.handle(&mut client, reply));
+            }
+        }
+        assert_eq!(client.root_hash(), server.tree.lock().unwrap().root_hash());
+    }
+
+    #[test]
+    fn test_serve() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = Arc::new(endpoint(10));
+        thread::spawn(move || server.serve(listener));
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream
+            .write_all(b"GET /root HTTP/1.1\r\nHost: test\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\"entries\":10}"));
+    }
+
+    #[test]
+    fn test_serve_limits() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = endpoint(10)
+            .with_timeout(Duration::from_millis(500))
+            .with_max_connections(1);
+        thread::spawn(move || Arc::new(server).serve(listener));
+        let request = |head: &[u8]| {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream.write_all(head).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        let long = format!("GET /root HTTP/1.1\r\nX: {}\r\n\r\n", "a".repeat(MAX_LINE));
+        assert!(request(long.as_bytes()).starts_with("HTTP/1.1 431 "));
+        let many = format!(
+            "GET /root HTTP/1.1\r\n{}\r\n",
+            "X: a\r\n".repeat(MAX_HEADERS + 1)
+        );
+        assert!(request(many.as_bytes()).starts_with("HTTP/1.1 431 "));
+
+        // A client that stalls holds the only slot, once the refused ones
+        // let go of it, until it times out.
+        thread::sleep(Duration::from_millis(100));
+        let mut stalled = TcpStream::connect(address).unwrap();
+        stalled.write_all(b"GET /root HTTP/1.1\r\n").unwrap();
+        thread::sleep(Duration::from_millis(100));
+        let mut busy = String::new();
+        let mut refused = TcpStream::connect(address).unwrap();
+        refused.read_to_string(&mut busy).unwrap();
+        assert!(busy.starts_with("HTTP/1.1 503 "));
+        let mut dropped = Vec::new();
+        stalled.read_to_end(&mut dropped).unwrap();
+        assert!(dropped.is_empty());
+        thread::sleep(Duration::from_millis(100));
+        assert!(request(b"GET /root HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 200 "));
+    }
+
+    #[cfg(feature = "axum")]
+    #[test]
+    fn test_axum_router() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let listener = runtime
+            .block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))
+            .unwrap();
+        let address = listener.local_addr().unwrap();
+        let router = Arc::new(endpoint(10)).router();
+        runtime.spawn(async move { axum::serve(listener, router).await });
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream
+            .write_all(b"GET /root HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\"entries\":10}"));
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream
+            .write_all(b"GET /diff HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 405 "));
+    }
+}
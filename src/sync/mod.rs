@@ -0,0 +1,1089 @@
+// Reconciling replicas: the range reconciler below, and the handshake,
+// wire formats, gossip and transports around it. Behind the `sync` feature.
+
+pub mod gossip;
+pub mod handshake;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod packet;
+#[cfg(feature = "quic")]
+pub mod quic;
+#[cfg(any(test, feature = "sim"))]
+pub mod sim;
+pub mod transport;
+pub mod wire;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::RangeBounds;
+
+use crate::core::codec::Encode;
+use crate::core::error::Error;
+use crate::core::hash::NodeHash;
+pub use crate::core::range::KeyRange;
+use crate::core::tree::MerkleSearchTree;
+
+// Range-based reconciliation between two replicas.
+//
+// One side sends the XOR fingerprint of a key range. The other compares it
+// with its own: equal ranges are done, small ranges are answered with their
+// entries, and large ranges are split in two and fingerprinted again.
+// Fingerprints only depend on the entries, so replicas with different node
+// layouts (or fanouts) can still talk to each other, though both must use
+// the same hash width.
+//
+// A tiered session opens with a summary of the subtree ranges instead,
+// from which the receiver estimates how far apart the replicas are and picks
+// a strategy: recursing as above when few entries differ, shipping the stale
+// ranges' entries outright when that is cheaper than more round trips, or
+// giving up on entries in favor of a snapshot transfer when most of the data
+// differs.
+//
+// Every shipped list of entries carries the sender's hash of its range: for
+// a full listing, the hash of those very entries; for the reply to one, the
+// hash the sender reached after merging. A listing that doesn't hash as
+// claimed, or a reply after which the two sides still disagree, means the
+// peer's hashes don't match its data. Such a peer would keep sessions from
+// ever converging; `handle_checked` reports it as a `PeerFault`.
+//
+// Peers that sync often can open with the root they last shared instead.
+// If the other side logs `RecentChanges` back to that root, it answers with
+// just the keys changed since; otherwise, or if the replicas still differ
+// after the delta, the session falls back to fingerprints. A delta can only
+// be checked by a side still at the root it starts from, which applies it
+// as sent and must reach the peer's root. A side with local writes since
+// can't tell a peer's removals from lies, so it merges the delta's values
+// but keeps the keys it says were removed.
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Message<K, V, const N: usize = 32> {
+    // The sender's range hash over `range`.
+    Fingerprint {
+        range: KeyRange<K>,
+        hash: NodeHash<N>,
+    },
+    // The sender's entry count, and the hash and entry count of each of a
+    // set of its subtree ranges, which partition the key space.
+    Summary {
+        total: usize,
+        ranges: Vec<(KeyRange<K>, NodeHash<N>, usize)>,
+    },
+    // Every entry the sender holds in `range`.
+    // If `reply` is set, the receiver answers with the entries the sender lacks.
+    // Otherwise this is such an answer, holding only those entries.
+    Entries {
+        range: KeyRange<K>,
+        entries: Vec<(K, V)>,
+        reply: bool,
+        // The sender's range hash: of `entries` if `reply` is set, otherwise
+        // after merging the listing it answers.
+        hash: NodeHash<N>,
+    },
+    // The root the receiver and sender last agreed on, asking for the
+    // changes made since.
+    Since {
+        root: NodeHash<N>,
+    },
+    // The sender's value of each key changed since the `Since` root, None
+    // if removed, and the sender's root.
+    Delta {
+        since: NodeHash<N>,
+        changes: Vec<(K, Option<V>)>,
+        hash: NodeHash<N>,
+    },
+}
+
+// A peer whose shipped entries contradict the hashes it sent with them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerFault<K, const N: usize = 32> {
+    pub range: KeyRange<K>,
+    pub kind: FaultKind<N>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum FaultKind<const N: usize = 32> {
+    // A full listing whose entries don't hash to the claimed hash.
+    ListingMismatch {
+        claimed: NodeHash<N>,
+        actual: NodeHash<N>,
+    },
+    // A listing with keys out of order or outside its range, or a delta
+    // listing a key twice.
+    MalformedListing,
+    // After merging the peer's reply, the range doesn't hash to what the
+    // peer claimed to have reached. Local writes to the range during the
+    // session, or a merge function that isn't commutative, look the same.
+    Diverged {
+        claimed: NodeHash<N>,
+        actual: NodeHash<N>,
+    },
+    // Merging the peer's entries would grow the tree past its depth limit.
+    // The entries merged before the one refused stay merged.
+    DepthLimitExceeded {
+        limit: usize,
+    },
+    // A peer's value hashes like, but differs from, the local one, and the
+    // tree checks for collisions.
+    Collision,
+    // A delta that, applied to the root it starts from, doesn't reach the
+    // root the peer claimed.
+    DeltaMismatch {
+        claimed: NodeHash<N>,
+        actual: NodeHash<N>,
+    },
+}
+
+impl<const N: usize> FaultKind<N> {
+    // The fault for a replicated write the tree refused. Those fail only on
+    // the depth limit or a collision.
+    fn refused(err: Error) -> Self {
+        match err {
+            Error::DepthLimitExceeded { limit } => FaultKind::DepthLimitExceeded { limit },
+            _ => FaultKind::Collision,
+        }
+    }
+}
+
+impl<K: Ord + Clone + Default + Encode, V: AsRef<[u8]>, const N: usize> MerkleSearchTree<K, V, N> {
+    // Up to `k` ranges where `other` differs, largest first, for syncs that
+    // can only afford a few ranges at a time. Starting from the whole tree,
+    // the largest range is split into its subtrees, dropping those that
+    // match `other`, until there are `k` ranges or only leaf-level ones are
+    // left. Unless truncated to `k`, the ranges cover every difference.
+    pub fn top_divergent_ranges(&self, other: &Self, k: usize) -> Vec<DivergentRange<K>> {
+        let mut frontier = Vec::new();
+        if k > 0 && self.root_hash() != other.root_hash() {
+            frontier.push((&*self.root, KeyRange::full(), self.len(), other.len()));
+        }
+        while frontier.len() < k {
+            let splittable = frontier
+                .iter()
+                .enumerate()
+                .filter(|(_, (node, ..))| node.children().first().is_some_and(|c| c.is_internal()))
+                .max_by_key(|(_, (_, _, ours, theirs))| (*ours).max(*theirs));
+            let Some((index, _)) = splittable else {
+                break;
+            };
+            let (node, range, ..) = frontier.swap_remove(index);
+            let children = node.children();
+            let starts: Vec<Option<K>> = children
+                .iter()
+                .enumerate()
+                .map(|(i, child)| {
+                    if i == 0 {
+                        return range.start.clone();
+                    }
+                    let mut first = &**child;
+                    while first.is_internal() {
+                        first = &first.children()[0];
+                    }
+                    Some(first.key().clone())
+                })
+                .collect();
+            for (i, child) in children.iter().enumerate() {
+                let child_range = KeyRange {
+                    start: starts[i].clone(),
+                    end: match starts.get(i + 1) {
+                        Some(start) => start.clone(),
+                        None => range.end.clone(),
+                    },
+                };
+                if other.range_root(child_range.clone()) != *child.hash() {
+                    let theirs = other.count_in(&child_range);
+                    frontier.push((&**child, child_range, child.leaf_count(), theirs));
+                }
+            }
+        }
+        frontier.sort_by_key(|(_, _, ours, theirs)| std::cmp::Reverse((*ours).max(*theirs)));
+        frontier.truncate(k);
+        frontier
+            .into_iter()
+            .map(|(_, range, ours, theirs)| DivergentRange {
+                range,
+                ours,
+                theirs,
+            })
+            .collect()
+    }
+
+    fn count_in(&self, range: &KeyRange<K>) -> usize {
+        let end = range.end.as_ref().map_or(self.len(), |end| self.rank(end));
+        end - range.start.as_ref().map_or(0, |start| self.rank(start))
+    }
+}
+
+// A range two trees disagree on, with each side's entry count in it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DivergentRange<K> {
+    pub range: KeyRange<K>,
+    pub ours: usize,
+    pub theirs: usize,
+}
+
+impl<K> DivergentRange<K> {
+    // The entries a sync of the range may have to move.
+    pub fn estimate(&self) -> usize {
+        self.ours.max(self.theirs)
+    }
+}
+
+// The number of ranges a tiered session's summary aims for.
+const SUMMARY_RANGES: usize = 16;
+
+// How a tiered session reconciles, see `Reconciler::plan`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncStrategy {
+    // Nothing differs.
+    InSync,
+    // Fingerprint the stale ranges and recurse into them.
+    RangeRecursion,
+    // Ship every entry of the stale ranges in one round trip.
+    KeyList,
+    // Most of the data differs: transfer a snapshot instead.
+    Snapshot,
+}
+
+// The strategy picked for a session and what it was based on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SyncPlan<K> {
+    pub strategy: SyncStrategy,
+    // The summarized ranges whose hashes differ.
+    pub stale: Vec<KeyRange<K>>,
+    // The entries in the stale ranges, counting the larger side of each.
+    pub estimated_divergence: usize,
+    // The larger side's entry count.
+    pub total: usize,
+}
+
+pub struct Reconciler<F> {
+    // Ranges holding at most this many local entries are shipped instead of split.
+    split_threshold: usize,
+    // Tiered sessions ship the stale ranges whole up to this many entries.
+    key_list_threshold: usize,
+    // Tiered sessions fall back to a snapshot beyond this share of stale entries.
+    snapshot_fraction: f64,
+    // Resolves a key held with different values on both sides: `merge(local, remote)`.
+    // It must be commutative and idempotent (e.g. last-writer-wins) for replicas to converge.
+    merge: F,
+}
+
+impl<F> Reconciler<F> {
+    pub fn new(merge: F) -> Self {
+        Reconciler {
+            split_threshold: 16,
+            key_list_threshold: 256,
+            snapshot_fraction: 0.5,
+            merge,
+        }
+    }
+
+    // Sets the tiers of `plan`: stale ranges of up to `key_list_threshold`
+    // entries are shipped whole, and a snapshot is preferred once more than
+    // `snapshot_fraction` of the entries are stale, unless the key list
+    // would do.
+    pub fn with_tiers(mut self, key_list_threshold: usize, snapshot_fraction: f64) -> Self {
+        self.key_list_threshold = key_list_threshold;
+        self.snapshot_fraction = snapshot_fraction;
+        self
+    }
+
+    pub fn with_split_threshold(mut self, split_threshold: usize) -> Self {
+        self.split_threshold = split_threshold.max(1);
+        self
+    }
+
+    // The opening message of a session: the fingerprint of the whole key space.
+    pub fn start<K, V, const N: usize>(&self, tree: &MerkleSearchTree<K, V, N>) -> Message<K, V, N>
+    where
+        K: Ord + Clone + Default + Encode,
+        V: AsRef<[u8]>,
+    {
+        self.start_range(tree, KeyRange::full())
+    }
+
+    // Opens a tiered session: a summary of `tree`'s ranges at the first level
+    // with at least `SUMMARY_RANGES` subtrees, which the peer answers
+    // according to its `plan`.
+    pub fn start_tiered<K, V, const N: usize>(
+        &self,
+        tree: &MerkleSearchTree<K, V, N>,
+    ) -> Message<K, V, N>
+    where
+        K: Ord + Clone + Default + Encode,
+        V: AsRef<[u8]>,
+    {
+        let mut depth = 1;
+        while depth + 1 < tree.depth() && tree.subtree_roots_at_depth(depth).len() < SUMMARY_RANGES
+        {
+            depth += 1;
+        }
+        let ranges = tree
+            .subtree_roots_at_depth(depth)
+            .into_iter()
+            .map(|(range, hash)| {
+                let count = tree.usage(range.clone()).entries;
+                (range, hash, count)
+            })
+            .collect();
+        Message::Summary {
+            total: tree.len(),
+            ranges,
+        }
+    }
+
+    // Picks how to reconcile `tree` with the peer that sent a summary.
+    pub fn plan<K, V, const N: usize>(
+        &self,
+        tree: &MerkleSearchTree<K, V, N>,
+        total: usize,
+        ranges: &[(KeyRange<K>, NodeHash<N>, usize)],
+    ) -> SyncPlan<K>
+    where
+        K: Ord + Clone + Default + Encode,
+        V: AsRef<[u8]>,
+    {
+        let mut stale = Vec::new();
+        let mut estimated_divergence = 0;
+        for (range, hash, count) in ranges {
+            if tree.range_hash(range.clone()) != *hash {
+                let local = tree.usage(range.clone()).entries;
+                estimated_divergence += local.max(*count);
+                stale.push(range.clone());
+            }
+        }
+        let total = total.max(tree.len());
+        let strategy = if stale.is_empty() {
+            SyncStrategy::InSync
+        } else if estimated_divergence <= self.key_list_threshold {
+            SyncStrategy::KeyList
+        } else if estimated_divergence as f64 > self.snapshot_fraction * total as f64 {
+            SyncStrategy::Snapshot
+        } else {
+            SyncStrategy::RangeRecursion
+        };
+        SyncPlan {
+            strategy,
+            stale,
+            estimated_divergence,
+            total,
+        }
+    }
+
+    // The messages that carry out `plan`. A snapshot transfer happens outside
+    // of sessions, so it yields none.
+    pub fn execute<K, V, const N: usize>(
+        &self,
+        tree: &MerkleSearchTree<K, V, N>,
+        plan: SyncPlan<K>,
+    ) -> Vec<Message<K, V, N>>
+    where
+        K: Ord + Clone + Default + Encode,
+        V: AsRef<[u8]> + Clone,
+    {
+        match plan.strategy {
+            SyncStrategy::InSync | SyncStrategy::Snapshot => vec![],
+            SyncStrategy::KeyList => plan
+                .stale
+                .into_iter()
+                .map(|range| Message::Entries {
+                    entries: tree
+                        .range(range.clone())
+                        .map(|(k, v)| (k.clone(), v.clone()))
+                        .collect(),
+                    hash: tree.range_hash(range.clone()),
+                    range,
+                    reply: true,
+                })
+                .collect(),
+            SyncStrategy::RangeRecursion => plan
+                .stale
+                .into_iter()
+                .map(|range| Message::Fingerprint {
+                    hash: tree.range_root(range.clone()),
+                    range,
+                })
+                .collect(),
+        }
+    }
+
+    // Opens a session with a peer last seen at `root`, asking for its recent
+    // changes; see `RecentChanges`.
+    pub fn start_since<K, V, const N: usize>(&self, root: NodeHash<N>) -> Message<K, V, N> {
+        Message::Since { root }
+    }
+
+    // Opens a session limited to `range`. Sessions over disjoint ranges are
+    // independent and can run in parallel.
+    pub fn start_range<K, V, const N: usize>(
+        &self,
+        tree: &MerkleSearchTree<K, V, N>,
+        range: KeyRange<K>,
+    ) -> Message<K, V, N>
+    where
+        K: Ord + Clone + Default + Encode,
+        V: AsRef<[u8]>,
+    {
+        let hash = tree.range_root(range.clone());
+        Message::Fingerprint { range, hash }
+    }
+
+    // Applies a message from the peer to `tree` and returns the messages to send back.
+    // The session is over once neither side has anything left to send. A
+    // summary is answered by executing its `plan`; call `plan` and `execute`
+    // directly to see the decision. Faulty messages are dropped, see
+    // `handle_checked`.
+    pub fn handle<K, V, const N: usize>(
+        &self,
+        tree: &mut MerkleSearchTree<K, V, N>,
+        message: Message<K, V, N>,
+    ) -> Vec<Message<K, V, N>>
+    where
+        K: Ord + Clone + Default + Encode,
+        V: AsRef<[u8]> + Clone + PartialEq,
+        F: Fn(&V, &V) -> V,
+    {
+        self.handle_checked(tree, message).unwrap_or_default()
+    }
+
+    // Like `handle`, but reports a peer whose entries contradict its hashes.
+    // A faulty listing is rejected before anything is merged; the entries of
+    // a reply are merged before it can be checked.
+    pub fn handle_checked<K, V, const N: usize>(
+        &self,
+        tree: &mut MerkleSearchTree<K, V, N>,
+        message: Message<K, V, N>,
+    ) -> Result<Vec<Message<K, V, N>>, PeerFault<K, N>>
+    where
+        K: Ord + Clone + Default + Encode,
+        V: AsRef<[u8]> + Clone + PartialEq,
+        F: Fn(&V, &V) -> V,
+    {
+        Ok(match message {
+            Message::Summary { total, ranges } => {
+                let plan = self.plan(tree, total, &ranges);
+                self.execute(tree, plan)
+            }
+            Message::Fingerprint { range, hash } => self.handle_fingerprint(tree, range, hash),
+            Message::Entries {
+                range,
+                entries,
+                reply,
+                hash,
+            } => self.handle_entries(tree, range, entries, reply, hash)?,
+            Message::Since { root } => match tree.delta_since(&root) {
+                Some(changes) => vec![Message::Delta {
+                    since: root,
+                    changes,
+                    hash: tree.root_hash(),
+                }],
+                None => vec![self.start(tree)],
+            },
+            Message::Delta {
+                since,
+                changes,
+                hash,
+            } => self.handle_delta(tree, since, changes, hash)?,
+        })
+    }
+
+    fn handle_delta<K, V, const N: usize>(
+        &self,
+        tree: &mut MerkleSearchTree<K, V, N>,
+        since: NodeHash<N>,
+        changes: Vec<(K, Option<V>)>,
+        hash: NodeHash<N>,
+    ) -> Result<Vec<Message<K, V, N>>, PeerFault<K, N>>
+    where
+        K: Ord + Clone + Default + Encode,
+        V: AsRef<[u8]> + Clone + PartialEq,
+        F: Fn(&V, &V) -> V,
+    {
+        let fault = |kind| PeerFault {
+            range: KeyRange::full(),
+            kind,
+        };
+        let keys: BTreeSet<&K> = changes.iter().map(|(key, _)| key).collect();
+        if keys.len() != changes.len() {
+            return Err(fault(FaultKind::MalformedListing));
+        }
+        // Still at `since`, the delta must take us to `hash`; check that
+        // before applying anything.
+        let verified = tree.root_hash() == since;
+        if verified {
+            let (mut xor, mut entries) = (tree.range_hash(..), tree.len());
+            for (key, remote) in &changes {
+                if let Some(local) = tree.get(key) {
+                    xor.xor(&NodeHash::leaf(key, local.as_ref()));
+                    entries -= 1;
+                }
+                if let Some(remote) = remote {
+                    xor.xor(&NodeHash::leaf(key, remote.as_ref()));
+                    entries += 1;
+                }
+            }
+            let actual = NodeHash::root_of(xor, entries);
+            if actual != hash {
+                return Err(fault(FaultKind::DeltaMismatch {
+                    claimed: hash,
+                    actual,
+                }));
+            }
+        }
+
+        for (key, remote) in changes {
+            let merged = match (tree.get(&key), remote) {
+                (Some(local), Some(remote)) if *local == remote => continue,
+                (Some(_), Some(remote)) if verified => remote,
+                (Some(local), Some(remote)) => (self.merge)(local, &remote),
+                (None, Some(remote)) => remote,
+                (_, None) => {
+                    if verified {
+                        tree.remove(&key);
+                    }
+                    continue;
+                }
+            };
+            tree.insert_replicated(key, merged)
+                .map_err(|err| fault(FaultKind::refused(err)))?;
+        }
+        // Local writes since the shared root, or removals we couldn't check,
+        // still differ: reconcile fully.
+        Ok(if tree.root_hash() == hash {
+            vec![]
+        } else {
+            vec![self.start(tree)]
+        })
+    }
+
+    fn handle_fingerprint<K, V, const N: usize>(
+        &self,
+        tree: &MerkleSearchTree<K, V, N>,
+        range: KeyRange<K>,
+        hash: NodeHash<N>,
+    ) -> Vec<Message<K, V, N>>
+    where
+        K: Ord + Clone + Default + Encode,
+        V: AsRef<[u8]> + Clone,
+    {
+        if tree.range_root(range.clone()) == hash {
+            return vec![];
+        }
+
+        let count = tree.range(range.clone()).count();
+        if count <= self.split_threshold {
+            let entries = tree
+                .range(range.clone())
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect();
+            return vec![Message::Entries {
+                hash: tree.range_hash(range.clone()),
+                range,
+                entries,
+                reply: true,
+            }];
+        }
+
+        // Split at our median key. Both halves hold local entries, so each
+        // round strictly shrinks the ranges until they fall under the threshold.
+        let Some((mid, _)) = tree.range(range.clone()).nth(count / 2) else {
+            return vec![];
+        };
+        let left = KeyRange {
+            start: range.start.clone(),
+            end: Some(mid.clone()),
+        };
+        let right = KeyRange {
+            start: Some(mid.clone()),
+            end: range.end,
+        };
+        [left, right]
+            .into_iter()
+            .map(|range| Message::Fingerprint {
+                hash: tree.range_root(range.clone()),
+                range,
+            })
+            .collect()
+    }
+
+    fn handle_entries<K, V, const N: usize>(
+        &self,
+        tree: &mut MerkleSearchTree<K, V, N>,
+        range: KeyRange<K>,
+        entries: Vec<(K, V)>,
+        reply: bool,
+        hash: NodeHash<N>,
+    ) -> Result<Vec<Message<K, V, N>>, PeerFault<K, N>>
+    where
+        K: Ord + Clone + Default + Encode,
+        V: AsRef<[u8]> + Clone + PartialEq,
+        F: Fn(&V, &V) -> V,
+    {
+        let fault = |kind| PeerFault {
+            range: range.clone(),
+            kind,
+        };
+        let in_order = entries.windows(2).all(|pair| pair[0].0 < pair[1].0);
+        if !in_order || !entries.iter().all(|(key, _)| range.contains(key)) {
+            return Err(fault(FaultKind::MalformedListing));
+        }
+        if reply {
+            let mut actual = NodeHash::default();
+            for (key, value) in &entries {
+                actual.xor(&NodeHash::leaf(key, value.as_ref()));
+            }
+            if actual != hash {
+                return Err(fault(FaultKind::ListingMismatch {
+                    claimed: hash,
+                    actual,
+                }));
+            }
+        }
+
+        for (key, remote) in &entries {
+            let merged = match tree.get(key) {
+                Some(local) if local == remote => continue,
+                Some(local) => (self.merge)(local, remote),
+                None => remote.clone(),
+            };
+            tree.insert_replicated(key.clone(), merged)
+                .map_err(|err| fault(FaultKind::refused(err)))?;
+        }
+
+        if !reply {
+            let actual = tree.range_hash(range.clone());
+            if actual != hash {
+                return Err(fault(FaultKind::Diverged {
+                    claimed: hash,
+                    actual,
+                }));
+            }
+            return Ok(vec![]);
+        }
+
+        // Send back everything the peer doesn't hold in its merged form, even
+        // if that is nothing, so it can check where we ended up.
+        let remote: BTreeMap<&K, &V> = entries.iter().map(|(k, v)| (k, v)).collect();
+        let missing: Vec<(K, V)> = tree
+            .range(range.clone())
+            .filter(|(k, v)| remote.get(k) != Some(v))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        Ok(vec![Message::Entries {
+            hash: tree.range_hash(range.clone()),
+            range,
+            entries: missing,
+            reply: false,
+        }])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::branch::Diff;
+    use crate::core::hash::is_empty_root;
+    #[cfg(feature = "crdt")]
+    use crate::crdt::patch::create_patch;
+
+    // Last writer wins, with writers stamping a version in front of the value.
+    fn lww(local: &String, remote: &String) -> String {
+        local.max(remote).clone()
+    }
+
+    // Drives a session between `a` and `b` until no messages are left.
+    fn run_session(
+        reconciler: &Reconciler<fn(&String, &String) -> String>,
+        a: &mut MerkleSearchTree<u32>,
+        b: &mut MerkleSearchTree<u32>,
+    ) -> usize {
+        let first = reconciler.start(a);
+        run_session_from(reconciler, a, b, first)
+    }
+
+    fn run_session_from(
+        reconciler: &Reconciler<fn(&String, &String) -> String>,
+        a: &mut MerkleSearchTree<u32>,
+        b: &mut MerkleSearchTree<u32>,
+        first: Message<u32, String>,
+    ) -> usize {
+        let mut to_b = vec![first];
+        let mut to_a = vec![];
+        let mut messages = 0;
+        while !to_a.is_empty() || !to_b.is_empty() {
+            for message in std::mem::take(&mut to_b) {
+                messages += 1;
+                to_a.extend(reconciler.handle(b, message));
+            }
+            for message in std::mem::take(&mut to_a) {
+                messages += 1;
+                to_b.extend(reconciler.handle(a, message));
+            }
+        }
+        messages
+    }
+
+    #[test]
+    fn test_identical_trees_need_one_message() {
+        let reconciler = Reconciler::new(lww as fn(&String, &String) -> String);
+        let mut a = MerkleSearchTree::new(4);
+        let mut b = MerkleSearchTree::new(8);
+        for i in 0..100u32 {
+            a.insert(i, format!("v{i}"));
+            b.insert(99 - i, format!("v{}", 99 - i));
+        }
+
+        assert_eq!(run_session(&reconciler, &mut a, &mut b), 1);
+    }
+
+    #[test]
+    fn test_reconcile_divergent_trees() {
+        let reconciler =
+            Reconciler::new(lww as fn(&String, &String) -> String).with_split_threshold(4);
+        let mut a = MerkleSearchTree::new(4);
+        let mut b = MerkleSearchTree::new(3);
+        for i in 0..200u32 {
+            a.insert(i, format!("0/{i}"));
+            b.insert(i, format!("0/{i}"));
+        }
+        // Only a has key 500, only b has key 600, and both updated key 7.
+        a.insert(500, "0/500".to_string());
+        b.insert(600, "0/600".to_string());
+        a.insert(7, "1/7".to_string());
+        b.insert(7, "2/7".to_string());
+
+        run_session(&reconciler, &mut a, &mut b);
+
+        assert_eq!(a.hash(), b.hash());
+        assert_eq!(a.get(&500), Some(&"0/500".to_string()));
+        assert_eq!(a.get(&600), Some(&"0/600".to_string()));
+        assert_eq!(a.get(&7), Some(&"2/7".to_string()));
+        assert!(a.iter().eq(b.iter()));
+    }
+
+    #[test]
+    fn test_reconcile_subtree_slices() {
+        let reconciler = Reconciler::new(lww as fn(&String, &String) -> String);
+        let mut a = MerkleSearchTree::new(4);
+        let mut b = MerkleSearchTree::new(4);
+        for i in 0..1000u32 {
+            a.insert(i, format!("0/{i}"));
+            b.insert(i, format!("0/{i}"));
+        }
+        a.insert(123, "1/123".to_string());
+        b.insert(5000, "0/5000".to_string());
+
+        let slices = a.subtree_roots_at_depth(2);
+        assert!(slices.len() > 4);
+        assert_eq!(slices[0].0.start, None);
+        assert_eq!(slices.last().unwrap().0.end, None);
+        let mut root = NodeHash::default();
+        for (range, hash) in &slices {
+            assert_eq!(a.range_hash(range.clone()), *hash);
+            root.xor(hash);
+        }
+        assert_eq!(root, *a.hash());
+        assert_eq!(
+            a.subtree_roots_at_depth(100).len(),
+            a.subtree_roots_at_depth(a.depth() - 1).len()
+        );
+
+        // Only the slices holding a difference need a session.
+        let stale: Vec<_> = slices
+            .into_iter()
+            .filter(|(range, hash)| b.range_hash(range.clone()) != *hash)
+            .collect();
+        assert_eq!(stale.len(), 2);
+        for (range, _) in stale {
+            let first = reconciler.start_range(&a, range);
+            run_session_from(&reconciler, &mut a, &mut b, first);
+        }
+        assert!(a.content_eq(&b));
+    }
+
+    #[test]
+    fn test_equal_values_are_not_empty() {
+        let reconciler = Reconciler::new(lww as fn(&String, &String) -> String);
+        let mut a = MerkleSearchTree::new(4);
+        a.insert(1u32, "same".to_string());
+        a.insert(2, "same".to_string());
+        let mut b = MerkleSearchTree::new(4);
+        assert_ne!(a.hash(), b.hash());
+        assert_ne!(a.root_hash(), b.root_hash());
+        assert!(is_empty_root(&b.root_hash()));
+
+        run_session(&reconciler, &mut a, &mut b);
+        assert_eq!(b.len(), 2);
+        assert_eq!(
+            a.top_divergent_ranges(&MerkleSearchTree::new(4), 4).len(),
+            1
+        );
+    }
+
+    // Leaves hash their keys: entries holding equal values don't cancel out,
+    // and neither do two keys trading values.
+    #[test]
+    fn test_equal_and_swapped_values_differ() {
+        let reconciler = Reconciler::new(lww as fn(&String, &String) -> String);
+        let mut a = MerkleSearchTree::new(4);
+        for i in 0..100u32 {
+            a.insert(i, format!("v{i}"));
+        }
+        let mut b = a.fork();
+        b.insert(500, "{}".to_string());
+        b.insert(501, "{}".to_string());
+        assert_ne!(a.root_hash(), b.root_hash());
+        assert_eq!(a.diff(&b).len(), 2);
+        #[cfg(feature = "crdt")]
+        {
+            let mut patched = a.fork();
+            patched.apply_patch(create_patch(&a, &b)).unwrap();
+            assert_eq!(patched.len(), 102);
+        }
+        let mut synced = a.fork();
+        run_session(&reconciler, &mut b, &mut synced);
+        assert_eq!(synced.len(), 102);
+
+        let mut swapped = a.fork();
+        swapped.insert(1, "v2".to_string());
+        swapped.insert(2, "v1".to_string());
+        assert_ne!(a.root_hash(), swapped.root_hash());
+        assert_eq!(a.diff(&swapped).len(), 2);
+        let packet = swapped.make_digest_packet(1200);
+        assert!(!a.compare_digest_packet(&packet).unwrap().in_sync);
+    }
+
+    #[test]
+    fn test_top_divergent_ranges() {
+        let mut a = MerkleSearchTree::new(4);
+        for i in 0..1000u32 {
+            a.insert(i, format!("0/{i}"));
+        }
+        let mut b = a.fork();
+        for i in 200..300 {
+            b.insert(i, format!("1/{i}"));
+        }
+        b.remove(&700);
+        b.insert(5000, "0/5000".to_string());
+        assert!(a.top_divergent_ranges(&a.fork(), 8).is_empty());
+
+        let top = a.top_divergent_ranges(&b, 4);
+        assert_eq!(top.len(), 4);
+        assert!(top.windows(2).all(|w| w[0].estimate() >= w[1].estimate()));
+        assert!(top[0].range.start.is_none_or(|start| start <= 250));
+        assert!(top[0].range.end.is_none_or(|end| end > 250));
+        for range in &top {
+            assert_ne!(
+                a.range_hash(range.range.clone()),
+                b.range_hash(range.range.clone())
+            );
+            assert_eq!(range.theirs, b.range(range.range.clone()).count());
+        }
+
+        // Without truncation, the ranges hold every difference.
+        let all = a.top_divergent_ranges(&b, 1000);
+        assert!(all.len() < 1000);
+        for diff in a.diff(&b) {
+            let key = match diff {
+                Diff::Added(key, _) | Diff::Removed(key, _) | Diff::Changed { key, .. } => key,
+            };
+            assert!(all.iter().any(|range| range.range.contains(key)));
+        }
+    }
+
+    #[test]
+    fn test_tiered_strategies() {
+        let reconciler = Reconciler::new(lww as fn(&String, &String) -> String).with_tiers(20, 0.5);
+        let mut a = MerkleSearchTree::new(4);
+        for i in 0..1000u32 {
+            a.insert(i, format!("0/{i}"));
+        }
+        let plan_for = |a: &MerkleSearchTree<u32>, b: &MerkleSearchTree<u32>| {
+            let Message::Summary { total, ranges } = reconciler.start_tiered(a) else {
+                unreachable!("tiered sessions open with a summary");
+            };
+            reconciler.plan(b, total, &ranges)
+        };
+
+        let mut b = a.fork();
+        assert_eq!(plan_for(&a, &b).strategy, SyncStrategy::InSync);
+
+        // A stale range out of 16 or more holds more than the key list
+        // allows, but far less than half.
+        b.insert(500, "1/500".to_string());
+        let plan = plan_for(&a, &b);
+        assert_eq!(plan.strategy, SyncStrategy::RangeRecursion);
+        assert_eq!(plan.stale.len(), 1);
+        assert!(plan.estimated_divergence > 20 && plan.estimated_divergence < 500);
+
+        let small = MerkleSearchTree::new(4);
+        let mut tiny = MerkleSearchTree::new(4);
+        tiny.insert(1, "0/1".to_string());
+        assert_eq!(plan_for(&tiny, &small).strategy, SyncStrategy::KeyList);
+        assert_eq!(plan_for(&a, &small).strategy, SyncStrategy::Snapshot);
+
+        // Either way, the session converges.
+        let first = reconciler.start_tiered(&a);
+        run_session_from(&reconciler, &mut a, &mut b, first);
+        assert_eq!(a.hash(), b.hash());
+        let (mut small, first) = (small, reconciler.start_tiered(&tiny));
+        run_session_from(&reconciler, &mut tiny, &mut small, first);
+        assert!(tiny.iter().eq(small.iter()));
+    }
+
+    #[test]
+    fn test_peer_faults() {
+        let reconciler = Reconciler::new(lww as fn(&String, &String) -> String);
+        let mut a = MerkleSearchTree::new(4);
+        let mut b = MerkleSearchTree::new(4);
+        for i in 0..10u32 {
+            a.insert(i, format!("0/{i}"));
+            b.insert(i, format!("0/{i}"));
+        }
+        b.insert(5, "1/5".to_string());
+
+        // b answers a's fingerprint with a listing, which a tampers with.
+        let listing = reconciler.handle(&mut b, reconciler.start(&a)).remove(0);
+        let Message::Entries {
+            range,
+            mut entries,
+            hash,
+            ..
+        } = listing.clone()
+        else {
+            panic!("a small range is listed");
+        };
+        entries[0].1 = "9/forged".to_string();
+        let forged = Message::Entries {
+            range: range.clone(),
+            entries: entries.clone(),
+            reply: true,
+            hash,
+        };
+        let before = *a.hash();
+        let fault = reconciler.handle_checked(&mut a, forged).unwrap_err();
+        assert!(
+            matches!(fault.kind, FaultKind::ListingMismatch { claimed, .. } if claimed == hash)
+        );
+        assert_eq!(*a.hash(), before);
+
+        entries.swap(0, 1);
+        let unordered = Message::Entries {
+            range,
+            entries,
+            reply: true,
+            hash,
+        };
+        assert_eq!(
+            reconciler
+                .handle_checked(&mut a, unordered)
+                .unwrap_err()
+                .kind,
+            FaultKind::MalformedListing
+        );
+
+        // a's honest reply claims where a ended up; a false claim is caught.
+        let Message::Entries {
+            range,
+            entries,
+            reply,
+            ..
+        } = reconciler.handle(&mut a, listing).remove(0)
+        else {
+            panic!("listings are answered with entries");
+        };
+        assert!(!reply && entries.is_empty());
+        let lie = Message::Entries {
+            range,
+            entries,
+            reply,
+            hash: NodeHash::default(),
+        };
+        assert!(matches!(
+            reconciler.handle_checked(&mut b, lie).unwrap_err().kind,
+            FaultKind::Diverged { .. }
+        ));
+        assert_eq!(a.hash(), b.hash());
+    }
+
+    #[test]
+    fn test_delta_sessions() {
+        let reconciler = Reconciler::new(lww as fn(&String, &String) -> String);
+        let mut a = MerkleSearchTree::new(4).with_recent_changes(8);
+        for i in 0..100 {
+            a.insert(i, format!("v{i}"));
+        }
+        let mut b = a.fork();
+
+        // A peer at a logged root gets just the delta, removals included.
+        let shared = *a.hash();
+        a.insert(500, "new".to_string());
+        a.remove(&7);
+        let since = reconciler.start_since(shared);
+        assert_eq!(run_session_from(&reconciler, &mut b, &mut a, since), 2);
+        assert_eq!(a.hash(), b.hash());
+
+        // A removal the peer's root doesn't account for is refused whole.
+        let lie = Message::Delta {
+            since: *b.hash(),
+            changes: vec![(8, None), (600, Some("x".to_string()))],
+            hash: *a.hash(),
+        };
+        let before = *b.hash();
+        assert!(matches!(
+            reconciler.handle_checked(&mut b, lie).unwrap_err().kind,
+            FaultKind::DeltaMismatch { .. }
+        ));
+        assert_eq!(*b.hash(), before);
+        // Past local writes it can't be checked, and the key stays.
+        b.insert(503, "b".to_string());
+        let unchecked = Message::Delta {
+            since: before,
+            changes: vec![(8, None)],
+            hash: *a.hash(),
+        };
+        assert!(reconciler.handle_checked(&mut b, unchecked).is_ok());
+        assert!(b.get(&8).is_some());
+        b.remove(&503);
+
+        // Local writes on the requesting side fall back to fingerprints.
+        let shared = *a.hash();
+        a.insert(501, "a".to_string());
+        b.insert(502, "b".to_string());
+        let since = reconciler.start_since(shared);
+        assert!(run_session_from(&reconciler, &mut b, &mut a, since) > 2);
+        assert_eq!(a.hash(), b.hash());
+        assert_eq!(b.len(), 102);
+
+        // So does a peer that fell behind the log.
+        let mut c = MerkleSearchTree::new(4);
+        c.insert(1, "v1".to_string());
+        let since = reconciler.start_since(*c.hash());
+        run_session_from(&reconciler, &mut c, &mut a, since);
+        assert_eq!(a.hash(), c.hash());
+    }
+
+    #[test]
+    fn test_reconcile_narrow_hashes() {
+        let reconciler = Reconciler::new(lww as fn(&String, &String) -> String);
+        let mut a = MerkleSearchTree::new(4).with_hash_width::<16>();
+        let mut b = MerkleSearchTree::new(8).with_hash_width::<16>();
+        for i in 0..300u32 {
+            a.insert(i, format!("0/{i}"));
+        }
+        b.insert(1000, "0/1000".to_string());
+
+        let mut to_b = vec![reconciler.start(&a)];
+        while !to_b.is_empty() {
+            let to_a: Vec<_> = to_b
+                .drain(..)
+                .flat_map(|message| reconciler.handle(&mut b, message))
+                .collect();
+            for message in to_a {
+                to_b.extend(reconciler.handle(&mut a, message));
+            }
+        }
+        assert_eq!(a.hash(), b.hash());
+        assert_eq!(a.hash().len(), 16);
+        assert!(a.iter().eq(b.iter()));
+    }
+}
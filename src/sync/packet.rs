@@ -0,0 +1,179 @@
+// Digest packets: a tree's root and top-level range hashes in one datagram,
+// for SWIM-style gossip to piggyback on its probes without a session.
+//
+// A packet holds the root, the entry count, and the hashes of the ranges
+// the top-level subtrees cover, with the keys where they start. Range
+// hashes are cut to their first 8 bytes: a packet only says where to look,
+// and the session that follows checks everything in full. If the ranges
+// don't fit the size asked for, neighbours are merged, halving their
+// number until they do, so a packet is never larger than that unless even
+// a single range can't fit.
+//
+// The receiver hashes the same ranges of its own tree, so the comparison
+// doesn't depend on the two trees having the same shape, only on both
+// encoding keys alike.
+
+use crate::core::codec::{Decode, Encode, take};
+use crate::core::error::Error;
+use crate::core::hash::NodeHash;
+use crate::core::tree::MerkleSearchTree;
+use crate::sync::KeyRange;
+
+// Version 2 range hashes cover the keys.
+pub const DIGEST_PACKET_VERSION: u8 = 2;
+
+// Fits an IPv6 minimum MTU datagram with room for the gossip's own fields.
+pub const DIGEST_PACKET_SIZE: usize = 1200;
+
+const PREFIX: usize = 8;
+
+// How a peer's packet compares with the local tree.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DigestComparison<K> {
+    pub in_sync: bool,
+    // The entries the peer holds.
+    pub peer_entries: u64,
+    // The packet's ranges whose hashes differ; empty if in sync.
+    pub differing: Vec<KeyRange<K>>,
+}
+
+impl<K, V, const N: usize> MerkleSearchTree<K, V, N>
+where
+    K: Ord + Clone + Default + Encode + Decode,
+    V: AsRef<[u8]>,
+{
+    // A packet of at most `max_bytes`, unless the root and one range need
+    // more.
+    pub fn make_digest_packet(&self, max_bytes: usize) -> Vec<u8> {
+        let mut ranges = self.subtree_roots_at_depth(1);
+        loop {
+            let packet = self.encode_digest_packet(&ranges);
+            if packet.len() <= max_bytes || ranges.len() == 1 {
+                return packet;
+            }
+            ranges = ranges
+                .chunks(2)
+                .map(|pair| {
+                    let mut hash = pair[0].1;
+                    let mut range = pair[0].0.clone();
+                    if let Some((next, next_hash)) = pair.get(1) {
+                        hash.xor(next_hash);
+                        range.end = next.end.clone();
+                    }
+                    (range, hash)
+                })
+                .collect();
+        }
+    }
+
+    fn encode_digest_packet(&self, ranges: &[(KeyRange<K>, NodeHash<N>)]) -> Vec<u8> {
+        let mut packet = vec![DIGEST_PACKET_VERSION];
+        self.root_hash().encode(&mut packet);
+        (self.len() as u64).encode(&mut packet);
+        (ranges.len() as u16).encode(&mut packet);
+        // The first range starts unbounded; the others where they start.
+        for (range, _) in ranges.iter().skip(1) {
+            if let Some(start) = &range.start {
+                start.encode(&mut packet);
+            }
+        }
+        for (_, hash) in ranges {
+            packet.extend_from_slice(&hash.0[..PREFIX]);
+        }
+        packet
+    }
+
+    pub fn compare_digest_packet(&self, packet: &[u8]) -> Result<DigestComparison<K>, Error> {
+        let mut input = packet;
+        let version = u8::decode(&mut input)?;
+        if version != DIGEST_PACKET_VERSION {
+            return Err(Error::Malformed(format!(
+                "unsupported digest packet version {version}"
+            )));
+        }
+        let root = NodeHash::<N>::decode(&mut input)?;
+        let peer_entries = u64::decode(&mut input)?;
+        let count = u16::decode(&mut input)? as usize;
+        if count == 0 {
+            return Err(Error::Malformed("digest packet without ranges".to_string()));
+        }
+        let mut starts = vec![None];
+        for _ in 1..count {
+            starts.push(Some(K::decode(&mut input)?));
+        }
+        if !starts.windows(2).all(|pair| pair[0] < pair[1]) {
+            return Err(Error::Malformed(
+                "digest packet ranges are out of order".to_string(),
+            ));
+        }
+        let hashes = take(&mut input, count * PREFIX)?;
+        if !input.is_empty() {
+            return Err(Error::Malformed(
+                "trailing bytes after digest packet".to_string(),
+            ));
+        }
+
+        let in_sync = root == self.root_hash();
+        let mut differing = Vec::new();
+        if !in_sync {
+            for (i, hash) in hashes.chunks(PREFIX).enumerate() {
+                let range = KeyRange {
+                    start: starts[i].clone(),
+                    end: starts.get(i + 1).cloned().flatten(),
+                };
+                if self.range_hash(range.clone()).0[..PREFIX] != *hash {
+                    differing.push(range);
+                }
+            }
+        }
+        Ok(DigestComparison {
+            in_sync,
+            peer_entries,
+            differing,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::ops::RangeBounds;
+
+    fn tree(keys: impl Iterator<Item = u32>) -> MerkleSearchTree<u32> {
+        let mut tree = MerkleSearchTree::new(8);
+        for i in keys {
+            tree.insert(i, format!("v{i}"));
+        }
+        tree
+    }
+
+    #[test]
+    fn test_digest_packets() {
+        let ours = tree(0..5000);
+        let packet = ours.make_digest_packet(DIGEST_PACKET_SIZE);
+        assert!(packet.len() <= DIGEST_PACKET_SIZE);
+        let same = tree((0..5000).rev());
+        let comparison = same.compare_digest_packet(&packet).unwrap();
+        assert!(comparison.in_sync && comparison.differing.is_empty());
+        assert_eq!(comparison.peer_entries, 5000);
+
+        // Only the range holding the change differs.
+        let mut theirs = same.fork();
+        theirs.insert(1234, "changed".to_string());
+        let comparison = theirs.compare_digest_packet(&packet).unwrap();
+        assert!(!comparison.in_sync);
+        let [range] = comparison.differing.as_slice() else {
+            panic!("expected one range, got {:?}", comparison.differing);
+        };
+        assert!(range.contains(&1234));
+
+        // A tight budget merges ranges; too tight leaves the root and one.
+        let small = ours.make_digest_packet(100);
+        assert!(small.len() <= 100);
+        let tiny = ours.make_digest_packet(0);
+        assert_eq!(tiny.len(), 1 + 32 + 8 + 2 + PREFIX);
+        let comparison = theirs.compare_digest_packet(&tiny).unwrap();
+        assert_eq!(comparison.differing, [KeyRange::full()]);
+        assert!(theirs.compare_digest_packet(&packet[1..]).is_err());
+    }
+}
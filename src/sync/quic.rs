@@ -0,0 +1,179 @@
+// Sync sessions over QUIC, for peers behind NATs where listening for TCP is
+// awkward. Behind the `quic` feature.
+//
+// Each session gets a bidirectional stream of its own over a connection
+// both sides keep up: the dialing side opens one with `QuicStream::open`,
+// the other takes it with `QuicStream::accept`, and each wraps its end in a
+// `FramedStream`, which frames the batches as it does over TCP. The peer
+// only sees a new stream once something is sent on it, which the initiator
+// does first.
+//
+// Sessions are blocking, like the rest of `transport`, while quinn runs on
+// a tokio runtime. A `QuicStream` drives its stream on the runtime it is
+// given, so it must be used from a thread outside that runtime, e.g. one
+// from `spawn_blocking`; blocking inside a runtime's own task panics.
+
+use std::io::{self, Read, Write};
+
+use quinn::{Connection, RecvStream, SendStream};
+use tokio::runtime::Handle;
+
+use crate::core::error::Error;
+
+// One session's stream, read and written by blocking on `runtime`.
+pub struct QuicStream {
+    send: SendStream,
+    recv: RecvStream,
+    runtime: Handle,
+}
+
+impl QuicStream {
+    // Opens a stream for a new session on `connection`.
+    pub fn open(connection: &Connection, runtime: Handle) -> Result<Self, Error> {
+        let (send, recv) = runtime
+            .block_on(connection.open_bi())
+            .map_err(io::Error::other)?;
+        Ok(QuicStream {
+            send,
+            recv,
+            runtime,
+        })
+    }
+
+    // Waits for the peer to open a stream for a new session.
+    pub fn accept(connection: &Connection, runtime: Handle) -> Result<Self, Error> {
+        let (send, recv) = runtime
+            .block_on(connection.accept_bi())
+            .map_err(io::Error::other)?;
+        Ok(QuicStream {
+            send,
+            recv,
+            runtime,
+        })
+    }
+
+    // Tells the peer nothing more is sent on this stream, e.g. once
+    // `FramedStream::into_inner` hands it back after a session.
+    pub fn finish(mut self) -> Result<(), Error> {
+        self.send.finish().map_err(io::Error::other)?;
+        Ok(())
+    }
+}
+
+impl Read for QuicStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // None means the peer finished its side, which reads as end of file.
+        match self.runtime.block_on(self.recv.read(buf)) {
+            Ok(read) => Ok(read.unwrap_or(0)),
+            Err(err) => Err(io::Error::other(err)),
+        }
+    }
+}
+
+impl Write for QuicStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.runtime
+            .block_on(self.send.write(buf))
+            .map_err(io::Error::other)
+    }
+
+    // Written data is handed to quinn, which sends it as it can.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::thread;
+
+    use quinn::rustls::RootCertStore;
+    use quinn::rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer};
+    use quinn::{ClientConfig, Endpoint, ServerConfig};
+    use tokio::runtime::Runtime;
+
+    use crate::core::tree::MerkleSearchTree;
+    use crate::sync::Reconciler;
+    use crate::sync::transport::{FramedStream, initiate, respond};
+    use crate::sync::wire::WireFormat;
+
+    type Merge = fn(&String, &String) -> String;
+
+    fn lww(local: &String, remote: &String) -> String {
+        local.max(remote).clone()
+    }
+
+    // A server endpoint with a self-signed certificate, and a client
+    // endpoint trusting it.
+    fn endpoints(runtime: &Runtime) -> (Endpoint, Endpoint, SocketAddr) {
+        let _guard = runtime.enter();
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert = CertificateDer::from(certified.cert.der().to_vec());
+        let key = PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der());
+        let config = ServerConfig::with_single_cert(vec![cert.clone()], key.into()).unwrap();
+        let server = Endpoint::server(config, "127.0.0.1:0".parse().unwrap()).unwrap();
+        let address = server.local_addr().unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let mut client = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        client.set_default_client_config(
+            ClientConfig::with_root_certificates(Arc::new(roots)).unwrap(),
+        );
+        (server, client, address)
+    }
+
+    #[test]
+    fn test_sessions_over_quic() {
+        let runtime = Runtime::new().unwrap();
+        let (server, client, address) = endpoints(&runtime);
+
+        let handle = runtime.handle().clone();
+        let responder = thread::spawn(move || {
+            let mut tree = MerkleSearchTree::<u32>::new(4);
+            for i in 0..300 {
+                tree.insert(i, format!("v{i}"));
+            }
+            let connection = handle
+                .block_on(async { server.accept().await.unwrap().await })
+                .unwrap();
+            // Two sessions, each on a stream of its own.
+            for _ in 0..2 {
+                let stream = QuicStream::accept(&connection, handle.clone()).unwrap();
+                let mut transport = FramedStream::new(stream, WireFormat::new());
+                respond(&mut tree, &Reconciler::new(lww as Merge), &mut transport).unwrap();
+                transport.into_inner().finish().unwrap();
+            }
+            handle.block_on(connection.closed());
+            tree
+        });
+
+        let handle = runtime.handle().clone();
+        let connection = handle
+            .block_on(async { client.connect(address, "localhost").unwrap().await })
+            .unwrap();
+        let reconciler = Reconciler::new(lww as Merge);
+        let mut tree = MerkleSearchTree::<u32>::new(4);
+        for i in 100..400 {
+            tree.insert(i, format!("v{i}"));
+        }
+        for round in 0..2 {
+            tree.insert(1000 + round, "late".to_string());
+            let stream = QuicStream::open(&connection, handle.clone()).unwrap();
+            let mut transport = FramedStream::new(stream, WireFormat::new());
+            assert!(initiate(&mut tree, &reconciler, &mut transport).unwrap() > 1);
+            let mut stream = transport.into_inner();
+            // The responder finishes its side once it saw the session end.
+            assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+            stream.finish().unwrap();
+        }
+        connection.close(0u32.into(), b"done");
+
+        let peer = responder.join().unwrap();
+        assert_eq!(tree.root_hash(), peer.root_hash());
+        assert_eq!(tree.len(), 402);
+    }
+}
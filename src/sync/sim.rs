@@ -0,0 +1,216 @@
+// A deterministic network simulation for testing sync convergence.
+//
+// N replicas take random writes and run sync sessions with random peers over
+// a link that reorders and drops messages. Everything is driven by a seeded
+// RNG so a failing seed can be replayed.
+
+use crate::core::rng::Rng;
+use crate::core::tree::MerkleSearchTree;
+use crate::sync::{Message, Reconciler};
+
+pub type Replica = MerkleSearchTree<u32, String>;
+type Merge = fn(&String, &String) -> String;
+
+#[derive(Clone, Debug)]
+pub struct SimConfig {
+    pub replicas: usize,
+    pub max_children: usize,
+    // Writes pick keys from `0..key_space`, so a small space causes conflicts.
+    pub key_space: u32,
+    // Probability that a message is lost in transit.
+    pub drop_rate: f64,
+    pub split_threshold: usize,
+    pub seed: u64,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        SimConfig {
+            replicas: 4,
+            max_children: 4,
+            key_space: 1000,
+            drop_rate: 0.1,
+            split_threshold: 8,
+            seed: 42,
+        }
+    }
+}
+
+struct Envelope {
+    to: usize,
+    from: usize,
+    message: Message<u32, String>,
+}
+
+pub struct Simulation {
+    config: SimConfig,
+    replicas: Vec<Replica>,
+    in_flight: Vec<Envelope>,
+    reconciler: Reconciler<Merge>,
+    rng: Rng,
+    // Logical clock stamped into every written value.
+    clock: u64,
+    delivered: usize,
+    dropped: usize,
+}
+
+impl Simulation {
+    pub fn new(config: SimConfig) -> Self {
+        let replicas = (0..config.replicas)
+            .map(|_| MerkleSearchTree::new(config.max_children))
+            .collect();
+        let reconciler =
+            Reconciler::new(last_writer_wins as Merge).with_split_threshold(config.split_threshold);
+        Simulation {
+            rng: Rng(config.seed),
+            config,
+            replicas,
+            in_flight: vec![],
+            reconciler,
+            clock: 0,
+            delivered: 0,
+            dropped: 0,
+        }
+    }
+
+    pub fn replicas(&self) -> &[Replica] {
+        &self.replicas
+    }
+
+    pub fn delivered(&self) -> usize {
+        self.delivered
+    }
+
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    // Applies `count` writes, each to a random replica and key.
+    pub fn random_writes(&mut self, count: usize) {
+        for _ in 0..count {
+            let replica = self.rng.below(self.replicas.len() as u64) as usize;
+            let key = self.rng.below(self.config.key_space as u64) as u32;
+            self.clock += 1;
+            // The zero-padded clock makes string order match write order.
+            let value = format!("{:012}/{replica}/{key}", self.clock);
+            self.replicas[replica].insert(key, value);
+        }
+    }
+
+    // Every replica opens a session with a random other replica.
+    pub fn start_sync_round(&mut self) {
+        let n = self.replicas.len();
+        if n < 2 {
+            return;
+        }
+        for from in 0..n {
+            let to = (from + 1 + self.rng.below(n as u64 - 1) as usize) % n;
+            let message = self.reconciler.start(&self.replicas[from]);
+            self.in_flight.push(Envelope { to, from, message });
+        }
+    }
+
+    // Delivers a single in-flight message, chosen at random. Returns false when none are left.
+    pub fn step(&mut self) -> bool {
+        if self.in_flight.is_empty() {
+            return false;
+        }
+        let index = self.rng.below(self.in_flight.len() as u64) as usize;
+        let Envelope { to, from, message } = self.in_flight.swap_remove(index);
+
+        if self.rng.chance(self.config.drop_rate) {
+            self.dropped += 1;
+            return true;
+        }
+        self.delivered += 1;
+        let replies = self.reconciler.handle(&mut self.replicas[to], message);
+        self.in_flight
+            .extend(replies.into_iter().map(|message| Envelope {
+                to: from,
+                from: to,
+                message,
+            }));
+        true
+    }
+
+    pub fn deliver_all(&mut self) {
+        while self.step() {}
+    }
+
+    pub fn converged(&self) -> bool {
+        let first = self.replicas[0].hash();
+        self.replicas.iter().all(|replica| replica.hash() == first)
+    }
+
+    // Runs sync rounds until every replica has the same root.
+    // Returns the number of rounds it took, or None if `max_rounds` wasn't enough.
+    pub fn run_until_converged(&mut self, max_rounds: usize) -> Option<usize> {
+        for round in 0..=max_rounds {
+            if self.converged() {
+                return Some(round);
+            }
+            self.start_sync_round();
+            self.deliver_all();
+        }
+        None
+    }
+}
+
+fn last_writer_wins(local: &String, remote: &String) -> String {
+    local.max(remote).clone()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_converges_over_lossy_network() {
+        for seed in 0..5 {
+            let mut sim = Simulation::new(SimConfig {
+                replicas: 5,
+                drop_rate: 0.3,
+                seed,
+                ..Default::default()
+            });
+
+            // Writes interleaved with partially delivered sync rounds.
+            for _ in 0..10 {
+                sim.random_writes(100);
+                sim.start_sync_round();
+                for _ in 0..50 {
+                    sim.step();
+                }
+            }
+
+            assert!(
+                sim.run_until_converged(200).is_some(),
+                "seed {seed} did not converge"
+            );
+            assert!(sim.dropped() > 0);
+            let first: Vec<_> = sim.replicas()[0].iter().collect();
+            for replica in sim.replicas() {
+                assert!(replica.iter().eq(first.iter().copied()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_hot_keys_resolve_to_latest_write() {
+        let mut sim = Simulation::new(SimConfig {
+            replicas: 3,
+            key_space: 4,
+            drop_rate: 0.0,
+            ..Default::default()
+        });
+        sim.random_writes(50);
+        assert!(sim.run_until_converged(20).is_some());
+
+        // The last write went out with clock 50, so exactly one key carries it.
+        let latest = sim.replicas()[0]
+            .iter()
+            .filter(|(_, value)| value.starts_with("000000000050/"))
+            .count();
+        assert_eq!(latest, 1);
+    }
+}
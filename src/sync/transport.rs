@@ -0,0 +1,229 @@
+// Sync sessions over byte streams.
+//
+// A `SyncTransport` carries one session's messages in batches: the
+// initiator sends one message at a time and gets back the batch the peer
+// answers it with, and ends the session with an empty batch. `initiate`
+// and `respond` drive the two ends. `FramedStream` implements the
+// transport over anything that reads and writes, one stream per session,
+// each batch a length-prefixed `WireFormat` frame; the length is checked
+// against the format's cap before anything is read.
+//
+// For peers behind NATs, where listening for TCP is awkward, the stream
+// can be a QUIC bidirectional stream instead; see `quic`.
+
+use std::io::{Read, Write};
+
+use crate::core::codec::{Decode, Encode};
+use crate::core::error::Error;
+use crate::core::tree::MerkleSearchTree;
+use crate::sync::wire::WireFormat;
+use crate::sync::{Message, Reconciler};
+
+pub trait SyncTransport<K, V> {
+    fn send(&mut self, batch: &[Message<K, V>]) -> Result<(), Error>;
+
+    // The next batch from the peer, empty if it ended the session.
+    fn receive(&mut self) -> Result<Vec<Message<K, V>>, Error>;
+}
+
+pub struct FramedStream<S> {
+    stream: S,
+    wire: WireFormat,
+}
+
+impl<S: Read + Write> FramedStream<S> {
+    pub fn new(stream: S, wire: WireFormat) -> Self {
+        FramedStream { stream, wire }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S, K, V> SyncTransport<K, V> for FramedStream<S>
+where
+    S: Read + Write,
+    K: Encode + Decode,
+    V: Encode + Decode,
+{
+    fn send(&mut self, batch: &[Message<K, V>]) -> Result<(), Error> {
+        let frame = self.wire.encode_frame(&Batch(batch))?;
+        let mut out = Vec::with_capacity(frame.len() + 4);
+        frame.encode(&mut out);
+        self.stream.write_all(&out)?;
+        self.stream.flush()?;
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Result<Vec<Message<K, V>>, Error> {
+        let mut len = [0; 4];
+        self.stream.read_exact(&mut len)?;
+        let len = u32::from_be_bytes(len) as u64;
+        // The frame's flag and length fields come on top of the payload.
+        let limit = self.wire.max_message() + 5;
+        if len > limit {
+            return Err(Error::MessageTooLarge { size: len, limit });
+        }
+        let mut frame = vec![0; len as usize];
+        self.stream.read_exact(&mut frame)?;
+        Ok(self.wire.decode_frame::<DecodedBatch<K, V>>(&frame)?.0)
+    }
+}
+
+struct Batch<'a, K, V>(&'a [Message<K, V>]);
+
+struct DecodedBatch<K, V>(Vec<Message<K, V>>);
+
+impl<K: Encode, V: Encode> Encode for Batch<'_, K, V> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (self.0.len() as u32).encode(out);
+        for message in self.0 {
+            message.encode(out);
+        }
+    }
+}
+
+impl<K: Decode, V: Decode> Decode for DecodedBatch<K, V> {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        let count = u32::decode(input)?;
+        // Every message takes at least its tag byte.
+        if count as usize > input.len() {
+            return Err(Error::Malformed(format!(
+                "{count} messages in {} bytes",
+                input.len()
+            )));
+        }
+        let messages = (0..count)
+            .map(|_| Message::decode(input))
+            .collect::<Result<_, _>>()?;
+        Ok(DecodedBatch(messages))
+    }
+}
+
+// Runs a session from the initiating end to completion. Returns the number
+// of messages sent and received. A peer contradicting its hashes fails the
+// session with `Error::Malformed`.
+pub fn initiate<K, V, F, T>(
+    tree: &mut MerkleSearchTree<K, V>,
+    reconciler: &Reconciler<F>,
+    transport: &mut T,
+) -> Result<u64, Error>
+where
+    K: Ord + Clone + Default + Encode,
+    V: AsRef<[u8]> + Clone + PartialEq,
+    F: Fn(&V, &V) -> V,
+    T: SyncTransport<K, V>,
+{
+    let mut outgoing = vec![reconciler.start(tree)];
+    let mut messages = 0;
+    while let Some(message) = outgoing.pop() {
+        transport.send(&[message])?;
+        messages += 1;
+        for reply in transport.receive()? {
+            messages += 1;
+            outgoing.extend(handle(tree, reconciler, reply)?);
+        }
+    }
+    transport.send(&[])?;
+    Ok(messages)
+}
+
+// Answers a session until the initiator ends it. Returns the number of
+// messages received and sent.
+pub fn respond<K, V, F, T>(
+    tree: &mut MerkleSearchTree<K, V>,
+    reconciler: &Reconciler<F>,
+    transport: &mut T,
+) -> Result<u64, Error>
+where
+    K: Ord + Clone + Default + Encode,
+    V: AsRef<[u8]> + Clone + PartialEq,
+    F: Fn(&V, &V) -> V,
+    T: SyncTransport<K, V>,
+{
+    let mut messages = 0;
+    loop {
+        let batch = transport.receive()?;
+        if batch.is_empty() {
+            return Ok(messages);
+        }
+        let mut replies = Vec::new();
+        for message in batch {
+            messages += 1;
+            replies.extend(handle(tree, reconciler, message)?);
+        }
+        messages += replies.len() as u64;
+        transport.send(&replies)?;
+    }
+}
+
+fn handle<K, V, F>(
+    tree: &mut MerkleSearchTree<K, V>,
+    reconciler: &Reconciler<F>,
+    message: Message<K, V>,
+) -> Result<Vec<Message<K, V>>, Error>
+where
+    K: Ord + Clone + Default + Encode,
+    V: AsRef<[u8]> + Clone + PartialEq,
+    F: Fn(&V, &V) -> V,
+{
+    reconciler
+        .handle_checked(tree, message)
+        .map_err(|fault| Error::Malformed(format!("peer fault: {:?}", fault.kind)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    type Merge = fn(&String, &String) -> String;
+
+    fn lww(local: &String, remote: &String) -> String {
+        local.max(remote).clone()
+    }
+
+    #[test]
+    fn test_session_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let responder = thread::spawn(move || {
+            let mut tree = MerkleSearchTree::<u32>::new(4);
+            for i in 0..300 {
+                tree.insert(i, format!("v{i}"));
+            }
+            let (stream, _) = listener.accept().unwrap();
+            let mut transport = FramedStream::new(stream, WireFormat::new());
+            respond(&mut tree, &Reconciler::new(lww as Merge), &mut transport).unwrap();
+            tree
+        });
+
+        let mut tree = MerkleSearchTree::<u32>::new(4);
+        for i in 100..400 {
+            tree.insert(i, format!("v{i}"));
+        }
+        let stream = TcpStream::connect(address).unwrap();
+        let mut transport = FramedStream::new(stream, WireFormat::new());
+        let reconciler = Reconciler::new(lww as Merge);
+        let messages = initiate(&mut tree, &reconciler, &mut transport).unwrap();
+        let peer = responder.join().unwrap();
+        assert!(messages > 1);
+        assert_eq!(tree.root_hash(), peer.root_hash());
+        assert_eq!(tree.len(), 400);
+    }
+
+    #[test]
+    fn test_frame_cap() {
+        // A length prefix over the cap is refused before reading on.
+        let mut bytes = (1u32 << 20).to_be_bytes().to_vec();
+        bytes.extend_from_slice(&[0; 16]);
+        let mut transport = FramedStream::new(
+            std::io::Cursor::new(bytes),
+            WireFormat::new().with_max_message(1024),
+        );
+        let received: Result<Vec<Message<u32, String>>, _> = transport.receive();
+        assert!(matches!(received, Err(Error::MessageTooLarge { .. })));
+    }
+}
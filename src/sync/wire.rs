@@ -0,0 +1,559 @@
+// Wire encoding of sync messages and export chunks.
+//
+// Each frame is a flag byte and the payload's canonical encoding, which
+// may be compressed with the LZ77 coder snapshots use for values (with the
+// `compression` feature) or with zstd (with the `zstd` feature). Keys of
+// string-heavy keyspaces share long prefixes, which either coder removes.
+// Both sides describe what they accept in a `WireFormat` and talk in the
+// `negotiate`d one: zstd if both support it, else LZ77 if both do, and the
+// smaller of their size caps, which holds for a frame both before and after
+// decompression.
+
+use crate::core::codec::{Decode, Encode};
+use crate::core::error::Error;
+use crate::core::hash::NodeHash;
+use crate::sync::{KeyRange, Message};
+
+const RAW: u8 = 0;
+const COMPRESSED: u8 = 1;
+const ZSTD: u8 = 2;
+
+// Bits of the compression tag in an encoded `WireFormat`.
+const LZ77_TAG: u8 = 1;
+const ZSTD_TAG: u8 = 2;
+
+// Used where no cap is given.
+const DEFAULT_MAX_MESSAGE: u64 = 64 << 20;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WireFormat {
+    // Payloads longer than this are compressed, if that makes them shorter.
+    compress_above: Option<u32>,
+    // The same for zstd, which wins when both sides take it.
+    zstd_above: Option<u32>,
+    max_message: u64,
+}
+
+impl Default for WireFormat {
+    fn default() -> Self {
+        WireFormat {
+            compress_above: None,
+            zstd_above: None,
+            max_message: DEFAULT_MAX_MESSAGE,
+        }
+    }
+}
+
+impl WireFormat {
+    // Uncompressed frames of up to 64 MiB.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Compresses payloads longer than `threshold` bytes.
+    #[cfg(feature = "compression")]
+    pub fn with_compression(mut self, threshold: u32) -> Self {
+        self.compress_above = Some(threshold);
+        self
+    }
+
+    // Compresses payloads longer than `threshold` bytes with zstd, with
+    // peers that take it.
+    #[cfg(feature = "zstd")]
+    pub fn with_zstd(mut self, threshold: u32) -> Self {
+        self.zstd_above = Some(threshold);
+        self
+    }
+
+    // Rejects frames whose payload is longer than `max_message` bytes.
+    pub fn with_max_message(mut self, max_message: u64) -> Self {
+        self.max_message = max_message;
+        self
+    }
+
+    pub fn compresses(&self) -> bool {
+        self.compress_above.is_some() || self.zstd_above.is_some()
+    }
+
+    pub fn uses_zstd(&self) -> bool {
+        self.zstd_above.is_some()
+    }
+
+    pub fn max_message(&self) -> u64 {
+        self.max_message
+    }
+
+    // The format for talking to a peer that accepts `theirs`. Both sides
+    // reach the same one.
+    pub fn negotiate(&self, theirs: &WireFormat) -> WireFormat {
+        let both = |ours: Option<u32>, theirs: Option<u32>| ours.zip(theirs).map(|(a, b)| a.max(b));
+        let zstd_above = both(self.zstd_above, theirs.zstd_above);
+        WireFormat {
+            compress_above: match zstd_above {
+                Some(_) => None,
+                None => both(self.compress_above, theirs.compress_above),
+            },
+            zstd_above,
+            max_message: self.max_message.min(theirs.max_message),
+        }
+    }
+
+    pub fn encode_frame<T: Encode>(&self, message: &T) -> Result<Vec<u8>, Error> {
+        let mut payload = Vec::new();
+        message.encode(&mut payload);
+        self.check_size(payload.len() as u64)?;
+        #[cfg(feature = "zstd")]
+        if let Some(threshold) = self.zstd_above
+            && payload.len() > threshold as usize
+        {
+            let packed = zstd::bulk::compress(&payload, zstd::DEFAULT_COMPRESSION_LEVEL)
+                .map_err(Error::Io)?;
+            if packed.len() < payload.len() {
+                let mut frame = vec![ZSTD];
+                (payload.len() as u32).encode(&mut frame);
+                frame.extend_from_slice(&packed);
+                return Ok(frame);
+            }
+        }
+        #[cfg(feature = "compression")]
+        if let Some(threshold) = self.compress_above
+            && payload.len() > threshold as usize
+        {
+            let packed = crate::core::compress::compress(&payload);
+            if packed.len() < payload.len() {
+                let mut frame = vec![COMPRESSED];
+                (payload.len() as u32).encode(&mut frame);
+                frame.extend_from_slice(&packed);
+                return Ok(frame);
+            }
+        }
+        let mut frame = vec![RAW];
+        frame.extend_from_slice(&payload);
+        Ok(frame)
+    }
+
+    pub fn decode_frame<T: Decode>(&self, frame: &[u8]) -> Result<T, Error> {
+        let mut input = frame;
+        let unpacked;
+        let mut payload = match u8::decode(&mut input)? {
+            RAW => {
+                self.check_size(input.len() as u64)?;
+                input
+            }
+            COMPRESSED => {
+                let len = u32::decode(&mut input)?;
+                // Checked before expanding, so a small frame can't claim
+                // a huge payload.
+                self.check_size(len as u64)?;
+                unpacked = self.decompress(input, len as usize)?;
+                unpacked.as_slice()
+            }
+            ZSTD => {
+                let len = u32::decode(&mut input)?;
+                self.check_size(len as u64)?;
+                unpacked = self.unzstd(input, len as usize)?;
+                unpacked.as_slice()
+            }
+            flag => return Err(Error::Malformed(format!("unknown frame flag {flag}"))),
+        };
+        let message = T::decode(&mut payload)?;
+        if !payload.is_empty() {
+            return Err(Error::Malformed(format!(
+                "{} bytes after the message",
+                payload.len()
+            )));
+        }
+        Ok(message)
+    }
+
+    fn check_size(&self, size: u64) -> Result<(), Error> {
+        if size > self.max_message {
+            return Err(Error::MessageTooLarge {
+                size,
+                limit: self.max_message,
+            });
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "compression")]
+    fn decompress(&self, packed: &[u8], len: usize) -> Result<Vec<u8>, Error> {
+        if self.compress_above.is_none() {
+            return Err(Error::Malformed(
+                "compressed frame without negotiated compression".to_string(),
+            ));
+        }
+        crate::core::compress::decompress(packed, len)
+    }
+
+    #[cfg(not(feature = "compression"))]
+    fn decompress(&self, _: &[u8], _: usize) -> Result<Vec<u8>, Error> {
+        Err(Error::Malformed(
+            "compressed frame without negotiated compression".to_string(),
+        ))
+    }
+
+    #[cfg(feature = "zstd")]
+    fn unzstd(&self, packed: &[u8], len: usize) -> Result<Vec<u8>, Error> {
+        if !self.uses_zstd() {
+            return Err(Error::Malformed(
+                "zstd frame without negotiated zstd".to_string(),
+            ));
+        }
+        // Decompresses into at most `len` bytes, which were checked.
+        let unpacked = zstd::bulk::decompress(packed, len)
+            .map_err(|err| Error::Malformed(format!("zstd frame: {err}")))?;
+        if unpacked.len() != len {
+            return Err(Error::Malformed(format!(
+                "zstd frame of {} bytes, not {len}",
+                unpacked.len()
+            )));
+        }
+        Ok(unpacked)
+    }
+
+    #[cfg(not(feature = "zstd"))]
+    fn unzstd(&self, _: &[u8], _: usize) -> Result<Vec<u8>, Error> {
+        Err(Error::Malformed(
+            "zstd frame without negotiated zstd".to_string(),
+        ))
+    }
+}
+
+// A tag with a bit per coder taken, then each one's threshold, so formats
+// from before zstd decode the same.
+impl Encode for WireFormat {
+    fn encode(&self, out: &mut Vec<u8>) {
+        let tag = (self.compress_above.is_some() as u8 * LZ77_TAG)
+            | (self.zstd_above.is_some() as u8 * ZSTD_TAG);
+        tag.encode(out);
+        for threshold in self.compress_above.iter().chain(&self.zstd_above) {
+            threshold.encode(out);
+        }
+        self.max_message.encode(out);
+    }
+}
+
+impl Decode for WireFormat {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        let tag = u8::decode(input)?;
+        if tag & !(LZ77_TAG | ZSTD_TAG) != 0 {
+            return Err(Error::Malformed(format!("unknown compression tag {tag}")));
+        }
+        let mut threshold = |bit: u8| -> Result<Option<u32>, Error> {
+            (tag & bit != 0).then(|| u32::decode(input)).transpose()
+        };
+        Ok(WireFormat {
+            compress_above: threshold(LZ77_TAG)?,
+            zstd_above: threshold(ZSTD_TAG)?,
+            max_message: u64::decode(input)?,
+        })
+    }
+}
+
+impl<K: Encode, V: Encode, const N: usize> Encode for Message<K, V, N> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Message::Fingerprint { range, hash } => {
+                0u8.encode(out);
+                range.encode(out);
+                hash.encode(out);
+            }
+            Message::Summary { total, ranges } => {
+                1u8.encode(out);
+                (*total as u64).encode(out);
+                (ranges.len() as u64).encode(out);
+                for (range, hash, count) in ranges {
+                    range.encode(out);
+                    hash.encode(out);
+                    (*count as u64).encode(out);
+                }
+            }
+            Message::Entries {
+                range,
+                entries,
+                reply,
+                hash,
+            } => {
+                2u8.encode(out);
+                range.encode(out);
+                (entries.len() as u64).encode(out);
+                for (key, value) in entries {
+                    key.encode(out);
+                    value.encode(out);
+                }
+                (*reply as u8).encode(out);
+                hash.encode(out);
+            }
+            Message::Since { root } => {
+                3u8.encode(out);
+                root.encode(out);
+            }
+            Message::Delta {
+                since,
+                changes,
+                hash,
+            } => {
+                4u8.encode(out);
+                since.encode(out);
+                (changes.len() as u64).encode(out);
+                for (key, value) in changes {
+                    key.encode(out);
+                    match value {
+                        Some(value) => {
+                            1u8.encode(out);
+                            value.encode(out);
+                        }
+                        None => 0u8.encode(out),
+                    }
+                }
+                hash.encode(out);
+            }
+        }
+    }
+}
+
+impl<K: Decode, V: Decode, const N: usize> Decode for Message<K, V, N> {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        Ok(match u8::decode(input)? {
+            0 => Message::Fingerprint {
+                range: KeyRange::decode(input)?,
+                hash: NodeHash::decode(input)?,
+            },
+            1 => {
+                let total = u64::decode(input)? as usize;
+                let ranges = (0..decode_count(input)?)
+                    .map(|_| {
+                        Ok((
+                            KeyRange::decode(input)?,
+                            NodeHash::decode(input)?,
+                            u64::decode(input)? as usize,
+                        ))
+                    })
+                    .collect::<Result<_, Error>>()?;
+                Message::Summary { total, ranges }
+            }
+            2 => {
+                let range = KeyRange::decode(input)?;
+                let entries = (0..decode_count(input)?)
+                    .map(|_| Ok((K::decode(input)?, V::decode(input)?)))
+                    .collect::<Result<_, Error>>()?;
+                let reply = match u8::decode(input)? {
+                    0 => false,
+                    1 => true,
+                    flag => return Err(Error::Malformed(format!("unknown reply flag {flag}"))),
+                };
+                Message::Entries {
+                    range,
+                    entries,
+                    reply,
+                    hash: NodeHash::decode(input)?,
+                }
+            }
+            3 => Message::Since {
+                root: NodeHash::decode(input)?,
+            },
+            4 => {
+                let since = NodeHash::decode(input)?;
+                let changes = (0..decode_count(input)?)
+                    .map(|_| {
+                        let key = K::decode(input)?;
+                        let value = match u8::decode(input)? {
+                            0 => None,
+                            1 => Some(V::decode(input)?),
+                            tag => {
+                                return Err(Error::Malformed(format!("unknown value tag {tag}")));
+                            }
+                        };
+                        Ok((key, value))
+                    })
+                    .collect::<Result<_, Error>>()?;
+                Message::Delta {
+                    since,
+                    changes,
+                    hash: NodeHash::decode(input)?,
+                }
+            }
+            tag => return Err(Error::Malformed(format!("unknown message tag {tag}"))),
+        })
+    }
+}
+
+// A count written as a u64, which must fit this platform's usize. Counts
+// can't exceed the bytes left, so a corrupt one fails before allocating.
+fn decode_count(input: &mut &[u8]) -> Result<usize, Error> {
+    let count = u64::decode(input)?;
+    if count > input.len() as u64 {
+        return Err(Error::Malformed(format!(
+            "count {count} with {} bytes left",
+            input.len()
+        )));
+    }
+    Ok(count as usize)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_message_round_trip() {
+        let format = WireFormat::new();
+        let messages: Vec<Message<String, String>> = vec![
+            Message::Fingerprint {
+                range: KeyRange {
+                    start: Some("a".to_string()),
+                    end: None,
+                },
+                hash: NodeHash::digest(b"a"),
+            },
+            Message::Summary {
+                total: 3,
+                ranges: vec![(KeyRange::full(), NodeHash::default(), 3)],
+            },
+            Message::Entries {
+                range: KeyRange::full(),
+                entries: vec![("k".to_string(), "v".to_string())],
+                reply: true,
+                hash: NodeHash::digest(b"v"),
+            },
+            Message::Since {
+                root: NodeHash::empty_root(),
+            },
+            Message::Delta {
+                since: NodeHash::empty_root(),
+                changes: vec![
+                    ("k".to_string(), None),
+                    ("j".to_string(), Some("w".to_string())),
+                ],
+                hash: NodeHash::default(),
+            },
+        ];
+        for message in messages {
+            let frame = format.encode_frame(&message).unwrap();
+            assert_eq!(
+                format
+                    .decode_frame::<Message<String, String>>(&frame)
+                    .unwrap(),
+                message
+            );
+        }
+
+        let small = format.with_max_message(8);
+        let message = Message::<String, String>::Since {
+            root: NodeHash::empty_root(),
+        };
+        let frame = format.encode_frame(&message).unwrap();
+        assert!(matches!(
+            small.encode_frame(&message),
+            Err(Error::MessageTooLarge { .. })
+        ));
+        assert!(matches!(
+            small.decode_frame::<Message<String, String>>(&frame),
+            Err(Error::MessageTooLarge { .. })
+        ));
+        assert!(
+            format
+                .decode_frame::<Message<String, String>>(&frame[..frame.len() - 1])
+                .is_err()
+        );
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_compressed_entries() {
+        use crate::core::tree::MerkleSearchTree;
+
+        let mut tree = MerkleSearchTree::<String>::new(8);
+        for i in 0..500 {
+            tree.insert(format!("tenants/acme/users/{i:06}/profile"), format!("{i}"));
+        }
+        let message = Message::<String, String>::Entries {
+            range: KeyRange::full(),
+            entries: tree.iter().map(|(k, v)| (k.clone(), v.clone())).collect(),
+            reply: true,
+            hash: tree.range_hash(..),
+        };
+
+        let plain = WireFormat::new();
+        let ours = WireFormat::new().with_compression(256);
+        let theirs = WireFormat::new()
+            .with_compression(512)
+            .with_max_message(1 << 20);
+        let agreed = ours.negotiate(&theirs);
+        assert_eq!(agreed, theirs.negotiate(&ours));
+        assert!(agreed.compresses());
+        assert_eq!(agreed.max_message(), 1 << 20);
+        assert!(!ours.negotiate(&plain).compresses());
+
+        let raw = plain.encode_frame(&message).unwrap();
+        let packed = agreed.encode_frame(&message).unwrap();
+        assert!(
+            packed.len() < raw.len() / 2,
+            "{} of {}",
+            packed.len(),
+            raw.len()
+        );
+        assert_eq!(
+            agreed
+                .decode_frame::<Message<String, String>>(&packed)
+                .unwrap(),
+            message
+        );
+        // A side that didn't agree to compression refuses compressed frames.
+        assert!(
+            plain
+                .decode_frame::<Message<String, String>>(&packed)
+                .is_err()
+        );
+
+        let mut bytes = Vec::new();
+        agreed.encode(&mut bytes);
+        assert_eq!(WireFormat::decode(&mut bytes.as_slice()).unwrap(), agreed);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_zstd_entries() {
+        let entries = (0..500)
+            .map(|i| (format!("tenants/acme/users/{i:06}/profile"), format!("{i}")))
+            .collect();
+        let message = Message::<String, String>::Entries {
+            range: KeyRange::full(),
+            entries,
+            reply: false,
+            hash: NodeHash::default(),
+        };
+
+        let plain = WireFormat::new();
+        let ours = WireFormat::new().with_zstd(256);
+        let agreed = ours.negotiate(&WireFormat::new().with_zstd(128));
+        assert!(agreed.uses_zstd());
+        assert!(!ours.negotiate(&plain).compresses());
+
+        let raw = plain.encode_frame(&message).unwrap();
+        let packed = agreed.encode_frame(&message).unwrap();
+        assert_eq!(packed[0], ZSTD);
+        assert!(packed.len() < raw.len() / 4);
+        let decoded = agreed.decode_frame::<Message<String, String>>(&packed);
+        assert_eq!(decoded.unwrap(), message);
+        assert!(
+            plain
+                .decode_frame::<Message<String, String>>(&packed)
+                .is_err()
+        );
+
+        // A frame claiming a smaller payload than it holds is refused.
+        let mut short = packed.clone();
+        short[1..5].copy_from_slice(&100u32.to_be_bytes());
+        assert!(
+            agreed
+                .decode_frame::<Message<String, String>>(&short)
+                .is_err()
+        );
+
+        let mut bytes = Vec::new();
+        agreed.encode(&mut bytes);
+        assert_eq!(WireFormat::decode(&mut bytes.as_slice()).unwrap(), agreed);
+    }
+}